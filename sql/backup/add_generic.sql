@@ -0,0 +1,5 @@
+INSERT INTO generic_media (
+    url, ts, duration, title, requested_by, played, played_at, position
+) VALUES (
+    :url, :ts, :duration, :title, :requested_by, :played, :played_at, COALESCE(:position, :ts)
+);
\ No newline at end of file
@@ -0,0 +1,5 @@
+INSERT INTO local_songs (
+    ts, title, artist, album, path, duration, requested_by, played, played_at, position
+) VALUES (
+    :ts, :title, :artist, :album, :path, :duration, :requested_by, :played, :played_at, COALESCE(:position, :ts)
+);
\ No newline at end of file
@@ -0,0 +1,5 @@
+INSERT INTO soundcloud_tracks (
+    permalink, ts, duration, title, artist, requested_by, played, played_at, position
+) VALUES (
+    :permalink, :ts, :duration, :title, :artist, :requested_by, :played, :played_at, COALESCE(:position, :ts)
+);
\ No newline at end of file
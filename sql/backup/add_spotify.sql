@@ -0,0 +1,5 @@
+INSERT INTO spotify_tracks (
+    tid, ts, duration, title, artist, requested_by, played, played_at, position
+) VALUES (
+    :tid, :ts, :duration, :title, :artist, :requested_by, :played, :played_at, COALESCE(:position, :ts)
+);
\ No newline at end of file
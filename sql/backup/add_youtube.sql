@@ -0,0 +1,5 @@
+INSERT INTO youtube_videos (
+    vid, ts, duration, title, start, channel, thumbnail, requested_by, played, played_at, position
+) VALUES (
+    :vid, :ts, :duration, :title, :start, :channel, :thumbnail, :requested_by, :played, :played_at, COALESCE(:position, :ts)
+);
\ No newline at end of file
@@ -0,0 +1,3 @@
+SELECT url, ts, duration, title, requested_by, played, played_at, position
+    FROM generic_media
+    ORDER BY id ASC;
\ No newline at end of file
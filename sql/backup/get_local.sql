@@ -0,0 +1,3 @@
+SELECT ts, title, artist, album, requested_by, played, played_at, position, path, duration
+    FROM local_songs
+    ORDER BY id ASC;
\ No newline at end of file
@@ -0,0 +1,3 @@
+SELECT permalink, ts, duration, title, artist, requested_by, played, played_at, position
+    FROM soundcloud_tracks
+    ORDER BY id ASC;
\ No newline at end of file
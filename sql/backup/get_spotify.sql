@@ -0,0 +1,3 @@
+SELECT tid, ts, duration, title, artist, requested_by, played, played_at, position
+    FROM spotify_tracks
+    ORDER BY id ASC;
\ No newline at end of file
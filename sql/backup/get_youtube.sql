@@ -0,0 +1,3 @@
+SELECT vid, ts, duration, title, start, channel, thumbnail, requested_by, played, played_at, position
+    FROM youtube_videos
+    ORDER BY id ASC;
\ No newline at end of file
@@ -0,0 +1,4 @@
+SELECT EXISTS (
+    SELECT 1 FROM local_songs
+    WHERE title = :title AND artist = :artist AND album = :album
+);
\ No newline at end of file
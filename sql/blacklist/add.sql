@@ -0,0 +1,5 @@
+INSERT OR IGNORE INTO blacklist (
+    kind, value, ts
+) VALUES (
+    :kind, :value, :ts
+);
\ No newline at end of file
@@ -0,0 +1,4 @@
+SELECT EXISTS (
+    SELECT 1 FROM blacklist
+    WHERE kind = :kind AND value = :value
+);
\ No newline at end of file
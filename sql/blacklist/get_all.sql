@@ -0,0 +1,2 @@
+SELECT kind, value, ts FROM blacklist
+    ORDER BY id ASC;
\ No newline at end of file
@@ -0,0 +1,2 @@
+DELETE FROM blacklist
+    WHERE value = :value;
\ No newline at end of file
@@ -0,0 +1,5 @@
+INSERT INTO generic_media (
+    url, ts, duration, title, requested_by, position
+) VALUES (
+    :url, :ts, :duration, :title, :requested_by, :ts
+);
\ No newline at end of file
@@ -0,0 +1,7 @@
+UPDATE generic_media SET played = 1, played_at = :now
+    WHERE id = (
+        SELECT id FROM generic_media
+        WHERE played = 0
+        ORDER BY position ASC, id ASC
+        LIMIT 1
+    );
\ No newline at end of file
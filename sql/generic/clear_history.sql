@@ -0,0 +1,2 @@
+DELETE FROM generic_media
+    WHERE played = 1;
\ No newline at end of file
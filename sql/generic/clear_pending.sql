@@ -0,0 +1,2 @@
+DELETE FROM generic_media
+    WHERE played = 0;
\ No newline at end of file
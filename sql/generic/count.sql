@@ -0,0 +1 @@
+SELECT COUNT(*) FROM generic_media;
\ No newline at end of file
@@ -0,0 +1,4 @@
+SELECT EXISTS (
+    SELECT 1 FROM generic_media
+    WHERE url = :url AND ts >= :since
+);
\ No newline at end of file
@@ -0,0 +1,2 @@
+SELECT * FROM generic_media
+    WHERE id = :id;
\ No newline at end of file
@@ -0,0 +1,3 @@
+SELECT * FROM generic_media
+    ORDER BY id ASC
+LIMIT :limit OFFSET :offset;
\ No newline at end of file
@@ -0,0 +1,3 @@
+SELECT * FROM generic_media
+    WHERE played = 0
+    ORDER BY position ASC, id ASC;
\ No newline at end of file
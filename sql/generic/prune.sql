@@ -0,0 +1,2 @@
+DELETE FROM generic_media
+    WHERE played = 1 AND COALESCE(played_at, ts) < :before;
\ No newline at end of file
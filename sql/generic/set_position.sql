@@ -0,0 +1,2 @@
+UPDATE generic_media SET position = :position
+    WHERE id = :id;
\ No newline at end of file
@@ -0,0 +1,5 @@
+INSERT OR REPLACE INTO idempotency_keys (
+    key, ts, status, body
+) VALUES (
+    :key, :ts, :status, :body
+);
\ No newline at end of file
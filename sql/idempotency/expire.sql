@@ -0,0 +1,2 @@
+DELETE FROM idempotency_keys
+    WHERE ts < :before;
\ No newline at end of file
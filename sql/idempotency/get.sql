@@ -0,0 +1,2 @@
+SELECT status, body FROM idempotency_keys
+    WHERE key = :key;
\ No newline at end of file
@@ -1,5 +1,5 @@
 INSERT INTO local_songs (
-    ts, title, artist, album
+    ts, title, artist, album, path, duration, requested_by, position
 ) VALUES (
-    :ts, :title, :artist, :album
+    :ts, :title, :artist, :album, :path, :duration, :requested_by, :ts
 );
\ No newline at end of file
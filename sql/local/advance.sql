@@ -0,0 +1,7 @@
+UPDATE local_songs SET played = 1, played_at = :now
+    WHERE id = (
+        SELECT id FROM local_songs
+        WHERE played = 0
+        ORDER BY position ASC, id ASC
+        LIMIT 1
+    );
\ No newline at end of file
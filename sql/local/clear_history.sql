@@ -0,0 +1,2 @@
+DELETE FROM local_songs
+    WHERE played = 1;
\ No newline at end of file
@@ -0,0 +1,2 @@
+DELETE FROM local_songs
+    WHERE played = 0;
\ No newline at end of file
@@ -0,0 +1 @@
+SELECT COUNT(*) FROM local_songs;
\ No newline at end of file
@@ -0,0 +1,2 @@
+DELETE FROM local_songs
+    WHERE id = :id;
\ No newline at end of file
@@ -0,0 +1,2 @@
+SELECT * FROM local_songs
+    WHERE id = :id;
\ No newline at end of file
@@ -1 +1,3 @@
-SELECT * FROM local_songs;
\ No newline at end of file
+SELECT * FROM local_songs
+    ORDER BY id ASC
+LIMIT :limit OFFSET :offset;
\ No newline at end of file
@@ -1,3 +1,4 @@
-SELECT * FROM local_songs 
-    ORDER BY id DESC 
+SELECT * FROM local_songs
+    WHERE played = 0
+    ORDER BY position ASC, id ASC
 LIMIT 1;
\ No newline at end of file
@@ -0,0 +1,3 @@
+SELECT * FROM local_songs
+    WHERE played = 0
+    ORDER BY position ASC, id ASC;
\ No newline at end of file
@@ -0,0 +1,4 @@
+SELECT id, position FROM local_songs
+    WHERE played = 0
+    ORDER BY position ASC, id ASC
+LIMIT -1 OFFSET :skip;
\ No newline at end of file
@@ -1,7 +1,4 @@
-SELECT * FROM (
-    SELECT * FROM local_songs 
-    ORDER BY id DESC 
-    LIMIT 2
-) 
-ORDER BY id ASC 
+SELECT * FROM local_songs
+    WHERE played = 1
+    ORDER BY played_at DESC, id DESC
 LIMIT 1;
\ No newline at end of file
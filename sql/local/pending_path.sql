@@ -0,0 +1,4 @@
+SELECT EXISTS(
+    SELECT 1 FROM local_songs
+    WHERE played = 0 AND path = :path
+);
\ No newline at end of file
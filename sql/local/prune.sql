@@ -0,0 +1,2 @@
+DELETE FROM local_songs
+    WHERE played = 1 AND COALESCE(played_at, ts) < :before;
\ No newline at end of file
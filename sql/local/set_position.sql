@@ -0,0 +1,2 @@
+UPDATE local_songs SET position = :position
+    WHERE id = :id;
\ No newline at end of file
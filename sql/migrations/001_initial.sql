@@ -1,5 +1,3 @@
-BEGIN;
-
 CREATE TABLE IF NOT EXISTS `youtube_videos` (
 	`id`		INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT UNIQUE,
 	`vid`	    TEXT NOT NULL,
@@ -14,6 +12,4 @@ CREATE TABLE IF NOT EXISTS `local_songs` (
 	`title`	    TEXT NOT NULL,
 	`artist`	TEXT NOT NULL,
 	`album`	    TEXT NOT NULL
-);
-
-COMMIT;
\ No newline at end of file
+);
\ No newline at end of file
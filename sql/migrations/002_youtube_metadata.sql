@@ -0,0 +1,3 @@
+ALTER TABLE `youtube_videos` ADD COLUMN `start` INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE `youtube_videos` ADD COLUMN `channel` TEXT NOT NULL DEFAULT '';
+ALTER TABLE `youtube_videos` ADD COLUMN `thumbnail` TEXT NOT NULL DEFAULT '';
\ No newline at end of file
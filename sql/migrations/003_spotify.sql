@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS `spotify_tracks` (
+	`id`		INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT UNIQUE,
+	`tid`	    TEXT NOT NULL,
+	`ts`    	INTEGER NOT NULL,
+	`duration`	INTEGER NOT NULL,
+	`title`	    TEXT NOT NULL,
+	`artist`	TEXT NOT NULL
+);
\ No newline at end of file
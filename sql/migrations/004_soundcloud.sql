@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS `soundcloud_tracks` (
+	`id`		INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT UNIQUE,
+	`permalink`	TEXT NOT NULL,
+	`ts`    	INTEGER NOT NULL,
+	`duration`	INTEGER NOT NULL,
+	`title`	    TEXT NOT NULL,
+	`artist`	TEXT NOT NULL
+);
\ No newline at end of file
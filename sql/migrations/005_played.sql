@@ -0,0 +1,11 @@
+-- `played` is set once playback moves past a song, the oldest unplayed song is the current one
+ALTER TABLE `youtube_videos` ADD COLUMN `played` INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE `local_songs` ADD COLUMN `played` INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE `spotify_tracks` ADD COLUMN `played` INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE `soundcloud_tracks` ADD COLUMN `played` INTEGER NOT NULL DEFAULT 0;
+
+-- the newest request was the current song before this, so everything older counts as played
+UPDATE `youtube_videos` SET `played` = 1 WHERE `id` < (SELECT MAX(`id`) FROM `youtube_videos`);
+UPDATE `local_songs` SET `played` = 1 WHERE `id` < (SELECT MAX(`id`) FROM `local_songs`);
+UPDATE `spotify_tracks` SET `played` = 1 WHERE `id` < (SELECT MAX(`id`) FROM `spotify_tracks`);
+UPDATE `soundcloud_tracks` SET `played` = 1 WHERE `id` < (SELECT MAX(`id`) FROM `soundcloud_tracks`);
\ No newline at end of file
@@ -0,0 +1,4 @@
+ALTER TABLE `youtube_videos` ADD COLUMN `requested_by` TEXT NOT NULL DEFAULT '';
+ALTER TABLE `local_songs` ADD COLUMN `requested_by` TEXT NOT NULL DEFAULT '';
+ALTER TABLE `spotify_tracks` ADD COLUMN `requested_by` TEXT NOT NULL DEFAULT '';
+ALTER TABLE `soundcloud_tracks` ADD COLUMN `requested_by` TEXT NOT NULL DEFAULT '';
\ No newline at end of file
@@ -0,0 +1,8 @@
+-- `kind` is either 'video' (a youtube, spotify or soundcloud id) or 'user' (a requester)
+CREATE TABLE IF NOT EXISTS `blacklist` (
+	`id`		INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT UNIQUE,
+	`kind`	    TEXT NOT NULL,
+	`value`	    TEXT NOT NULL,
+	`ts`    	INTEGER NOT NULL,
+	UNIQUE (`kind`, `value`)
+);
\ No newline at end of file
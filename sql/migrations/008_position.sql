@@ -0,0 +1,12 @@
+-- the queue is played in `position` order instead of `ts` order. it starts out as the request
+-- timestamp, so new requests still go to the end, and a shuffle permutes the positions of the
+-- pending songs between themselves. played songs keep theirs
+ALTER TABLE `youtube_videos` ADD COLUMN `position` INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE `local_songs` ADD COLUMN `position` INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE `spotify_tracks` ADD COLUMN `position` INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE `soundcloud_tracks` ADD COLUMN `position` INTEGER NOT NULL DEFAULT 0;
+
+UPDATE `youtube_videos` SET `position` = `ts`;
+UPDATE `local_songs` SET `position` = `ts`;
+UPDATE `spotify_tracks` SET `position` = `ts`;
+UPDATE `soundcloud_tracks` SET `position` = `ts`;
\ No newline at end of file
@@ -0,0 +1,7 @@
+-- responses to inserts that carried an `Idempotency-Key` header, replayed for retries
+CREATE TABLE IF NOT EXISTS `idempotency_keys` (
+	`key`		TEXT NOT NULL PRIMARY KEY,
+	`ts`		INTEGER NOT NULL,
+	`status`	INTEGER NOT NULL,
+	`body`		BLOB NOT NULL
+);
\ No newline at end of file
@@ -0,0 +1,6 @@
+-- unix time of when `advance` marked the song played, null for songs that haven't played
+-- or that were played before the column existed
+ALTER TABLE `youtube_videos` ADD COLUMN `played_at` INTEGER;
+ALTER TABLE `local_songs` ADD COLUMN `played_at` INTEGER;
+ALTER TABLE `spotify_tracks` ADD COLUMN `played_at` INTEGER;
+ALTER TABLE `soundcloud_tracks` ADD COLUMN `played_at` INTEGER;
\ No newline at end of file
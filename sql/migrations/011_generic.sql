@@ -0,0 +1,12 @@
+-- links to anything else, the metadata comes from the request since there's nothing to look it up with
+CREATE TABLE IF NOT EXISTS `generic_media` (
+	`id`		    INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT UNIQUE,
+	`url`		    TEXT NOT NULL,
+	`ts`    	    INTEGER NOT NULL,
+	`duration`	    INTEGER NOT NULL,
+	`title`	        TEXT NOT NULL,
+	`requested_by`	TEXT NOT NULL DEFAULT '',
+	`played`	    INTEGER NOT NULL DEFAULT 0,
+	`position`	    INTEGER NOT NULL DEFAULT 0,
+	`played_at`	    INTEGER
+);
\ No newline at end of file
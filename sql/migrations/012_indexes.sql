@@ -0,0 +1,21 @@
+-- the queue and history are read by played and position, duplicate checks by id and
+-- request time, the per-requester limits by requester
+CREATE INDEX IF NOT EXISTS `youtube_videos_played_position` ON `youtube_videos` (`played`, `position`);
+CREATE INDEX IF NOT EXISTS `youtube_videos_vid_ts` ON `youtube_videos` (`vid`, `ts`);
+CREATE INDEX IF NOT EXISTS `youtube_videos_ts` ON `youtube_videos` (`ts`);
+CREATE INDEX IF NOT EXISTS `youtube_videos_requested_by` ON `youtube_videos` (`requested_by`, `played`);
+CREATE INDEX IF NOT EXISTS `local_songs_played_position` ON `local_songs` (`played`, `position`);
+CREATE INDEX IF NOT EXISTS `local_songs_ts` ON `local_songs` (`ts`);
+CREATE INDEX IF NOT EXISTS `local_songs_requested_by` ON `local_songs` (`requested_by`, `played`);
+CREATE INDEX IF NOT EXISTS `spotify_tracks_played_position` ON `spotify_tracks` (`played`, `position`);
+CREATE INDEX IF NOT EXISTS `spotify_tracks_tid_ts` ON `spotify_tracks` (`tid`, `ts`);
+CREATE INDEX IF NOT EXISTS `spotify_tracks_ts` ON `spotify_tracks` (`ts`);
+CREATE INDEX IF NOT EXISTS `spotify_tracks_requested_by` ON `spotify_tracks` (`requested_by`, `played`);
+CREATE INDEX IF NOT EXISTS `soundcloud_tracks_played_position` ON `soundcloud_tracks` (`played`, `position`);
+CREATE INDEX IF NOT EXISTS `soundcloud_tracks_permalink_ts` ON `soundcloud_tracks` (`permalink`, `ts`);
+CREATE INDEX IF NOT EXISTS `soundcloud_tracks_ts` ON `soundcloud_tracks` (`ts`);
+CREATE INDEX IF NOT EXISTS `soundcloud_tracks_requested_by` ON `soundcloud_tracks` (`requested_by`, `played`);
+CREATE INDEX IF NOT EXISTS `generic_media_played_position` ON `generic_media` (`played`, `position`);
+CREATE INDEX IF NOT EXISTS `generic_media_url_ts` ON `generic_media` (`url`, `ts`);
+CREATE INDEX IF NOT EXISTS `generic_media_ts` ON `generic_media` (`ts`);
+CREATE INDEX IF NOT EXISTS `generic_media_requested_by` ON `generic_media` (`requested_by`, `played`);
\ No newline at end of file
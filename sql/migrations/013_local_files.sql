@@ -0,0 +1,4 @@
+-- the file a local song was requested from, null for songs that were only described.
+-- the duration comes from the file's tags, 0 when they don't have one
+ALTER TABLE `local_songs` ADD COLUMN `path` TEXT;
+ALTER TABLE `local_songs` ADD COLUMN `duration` INTEGER NOT NULL DEFAULT 0;
\ No newline at end of file
@@ -0,0 +1,5 @@
+INSERT INTO soundcloud_tracks (
+    permalink, ts, duration, title, artist, requested_by, position
+) VALUES (
+    :permalink, :ts, :duration, :title, :artist, :requested_by, :ts
+);
\ No newline at end of file
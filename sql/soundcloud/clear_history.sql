@@ -0,0 +1,2 @@
+DELETE FROM soundcloud_tracks
+    WHERE played = 1;
\ No newline at end of file
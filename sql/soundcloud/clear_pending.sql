@@ -0,0 +1,2 @@
+DELETE FROM soundcloud_tracks
+    WHERE played = 0;
\ No newline at end of file
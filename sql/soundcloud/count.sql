@@ -0,0 +1 @@
+SELECT COUNT(*) FROM soundcloud_tracks;
\ No newline at end of file
@@ -0,0 +1,4 @@
+SELECT EXISTS (
+    SELECT 1 FROM soundcloud_tracks
+    WHERE permalink = :permalink AND ts >= :since
+);
\ No newline at end of file
@@ -0,0 +1,2 @@
+UPDATE soundcloud_tracks SET position = :position
+    WHERE id = :id;
\ No newline at end of file
@@ -0,0 +1,5 @@
+INSERT INTO spotify_tracks (
+    tid, ts, duration, title, artist, requested_by, position
+) VALUES (
+    :tid, :ts, :duration, :title, :artist, :requested_by, :ts
+);
\ No newline at end of file
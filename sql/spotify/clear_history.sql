@@ -0,0 +1,2 @@
+DELETE FROM spotify_tracks
+    WHERE played = 1;
\ No newline at end of file
@@ -0,0 +1 @@
+SELECT COUNT(*) FROM spotify_tracks;
\ No newline at end of file
@@ -0,0 +1,2 @@
+DELETE FROM spotify_tracks
+    WHERE id = :id;
\ No newline at end of file
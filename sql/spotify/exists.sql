@@ -0,0 +1,4 @@
+SELECT EXISTS (
+    SELECT 1 FROM spotify_tracks
+    WHERE tid = :tid AND ts >= :since
+);
\ No newline at end of file
@@ -0,0 +1,3 @@
+SELECT * FROM spotify_tracks
+    ORDER BY id ASC
+LIMIT :limit OFFSET :offset;
\ No newline at end of file
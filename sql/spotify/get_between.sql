@@ -0,0 +1,3 @@
+SELECT * FROM spotify_tracks
+    WHERE ts BETWEEN :start AND :end
+    ORDER BY ts ASC;
\ No newline at end of file
@@ -0,0 +1,4 @@
+SELECT * FROM spotify_tracks
+    WHERE played = 0
+    ORDER BY position ASC, id ASC
+LIMIT 1;
\ No newline at end of file
@@ -0,0 +1,4 @@
+SELECT * FROM spotify_tracks
+    WHERE played = 1
+    ORDER BY played_at DESC, id DESC
+LIMIT :limit;
\ No newline at end of file
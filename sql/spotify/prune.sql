@@ -0,0 +1,2 @@
+DELETE FROM spotify_tracks
+    WHERE played = 1 AND COALESCE(played_at, ts) < :before;
\ No newline at end of file
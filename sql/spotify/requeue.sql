@@ -0,0 +1,2 @@
+UPDATE spotify_tracks SET played = 0, played_at = NULL, position = :position
+    WHERE id = :id;
\ No newline at end of file
@@ -0,0 +1,2 @@
+UPDATE spotify_tracks SET position = :position
+    WHERE id = :id;
\ No newline at end of file
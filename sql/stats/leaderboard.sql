@@ -0,0 +1,15 @@
+SELECT requested_by, COUNT(*), SUM(duration) FROM (
+    SELECT requested_by, ts, duration FROM youtube_videos
+    UNION ALL
+    SELECT requested_by, ts, duration FROM local_songs
+    UNION ALL
+    SELECT requested_by, ts, duration FROM spotify_tracks
+    UNION ALL
+    SELECT requested_by, ts, duration FROM soundcloud_tracks
+    UNION ALL
+    SELECT requested_by, ts, duration FROM generic_media
+)
+    WHERE requested_by != '' AND ts BETWEEN :start AND :end
+    GROUP BY requested_by
+    ORDER BY COUNT(*) DESC, SUM(duration) DESC, requested_by ASC
+LIMIT :limit;
\ No newline at end of file
@@ -0,0 +1,6 @@
+SELECT
+    (SELECT COUNT(*) FROM youtube_videos WHERE played = 0)
+    + (SELECT COUNT(*) FROM local_songs WHERE played = 0)
+    + (SELECT COUNT(*) FROM spotify_tracks WHERE played = 0)
+    + (SELECT COUNT(*) FROM soundcloud_tracks WHERE played = 0)
+    + (SELECT COUNT(*) FROM generic_media WHERE played = 0);
\ No newline at end of file
@@ -0,0 +1,6 @@
+SELECT
+    (SELECT COUNT(*) FROM youtube_videos WHERE played = 0 AND requested_by = :requested_by)
+    + (SELECT COUNT(*) FROM local_songs WHERE played = 0 AND requested_by = :requested_by)
+    + (SELECT COUNT(*) FROM spotify_tracks WHERE played = 0 AND requested_by = :requested_by)
+    + (SELECT COUNT(*) FROM soundcloud_tracks WHERE played = 0 AND requested_by = :requested_by)
+    + (SELECT COUNT(*) FROM generic_media WHERE played = 0 AND requested_by = :requested_by);
\ No newline at end of file
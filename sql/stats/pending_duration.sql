@@ -0,0 +1,6 @@
+SELECT
+    (SELECT COALESCE(SUM(duration), 0) FROM youtube_videos WHERE played = 0)
+    + (SELECT COALESCE(SUM(duration), 0) FROM local_songs WHERE played = 0)
+    + (SELECT COALESCE(SUM(duration), 0) FROM spotify_tracks WHERE played = 0)
+    + (SELECT COALESCE(SUM(duration), 0) FROM soundcloud_tracks WHERE played = 0)
+    + (SELECT COALESCE(SUM(duration), 0) FROM generic_media WHERE played = 0);
\ No newline at end of file
@@ -1,5 +1,5 @@
 INSERT INTO youtube_videos (
-    vid, ts, duration, title
+    vid, ts, duration, title, start, channel, thumbnail, requested_by, position
 ) VALUES (
-    :vid, :ts, :duration, :title
+    :vid, :ts, :duration, :title, :start, :channel, :thumbnail, :requested_by, :ts
 );
\ No newline at end of file
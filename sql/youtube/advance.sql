@@ -0,0 +1,7 @@
+UPDATE youtube_videos SET played = 1, played_at = :now
+    WHERE id = (
+        SELECT id FROM youtube_videos
+        WHERE played = 0
+        ORDER BY position ASC, id ASC
+        LIMIT 1
+    );
\ No newline at end of file
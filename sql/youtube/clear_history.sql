@@ -0,0 +1,2 @@
+DELETE FROM youtube_videos
+    WHERE played = 1;
\ No newline at end of file
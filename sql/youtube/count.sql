@@ -0,0 +1 @@
+SELECT COUNT(*) FROM youtube_videos;
\ No newline at end of file
@@ -0,0 +1,2 @@
+DELETE FROM youtube_videos
+    WHERE id = :id;
\ No newline at end of file
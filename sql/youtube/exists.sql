@@ -0,0 +1,4 @@
+SELECT EXISTS (
+    SELECT 1 FROM youtube_videos
+    WHERE vid = :vid AND ts >= :since
+);
\ No newline at end of file
@@ -0,0 +1,2 @@
+SELECT * FROM youtube_videos
+    WHERE id = :id;
\ No newline at end of file
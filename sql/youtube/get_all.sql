@@ -1 +1,3 @@
-SELECT * FROM youtube_videos;
\ No newline at end of file
+SELECT * FROM youtube_videos
+    ORDER BY id ASC
+LIMIT :limit OFFSET :offset;
\ No newline at end of file
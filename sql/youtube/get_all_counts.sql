@@ -0,0 +1,5 @@
+SELECT v.*, c.requested FROM youtube_videos v
+    JOIN (SELECT vid, COUNT(*) AS requested FROM youtube_videos GROUP BY vid) c
+        ON c.vid = v.vid
+    ORDER BY v.id ASC
+LIMIT :limit OFFSET :offset;
\ No newline at end of file
@@ -1,3 +1,4 @@
-SELECT * FROM youtube_videos 
-    ORDER BY id DESC 
+SELECT * FROM youtube_videos
+    WHERE played = 0
+    ORDER BY position ASC, id ASC
 LIMIT 1;
\ No newline at end of file
@@ -0,0 +1,2 @@
+UPDATE youtube_videos SET played = 0, played_at = NULL, position = :position
+    WHERE id = :id;
\ No newline at end of file
@@ -0,0 +1,2 @@
+UPDATE youtube_videos SET position = :position
+    WHERE id = :id;
\ No newline at end of file
@@ -0,0 +1,153 @@
+use std::path::PathBuf;
+
+use crate::config::Config;
+
+pub const USAGE: &str = "\
+usage: dono-server [options]
+
+options:
+    --config <path>          read the config from <path> instead of the project config dir
+    --address <address>      overrides `address`
+    --port <port>            overrides `port`
+    --database-path <path>   overrides `database_path`, `:memory:` keeps nothing on disk
+    --print-config           print the config after every override is applied and exit
+    -h, --help               print this message";
+
+// flags win over both the config file and `DONO_` env vars
+#[derive(Debug, Default)]
+pub struct Args {
+    pub help: bool,
+    pub print_config: bool,
+    pub config: Option<PathBuf>,
+    pub address: Option<String>,
+    pub port: Option<u16>,
+    pub database_path: Option<PathBuf>,
+}
+
+impl Args {
+    // accepts both `--flag value` and `--flag=value`
+    pub fn parse<I>(args: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut out = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, value) = match arg.find('=') {
+                Some(pos) if arg.starts_with("--") => {
+                    (arg[..pos].to_string(), Some(arg[pos + 1..].to_string()))
+                }
+                _ => (arg, None),
+            };
+
+            if flag == "-h" || flag == "--help" {
+                out.help = true;
+                continue;
+            }
+            if flag == "--print-config" {
+                out.print_config = true;
+                continue;
+            }
+
+            let value = || {
+                value
+                    .or_else(|| args.next())
+                    .ok_or_else(|| format!("missing value for {}", flag))
+            };
+            match flag.as_str() {
+                "--config" => out.config = Some(value()?.into()),
+                "--address" => out.address = Some(value()?),
+                "--port" => {
+                    let port = value()?;
+                    out.port = Some(
+                        port.parse()
+                            .map_err(|_| format!("invalid port: {}", port))?,
+                    )
+                }
+                "--database-path" => out.database_path = Some(value()?.into()),
+                _ => return Err(format!("unknown argument: {}", flag)),
+            }
+        }
+        Ok(out)
+    }
+
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(address) = &self.address {
+            config.address = address.clone();
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(path) = &self.database_path {
+            config.database_path = Some(path.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        Args::parse(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn flags() {
+        let args = parse(&[
+            "--config",
+            "/etc/dono.toml",
+            "--port=8080",
+            "--address",
+            "0.0.0.0",
+            "--database-path=:memory:",
+        ])
+        .unwrap();
+        assert_eq!(args.config, Some(PathBuf::from("/etc/dono.toml")));
+        assert_eq!(args.port, Some(8080));
+        assert_eq!(args.address.as_deref(), Some("0.0.0.0"));
+        assert_eq!(args.database_path, Some(PathBuf::from(":memory:")));
+        assert!(!args.help);
+
+        assert!(parse(&["-h"]).unwrap().help);
+        assert!(parse(&["--help"]).unwrap().help);
+        let none = parse(&[]).unwrap();
+        assert!(none.config.is_none() && none.port.is_none());
+    }
+
+    #[test]
+    fn bad_flags() {
+        assert_eq!(parse(&["--port"]).unwrap_err(), "missing value for --port");
+        assert_eq!(
+            parse(&["--port", "http"]).unwrap_err(),
+            "invalid port: http"
+        );
+        assert_eq!(parse(&["--port=70000"]).unwrap_err(), "invalid port: 70000");
+        assert_eq!(
+            parse(&["--verbose"]).unwrap_err(),
+            "unknown argument: --verbose"
+        );
+        assert_eq!(
+            parse(&["config.toml"]).unwrap_err(),
+            "unknown argument: config.toml"
+        );
+    }
+
+    #[test]
+    fn flags_override_the_config() {
+        let mut config = Config {
+            address: "127.0.0.1".into(),
+            port: 1234,
+            ..Config::default()
+        };
+        parse(&["--port", "8080"]).unwrap().apply(&mut config);
+        assert_eq!((config.address.as_str(), config.port), ("127.0.0.1", 8080));
+        assert_eq!(config.database_path, None);
+
+        parse(&["--address=0.0.0.0", "--database-path", "/tmp/dono.db"])
+            .unwrap()
+            .apply(&mut config);
+        assert_eq!((config.address.as_str(), config.port), ("0.0.0.0", 8080));
+        assert_eq!(config.database_path, Some(PathBuf::from("/tmp/dono.db")));
+    }
+}
@@ -0,0 +1,527 @@
+use serde::{Deserialize, Serialize};
+
+use crate::database;
+use crate::error::{Error, Result};
+
+// the whole song database, one list per table in insertion order. row ids are
+// not kept, they're reassigned on import
+//
+// {
+//   "version": 1,
+//   "youtube": [{"vid", "ts", "duration", "title", "start", "channel", "thumbnail", "requested_by", "played", "played_at", "position"}],
+//   "local": [{"ts", "title", "artist", "album", "path", "duration", "requested_by", "played", "played_at", "position"}],
+//   "spotify": [{"tid", "ts", "duration", "title", "artist", "requested_by", "played", "played_at", "position"}],
+//   "soundcloud": [{"permalink", "ts", "duration", "title", "artist", "requested_by", "played", "played_at", "position"}],
+//   "generic": [{"url", "ts", "duration", "title", "requested_by", "played", "played_at", "position"}]
+// }
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Backup {
+    pub version: u32,
+    #[serde(default)]
+    pub youtube: Vec<Youtube>,
+    #[serde(default)]
+    pub local: Vec<Local>,
+    #[serde(default)]
+    pub spotify: Vec<Spotify>,
+    #[serde(default)]
+    pub soundcloud: Vec<SoundCloud>,
+    #[serde(default)]
+    pub generic: Vec<Generic>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Youtube {
+    pub vid: String,
+    pub ts: i64,
+    pub duration: i64,
+    pub title: String,
+    #[serde(default)]
+    pub start: i64,
+    #[serde(default)]
+    pub channel: String,
+    #[serde(default)]
+    pub thumbnail: String,
+    #[serde(default)]
+    pub requested_by: String,
+    #[serde(default)]
+    pub played: bool,
+    #[serde(default)]
+    pub played_at: Option<i64>,
+    // backups from before it was exported are queued in request order
+    #[serde(default)]
+    pub position: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Local {
+    pub ts: i64,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub duration: i64,
+    #[serde(default)]
+    pub requested_by: String,
+    #[serde(default)]
+    pub played: bool,
+    #[serde(default)]
+    pub played_at: Option<i64>,
+    pub position: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Spotify {
+    pub tid: String,
+    pub ts: i64,
+    pub duration: i64,
+    pub title: String,
+    pub artist: String,
+    #[serde(default)]
+    pub requested_by: String,
+    #[serde(default)]
+    pub played: bool,
+    #[serde(default)]
+    pub played_at: Option<i64>,
+    pub position: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SoundCloud {
+    pub permalink: String,
+    pub ts: i64,
+    pub duration: i64,
+    pub title: String,
+    pub artist: String,
+    #[serde(default)]
+    pub requested_by: String,
+    #[serde(default)]
+    pub played: bool,
+    #[serde(default)]
+    pub played_at: Option<i64>,
+    pub position: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Generic {
+    pub url: String,
+    pub ts: i64,
+    pub duration: i64,
+    pub title: String,
+    #[serde(default)]
+    pub requested_by: String,
+    #[serde(default)]
+    pub played: bool,
+    #[serde(default)]
+    pub played_at: Option<i64>,
+    pub position: Option<i64>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Summary {
+    pub imported: usize,
+    pub skipped: usize,
+}
+
+pub fn export() -> Result<Backup> {
+    let conn = database::get_connection();
+
+    let youtube = conn
+        .prepare(include_str!("../sql/backup/get_youtube.sql"))?
+        .query_map(rusqlite::NO_PARAMS, |row| Youtube {
+            vid: row.get(0),
+            ts: row.get(1),
+            duration: row.get(2),
+            title: row.get(3),
+            start: row.get(4),
+            channel: row.get(5),
+            thumbnail: row.get(6),
+            requested_by: row.get(7),
+            played: row.get(8),
+            played_at: row.get(9),
+            position: row.get(10),
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let local = conn
+        .prepare(include_str!("../sql/backup/get_local.sql"))?
+        .query_map(rusqlite::NO_PARAMS, |row| Local {
+            ts: row.get(0),
+            title: row.get(1),
+            artist: row.get(2),
+            album: row.get(3),
+            requested_by: row.get(4),
+            played: row.get(5),
+            played_at: row.get(6),
+            position: row.get(7),
+            path: row.get(8),
+            duration: row.get(9),
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let spotify = conn
+        .prepare(include_str!("../sql/backup/get_spotify.sql"))?
+        .query_map(rusqlite::NO_PARAMS, |row| Spotify {
+            tid: row.get(0),
+            ts: row.get(1),
+            duration: row.get(2),
+            title: row.get(3),
+            artist: row.get(4),
+            requested_by: row.get(5),
+            played: row.get(6),
+            played_at: row.get(7),
+            position: row.get(8),
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let soundcloud = conn
+        .prepare(include_str!("../sql/backup/get_soundcloud.sql"))?
+        .query_map(rusqlite::NO_PARAMS, |row| SoundCloud {
+            permalink: row.get(0),
+            ts: row.get(1),
+            duration: row.get(2),
+            title: row.get(3),
+            artist: row.get(4),
+            requested_by: row.get(5),
+            played: row.get(6),
+            played_at: row.get(7),
+            position: row.get(8),
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let generic = conn
+        .prepare(include_str!("../sql/backup/get_generic.sql"))?
+        .query_map(rusqlite::NO_PARAMS, |row| Generic {
+            url: row.get(0),
+            ts: row.get(1),
+            duration: row.get(2),
+            title: row.get(3),
+            requested_by: row.get(4),
+            played: row.get(5),
+            played_at: row.get(6),
+            position: row.get(7),
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    Ok(Backup {
+        version: 1,
+        youtube,
+        local,
+        spotify,
+        soundcloud,
+        generic,
+    })
+}
+
+// everything is inserted in one transaction, a bad record rolls back the whole batch.
+// with `skip_duplicates` songs that are already stored are left out instead
+pub fn import(backup: &Backup, skip_duplicates: bool) -> Result<Summary> {
+    if backup.version != 1 {
+        return Err(Error::UnsupportedVersion(backup.version));
+    }
+
+    let mut conn = database::get_connection();
+    let tx = conn.transaction()?;
+    let mut summary = Summary::default();
+
+    // the per-kind existence checks only look at requests made since `since`
+    let since = i64::MIN;
+
+    for (i, song) in backup.youtube.iter().enumerate() {
+        if song.vid.is_empty() || song.duration < 0 {
+            return Err(Error::InvalidBackup(format!("youtube entry {}", i)));
+        }
+        if skip_duplicates
+            && tx.query_row_named(
+                include_str!("../sql/youtube/exists.sql"),
+                &[(":vid", &song.vid), (":since", &since)],
+                |row| row.get(0),
+            )?
+        {
+            summary.skipped += 1;
+            continue;
+        }
+        tx.execute_named(
+            include_str!("../sql/backup/add_youtube.sql"),
+            &[
+                (":vid", &song.vid),
+                (":ts", &song.ts),
+                (":duration", &song.duration),
+                (":title", &song.title),
+                (":start", &song.start),
+                (":channel", &song.channel),
+                (":thumbnail", &song.thumbnail),
+                (":requested_by", &song.requested_by),
+                (":played", &song.played),
+                (":played_at", &song.played_at),
+                (":position", &song.position),
+            ],
+        )?;
+        summary.imported += 1;
+    }
+
+    for (i, song) in backup.local.iter().enumerate() {
+        if song.title.is_empty() {
+            return Err(Error::InvalidBackup(format!("local entry {}", i)));
+        }
+        if skip_duplicates
+            && tx.query_row_named(
+                include_str!("../sql/backup/local_exists.sql"),
+                &[
+                    (":title", &song.title),
+                    (":artist", &song.artist),
+                    (":album", &song.album),
+                ],
+                |row| row.get(0),
+            )?
+        {
+            summary.skipped += 1;
+            continue;
+        }
+        tx.execute_named(
+            include_str!("../sql/backup/add_local.sql"),
+            &[
+                (":ts", &song.ts),
+                (":title", &song.title),
+                (":artist", &song.artist),
+                (":album", &song.album),
+                (":path", &song.path),
+                (":duration", &song.duration),
+                (":requested_by", &song.requested_by),
+                (":played", &song.played),
+                (":played_at", &song.played_at),
+                (":position", &song.position),
+            ],
+        )?;
+        summary.imported += 1;
+    }
+
+    for (i, song) in backup.spotify.iter().enumerate() {
+        if song.tid.is_empty() || song.duration < 0 {
+            return Err(Error::InvalidBackup(format!("spotify entry {}", i)));
+        }
+        if skip_duplicates
+            && tx.query_row_named(
+                include_str!("../sql/spotify/exists.sql"),
+                &[(":tid", &song.tid), (":since", &since)],
+                |row| row.get(0),
+            )?
+        {
+            summary.skipped += 1;
+            continue;
+        }
+        tx.execute_named(
+            include_str!("../sql/backup/add_spotify.sql"),
+            &[
+                (":tid", &song.tid),
+                (":ts", &song.ts),
+                (":duration", &song.duration),
+                (":title", &song.title),
+                (":artist", &song.artist),
+                (":requested_by", &song.requested_by),
+                (":played", &song.played),
+                (":played_at", &song.played_at),
+                (":position", &song.position),
+            ],
+        )?;
+        summary.imported += 1;
+    }
+
+    for (i, song) in backup.soundcloud.iter().enumerate() {
+        if song.permalink.is_empty() || song.duration < 0 {
+            return Err(Error::InvalidBackup(format!("soundcloud entry {}", i)));
+        }
+        if skip_duplicates
+            && tx.query_row_named(
+                include_str!("../sql/soundcloud/exists.sql"),
+                &[(":permalink", &song.permalink), (":since", &since)],
+                |row| row.get(0),
+            )?
+        {
+            summary.skipped += 1;
+            continue;
+        }
+        tx.execute_named(
+            include_str!("../sql/backup/add_soundcloud.sql"),
+            &[
+                (":permalink", &song.permalink),
+                (":ts", &song.ts),
+                (":duration", &song.duration),
+                (":title", &song.title),
+                (":artist", &song.artist),
+                (":requested_by", &song.requested_by),
+                (":played", &song.played),
+                (":played_at", &song.played_at),
+                (":position", &song.position),
+            ],
+        )?;
+        summary.imported += 1;
+    }
+
+    for (i, song) in backup.generic.iter().enumerate() {
+        if song.url.is_empty() || song.duration < 0 {
+            return Err(Error::InvalidBackup(format!("generic entry {}", i)));
+        }
+        if skip_duplicates
+            && tx.query_row_named(
+                include_str!("../sql/generic/exists.sql"),
+                &[(":url", &song.url), (":since", &since)],
+                |row| row.get(0),
+            )?
+        {
+            summary.skipped += 1;
+            continue;
+        }
+        tx.execute_named(
+            include_str!("../sql/backup/add_generic.sql"),
+            &[
+                (":url", &song.url),
+                (":ts", &song.ts),
+                (":duration", &song.duration),
+                (":title", &song.title),
+                (":requested_by", &song.requested_by),
+                (":played", &song.played),
+                (":played_at", &song.played_at),
+                (":position", &song.position),
+            ],
+        )?;
+        summary.imported += 1;
+    }
+
+    tx.commit()?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test::setup;
+    use crate::Storage;
+    use rand::SeedableRng;
+
+    fn local(title: &str, ts: i64) -> Local {
+        Local {
+            ts,
+            title: title.into(),
+            artist: "a".into(),
+            album: "b".into(),
+            path: None,
+            duration: 0,
+            requested_by: String::new(),
+            played: false,
+            played_at: None,
+            position: None,
+        }
+    }
+
+    fn pending() -> Vec<i64> {
+        crate::local::Local
+            .pending()
+            .unwrap()
+            .into_iter()
+            .map(|song| song.timestamp)
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let _guard = setup();
+        let backup = serde_json::from_str::<Backup>(
+            r#"{"version": 1,
+                "youtube": [{"vid": "aaaaaaaaaaa", "ts": 10, "duration": 180, "title": "a",
+                    "start": 5, "channel": "c", "thumbnail": "t", "requested_by": "someone",
+                    "played": true}],
+                "spotify": [{"tid": "4uLU6hMCjMI75M1A2tKUQC", "ts": 30, "duration": 200,
+                    "title": "b", "artist": "d"}],
+                "local": [{"ts": 40, "title": "e", "artist": "f", "album": "g"}]}"#,
+        )
+        .unwrap();
+        let summary = import(&backup, false).unwrap();
+        assert_eq!((summary.imported, summary.skipped), (3, 0));
+
+        let exported = serde_json::to_value(export().unwrap()).unwrap();
+        let youtube = &exported["youtube"][0];
+        assert_eq!(youtube["vid"], "aaaaaaaaaaa");
+        assert_eq!(youtube["ts"], 10);
+        assert_eq!(youtube["start"], 5);
+        assert_eq!(youtube["requested_by"], "someone");
+        assert_eq!(youtube["played"], true);
+        assert_eq!(exported["spotify"][0]["artist"], "d");
+        assert_eq!(exported["local"][0]["album"], "g");
+        assert_eq!(exported["local"][0]["played"], false);
+
+        // everything is already there
+        let again = serde_json::from_value::<Backup>(exported).unwrap();
+        let summary = import(&again, true).unwrap();
+        assert_eq!((summary.imported, summary.skipped), (0, 3));
+    }
+
+    #[test]
+    fn bad_records_roll_back() {
+        let _guard = setup();
+        let backup = Backup {
+            version: 1,
+            youtube: vec![],
+            // the second one has no title
+            local: vec![local("a", 100), local("", 100)],
+            spotify: vec![],
+            soundcloud: vec![],
+            generic: vec![],
+        };
+        assert!(matches!(
+            import(&backup, false),
+            Err(Error::InvalidBackup(ref entry)) if entry == "local entry 1"
+        ));
+        assert!(export().unwrap().local.is_empty());
+
+        let backup = Backup {
+            version: 2,
+            ..backup
+        };
+        assert!(matches!(
+            import(&backup, false),
+            Err(Error::UnsupportedVersion(2))
+        ));
+    }
+
+    #[test]
+    fn keeps_queue_order() {
+        let _guard = setup();
+        let backup = Backup {
+            version: 1,
+            youtube: vec![],
+            local: (100..105).map(|ts| local("song", ts)).collect(),
+            spotify: vec![],
+            soundcloud: vec![],
+            generic: vec![],
+        };
+        import(&backup, false).unwrap();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        crate::local::Local.shuffle(&mut rng, 0).unwrap();
+        let shuffled = pending();
+        assert_ne!(shuffled, [100, 101, 102, 103, 104]);
+
+        let backup = export().unwrap();
+        assert!(backup.local.iter().all(|song| song.position.is_some()));
+        for song in crate::local::Local.pending().unwrap() {
+            crate::local::Local.delete(song.id).unwrap();
+        }
+        import(&backup, false).unwrap();
+        assert_eq!(pending(), shuffled);
+    }
+
+    #[test]
+    fn position_defaults_to_ts() {
+        let _guard = setup();
+        let backup = serde_json::from_str::<Backup>(
+            r#"{"version": 1, "local": [
+                {"ts": 200, "title": "one", "artist": "a", "album": "b"},
+                {"ts": 100, "title": "two", "artist": "a", "album": "b"}
+            ]}"#,
+        )
+        .unwrap();
+        import(&backup, false).unwrap();
+        assert_eq!(pending(), [100, 200]);
+    }
+}
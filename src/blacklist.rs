@@ -0,0 +1,121 @@
+use serde::{Deserialize, Serialize};
+
+use crate::database;
+use crate::error::{Error, Result};
+
+#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    Video,
+    User,
+}
+
+impl EntryKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            EntryKind::Video => "video",
+            EntryKind::User => "user",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Entry {
+    pub kind: EntryKind,
+    pub value: String,
+    #[serde(default)]
+    pub ts: i64,
+}
+
+pub fn add(entry: &Entry) -> Result<()> {
+    database::get_connection()
+        .execute_named(
+            include_str!("../sql/blacklist/add.sql"),
+            &[
+                (":kind", &entry.kind.as_str()),
+                (":value", &entry.value),
+                (":ts", &entry.ts),
+            ],
+        )
+        .map_err(Error::Sql)
+        .map(|_| ())
+}
+
+// removes the value whether it was a video or a user
+pub fn remove(value: &str) -> Result<bool> {
+    database::get_connection()
+        .execute_named(
+            include_str!("../sql/blacklist/remove.sql"),
+            &[(":value", &value)],
+        )
+        .map_err(Error::Sql)
+        .map(|n| n > 0)
+}
+
+pub fn all() -> Result<Vec<Entry>> {
+    Ok(database::get_connection()
+        .prepare(include_str!("../sql/blacklist/get_all.sql"))?
+        .query_map(rusqlite::NO_PARAMS, |row| {
+            let kind = match row.get::<_, String>(0).as_str() {
+                "user" => EntryKind::User,
+                _ => EntryKind::Video,
+            };
+            Entry {
+                kind,
+                value: row.get(1),
+                ts: row.get(2),
+            }
+        })
+        .map_err(Error::Sql)?
+        .filter_map(|s| s.ok())
+        .collect::<Vec<_>>())
+}
+
+pub fn check(kind: EntryKind, value: &str) -> Result<()> {
+    if value.is_empty() {
+        return Ok(());
+    }
+
+    let listed: bool = database::get_connection().query_row_named(
+        include_str!("../sql/blacklist/contains.sql"),
+        &[(":kind", &kind.as_str()), (":value", &value)],
+        |row| row.get(0),
+    )?;
+    if listed {
+        return Err(Error::Blacklisted(value.to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test::setup;
+
+    #[test]
+    fn kinds_are_separate() {
+        let _guard = setup();
+        let entry = |kind, value: &str| Entry {
+            kind,
+            value: value.into(),
+            ts: 1,
+        };
+        add(&entry(EntryKind::User, "someone")).unwrap();
+        add(&entry(EntryKind::Video, "aaaaaaaaaaa")).unwrap();
+
+        assert!(matches!(
+            check(EntryKind::User, "someone"),
+            Err(Error::Blacklisted(ref value)) if value == "someone"
+        ));
+        assert!(check(EntryKind::Video, "someone").is_ok());
+        assert!(check(EntryKind::Video, "aaaaaaaaaaa").is_err());
+        assert!(check(EntryKind::User, "someone else").is_ok());
+        // anonymous requests can't be listed
+        assert!(check(EntryKind::User, "").is_ok());
+
+        assert_eq!(all().unwrap().len(), 2);
+        assert!(remove("someone").unwrap());
+        assert!(!remove("someone").unwrap());
+        assert!(check(EntryKind::User, "someone").is_ok());
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::{DateTime, TimeZone, Utc};
+use once_cell::sync::Lazy;
+use once_cell::sync_lazy;
+
+// the last etag sent for each resource and when it first appeared. there's no single place
+// every write goes through, so a change is noticed when the body is next rendered
+static SEEN: Lazy<Mutex<HashMap<String, (String, i64)>>> = sync_lazy! {
+    Mutex::new(HashMap::new())
+};
+
+// every distinct query string is its own resource, so this is forgotten now and then
+const MAX_RESOURCES: usize = 1024;
+
+pub struct Validators {
+    pub etag: String,
+    // unix time
+    pub last_modified: i64,
+}
+
+impl Validators {
+    pub fn last_modified_header(&self) -> String {
+        http_date(self.last_modified)
+    }
+}
+
+// `resource` has to tell representations apart, the same body as json and xml are unrelated.
+// `Last-Modified` only has whole seconds, so it moves forward at least a second on every change
+// and a client that saw the previous body never matches the new one
+pub fn validators(resource: &str, body: &[u8]) -> Validators {
+    let etag = format!("\"{}\"", sha1_smol::Sha1::from(body).digest());
+    let now = crate::unix_time();
+
+    let mut seen = SEEN.lock().unwrap();
+    if seen.len() >= MAX_RESOURCES && !seen.contains_key(resource) {
+        seen.clear();
+    }
+    let last_modified = match seen.get(resource) {
+        Some((seen, at)) if *seen == etag => *at,
+        Some((_, at)) => now.max(at + 1),
+        None => now,
+    };
+    seen.insert(resource.to_string(), (etag.clone(), last_modified));
+    Validators {
+        etag,
+        last_modified,
+    }
+}
+
+// whether the client's copy is still current. `If-Modified-Since` is only looked at when
+// there's no `If-None-Match`, and a date that can't be parsed is ignored
+pub fn is_fresh(
+    validators: &Validators,
+    if_none_match: Option<&str>,
+    if_modified_since: Option<&str>,
+) -> bool {
+    if let Some(tags) = if_none_match {
+        // weak comparison, `W/"x"` matches `"x"`
+        return tags
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == validators.etag);
+    }
+    if_modified_since
+        .and_then(|date| DateTime::parse_from_rfc2822(date.trim()).ok())
+        .is_some_and(|since| validators.last_modified <= since.timestamp())
+}
+
+// imf-fixdate, `Sun, 06 Nov 1994 08:49:37 GMT`
+fn http_date(ts: i64) -> String {
+    Utc.timestamp_opt(ts, 0)
+        .single()
+        .map(|date| date.format("%a, %d %b %Y %H:%M:%S GMT").to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_modified_moves_forward() {
+        let first = validators("test /changes", b"one");
+        assert_eq!(
+            first.etag,
+            format!("\"{}\"", sha1_smol::Sha1::from("one").digest())
+        );
+        let same = validators("test /changes", b"one");
+        assert_eq!(
+            (same.etag.as_str(), same.last_modified),
+            (first.etag.as_str(), first.last_modified)
+        );
+
+        // a change inside the same second still gets a later date
+        let changed = validators("test /changes", b"two");
+        assert_ne!(changed.etag, first.etag);
+        assert!(changed.last_modified > first.last_modified);
+        let back = validators("test /changes", b"one");
+        assert!(back.last_modified > changed.last_modified);
+
+        // resources don't share dates
+        let other = validators("test /other", b"one");
+        assert_eq!(other.etag, first.etag);
+        assert!(other.last_modified <= back.last_modified);
+    }
+
+    #[test]
+    fn freshness() {
+        let validators = Validators {
+            etag: "\"abc\"".into(),
+            last_modified: 784111777,
+        };
+        assert_eq!(
+            validators.last_modified_header(),
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        );
+
+        for (if_none_match, if_modified_since, want) in &[
+            (None, None, false),
+            (Some("\"abc\""), None, true),
+            (Some("W/\"abc\""), None, true),
+            (Some("\"x\", \"abc\""), None, true),
+            (Some("*"), None, true),
+            (Some("\"abcd\""), None, false),
+            (Some("abc"), None, false),
+            // the etag wins over the date
+            (Some("\"x\""), Some("Sun, 06 Nov 1994 08:49:37 GMT"), false),
+            (None, Some("Sun, 06 Nov 1994 08:49:37 GMT"), true),
+            (None, Some("Mon, 07 Nov 1994 08:49:37 GMT"), true),
+            (None, Some("Sun, 06 Nov 1994 08:49:36 GMT"), false),
+            (None, Some("not a date"), false),
+        ] {
+            assert_eq!(
+                is_fresh(&validators, *if_none_match, *if_modified_since),
+                *want,
+                "{:?} {:?}",
+                if_none_match,
+                if_modified_since
+            );
+        }
+    }
+}
@@ -0,0 +1,788 @@
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+
+use std::collections::BTreeSet;
+use std::sync::{Arc, RwLock};
+
+use log::*;
+use once_cell::sync::Lazy;
+use once_cell::sync_lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::server::Kind;
+
+static CONFIG: Lazy<RwLock<Arc<Config>>> = sync_lazy! {
+    RwLock::new(Arc::new(Config::default()))
+};
+
+// these are only read at startup, so changing them needs a restart
+const RESTART_REQUIRED: &[&str] = &[
+    "address",
+    "port",
+    "listen",
+    "unix_socket",
+    "tls_cert",
+    "tls_key",
+    "database_path",
+    "busy_timeout_ms",
+    "pool_size",
+    "youtube_api_key",
+    "youtube_api_keys",
+    "log_level",
+    "log_format",
+];
+
+// unset by default, so a written config only has them as placeholders
+const OPTIONAL: &[&str] = &[
+    "log_level",
+    "youtube_api_key",
+    "region",
+    "database_path",
+    "local_root",
+    "api_token",
+    "spotify_client_id",
+    "spotify_client_secret",
+    "soundcloud_client_id",
+];
+
+// hidden when the config is printed
+const SECRETS: &[&str] = &[
+    "youtube_api_key",
+    "youtube_api_keys",
+    "api_token",
+    "spotify_client_secret",
+];
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Config {
+    pub address: String,
+    pub port: u16,
+    // `RUST_LOG` style filters, e.g. `info` or `warn,dono_server=debug`. `RUST_LOG` wins when set
+    #[serde(default)]
+    pub log_level: Option<String>,
+    // `compact` for people, `json` for one object per line for log pipelines
+    #[serde(default)]
+    pub log_format: LogFormat,
+    // `address:port` pairs to listen on, replaces `address` and `port` when not empty
+    #[serde(default)]
+    pub listen: Vec<String>,
+    // listen on a unix socket at this path instead of any tcp address
+    #[serde(default)]
+    pub unix_socket: Option<PathBuf>,
+    // pem files, with both set the server speaks https instead of http
+    #[serde(default)]
+    pub tls_cert: Option<PathBuf>,
+    #[serde(default)]
+    pub tls_key: Option<PathBuf>,
+    // 0 means no limit
+    #[serde(default)]
+    pub max_duration_secs: i64,
+    // number of youtube lookups to keep, 0 disables the cache
+    #[serde(default = "default_cache_size")]
+    pub cache_size: usize,
+    // transient youtube failures are retried with an exponential backoff
+    #[serde(default = "default_retry_count")]
+    pub retry_count: u32,
+    #[serde(default = "default_retry_delay_ms")]
+    pub retry_delay_ms: u64,
+    // 0 means a video can never be requested twice
+    #[serde(default)]
+    pub dedupe_window_secs: i64,
+    // also refuse songs whose title matches a pending one once noise like `(official video)`
+    // is stripped, catches reuploads under a different id
+    #[serde(default)]
+    pub dedupe_titles: bool,
+    // titles are cut to this many characters, 0 keeps them whole
+    #[serde(default)]
+    pub max_title_len: usize,
+    // songs whose title or channel contains one of these, ignoring case, are refused
+    #[serde(default)]
+    pub blocked_words: Vec<String>,
+    // words let through even though they contain a blocked one, e.g. `classic` when `ass` is blocked
+    #[serde(default)]
+    pub allowed_words: Vec<String>,
+    // blocked words only match whole words instead of anywhere inside one
+    #[serde(default)]
+    pub filter_whole_words: bool,
+    // kinds of song that can be requested, any of youtube, local, spotify, soundcloud and generic
+    #[serde(default = "default_enabled_kinds")]
+    pub enabled_kinds: Vec<Kind>,
+    #[serde(default = "default_youtube_base_url")]
+    pub youtube_base_url: String,
+    // for connecting and for every read or write after, 0 waits forever
+    #[serde(default = "default_youtube_timeout_secs")]
+    pub youtube_timeout_secs: u64,
+    // local files have to be in this directory, relative paths are relative to it.
+    // unset, songs can't be requested by path
+    #[serde(default)]
+    pub local_root: Option<PathBuf>,
+    // `SHAKEN_YOUTUBE_API_KEY` takes precedence when it is set
+    #[serde(default)]
+    pub youtube_api_key: Option<String>,
+    // used in addition to the key above, rotated on quota exhaustion
+    #[serde(default)]
+    pub youtube_api_keys: Vec<String>,
+    // anything that isn't a youtube url is searched for, each search costs 100 quota units
+    #[serde(default)]
+    pub allow_search: bool,
+    // two letter country code, videos blocked there are refused. unset skips the check
+    #[serde(default)]
+    pub region: Option<String>,
+    // how long to wait on a locked database before giving up
+    #[serde(default = "default_busy_timeout_ms")]
+    pub busy_timeout_ms: u64,
+    #[serde(default = "default_pool_size")]
+    pub pool_size: u32,
+    // overrides the default location in the project data dir, `:memory:` keeps nothing on disk
+    #[serde(default)]
+    pub database_path: Option<PathBuf>,
+    // origins allowed to make cross-origin requests, `*` allows any
+    #[serde(default)]
+    pub cors_allowed_origins: Vec<String>,
+    // when set, writes need an `Authorization: Bearer <token>` header
+    #[serde(default)]
+    pub api_token: Option<String>,
+    // also require the token for reads
+    #[serde(default)]
+    pub protect_reads: bool,
+    // 0 disables either limit
+    #[serde(default)]
+    pub request_cooldown_secs: u64,
+    // how long an `Idempotency-Key` is remembered for
+    #[serde(default = "default_idempotency_window_secs")]
+    pub idempotency_window_secs: u64,
+    #[serde(default)]
+    pub requests_per_minute: u32,
+    // pending songs across every kind, 0 means unlimited
+    #[serde(default)]
+    pub max_queue_len: i64,
+    // pending songs for a single requester, 0 means unlimited
+    #[serde(default)]
+    pub max_per_user: i64,
+    // distinct voters needed to skip the current song, 0 disables voting
+    #[serde(default)]
+    pub skip_threshold: u32,
+    // played songs are deleted once they're this many days old, 0 keeps them forever
+    #[serde(default)]
+    pub retention_days: u32,
+    // require the token for /metrics, regardless of `protect_reads`
+    #[serde(default)]
+    pub protect_metrics: bool,
+    // client credentials for the spotify web api, spotify requests fail without them
+    #[serde(default)]
+    pub spotify_client_id: Option<String>,
+    #[serde(default)]
+    pub spotify_client_secret: Option<String>,
+    #[serde(default = "default_spotify_base_url")]
+    pub spotify_base_url: String,
+    #[serde(default = "default_spotify_accounts_url")]
+    pub spotify_accounts_url: String,
+    // how long to wait on the spotify web api, 0 waits forever
+    #[serde(default = "default_spotify_timeout_secs")]
+    pub spotify_timeout_secs: u64,
+    // soundcloud requests fail without a client id
+    #[serde(default)]
+    pub soundcloud_client_id: Option<String>,
+    #[serde(default = "default_soundcloud_base_url")]
+    pub soundcloud_base_url: String,
+}
+
+#[derive(Deserialize, Serialize, Copy, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Compact,
+    Json,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            address: "localhost".into(),
+            port: 50006,
+            log_level: None,
+            log_format: LogFormat::default(),
+            listen: vec![],
+            unix_socket: None,
+            tls_cert: None,
+            tls_key: None,
+            max_duration_secs: 0,
+            cache_size: default_cache_size(),
+            retry_count: default_retry_count(),
+            retry_delay_ms: default_retry_delay_ms(),
+            dedupe_window_secs: 0,
+            dedupe_titles: false,
+            max_title_len: 0,
+            blocked_words: vec![],
+            allowed_words: vec![],
+            filter_whole_words: false,
+            enabled_kinds: default_enabled_kinds(),
+            youtube_base_url: default_youtube_base_url(),
+            youtube_timeout_secs: default_youtube_timeout_secs(),
+            local_root: None,
+            youtube_api_key: None,
+            youtube_api_keys: vec![],
+            allow_search: false,
+            region: None,
+            busy_timeout_ms: default_busy_timeout_ms(),
+            pool_size: default_pool_size(),
+            database_path: None,
+            cors_allowed_origins: vec![],
+            api_token: None,
+            protect_reads: false,
+            request_cooldown_secs: 0,
+            idempotency_window_secs: default_idempotency_window_secs(),
+            requests_per_minute: 0,
+            max_queue_len: 0,
+            max_per_user: 0,
+            skip_threshold: 0,
+            retention_days: 0,
+            protect_metrics: false,
+            spotify_client_id: None,
+            spotify_client_secret: None,
+            spotify_base_url: default_spotify_base_url(),
+            spotify_accounts_url: default_spotify_accounts_url(),
+            spotify_timeout_secs: default_spotify_timeout_secs(),
+            soundcloud_client_id: None,
+            soundcloud_base_url: default_soundcloud_base_url(),
+        }
+    }
+}
+
+fn default_cache_size() -> usize {
+    256
+}
+
+impl Config {
+    // `DONO_<FIELD>` overrides `<field>`, e.g. `DONO_PORT=8080` or `DONO_DATABASE_PATH=/tmp/db`.
+    // string fields take the value as is, anything else is parsed as a toml value (`DONO_LISTEN=["a:1"]`)
+    pub fn with_env_overrides(self) -> Result<Self, String> {
+        const PREFIX: &str = "DONO_";
+
+        let mut table = match toml::Value::try_from(&self) {
+            Ok(toml::Value::Table(table)) => table,
+            _ => unreachable!("config must serialize to a table"),
+        };
+
+        let mut overridden = false;
+        for (key, raw) in std::env::vars() {
+            let field = match key.strip_prefix(PREFIX) {
+                Some(field) => field.to_lowercase(),
+                None => continue,
+            };
+            // the only optional fields are strings, so missing ones are too
+            let value = match table.get(&field) {
+                None | Some(toml::Value::String(..)) => toml::Value::String(raw),
+                Some(..) => format!("value = {}", raw)
+                    .parse::<toml::Value>()
+                    .ok()
+                    .and_then(|value| value.get("value").cloned())
+                    .ok_or_else(|| format!("invalid value for `{}`: {}", key, raw))?,
+            };
+            table.insert(field, value);
+            overridden = true;
+        }
+
+        if !overridden {
+            return Ok(self);
+        }
+        toml::Value::Table(table)
+            .try_into()
+            .map_err(|err| format!("invalid config override: {}", err))
+    }
+
+    // every setting with its default, the unset ones are commented out
+    pub fn template() -> String {
+        let mut out = toml::to_string_pretty(&Self::default()).expect("valid config");
+        out.push_str("\n# unset by default\n");
+        for key in OPTIONAL {
+            out.push_str(&format!("# {} = \"\"\n", key));
+        }
+        out
+    }
+
+    // as toml with the credentials blanked out, for printing
+    pub fn redacted(&self) -> String {
+        let mut table = match toml::Value::try_from(self) {
+            Ok(toml::Value::Table(table)) => table,
+            _ => unreachable!("config must serialize to a table"),
+        };
+        let hidden = || toml::Value::String("<redacted>".into());
+        for key in SECRETS {
+            match table.get_mut(*key) {
+                Some(toml::Value::Array(values)) => values.iter_mut().for_each(|v| *v = hidden()),
+                Some(value) => *value = hidden(),
+                None => {}
+            }
+        }
+        toml::to_string_pretty(&toml::Value::Table(table)).expect("valid config")
+    }
+
+    // every problem at once, so they can be fixed in one go
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = vec![];
+
+        // a unix socket replaces every tcp address
+        if self.unix_socket.is_none() {
+            if self.listen.is_empty() && self.port == 0 {
+                problems.push("`port` cannot be 0".to_string());
+            }
+            let addresses = match self.listen.as_slice() {
+                [] => vec![Ok((self.address.clone(), self.port))],
+                listen => listen.iter().map(|addr| parse_address(addr)).collect(),
+            };
+            for addr in addresses {
+                let (host, port) = match addr {
+                    Ok(addr) => addr,
+                    Err(err) => {
+                        problems.push(err);
+                        continue;
+                    }
+                };
+                let resolves = (host.as_str(), port)
+                    .to_socket_addrs()
+                    .map(|mut addrs| addrs.next().is_some())
+                    .unwrap_or(false);
+                if !resolves {
+                    problems.push(format!("cannot resolve address: {}:{}", host, port));
+                }
+            }
+        }
+
+        if let Some(path) = &self.database_path {
+            if path.as_os_str() != ":memory:" && !writable(path) {
+                problems.push(format!(
+                    "`database_path` is not writable: {}",
+                    path.display()
+                ));
+            }
+        }
+
+        if let Some(region) = &self.region {
+            if region.len() != 2 || !region.chars().all(|c| c.is_ascii_alphabetic()) {
+                problems.push(format!(
+                    "`region` is not a two letter country code: {}",
+                    region
+                ));
+            }
+        }
+
+        if self.enabled_kinds.is_empty() {
+            problems.push("`enabled_kinds` is empty, nothing could be requested".to_string());
+        }
+        if let Some(root) = &self.local_root {
+            if !root.is_dir() {
+                problems.push(format!(
+                    "`local_root` is not a directory: {}",
+                    root.display()
+                ));
+            }
+        }
+        if self.pool_size == 0 {
+            problems.push("`pool_size` cannot be 0".to_string());
+        }
+        if self.max_queue_len < 0 || self.max_per_user < 0 {
+            problems.push("`max_queue_len` and `max_per_user` cannot be negative".to_string());
+        }
+
+        match &self.api_token {
+            Some(token) if token.is_empty() => problems.push("`api_token` is empty".to_string()),
+            None if self.protect_reads || self.protect_metrics => problems
+                .push("`protect_reads` and `protect_metrics` need an `api_token`".to_string()),
+            _ => {}
+        }
+
+        if self.spotify_client_id.is_some() != self.spotify_client_secret.is_some() {
+            problems.push(
+                "`spotify_client_id` and `spotify_client_secret` have to be set together"
+                    .to_string(),
+            );
+        }
+
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => {
+                for (name, path) in &[("tls_cert", cert), ("tls_key", key)] {
+                    if !path.is_file() {
+                        problems.push(format!("`{}` is not a file: {}", name, path.display()));
+                    }
+                }
+            }
+            (None, None) => {}
+            _ => problems.push("`tls_cert` and `tls_key` have to be set together".to_string()),
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    // every address to bind, ipv6 addresses in `listen` need brackets
+    pub fn addresses(&self) -> Result<Vec<(String, u16)>, String> {
+        if self.listen.is_empty() {
+            return Ok(vec![(self.address.clone(), self.port)]);
+        }
+
+        self.listen.iter().map(|addr| parse_address(addr)).collect()
+    }
+}
+
+fn parse_address(addr: &str) -> Result<(String, u16), String> {
+    let invalid = || format!("invalid listen address: {}", addr);
+    let pos = addr.rfind(':').ok_or_else(invalid)?;
+    let port = addr[pos + 1..].parse().map_err(|_| invalid())?;
+    let host = addr[..pos].trim_start_matches('[').trim_end_matches(']');
+    Ok((host.to_string(), port))
+}
+
+// the closest directory that exists has to be writable, anything missing is created later
+fn writable(path: &Path) -> bool {
+    path.ancestors()
+        .skip(1)
+        .find(|dir| dir.is_dir())
+        .or_else(|| Some(Path::new(".")))
+        .and_then(|dir| std::fs::metadata(dir).ok())
+        .map(|meta| !meta.permissions().readonly())
+        .unwrap_or(false)
+}
+
+pub fn get() -> Arc<Config> {
+    CONFIG.read().unwrap().clone()
+}
+
+pub fn set(config: Config) {
+    *CONFIG.write().unwrap() = Arc::new(config)
+}
+
+#[derive(Debug, Default)]
+pub struct Reload {
+    pub applied: Vec<String>,
+    pub restart_required: Vec<String>,
+}
+
+// swaps in every changed setting that can change at runtime, the rest keep their old values
+pub fn reload(new: Config) -> Reload {
+    fn table(config: &Config) -> toml::value::Table {
+        match toml::Value::try_from(config) {
+            Ok(toml::Value::Table(table)) => table,
+            _ => unreachable!("config must serialize to a table"),
+        }
+    }
+
+    let (old, mut new) = (table(&get()), table(&new));
+    let keys = old
+        .keys()
+        .chain(new.keys())
+        .cloned()
+        .collect::<BTreeSet<_>>();
+
+    let mut reload = Reload::default();
+    for key in keys {
+        if old.get(&key) == new.get(&key) {
+            continue;
+        }
+        if !RESTART_REQUIRED.contains(&key.as_str()) {
+            reload.applied.push(key);
+            continue;
+        }
+        match old.get(&key) {
+            Some(value) => new.insert(key.clone(), value.clone()),
+            None => new.remove(&key),
+        };
+        reload.restart_required.push(key);
+    }
+
+    match toml::Value::Table(new).try_into() {
+        Ok(config) => set(config),
+        Err(err) => error!("cannot apply the reloaded config: {}", err),
+    }
+    reload
+}
+
+fn default_retry_count() -> u32 {
+    3
+}
+
+fn default_retry_delay_ms() -> u64 {
+    250
+}
+
+fn default_youtube_timeout_secs() -> u64 {
+    5
+}
+
+fn default_idempotency_window_secs() -> u64 {
+    60 * 60 * 24
+}
+
+fn default_enabled_kinds() -> Vec<Kind> {
+    Kind::ALL.to_vec()
+}
+
+fn default_youtube_base_url() -> String {
+    "https://www.googleapis.com/youtube/v3".into()
+}
+
+fn default_busy_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_pool_size() -> u32 {
+    4
+}
+
+fn default_spotify_base_url() -> String {
+    "https://api.spotify.com/v1".into()
+}
+
+fn default_spotify_accounts_url() -> String {
+    "https://accounts.spotify.com".into()
+}
+
+fn default_spotify_timeout_secs() -> u64 {
+    5
+}
+
+fn default_soundcloud_base_url() -> String {
+    "https://api.soundcloud.com".into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_duration_defaults_to_no_limit() {
+        let config: Config = toml::from_str("address = 'localhost'\nport = 50006").unwrap();
+        assert_eq!(config.max_duration_secs, 0);
+
+        let config: Config =
+            toml::from_str("address = 'localhost'\nport = 50006\nmax_duration_secs = 600").unwrap();
+        assert_eq!(config.max_duration_secs, 600);
+    }
+
+    #[test]
+    fn listen_addresses() {
+        let config = Config::default();
+        assert_eq!(
+            config.addresses().unwrap(),
+            [(config.address.clone(), config.port)]
+        );
+
+        let config = Config {
+            listen: vec![
+                "127.0.0.1:50006".into(),
+                "[::1]:8080".into(),
+                "localhost:1".into(),
+            ],
+            ..Config::default()
+        };
+        assert_eq!(
+            config.addresses().unwrap(),
+            [
+                ("127.0.0.1".to_string(), 50006),
+                ("::1".to_string(), 8080),
+                ("localhost".to_string(), 1),
+            ]
+        );
+
+        for bad in &["127.0.0.1", "127.0.0.1:port", "host:70000"] {
+            let config = Config {
+                listen: vec![bad.to_string()],
+                ..Config::default()
+            };
+            assert_eq!(
+                config.addresses().unwrap_err(),
+                format!("invalid listen address: {}", bad)
+            );
+        }
+    }
+
+    #[test]
+    fn every_problem_at_once() {
+        assert!(Config::default().validate().is_ok());
+
+        let config = Config {
+            port: 0,
+            region: Some("usa".into()),
+            pool_size: 0,
+            max_queue_len: -1,
+            protect_reads: true,
+            spotify_client_id: Some("id".into()),
+            tls_key: Some("key.pem".into()),
+            ..Config::default()
+        };
+        assert_eq!(
+            config.validate().unwrap_err(),
+            [
+                "`port` cannot be 0",
+                "`region` is not a two letter country code: usa",
+                "`pool_size` cannot be 0",
+                "`max_queue_len` and `max_per_user` cannot be negative",
+                "`protect_reads` and `protect_metrics` need an `api_token`",
+                "`spotify_client_id` and `spotify_client_secret` have to be set together",
+                "`tls_cert` and `tls_key` have to be set together",
+            ]
+        );
+
+        let config = Config {
+            listen: vec![
+                "127.0.0.1:1".into(),
+                "nope".into(),
+                "no.such.host.invalid:1".into(),
+            ],
+            api_token: Some("".into()),
+            database_path: Some(":memory:".into()),
+            ..Config::default()
+        };
+        assert_eq!(
+            config.validate().unwrap_err(),
+            [
+                "invalid listen address: nope",
+                "cannot resolve address: no.such.host.invalid:1",
+                "`api_token` is empty",
+            ]
+        );
+
+        // the tcp addresses don't matter for a unix socket
+        let config = Config {
+            port: 0,
+            unix_socket: Some("dono.sock".into()),
+            ..Config::default()
+        };
+        assert!(config.validate().is_ok());
+    }
+
+    // env vars are shared by every test, the database lock keeps these from overlapping
+    fn with_env<T>(vars: &[(&str, &str)], f: impl FnOnce() -> T) -> T {
+        let _guard = crate::database::test::setup();
+        for (key, value) in vars {
+            std::env::set_var(key, value);
+        }
+        let out = f();
+        for (key, _) in vars {
+            std::env::remove_var(key);
+        }
+        out
+    }
+
+    #[test]
+    fn env_overrides() {
+        let config = with_env(
+            &[
+                ("DONO_PORT", "8080"),
+                ("DONO_ADDRESS", "0.0.0.0"),
+                ("DONO_DATABASE_PATH", "/data/videos.db"),
+                ("DONO_LISTEN", r#"["127.0.0.1:1", "[::1]:2"]"#),
+                ("DONO_ALLOW_SEARCH", "true"),
+                ("DONO_UNIX_SOCKET", "/run/dono.sock"),
+            ],
+            || Config::default().with_env_overrides(),
+        )
+        .unwrap();
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.address, "0.0.0.0");
+        assert_eq!(config.database_path, Some(PathBuf::from("/data/videos.db")));
+        assert_eq!(config.listen, ["127.0.0.1:1", "[::1]:2"]);
+        assert!(config.allow_search);
+        assert_eq!(config.unix_socket, Some(PathBuf::from("/run/dono.sock")));
+        // untouched
+        assert_eq!(config.pool_size, Config::default().pool_size);
+    }
+
+    #[test]
+    fn bad_env_overrides() {
+        let err = with_env(&[("DONO_PORT", "eighty")], || {
+            Config::default().with_env_overrides()
+        })
+        .unwrap_err();
+        assert_eq!(err, "invalid value for `DONO_PORT`: eighty");
+
+        let err = with_env(&[("DONO_PORT", "70000")], || {
+            Config::default().with_env_overrides()
+        })
+        .unwrap_err();
+        assert!(err.starts_with("invalid config override"), "{}", err);
+    }
+
+    #[test]
+    fn reload_at_runtime() {
+        let _guard = crate::database::test::setup();
+        let new = Config {
+            max_duration_secs: 60,
+            requests_per_minute: 5,
+            port: 1,
+            pool_size: 99,
+            tls_cert: Some("cert.pem".into()),
+            ..Config::default()
+        };
+        let reloaded = reload(new);
+        assert_eq!(
+            reloaded.applied,
+            ["max_duration_secs", "requests_per_minute"]
+        );
+        assert_eq!(reloaded.restart_required, ["pool_size", "port", "tls_cert"]);
+
+        let config = get();
+        assert_eq!(config.max_duration_secs, 60);
+        assert_eq!(config.requests_per_minute, 5);
+        // still what the server was started with
+        assert_eq!(config.port, Config::default().port);
+        assert_eq!(config.pool_size, Config::default().pool_size);
+
+        // optional settings can be added and taken away
+        let with_token = Config {
+            api_token: Some("secret".into()),
+            ..(*get()).clone()
+        };
+        assert_eq!(reload(with_token).applied, ["api_token"]);
+        assert_eq!(get().api_token.as_deref(), Some("secret"));
+        let without = Config {
+            api_token: None,
+            ..(*get()).clone()
+        };
+        assert_eq!(reload(without).applied, ["api_token"]);
+        assert!(get().api_token.is_none());
+    }
+
+    #[test]
+    fn template_round_trips() {
+        let template = Config::template();
+        let config = toml::from_str::<Config>(&template).unwrap();
+        assert_eq!(
+            toml::Value::try_from(&config).unwrap(),
+            toml::Value::try_from(Config::default()).unwrap()
+        );
+        // every field is written out, set or not
+        let table = toml::Value::try_from(Config::default()).unwrap();
+        for key in table.as_table().unwrap().keys() {
+            assert!(template.contains(&format!("{} = ", key)), "{}", key);
+        }
+        for key in OPTIONAL {
+            assert!(template.contains(&format!("# {} = ", key)), "{}", key);
+        }
+    }
+
+    #[test]
+    fn redacted() {
+        let config = Config {
+            youtube_api_key: Some("key".into()),
+            youtube_api_keys: vec!["one".into(), "two".into()],
+            api_token: Some("token".into()),
+            spotify_client_secret: Some("secret".into()),
+            spotify_client_id: Some("client".into()),
+            ..Config::default()
+        };
+        let printed = config.redacted();
+        for secret in &["\"key\"", "\"one\"", "\"two\"", "\"token\"", "\"secret\""] {
+            assert!(!printed.contains(secret), "{}", printed);
+        }
+        // still a config, with the rest as it was
+        let printed = toml::from_str::<Config>(&printed).unwrap();
+        assert_eq!(printed.youtube_api_key.as_deref(), Some("<redacted>"));
+        assert_eq!(printed.youtube_api_keys, ["<redacted>", "<redacted>"]);
+        assert_eq!(printed.spotify_client_id.as_deref(), Some("client"));
+        assert_eq!(printed.port, config.port);
+    }
+}
@@ -1,21 +1,213 @@
-use std::path::PathBuf;
-
-use once_cell::sync::OnceCell;
-pub(crate) static DB_PATH: OnceCell<PathBuf> = OnceCell::INIT;
-
-// type Result<T> = std::result::Result<T, Error>;
-
-// #[derive(Debug)]
-// pub enum Error {
-//     Rows(rusqlite::Error),
-//     CurrentRow(rusqlite::Error),
-//     PreviousRow(rusqlite::Error),
-// }
-
-pub fn get_connection() -> rusqlite::Connection {
-    rusqlite::Connection::open(DB_PATH.get().unwrap()).expect("connect to database")
-}
-
-// let mut stmt = conn.prepare(include_str!("../sql/youtube/get_current.sql"))?;
-// let mut stmt = conn.prepare(include_str!("../sql/youtube/get_previous.sql"))?;
-// let mut stmt = conn.prepare(include_str!("../sql/youtube/get_all.sql"))?;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use log::*;
+use once_cell::sync::OnceCell;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::OpenFlags;
+
+use crate::config;
+
+pub(crate) static SOURCE: OnceCell<Source> = OnceCell::INIT;
+static POOL: OnceCell<r2d2::Pool<SqliteConnectionManager>> = OnceCell::INIT;
+
+pub type Connection = r2d2::PooledConnection<SqliteConnectionManager>;
+
+#[derive(Debug, Clone)]
+pub enum Source {
+    File(PathBuf),
+    // nothing is persisted, useful for tests and throwaway instances
+    Memory,
+}
+
+// type Result<T> = std::result::Result<T, Error>;
+
+// #[derive(Debug)]
+// pub enum Error {
+//     Rows(rusqlite::Error),
+//     CurrentRow(rusqlite::Error),
+//     PreviousRow(rusqlite::Error),
+// }
+
+pub fn get_connection() -> Connection {
+    pool().get().expect("connect to database")
+}
+
+// whether a connection can be made and used, without panicking. an exhausted pool counts as
+// unavailable rather than holding up the caller
+pub fn ping() -> bool {
+    pool()
+        .get_timeout(Duration::from_secs(1))
+        .ok()
+        .and_then(|conn| {
+            conn.query_row("SELECT 1", rusqlite::NO_PARAMS, |row| row.get::<_, i64>(0))
+                .ok()
+        })
+        .is_some()
+}
+
+fn pool() -> &'static r2d2::Pool<SqliteConnectionManager> {
+    POOL.get_or_init(|| {
+        let config = config::get();
+        let busy_timeout = Duration::from_millis(config.busy_timeout_ms);
+        let source = SOURCE.get().expect("database source must be set");
+        let shared_cache = matches!(source, Source::Memory);
+        let manager = match source {
+            Source::File(path) => SqliteConnectionManager::file(path),
+            // every connection in the pool has to share the same database
+            Source::Memory => SqliteConnectionManager::file("file:dono?mode=memory&cache=shared")
+                .with_flags(OpenFlags::default() | OpenFlags::SQLITE_OPEN_URI),
+        }
+        .with_init(move |conn| {
+            // wal lets readers and writers proceed without blocking each other
+            conn.query_row("PRAGMA journal_mode = WAL", rusqlite::NO_PARAMS, |row| {
+                row.get::<_, String>(0)
+            })?;
+            // a shared cache locks whole tables, and the busy timeout doesn't wait on those.
+            // reads skip the locks so they can't fail against a write that's in progress
+            if shared_cache {
+                conn.execute_batch("PRAGMA read_uncommitted = true")?;
+            }
+            conn.busy_timeout(busy_timeout)
+        });
+
+        r2d2::Pool::builder()
+            .max_size(config.pool_size)
+            .build(manager)
+            .expect("create connection pool")
+    })
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Maintenance {
+    // file sizes in bytes, there's no file for an in-memory database
+    pub before: Option<u64>,
+    pub after: Option<u64>,
+}
+
+// rebuilds the database file to reclaim the space left by deleted rows and refreshes the
+// query planner stats. vacuum can't run while another connection is mid transaction, those
+// are waited on for up to `busy_timeout_ms`
+pub fn maintenance() -> crate::Result<Maintenance> {
+    let conn = get_connection();
+    // the log is folded in first so the sizes are of the whole database
+    conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")?;
+    let before = file_size();
+    conn.execute_batch("VACUUM; ANALYZE;")?;
+    conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")?;
+    Ok(Maintenance {
+        before,
+        after: file_size(),
+    })
+}
+
+fn file_size() -> Option<u64> {
+    match SOURCE.get()? {
+        Source::File(path) => std::fs::metadata(path).map(|md| md.len()).ok(),
+        Source::Memory => None,
+    }
+}
+
+// flushes the write-ahead log back into the database file
+pub fn close() {
+    if let Err(err) = get_connection().execute_batch("PRAGMA wal_checkpoint(TRUNCATE)") {
+        warn!("cannot checkpoint database: {}", err)
+    }
+}
+
+// let mut stmt = conn.prepare(include_str!("../sql/youtube/get_current.sql"))?;
+// let mut stmt = conn.prepare(include_str!("../sql/youtube/get_previous.sql"))?;
+// let mut stmt = conn.prepare(include_str!("../sql/youtube/get_all.sql"))?;
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::sync::{Mutex, MutexGuard, Once};
+
+    use once_cell::sync::Lazy;
+    use once_cell::sync_lazy;
+
+    use super::*;
+
+    // the database and the config are shared by every test, so the tests using them take turns
+    static LOCK: Lazy<Mutex<()>> = sync_lazy! { Mutex::new(()) };
+    static SCHEMA: Once = Once::new();
+
+    // an empty in-memory database and the default config, until the guard is dropped
+    pub fn setup() -> MutexGuard<'static, ()> {
+        setup_with(|_| {})
+    }
+
+    pub fn setup_with(f: impl FnOnce(&mut config::Config)) -> MutexGuard<'static, ()> {
+        // a failed test poisons the lock, the next one starts over anyway
+        let guard = LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let mut config = config::Config::default();
+        f(&mut config);
+        config::set(config);
+
+        SCHEMA.call_once(|| {
+            let _ = SOURCE.set(Source::Memory);
+            crate::migrations::run(&mut get_connection()).expect("migrate database");
+        });
+        let conn = get_connection();
+        let tables = conn
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table'")
+            .and_then(|mut stmt| {
+                stmt.query_map(rusqlite::NO_PARAMS, |row| row.get::<_, String>(0))?
+                    .collect::<std::result::Result<Vec<_>, _>>()
+            })
+            .expect("list tables");
+        for table in tables {
+            conn.execute_batch(&format!("DELETE FROM {}", table))
+                .expect("empty table");
+        }
+        guard
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn busy_timeout() {
+        let _guard = test::setup();
+        // the pool is set up with the config of the first test to use it
+        let timeout: i64 = get_connection()
+            .query_row("PRAGMA busy_timeout", rusqlite::NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert_eq!(timeout as u64, config::Config::default().busy_timeout_ms);
+    }
+
+    #[test]
+    fn pooled_connections() {
+        let _guard = test::setup();
+        let (a, b) = (get_connection(), get_connection());
+        a.execute_batch(
+            "INSERT INTO local_songs (ts, title, artist, album) VALUES (1, 'a', 'b', 'c')",
+        )
+        .unwrap();
+        let count: i64 = b
+            .query_row(
+                "SELECT COUNT(*) FROM local_songs",
+                rusqlite::NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn in_memory() {
+        let _guard = test::setup();
+        assert!(matches!(SOURCE.get(), Some(Source::Memory)));
+        // every test starts from an empty database
+        let count: i64 = get_connection()
+            .query_row(
+                "SELECT COUNT(*) FROM local_songs",
+                rusqlite::NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+}
@@ -1,71 +1,296 @@
-use std::fmt;
-
-pub type Result<T> = std::result::Result<T, Error>;
-
-#[derive(Debug)]
-pub enum Error {
-    Io(std::io::Error),
-    Sql(rusqlite::Error),
-    Deserialize(serde_json::Error),
-    Serialize(serde_json::Error),
-    HttpClient(http_req::error::Error),
-    HttpResponse(u16, String),
-    BindHttp(String),
-
-    InvalidYoutubeUrl(String),
-    InvalidYoutubeData, // context?
-}
-
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Error::Io(err) => write!(f, "io error: {}", err),
-            Error::Sql(err) => write!(f, "sql error: {}", err),
-            Error::Deserialize(err) => write!(f, "deserialization error: {}", err),
-            Error::Serialize(err) => write!(f, "serialization error: {}", err),
-            Error::HttpClient(err) => write!(f, "http client error: {}", err),
-            Error::HttpResponse(code, reason) => {
-                write!(f, "http get failed: ({}) {}", code, reason)
-            }
-            Error::BindHttp(addr) => write!(f, "cannot bind http server to {}", addr),
-            Error::InvalidYoutubeUrl(url) => write!(f, "invalid youtube url: {}", url),
-            Error::InvalidYoutubeData => write!(f, "missing snippet from youtube response"),
-        }
-    }
-}
-
-impl std::error::Error for Error {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        macro_rules! cast {
-            ($e:expr) => {
-                Some($e as &(dyn std::error::Error + 'static))
-            };
-        }
-
-        match self {
-            Error::Io(err) => cast!(err),
-            Error::Sql(err) => cast!(err),
-            Error::Deserialize(err) | Error::Serialize(err) => cast!(err),
-            Error::HttpClient(err) => cast!(err),
-            _ => None,
-        }
-    }
-}
-
-impl From<std::io::Error> for Error {
-    fn from(err: std::io::Error) -> Self {
-        Error::Io(err)
-    }
-}
-
-impl From<rusqlite::Error> for Error {
-    fn from(err: rusqlite::Error) -> Self {
-        Error::Sql(err)
-    }
-}
-
-impl From<http_req::error::Error> for Error {
-    fn from(err: http_req::error::Error) -> Self {
-        Error::HttpClient(err)
-    }
-}
+use std::fmt;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Sql(rusqlite::Error),
+    Deserialize(serde_json::Error),
+    Serialize(serde_json::Error),
+    HttpClient(http_req::error::Error),
+    HttpResponse(u16, String),
+    Timeout,
+    BindHttp(String),
+    InvalidConfig(String),
+
+    InvalidYoutubeUrl(String),
+    YoutubeNotConfigured,
+    InvalidSpotifyUrl(String),
+    SpotifyNotConfigured,
+    InvalidSoundCloudUrl(String),
+    SoundCloudNotConfigured,
+    InvalidMedia(String),
+    LocalFileNotFound(String),
+    NotAFile(String),
+    NotADirectory(String),
+    LocalFileUnreadable(String),
+    PathNotAllowed(String),
+    InvalidYoutubeData, // context?
+    InvalidDuration(String),
+    DurationTooLong { got: i64, max: i64 },
+    LiveStreamNotAllowed,
+    AgeRestricted,
+    RegionBlocked(String),
+    QuotaExceeded,
+    AllKeysExhausted,
+    VideoUnavailable(String),
+    NoSearchResults(String),
+    DuplicateSong { vid: String },
+    DuplicateTitle(String),
+    UnsupportedVersion(u32),
+    Unauthorized,
+    RateLimited { retry_after: u64 },
+    QueueFull { max: i64 },
+    UserQueueFull { max: i64 },
+    Blacklisted(String),
+    FilteredContent(String),
+    InvalidBackup(String),
+    SkipDisabled,
+    SearchDisabled,
+    SongNotFound(i64),
+    WrongItemKind(&'static str),
+    ItemKindDisabled(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "io error: {}", err),
+            Error::Sql(err) => write!(f, "sql error: {}", err),
+            Error::Deserialize(err) => write!(f, "deserialization error: {}", err),
+            Error::Serialize(err) => write!(f, "serialization error: {}", err),
+            Error::HttpClient(err) => write!(f, "http client error: {}", err),
+            Error::Timeout => write!(f, "upstream request timed out"),
+            Error::HttpResponse(code, reason) => {
+                write!(f, "http get failed: ({}) {}", code, reason)
+            }
+            Error::BindHttp(addr) => write!(f, "cannot bind http server to {}", addr),
+            Error::InvalidConfig(reason) => write!(f, "invalid config: {}", reason),
+            Error::InvalidYoutubeUrl(url) => write!(f, "invalid youtube url: {}", url),
+            Error::YoutubeNotConfigured => write!(
+                f,
+                "youtube requests are not configured, set `youtube_api_key` or `SHAKEN_YOUTUBE_API_KEY`"
+            ),
+            Error::InvalidSpotifyUrl(url) => write!(f, "invalid spotify url: {}", url),
+            Error::SpotifyNotConfigured => write!(f, "spotify requests are not configured"),
+            Error::InvalidSoundCloudUrl(url) => write!(f, "invalid soundcloud url: {}", url),
+            Error::SoundCloudNotConfigured => write!(f, "soundcloud requests are not configured"),
+            Error::InvalidMedia(reason) => write!(f, "invalid media: {}", reason),
+            Error::LocalFileNotFound(path) => write!(f, "no such file: {}", path),
+            Error::NotAFile(path) => write!(f, "not a regular file: {}", path),
+            Error::NotADirectory(path) => write!(f, "not a directory: {}", path),
+            Error::LocalFileUnreadable(path) => write!(f, "cannot read {}", path),
+            Error::PathNotAllowed(path) => write!(f, "{} is outside of the local root", path),
+            Error::InvalidYoutubeData => write!(f, "missing snippet from youtube response"),
+            Error::InvalidDuration(period) => write!(f, "invalid iso8601 duration: {}", period),
+            Error::DurationTooLong { got, max } => {
+                write!(f, "video is too long: {}s (max is {}s)", got, max)
+            }
+            Error::LiveStreamNotAllowed => write!(f, "live streams and premieres are not allowed"),
+            Error::AgeRestricted => write!(f, "age restricted videos are not allowed"),
+            Error::RegionBlocked(region) => write!(f, "video is blocked in {}", region),
+            Error::QuotaExceeded => write!(f, "youtube api quota exceeded, try again later"),
+            Error::AllKeysExhausted => write!(
+                f,
+                "all youtube api keys have exceeded their quota, try again later"
+            ),
+            Error::VideoUnavailable(reason) => {
+                write!(f, "video is unavailable or private: {}", reason)
+            }
+            Error::NoSearchResults(query) => write!(f, "no videos found for: {}", query),
+            Error::DuplicateSong { vid } => write!(f, "video was already requested: {}", vid),
+            Error::DuplicateTitle(title) => write!(f, "song is already in the queue: {}", title),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "unsupported item version: {}", version)
+            }
+            Error::Unauthorized => write!(f, "missing or invalid api token"),
+            Error::RateLimited { retry_after } => {
+                write!(f, "too many requests, try again in {}s", retry_after)
+            }
+            Error::QueueFull { max } => write!(f, "the queue is full ({} songs)", max),
+            Error::Blacklisted(value) => write!(f, "{} is blacklisted", value),
+            Error::FilteredContent(word) => write!(f, "song contains a blocked word: {}", word),
+            Error::InvalidBackup(entry) => write!(f, "invalid backup, bad {}", entry),
+            Error::SkipDisabled => write!(f, "skip voting is disabled"),
+            Error::SearchDisabled => write!(f, "search is disabled, set `allow_search`"),
+            Error::SongNotFound(id) => write!(f, "no unplayed song with id {}", id),
+            Error::WrongItemKind(kind) => write!(f, "expected a {} item", kind),
+            Error::ItemKindDisabled(kind) => write!(f, "{} requests are disabled", kind),
+            Error::UserQueueFull { max } => {
+                write!(
+                    f,
+                    "too many pending songs for this requester (max is {})",
+                    max
+                )
+            }
+        }
+    }
+}
+
+impl Error {
+    pub fn status(&self) -> u16 {
+        match self {
+            Error::Deserialize(..)
+            | Error::InvalidYoutubeUrl(..)
+            | Error::InvalidSpotifyUrl(..)
+            | Error::InvalidSoundCloudUrl(..)
+            | Error::InvalidMedia(..)
+            | Error::NotAFile(..)
+            | Error::NotADirectory(..)
+            | Error::DurationTooLong { .. }
+            | Error::LiveStreamNotAllowed
+            | Error::AgeRestricted
+            | Error::RegionBlocked(..)
+            | Error::UnsupportedVersion(..)
+            | Error::InvalidBackup(..)
+            | Error::ItemKindDisabled(..) => 400,
+            Error::Unauthorized => 401,
+            Error::Blacklisted(..)
+            | Error::FilteredContent(..)
+            | Error::LocalFileUnreadable(..)
+            | Error::PathNotAllowed(..)
+            | Error::SkipDisabled
+            | Error::SearchDisabled => 403,
+            Error::VideoUnavailable(..)
+            | Error::NoSearchResults(..)
+            | Error::SongNotFound(..)
+            | Error::LocalFileNotFound(..) => 404,
+            Error::DuplicateSong { .. }
+            | Error::DuplicateTitle(..)
+            | Error::QueueFull { .. }
+            | Error::UserQueueFull { .. } => 409,
+            Error::QuotaExceeded | Error::AllKeysExhausted | Error::RateLimited { .. } => 429,
+            Error::HttpClient(..)
+            | Error::HttpResponse(..)
+            | Error::InvalidYoutubeData
+            | Error::InvalidDuration(..) => 502,
+            Error::Io(..)
+            | Error::Sql(..)
+            | Error::Serialize(..)
+            | Error::BindHttp(..)
+            | Error::InvalidConfig(..)
+            | Error::WrongItemKind(..) => 500,
+            Error::YoutubeNotConfigured
+            | Error::SpotifyNotConfigured
+            | Error::SoundCloudNotConfigured => 503,
+            Error::Timeout => 504,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Io(..) => "io",
+            Error::Sql(..) => "sql",
+            Error::Deserialize(..) => "invalid_request",
+            Error::Serialize(..) => "serialize",
+            Error::HttpClient(..) => "upstream_unreachable",
+            Error::HttpResponse(..) => "upstream_error",
+            Error::Timeout => "upstream_timeout",
+            Error::BindHttp(..) => "bind_http",
+            Error::InvalidConfig(..) => "invalid_config",
+            Error::InvalidYoutubeUrl(..) => "invalid_youtube_url",
+            Error::YoutubeNotConfigured => "youtube_not_configured",
+            Error::InvalidSpotifyUrl(..) => "invalid_spotify_url",
+            Error::SpotifyNotConfigured => "spotify_not_configured",
+            Error::InvalidSoundCloudUrl(..) => "invalid_soundcloud_url",
+            Error::SoundCloudNotConfigured => "soundcloud_not_configured",
+            Error::InvalidMedia(..) => "invalid_media",
+            Error::LocalFileNotFound(..) => "local_file_not_found",
+            Error::NotAFile(..) => "not_a_file",
+            Error::NotADirectory(..) => "not_a_directory",
+            Error::LocalFileUnreadable(..) => "local_file_unreadable",
+            Error::PathNotAllowed(..) => "path_not_allowed",
+            Error::InvalidYoutubeData => "invalid_youtube_data",
+            Error::InvalidDuration(..) => "invalid_duration",
+            Error::DurationTooLong { .. } => "duration_too_long",
+            Error::LiveStreamNotAllowed => "live_stream_not_allowed",
+            Error::AgeRestricted => "age_restricted",
+            Error::RegionBlocked(..) => "region_blocked",
+            Error::QuotaExceeded => "quota_exceeded",
+            Error::AllKeysExhausted => "all_keys_exhausted",
+            Error::VideoUnavailable(..) => "video_unavailable",
+            Error::NoSearchResults(..) => "no_search_results",
+            Error::DuplicateSong { .. } => "duplicate_song",
+            Error::DuplicateTitle(..) => "duplicate_title",
+            Error::UnsupportedVersion(..) => "unsupported_version",
+            Error::Unauthorized => "unauthorized",
+            Error::RateLimited { .. } => "rate_limited",
+            Error::QueueFull { .. } => "queue_full",
+            Error::UserQueueFull { .. } => "user_queue_full",
+            Error::Blacklisted(..) => "blacklisted",
+            Error::FilteredContent(..) => "filtered_content",
+            Error::InvalidBackup(..) => "invalid_backup",
+            Error::SkipDisabled => "skip_disabled",
+            Error::SearchDisabled => "search_disabled",
+            Error::SongNotFound(..) => "song_not_found",
+            Error::WrongItemKind(..) => "wrong_item_kind",
+            Error::ItemKindDisabled(..) => "item_kind_disabled",
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        macro_rules! cast {
+            ($e:expr) => {
+                Some($e as &(dyn std::error::Error + 'static))
+            };
+        }
+
+        match self {
+            Error::Io(err) => cast!(err),
+            Error::Sql(err) => cast!(err),
+            Error::Deserialize(err) | Error::Serialize(err) => cast!(err),
+            Error::HttpClient(err) => cast!(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        Error::Sql(err)
+    }
+}
+
+impl From<http_req::error::Error> for Error {
+    fn from(err: http_req::error::Error) -> Self {
+        Error::HttpClient(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as _;
+
+    use super::*;
+
+    #[test]
+    fn sources() {
+        let err = Error::from(rusqlite::Error::QueryReturnedNoRows);
+        let source = err.source().unwrap();
+        assert!(matches!(
+            source.downcast_ref::<rusqlite::Error>(),
+            Some(rusqlite::Error::QueryReturnedNoRows)
+        ));
+
+        let json = serde_json::from_str::<u32>("nope").unwrap_err();
+        let message = json.to_string();
+        let err = Error::Deserialize(json);
+        assert_eq!(err.source().unwrap().to_string(), message);
+
+        let io = std::io::Error::other("broken");
+        assert_eq!(Error::from(io).source().unwrap().to_string(), "broken");
+
+        // nothing underneath
+        assert!(Error::QuotaExceeded.source().is_none());
+        assert!(Error::HttpResponse(500, "oops".into()).source().is_none());
+    }
+}
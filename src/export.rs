@@ -0,0 +1,97 @@
+use std::fmt::Write;
+
+// a single playable line in an exported playlist
+pub struct Entry {
+    pub position: i64,
+    pub duration: i64,
+    pub title: String,
+    pub url: String,
+}
+
+// extended m3u, players read the `#EXTINF` duration and title for the line after it
+pub fn m3u(entries: &[Entry]) -> String {
+    let mut out = String::from("#EXTM3U\n");
+    for entry in entries {
+        // a newline in the title would end the directive early
+        let title = entry.title.replace(['\r', '\n'], " ");
+        let _ = writeln!(out, "#EXTINF:{},{}", entry.duration, title);
+        let _ = writeln!(out, "{}", entry.url);
+    }
+    out
+}
+
+// rfc 4180, fields with a separator, quote or line break are quoted
+pub fn csv<R>(header: &[&str], rows: R) -> String
+where
+    R: IntoIterator<Item = Vec<String>>,
+{
+    fn escape(field: &str) -> String {
+        if field.contains([',', '"', '\r', '\n']) {
+            format!("\"{}\"", field.replace('"', "\"\""))
+        } else {
+            field.to_string()
+        }
+    }
+
+    let mut out = String::new();
+    let header = header.iter().map(|s| escape(s)).collect::<Vec<_>>();
+    out.push_str(&header.join(","));
+    out.push_str("\r\n");
+    for row in rows {
+        let row = row.iter().map(|s| escape(s)).collect::<Vec<_>>();
+        out.push_str(&row.join(","));
+        out.push_str("\r\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extended_m3u() {
+        let entries = [
+            Entry {
+                position: 1,
+                duration: 213,
+                title: "first, song".into(),
+                url: "https://youtu.be/aaaaaaaaaaa".into(),
+            },
+            Entry {
+                position: 2,
+                duration: 60,
+                title: "two\r\nlines".into(),
+                url: "https://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC".into(),
+            },
+        ];
+        assert_eq!(
+            m3u(&entries),
+            "#EXTM3U\n\
+             #EXTINF:213,first, song\n\
+             https://youtu.be/aaaaaaaaaaa\n\
+             #EXTINF:60,two  lines\n\
+             https://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC\n"
+        );
+        assert_eq!(m3u(&[]), "#EXTM3U\n");
+    }
+
+    #[test]
+    fn csv_quoting() {
+        let rows = vec![
+            vec!["1".to_string(), "plain".to_string()],
+            vec!["2".to_string(), "Hello, \"World\"".to_string()],
+            vec!["3".to_string(), "two\nlines".to_string()],
+            vec!["4".to_string(), "".to_string()],
+        ];
+        assert_eq!(
+            csv(&["id", "title"], rows),
+            "id,title\r\n\
+             1,plain\r\n\
+             2,\"Hello, \"\"World\"\"\"\r\n\
+             3,\"two\nlines\"\r\n\
+             4,\r\n"
+        );
+        assert_eq!(csv(&["a,b"], vec![]), "\"a,b\"\r\n");
+    }
+}
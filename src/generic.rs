@@ -0,0 +1,397 @@
+use once_cell::sync::Lazy;
+use once_cell::sync_lazy;
+use rand::seq::SliceRandom;
+use regex::Regex;
+use rusqlite::OptionalExtension;
+use serde::Serialize;
+
+use crate::blacklist;
+use crate::config;
+use crate::database;
+use crate::error::{Error, Result};
+use crate::server;
+use crate::title;
+use crate::FromRow;
+
+// anything with a host, the link is stored as it was given
+static PATTERN: Lazy<Regex> = sync_lazy! {
+    Regex::new(r#"^https?://[^\s/?#@]+(?:[/?#]\S*)?$"#).expect("valid regex")
+};
+
+#[derive(Serialize)]
+pub struct Media {
+    pub id: i64,
+    pub url: String,
+    pub timestamp: i64,
+    pub duration: i64,
+    pub title: String,
+    pub requested_by: String,
+    pub position: i64,
+    // unix time, null until the song has played
+    pub played_at: Option<i64>,
+}
+
+impl FromRow for Media {
+    fn from_row(row: &rusqlite::Row<'_, '_>) -> Self {
+        Self {
+            id: row.get(0),
+            url: row.get(1),
+            timestamp: row.get(2),
+            duration: row.get(3),
+            title: row.get(4),
+            requested_by: crate::requested_by(row),
+            position: row.get("position"),
+            played_at: row.get("played_at"),
+        }
+    }
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+
+    fn position(&self) -> i64 {
+        self.position
+    }
+
+    fn played_at(&self) -> Option<i64> {
+        self.played_at
+    }
+}
+
+#[derive(Default)]
+pub struct Generic;
+
+impl crate::Storage<Media> for Generic {
+    fn store(&self, tx: &rusqlite::Transaction<'_>, media: &Media) -> Result<Media> {
+        tx.execute_named(
+            include_str!("../sql/generic/add_media.sql"),
+            &[
+                (":url", &media.url),
+                (":ts", &media.timestamp),
+                (":duration", &media.duration),
+                (":title", &media.title),
+                (":requested_by", &media.requested_by),
+            ],
+        )?;
+        tx.query_row_named(
+            include_str!("../sql/generic/get.sql"),
+            &[(":id", &tx.last_insert_rowid())],
+            Media::from_row,
+        )
+        .map_err(Error::Sql)
+    }
+
+    // there's no api to ask, so the request has to say what it is
+    fn validate(&self, item: &server::Item) -> Result<Media> {
+        let (url, title, duration) = match &item.kind {
+            server::ItemKind::Generic {
+                url,
+                title,
+                duration,
+            } => (
+                url.trim(),
+                title::sanitize(title, config::get().max_title_len),
+                *duration,
+            ),
+            _ => return Err(Error::WrongItemKind("generic")),
+        };
+
+        if !PATTERN.is_match(url) {
+            return Err(Error::InvalidMedia(format!("not a http(s) url: {}", url)));
+        }
+        if title.is_empty() {
+            return Err(Error::InvalidMedia("the title is empty".into()));
+        }
+        if duration < 0 {
+            return Err(Error::InvalidMedia(format!(
+                "negative duration: {}",
+                duration
+            )));
+        }
+
+        blacklist::check(blacklist::EntryKind::Video, url)?;
+        title::check_words(&[&title])?;
+
+        // a window of 0 means a link can only ever be requested once
+        let since = match config::get().dedupe_window_secs {
+            0 => i64::MIN,
+            window => item.ts - window,
+        };
+        let exists: bool = database::get_connection().query_row_named(
+            include_str!("../sql/generic/exists.sql"),
+            &[(":url", &url), (":since", &since)],
+            |row| row.get(0),
+        )?;
+        if exists {
+            return Err(Error::DuplicateSong {
+                vid: url.to_string(),
+            });
+        }
+
+        let max = config::get().max_duration_secs;
+        if max > 0 && duration > max {
+            return Err(Error::DurationTooLong { got: duration, max });
+        }
+
+        if config::get().dedupe_titles {
+            let normalized = title::normalize(&title);
+            if self
+                .pending()?
+                .iter()
+                .any(|media| title::normalize(&media.title) == normalized)
+            {
+                return Err(Error::DuplicateTitle(title));
+            }
+        }
+
+        // not stored yet, so there's no row id
+        Ok(Media {
+            id: 0,
+            url: url.to_string(),
+            timestamp: item.ts,
+            duration,
+            title,
+            requested_by: item.requested_by.clone(),
+            position: item.ts,
+            played_at: None,
+        })
+    }
+
+    fn get(&self, id: i64) -> Result<Option<Media>> {
+        database::get_connection()
+            .query_row_named(
+                include_str!("../sql/generic/get.sql"),
+                &[(":id", &id)],
+                Media::from_row,
+            )
+            .optional()
+            .map_err(Error::Sql)
+    }
+
+    fn current(&self) -> Result<Option<Media>> {
+        database::get_connection()
+            .query_row(
+                include_str!("../sql/generic/get_current.sql"),
+                rusqlite::NO_PARAMS,
+                Media::from_row,
+            )
+            .optional()
+            .map_err(Error::Sql)
+    }
+
+    fn previous(&self) -> Result<Option<Media>> {
+        database::get_connection()
+            .query_row(
+                include_str!("../sql/generic/get_previous.sql"),
+                rusqlite::NO_PARAMS,
+                Media::from_row,
+            )
+            .optional()
+            .map_err(Error::Sql)
+    }
+
+    fn history(&self, n: u32) -> Result<Vec<Media>> {
+        Ok(database::get_connection()
+            .prepare(include_str!("../sql/generic/get_history.sql"))?
+            .query_map_named(&[(":limit", &n)], Media::from_row)
+            .map_err(Error::Sql)?
+            .filter_map(|s| s.ok())
+            .collect::<Vec<_>>())
+    }
+
+    fn shuffle<R: rand::Rng + ?Sized>(&self, rng: &mut R, skip: u32) -> Result<()> {
+        let mut conn = database::get_connection();
+        let tx = conn.transaction()?;
+        let pending = tx
+            .prepare(include_str!("../sql/generic/get_pending_positions.sql"))?
+            .query_map_named(&[(":skip", &skip)], |row| {
+                (row.get::<_, i64>(0), row.get::<_, i64>(1))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut positions = pending.iter().map(|(_, pos)| *pos).collect::<Vec<_>>();
+        positions.shuffle(rng);
+        for ((id, _), position) in pending.iter().zip(positions) {
+            tx.execute_named(
+                include_str!("../sql/generic/set_position.sql"),
+                &[(":id", id), (":position", &position)],
+            )?;
+        }
+        tx.commit().map_err(Error::Sql)
+    }
+
+    fn move_to(&self, id: i64, pos: u32) -> Result<()> {
+        let mut conn = database::get_connection();
+        let tx = conn.transaction()?;
+        let pending = tx
+            .prepare(include_str!("../sql/generic/get_pending_positions.sql"))?
+            .query_map_named(&[(":skip", &0)], |row| {
+                (row.get::<_, i64>(0), row.get::<_, i64>(1))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        for (id, position) in crate::reorder(pending, id, pos)? {
+            tx.execute_named(
+                include_str!("../sql/generic/set_position.sql"),
+                &[(":id", &id), (":position", &position)],
+            )?;
+        }
+        tx.commit().map_err(Error::Sql)
+    }
+
+    fn requeue(&self, id: i64, position: i64) -> Result<bool> {
+        database::get_connection()
+            .execute_named(
+                include_str!("../sql/generic/requeue.sql"),
+                &[(":id", &id), (":position", &position)],
+            )
+            .map(|n| n > 0)
+            .map_err(Error::Sql)
+    }
+
+    fn advance(&self) -> Result<Option<Media>> {
+        database::get_connection()
+            .execute_named(
+                include_str!("../sql/generic/advance.sql"),
+                &[(":now", &crate::unix_time())],
+            )
+            .map_err(Error::Sql)?;
+        self.current()
+    }
+
+    fn all(&self, limit: Option<u32>, offset: Option<u32>) -> Result<Vec<Media>> {
+        // a negative limit is unbounded in sqlite
+        let limit = limit.map(i64::from).unwrap_or(-1);
+        let offset = offset.map(i64::from).unwrap_or(0);
+        Ok(database::get_connection()
+            .prepare(include_str!("../sql/generic/get_all.sql"))?
+            .query_map_named(&[(":limit", &limit), (":offset", &offset)], Media::from_row)
+            .map_err(Error::Sql)?
+            .filter_map(|s| s.ok())
+            .collect::<Vec<_>>())
+    }
+
+    fn pending(&self) -> Result<Vec<Media>> {
+        Ok(database::get_connection()
+            .prepare(include_str!("../sql/generic/get_pending.sql"))?
+            .query_map(rusqlite::NO_PARAMS, Media::from_row)
+            .map_err(Error::Sql)?
+            .filter_map(|s| s.ok())
+            .collect::<Vec<_>>())
+    }
+
+    fn between(&self, start: i64, end: i64) -> Result<Vec<Media>> {
+        Ok(database::get_connection()
+            .prepare(include_str!("../sql/generic/get_between.sql"))?
+            .query_map_named(&[(":start", &start), (":end", &end)], Media::from_row)
+            .map_err(Error::Sql)?
+            .filter_map(|s| s.ok())
+            .collect::<Vec<_>>())
+    }
+
+    fn count(&self) -> Result<i64> {
+        database::get_connection()
+            .query_row(
+                include_str!("../sql/generic/count.sql"),
+                rusqlite::NO_PARAMS,
+                |row| row.get(0),
+            )
+            .map_err(Error::Sql)
+    }
+
+    fn clear_pending(&self) -> Result<u64> {
+        database::get_connection()
+            .execute(
+                include_str!("../sql/generic/clear_pending.sql"),
+                rusqlite::NO_PARAMS,
+            )
+            .map_err(Error::Sql)
+            .map(|n| n as u64)
+    }
+
+    fn clear_history(&self) -> Result<u64> {
+        database::get_connection()
+            .execute(
+                include_str!("../sql/generic/clear_history.sql"),
+                rusqlite::NO_PARAMS,
+            )
+            .map_err(Error::Sql)
+            .map(|n| n as u64)
+    }
+
+    fn prune(&self, before: i64) -> Result<u64> {
+        database::get_connection()
+            .execute_named(
+                include_str!("../sql/generic/prune.sql"),
+                &[(":before", &before)],
+            )
+            .map_err(Error::Sql)
+            .map(|n| n as u64)
+    }
+
+    fn delete(&self, id: i64) -> Result<bool> {
+        database::get_connection()
+            .execute_named(include_str!("../sql/generic/delete.sql"), &[(":id", &id)])
+            .map_err(Error::Sql)
+            .map(|n| n > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test::setup;
+    use crate::Storage;
+
+    #[test]
+    fn validate_media() {
+        let _guard = setup();
+        let item = |url: &str, title: &str, duration| server::Item {
+            kind: server::ItemKind::Generic {
+                url: url.into(),
+                title: title.into(),
+                duration,
+            },
+            ts: 100,
+            version: 1,
+            requested_by: "someone".into(),
+        };
+
+        Generic
+            .insert(
+                &item("https://example.com/a.mp3?x=1", "  a song ", 90),
+                |_, _| Ok(()),
+            )
+            .unwrap();
+        let media = Generic.current().unwrap().unwrap();
+        assert_eq!(media.url, "https://example.com/a.mp3?x=1");
+        assert_eq!(media.title, "a song");
+        assert_eq!(media.duration, 90);
+        assert_eq!(media.requested_by, "someone");
+
+        for url in &[
+            "example.com/a.mp3",
+            "ftp://example.com/a.mp3",
+            "https://",
+            "https://exa mple.com/a.mp3",
+            "https://user@example.com/a.mp3",
+        ] {
+            assert!(
+                matches!(
+                    Generic.validate(&item(url, "a", 1)).err(),
+                    Some(Error::InvalidMedia(..))
+                ),
+                "{}",
+                url
+            );
+        }
+        assert!(matches!(
+            Generic.validate(&item("http://a/1", " ", 1)).err(),
+            Some(Error::InvalidMedia(..))
+        ));
+        assert!(matches!(
+            Generic.validate(&item("http://a/1", "a", -1)).err(),
+            Some(Error::InvalidMedia(..))
+        ));
+    }
+}
@@ -0,0 +1,39 @@
+use rusqlite::OptionalExtension;
+
+use crate::config;
+use crate::database;
+use crate::error::{Error, Result};
+
+// the recorded status and body for `key`, if it was used within `idempotency_window_secs`
+pub fn replay(key: &str) -> Result<Option<(u16, Vec<u8>)>> {
+    let conn = database::get_connection();
+    // expired keys are dropped here rather than on a timer
+    let before = crate::unix_time() - config::get().idempotency_window_secs as i64;
+    conn.execute_named(
+        include_str!("../sql/idempotency/expire.sql"),
+        &[(":before", &before)],
+    )?;
+
+    conn.query_row_named(
+        include_str!("../sql/idempotency/get.sql"),
+        &[(":key", &key)],
+        |row| (row.get::<_, u32>(0) as u16, row.get(1)),
+    )
+    .optional()
+    .map_err(Error::Sql)
+}
+
+// takes the connection so the key can be stored in the same transaction as the request
+pub fn record(conn: &rusqlite::Connection, key: &str, status: u16, body: &[u8]) -> Result<()> {
+    conn.execute_named(
+        include_str!("../sql/idempotency/add.sql"),
+        &[
+            (":key", &key),
+            (":ts", &crate::unix_time()),
+            (":status", &u32::from(status)),
+            (":body", &body),
+        ],
+    )
+    .map_err(Error::Sql)
+    .map(|_| ())
+}
@@ -1,87 +1,585 @@
-use serde::Serialize;
-
-use crate::database;
-use crate::error::{Error, Result};
-use crate::server;
-use crate::FromRow;
-
-#[derive(Serialize)]
-pub struct Song {
-    pub id: i64,
-    pub timestamp: i64,
-    pub artist: String,
-    pub album: String,
-    pub title: String,
-}
-
-impl crate::FromRow for Song {
-    fn from_row(row: &rusqlite::Row<'_, '_>) -> Self {
-        Song {
-            id: row.get(0),
-            timestamp: row.get(1),
-            artist: row.get(2),
-            album: row.get(3),
-            title: row.get(4),
-        }
-    }
-
-    fn timestamp(&self) -> i64 {
-        self.timestamp
-    }
-}
-
-pub struct Local;
-impl crate::Storage<Song> for Local {
-    fn insert(&self, item: &server::Item) -> Result<()> {
-        let (title, artist, album) = match &item.kind {
-            server::ItemKind::Local {
-                title,
-                artist,
-                album,
-            } => (title, artist, album),
-            _ => unreachable!("expected a local item"),
-        };
-
-        database::get_connection()
-            .execute_named(
-                include_str!("../sql/local/add_video.sql"),
-                &[
-                    (":ts", &item.ts),
-                    (":title", &title),
-                    (":artist", &artist),
-                    (":album", &album),
-                ],
-            )
-            .map_err(Error::Sql)
-            .map(|_| ())
-    }
-
-    fn current(&self) -> Result<Song> {
-        database::get_connection()
-            .query_row(
-                include_str!("../sql/local/get_current.sql"),
-                rusqlite::NO_PARAMS,
-                Song::from_row,
-            )
-            .map_err(Error::Sql)
-    }
-
-    fn previous(&self) -> Result<Song> {
-        database::get_connection()
-            .query_row(
-                include_str!("../sql/local/get_previous.sql"),
-                rusqlite::NO_PARAMS,
-                Song::from_row,
-            )
-            .map_err(Error::Sql)
-    }
-
-    fn all(&self) -> Result<Vec<Song>> {
-        Ok(database::get_connection()
-            .prepare(include_str!("../sql/local/get_all.sql"))?
-            .query_map(rusqlite::NO_PARAMS, Song::from_row)
-            .map_err(Error::Sql)?
-            .filter_map(|s| s.ok())
-            .collect::<Vec<_>>())
-    }
-}
+use rand::seq::SliceRandom;
+use rusqlite::OptionalExtension;
+use serde::Serialize;
+
+use std::fs::File;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use crate::config;
+use crate::database;
+use crate::error::{Error, Result};
+use crate::server;
+use crate::tags;
+use crate::title;
+use crate::FromRow;
+
+#[derive(Serialize)]
+pub struct Song {
+    pub id: i64,
+    pub timestamp: i64,
+    pub artist: String,
+    pub album: String,
+    pub title: String,
+    // null when the request only described the song
+    pub path: Option<String>,
+    pub duration: i64,
+    pub requested_by: String,
+    pub position: i64,
+    // unix time, null until the song has played
+    pub played_at: Option<i64>,
+}
+
+impl crate::FromRow for Song {
+    fn from_row(row: &rusqlite::Row<'_, '_>) -> Self {
+        Song {
+            id: row.get(0),
+            timestamp: row.get(1),
+            // by name, the table has `title` before `artist` and `album`
+            artist: row.get("artist"),
+            album: row.get("album"),
+            title: row.get("title"),
+            path: row.get("path"),
+            duration: row.get("duration"),
+            requested_by: crate::requested_by(row),
+            position: row.get("position"),
+            played_at: row.get("played_at"),
+        }
+    }
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+
+    fn position(&self) -> i64 {
+        self.position
+    }
+
+    fn played_at(&self) -> Option<i64> {
+        self.played_at
+    }
+}
+
+// the canonical path of a readable audio file
+pub fn resolve(path: &str) -> Result<PathBuf> {
+    let canonical = confine(path)?;
+    let display = canonical.display().to_string();
+    if !canonical.is_file() {
+        return Err(Error::NotAFile(display));
+    }
+    if !is_audio(&canonical) {
+        return Err(Error::InvalidMedia(format!(
+            "not an audio file: {}",
+            display
+        )));
+    }
+    File::open(&canonical).map_err(|_| Error::LocalFileUnreadable(display))?;
+    Ok(canonical)
+}
+
+// the audio files anywhere under `dir` in path order, and how many other files there were.
+// symlinked directories aren't followed, they can loop
+pub fn scan(dir: &str) -> Result<(Vec<PathBuf>, usize)> {
+    let dir = confine(dir)?;
+    if !dir.is_dir() {
+        return Err(Error::NotADirectory(dir.display().to_string()));
+    }
+
+    let (mut files, mut skipped) = (vec![], 0);
+    let mut dirs = vec![dir];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                dirs.push(path);
+            } else if is_audio(&path) && path.is_file() {
+                files.push(path)
+            } else {
+                skipped += 1
+            }
+        }
+    }
+    files.sort();
+    Ok((files, skipped))
+}
+
+// whether the file is already waiting to be played
+pub fn is_pending(conn: &rusqlite::Connection, path: &Path) -> Result<bool> {
+    conn.query_row_named(
+        include_str!("../sql/local/pending_path.sql"),
+        &[(":path", &path.to_string_lossy())],
+        |row| row.get(0),
+    )
+    .map_err(Error::Sql)
+}
+
+// what's served for each extension, files lofty reads that aren't listed are sent as
+// `application/octet-stream`
+pub fn content_type(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    Some(match ext.as_str() {
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" | "oga" | "opus" => "audio/ogg",
+        "wav" => "audio/wav",
+        "m4a" => "audio/mp4",
+        "aac" => "audio/aac",
+        "webm" => "audio/webm",
+        _ => return None,
+    })
+}
+
+// by extension, the contents are only looked at for the tags
+fn is_audio(path: &Path) -> bool {
+    lofty::file::FileType::from_path(path).is_some()
+}
+
+// canonicalizes `path`. relative paths are relative to `local_root`, and nothing outside of it
+// is allowed, symlinks included. without a root there are no local files
+fn confine(path: &str) -> Result<PathBuf> {
+    let root = match config::get().local_root.clone() {
+        Some(root) => root,
+        None => return Err(Error::PathNotAllowed(path.to_string())),
+    };
+    let path = root.join(path);
+    // checked on the path as given too, so a missing file outside the root isn't
+    // reported as missing
+    if !path.starts_with(&root) || path.components().any(|c| c == Component::ParentDir) {
+        return Err(Error::PathNotAllowed(path.display().to_string()));
+    }
+
+    let display = path.display().to_string();
+    let canonical = path.canonicalize().map_err(|err| match err.kind() {
+        io::ErrorKind::NotFound => Error::LocalFileNotFound(display.clone()),
+        io::ErrorKind::PermissionDenied => Error::LocalFileUnreadable(display.clone()),
+        _ => Error::Io(err),
+    })?;
+    if !canonical.starts_with(root.canonicalize()?) {
+        return Err(Error::PathNotAllowed(display));
+    }
+    Ok(canonical)
+}
+
+pub struct Local;
+impl crate::Storage<Song> for Local {
+    fn store(&self, tx: &rusqlite::Transaction<'_>, song: &Song) -> Result<Song> {
+        tx.execute_named(
+            include_str!("../sql/local/add_video.sql"),
+            &[
+                (":ts", &song.timestamp),
+                (":title", &song.title),
+                (":artist", &song.artist),
+                (":album", &song.album),
+                (":path", &song.path),
+                (":duration", &song.duration),
+                (":requested_by", &song.requested_by),
+            ],
+        )?;
+        tx.query_row_named(
+            include_str!("../sql/local/get.sql"),
+            &[(":id", &tx.last_insert_rowid())],
+            Song::from_row,
+        )
+        .map_err(Error::Sql)
+    }
+
+    // with a path the file's tags fill in whatever the request left empty, the file name is
+    // the title of last resort
+    fn validate(&self, item: &server::Item) -> Result<Song> {
+        let (title, artist, album, path) = match &item.kind {
+            server::ItemKind::Local {
+                title,
+                artist,
+                album,
+                path,
+            } => (title, artist, album, path),
+            _ => return Err(Error::WrongItemKind("local")),
+        };
+
+        let path = path.as_deref().map(resolve).transpose()?;
+        let (tags, name) = match &path {
+            Some(path) => {
+                let name = path
+                    .file_stem()
+                    .map(|name| name.to_string_lossy().into_owned());
+                (tags::read(path)?, name)
+            }
+            None => (tags::Tags::default(), None),
+        };
+        let or = |given: &String, tag: Option<String>| match given.trim() {
+            "" => tag.unwrap_or_default(),
+            _ => given.clone(),
+        };
+        let title = title::sanitize(&or(title, tags.title.or(name)), config::get().max_title_len);
+        let artist = or(artist, tags.artist);
+        title::check_words(&[&title, &artist])?;
+
+        // a file whose length couldn't be read is let through
+        let duration = tags.duration.unwrap_or(0);
+        let max = config::get().max_duration_secs;
+        if max > 0 && duration > max {
+            return Err(Error::DurationTooLong { got: duration, max });
+        }
+
+        if config::get().dedupe_titles {
+            let normalized = title::normalize(&title);
+            if self
+                .pending()?
+                .iter()
+                .any(|song| title::normalize(&song.title) == normalized)
+            {
+                return Err(Error::DuplicateTitle(title));
+            }
+        }
+
+        Ok(Song {
+            id: 0,
+            timestamp: item.ts,
+            artist,
+            album: or(album, tags.album),
+            title,
+            path: path.map(|path| path.to_string_lossy().into_owned()),
+            duration,
+            requested_by: item.requested_by.clone(),
+            position: item.ts,
+            played_at: None,
+        })
+    }
+
+    fn get(&self, id: i64) -> Result<Option<Song>> {
+        database::get_connection()
+            .query_row_named(
+                include_str!("../sql/local/get.sql"),
+                &[(":id", &id)],
+                Song::from_row,
+            )
+            .optional()
+            .map_err(Error::Sql)
+    }
+
+    fn current(&self) -> Result<Option<Song>> {
+        database::get_connection()
+            .query_row(
+                include_str!("../sql/local/get_current.sql"),
+                rusqlite::NO_PARAMS,
+                Song::from_row,
+            )
+            .optional()
+            .map_err(Error::Sql)
+    }
+
+    fn previous(&self) -> Result<Option<Song>> {
+        database::get_connection()
+            .query_row(
+                include_str!("../sql/local/get_previous.sql"),
+                rusqlite::NO_PARAMS,
+                Song::from_row,
+            )
+            .optional()
+            .map_err(Error::Sql)
+    }
+
+    fn history(&self, n: u32) -> Result<Vec<Song>> {
+        Ok(database::get_connection()
+            .prepare(include_str!("../sql/local/get_history.sql"))?
+            .query_map_named(&[(":limit", &n)], Song::from_row)
+            .map_err(Error::Sql)?
+            .filter_map(|s| s.ok())
+            .collect::<Vec<_>>())
+    }
+
+    fn shuffle<R: rand::Rng + ?Sized>(&self, rng: &mut R, skip: u32) -> Result<()> {
+        let mut conn = database::get_connection();
+        let tx = conn.transaction()?;
+        let pending = tx
+            .prepare(include_str!("../sql/local/get_pending_positions.sql"))?
+            .query_map_named(&[(":skip", &skip)], |row| {
+                (row.get::<_, i64>(0), row.get::<_, i64>(1))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut positions = pending.iter().map(|(_, pos)| *pos).collect::<Vec<_>>();
+        positions.shuffle(rng);
+        for ((id, _), position) in pending.iter().zip(positions) {
+            tx.execute_named(
+                include_str!("../sql/local/set_position.sql"),
+                &[(":id", id), (":position", &position)],
+            )?;
+        }
+        tx.commit().map_err(Error::Sql)
+    }
+
+    fn move_to(&self, id: i64, pos: u32) -> Result<()> {
+        let mut conn = database::get_connection();
+        let tx = conn.transaction()?;
+        let pending = tx
+            .prepare(include_str!("../sql/local/get_pending_positions.sql"))?
+            .query_map_named(&[(":skip", &0)], |row| {
+                (row.get::<_, i64>(0), row.get::<_, i64>(1))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        for (id, position) in crate::reorder(pending, id, pos)? {
+            tx.execute_named(
+                include_str!("../sql/local/set_position.sql"),
+                &[(":id", &id), (":position", &position)],
+            )?;
+        }
+        tx.commit().map_err(Error::Sql)
+    }
+
+    fn requeue(&self, id: i64, position: i64) -> Result<bool> {
+        database::get_connection()
+            .execute_named(
+                include_str!("../sql/local/requeue.sql"),
+                &[(":id", &id), (":position", &position)],
+            )
+            .map(|n| n > 0)
+            .map_err(Error::Sql)
+    }
+
+    fn advance(&self) -> Result<Option<Song>> {
+        database::get_connection()
+            .execute_named(
+                include_str!("../sql/local/advance.sql"),
+                &[(":now", &crate::unix_time())],
+            )
+            .map_err(Error::Sql)?;
+        self.current()
+    }
+
+    fn all(&self, limit: Option<u32>, offset: Option<u32>) -> Result<Vec<Song>> {
+        // a negative limit is unbounded in sqlite
+        let limit = limit.map(i64::from).unwrap_or(-1);
+        let offset = offset.map(i64::from).unwrap_or(0);
+        Ok(database::get_connection()
+            .prepare(include_str!("../sql/local/get_all.sql"))?
+            .query_map_named(&[(":limit", &limit), (":offset", &offset)], Song::from_row)
+            .map_err(Error::Sql)?
+            .filter_map(|s| s.ok())
+            .collect::<Vec<_>>())
+    }
+
+    fn pending(&self) -> Result<Vec<Song>> {
+        Ok(database::get_connection()
+            .prepare(include_str!("../sql/local/get_pending.sql"))?
+            .query_map(rusqlite::NO_PARAMS, Song::from_row)
+            .map_err(Error::Sql)?
+            .filter_map(|s| s.ok())
+            .collect::<Vec<_>>())
+    }
+
+    fn between(&self, start: i64, end: i64) -> Result<Vec<Song>> {
+        Ok(database::get_connection()
+            .prepare(include_str!("../sql/local/get_between.sql"))?
+            .query_map_named(&[(":start", &start), (":end", &end)], Song::from_row)
+            .map_err(Error::Sql)?
+            .filter_map(|s| s.ok())
+            .collect::<Vec<_>>())
+    }
+
+    fn count(&self) -> Result<i64> {
+        database::get_connection()
+            .query_row(
+                include_str!("../sql/local/count.sql"),
+                rusqlite::NO_PARAMS,
+                |row| row.get(0),
+            )
+            .map_err(Error::Sql)
+    }
+
+    fn clear_pending(&self) -> Result<u64> {
+        database::get_connection()
+            .execute(
+                include_str!("../sql/local/clear_pending.sql"),
+                rusqlite::NO_PARAMS,
+            )
+            .map_err(Error::Sql)
+            .map(|n| n as u64)
+    }
+
+    fn clear_history(&self) -> Result<u64> {
+        database::get_connection()
+            .execute(
+                include_str!("../sql/local/clear_history.sql"),
+                rusqlite::NO_PARAMS,
+            )
+            .map_err(Error::Sql)
+            .map(|n| n as u64)
+    }
+
+    fn prune(&self, before: i64) -> Result<u64> {
+        database::get_connection()
+            .execute_named(
+                include_str!("../sql/local/prune.sql"),
+                &[(":before", &before)],
+            )
+            .map_err(Error::Sql)
+            .map(|n| n as u64)
+    }
+
+    fn delete(&self, id: i64) -> Result<bool> {
+        database::get_connection()
+            .execute_named(include_str!("../sql/local/delete.sql"), &[(":id", &id)])
+            .map_err(Error::Sql)
+            .map(|n| n > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test::setup_with;
+    use crate::tags::test::{flac, TempDir};
+    use crate::Storage;
+
+    fn item(path: &str) -> server::Item {
+        server::Item {
+            kind: server::ItemKind::Local {
+                artist: String::new(),
+                title: String::new(),
+                album: String::new(),
+                path: Some(path.to_string()),
+            },
+            ts: 1,
+            version: 1,
+            requested_by: String::new(),
+        }
+    }
+
+    #[test]
+    fn tag_fallbacks() {
+        let dir = TempDir::new();
+        let root = dir.path().to_path_buf();
+        let _guard = setup_with(|config| config.local_root = Some(root));
+
+        let tagged = dir.file(
+            "tagged.flac",
+            &flac(&["TITLE=a song", "ARTIST=an artist"], 90),
+        );
+        let song = Local.insert(&item("tagged.flac"), |_, _| Ok(())).unwrap();
+        assert_eq!(
+            (song.title.as_str(), song.artist.as_str()),
+            ("a song", "an artist")
+        );
+        assert_eq!(song.duration, 90);
+        assert_eq!(song.path, Some(tagged.to_string_lossy().into_owned()));
+
+        // what's in the request wins over the tags
+        let mut given = item("tagged.flac");
+        if let server::ItemKind::Local { title, .. } = &mut given.kind {
+            *title = "given".into();
+        }
+        assert_eq!(Local.validate(&given).unwrap().title, "given");
+
+        // without tags the file's name is the title
+        dir.file("Some Song.flac", &flac(&[], 30));
+        let song = Local.validate(&item("Some Song.flac")).unwrap();
+        assert_eq!(song.title, "Some Song");
+        assert_eq!(song.artist, "");
+        assert_eq!(song.duration, 30);
+
+        dir.file("notes.mp3", b"not audio");
+        let song = Local.validate(&item("notes.mp3")).unwrap();
+        assert_eq!((song.title.as_str(), song.duration), ("notes", 0));
+    }
+
+    #[test]
+    fn shared_checks() {
+        let dir = TempDir::new();
+        let root = dir.path().to_path_buf();
+        let _guard = setup_with(|config| {
+            config.local_root = Some(root);
+            config.max_duration_secs = 60;
+            config.blocked_words = vec!["nope".into()];
+            config.dedupe_titles = true;
+        });
+
+        dir.file("long.flac", &flac(&["TITLE=long"], 120));
+        assert!(matches!(
+            Local.validate(&item("long.flac")),
+            Err(Error::DurationTooLong { got: 120, max: 60 })
+        ));
+
+        dir.file("blocked.flac", &flac(&["TITLE=a song", "ARTIST=nope"], 30));
+        assert!(matches!(
+            Local.validate(&item("blocked.flac")),
+            Err(Error::FilteredContent(..))
+        ));
+
+        dir.file("first.flac", &flac(&["TITLE=Song (Official Video)"], 30));
+        dir.file("again.flac", &flac(&["TITLE=song"], 30));
+        Local.insert(&item("first.flac"), |_, _| Ok(())).unwrap();
+        assert!(matches!(
+            Local.validate(&item("again.flac")),
+            Err(Error::DuplicateTitle(..))
+        ));
+    }
+
+    #[test]
+    fn resolve_paths() {
+        let dir = TempDir::new();
+        dir.file("music/song.flac", &flac(&[], 30));
+        dir.file("music/notes.txt", b"");
+        dir.file("secret.flac", b"");
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(
+            dir.path().join("secret.flac"),
+            dir.path().join("music/link.flac"),
+        )
+        .unwrap();
+
+        {
+            // without a root nothing can be requested by path
+            let _guard = setup_with(|_| {});
+            let song = dir.path().join("music/song.flac");
+            assert!(matches!(
+                resolve(&song.to_string_lossy()),
+                Err(Error::PathNotAllowed(..))
+            ));
+        }
+
+        let root = dir.path().join("music");
+        let _guard = setup_with(|config| config.local_root = Some(root));
+        assert_eq!(
+            resolve("song.flac").unwrap(),
+            dir.path().join("music/song.flac")
+        );
+        assert!(matches!(
+            resolve("missing.flac"),
+            Err(Error::LocalFileNotFound(..))
+        ));
+        assert!(matches!(resolve("notes.txt"), Err(Error::InvalidMedia(..))));
+        assert!(matches!(resolve("."), Err(Error::NotAFile(..))));
+        assert!(matches!(
+            resolve("../secret.flac"),
+            Err(Error::PathNotAllowed(..))
+        ));
+        assert!(matches!(
+            resolve(&dir.path().join("secret.flac").to_string_lossy()),
+            Err(Error::PathNotAllowed(..))
+        ));
+        #[cfg(unix)]
+        assert!(matches!(
+            resolve("link.flac"),
+            Err(Error::PathNotAllowed(..))
+        ));
+    }
+
+    #[test]
+    fn content_types() {
+        for (name, want) in &[
+            ("a.mp3", Some("audio/mpeg")),
+            ("a.FLAC", Some("audio/flac")),
+            ("a.opus", Some("audio/ogg")),
+            ("a.tar.m4a", Some("audio/mp4")),
+            ("a.webm", Some("audio/webm")),
+            ("a.txt", None),
+            ("mp3", None),
+            (".mp3", None),
+        ] {
+            assert_eq!(content_type(Path::new(name)), *want, "{}", name);
+        }
+    }
+}
@@ -1,65 +1,157 @@
 use log::*;
-use serde::{Deserialize, Serialize};
 
+mod generic;
 mod local;
+mod soundcloud;
+mod spotify;
 mod youtube;
 
+mod args;
+mod backup;
+mod blacklist;
+mod conditional;
+mod config;
 mod database;
 mod error;
+mod export;
+mod idempotency;
+mod metrics;
+mod migrations;
+mod ratelimit;
 mod server;
+mod skip;
+mod stats;
+mod subscribers;
+mod tags;
+mod title;
+mod websocket;
+mod xml;
 
+use config::Config;
 use server::HttpServer;
 
 use error::Error;
 type Result<T> = std::result::Result<T, Error>;
 
 fn main() {
-    env_logger::Builder::from_default_env()
-        .default_format_timestamp(false)
-        .init();
+    let args = match args::Args::parse(std::env::args().skip(1)) {
+        Ok(args) if args.help => {
+            println!("{}", args::USAGE);
+            return;
+        }
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, args::USAGE);
+            std::process::exit(2)
+        }
+    };
 
     let dir = directories::ProjectDirs::from("com.github", "museun", "dono_server").unwrap();
     std::fs::create_dir_all(dir.data_dir()).expect("must be able to create project dirs");
     std::fs::create_dir_all(dir.config_dir()).expect("must be able to create project dirs");
 
-    #[derive(Deserialize, Serialize, Clone, Debug)]
-    struct Config {
-        pub address: String,
-        pub port: u16,
+    let file = args
+        .config
+        .clone()
+        .unwrap_or_else(|| dir.config_dir().join("config.toml"));
+    // the config decides the log level, so anything wrong with it is reported after
+    let config = match read_config(&file) {
+        Ok(config) => config.map(|config| apply_overrides(config, &args)),
+        // reported like any other problem, so a broken file is never replaced with the defaults
+        Err(err) => Some(Err(vec![err.to_string()])),
+    };
+
+    if args.print_config {
+        // a missing file is printed as the defaults it would be created with
+        match config.unwrap_or_else(|| apply_overrides(Config::default(), &args)) {
+            Ok(config) => print!("{}", config.redacted()),
+            Err(problems) => {
+                for problem in &problems {
+                    eprintln!("{}", problem);
+                }
+                std::process::exit(1)
+            }
+        }
+        return;
+    }
+    match &config {
+        Some(Ok(config)) => init_logger(config.log_level.as_deref(), config.log_format),
+        _ => init_logger(None, config::LogFormat::default()),
     }
 
-    let file = dir.config_dir().join("config.toml");
-    let config: Config = match std::fs::read(&file)
-        .ok()
-        .and_then(|data| toml::from_slice(&data).ok())
-    {
-        Some(config) => config,
+    let config = match config {
+        Some(Ok(config)) => config,
+        Some(Err(problems)) => {
+            for problem in &problems {
+                error!("{}", problem);
+            }
+            error!("fix {} and re-run", file.to_str().unwrap());
+            std::process::exit(1)
+        }
         None => {
             warn!("creating default config.toml at {}", file.to_str().unwrap());
             warn!("edit and re-run");
-            let data = toml::to_string_pretty(&Config {
-                address: "localhost".into(),
-                port: 50006,
-            })
-            .expect("valid config");
-            std::fs::write(file, &data).expect("write config");
+            std::fs::write(file, Config::template()).expect("write config");
             std::process::exit(1)
         }
     };
 
-    database::DB_PATH
-        .set(dir.data_dir().join("videos.db"))
-        .expect("must be able to set DB path");
+    config::set(config.clone());
+    if let Err(err) = watch_config(file, args) {
+        warn!("cannot install the SIGHUP handler: {}", err)
+    }
 
-    if let Err(err) = database::get_connection()
-        .execute_batch(include_str!("../sql/schema.sql"))
-        .map_err(Error::Sql)
-    {
-        error!("cannot create tables from schema: {}", err);
+    // only youtube requests need a key, everything else still works without one
+    if let Err(err) = youtube::api_keys() {
+        warn!("{}", err)
+    }
+
+    let source = database_source(config.database_path.as_deref(), dir.data_dir());
+    match &source {
+        database::Source::File(path) => {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent).expect("must be able to create database dir");
+            }
+            info!("using database at {}", path.display());
+        }
+        database::Source::Memory => warn!("using an in-memory database, nothing will be saved"),
+    }
+
+    database::SOURCE
+        .set(source)
+        .expect("must be able to set database source");
+
+    if let Err(err) = migrations::run(&mut database::get_connection()) {
+        error!("cannot migrate database: {}", err);
         std::process::exit(1)
     }
 
-    let server = match HttpServer::new((config.address.as_str(), config.port)) {
+    let tls = match tls_config(&config) {
+        Ok(tls) => tls,
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1)
+        }
+    };
+
+    let server = match &config.unix_socket {
+        #[cfg(unix)]
+        Some(path) => HttpServer::unix(path, tls.as_ref()),
+        #[cfg(not(unix))]
+        Some(..) => {
+            error!("unix sockets are not supported on this platform");
+            std::process::exit(1)
+        }
+        None => match config.addresses() {
+            Ok(addresses) => HttpServer::new(&addresses, tls.as_ref()),
+            Err(err) => {
+                error!("{}", err);
+                std::process::exit(1)
+            }
+        },
+    };
+
+    let server = match server {
         Ok(server) => server,
         Err(err) => {
             error!("cannot start http server: {}", err);
@@ -70,17 +162,502 @@ fn main() {
     server.run()
 }
 
+fn init_logger(level: Option<&str>, format: config::LogFormat) {
+    logger(level, format).init()
+}
+
+// `level` is only the default, `RUST_LOG` still overrides it
+fn logger(level: Option<&str>, format: config::LogFormat) -> env_logger::Builder {
+    use std::io::Write;
+
+    let env = env_logger::Env::default();
+    let env = match level {
+        Some(level) => env.default_filter_or(level),
+        None => env,
+    };
+    let mut builder = env_logger::Builder::from_env(env);
+
+    if format == config::LogFormat::Json {
+        builder.format(|buf, record| writeln!(buf, "{}", json_line(record)));
+        return builder;
+    }
+
+    builder.format(|buf, record| {
+        let level = buf.default_styled_level(record.level());
+        match server::request_id() {
+            Some(id) => writeln!(
+                buf,
+                "[{:<5} {}] #{} {}",
+                level,
+                record.target(),
+                id,
+                record.args()
+            ),
+            None => writeln!(buf, "[{:<5} {}] {}", level, record.target(), record.args()),
+        }
+    });
+    builder
+}
+
+// one object per line
+fn json_line(record: &log::Record<'_>) -> serde_json::Value {
+    let mut line = serde_json::json!({
+        "timestamp": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        "level": record.level().to_string(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    });
+    if let Some(id) = server::request_id() {
+        line["request_id"] = id.into();
+    }
+    line
+}
+
+// `None` when there's no file, one that can't be read or parsed is an error
+fn read_config(file: &std::path::Path) -> Result<Option<Config>> {
+    let data = match std::fs::read(file) {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(Error::Io(err)),
+    };
+    toml::from_slice(&data)
+        .map(Some)
+        .map_err(|err| Error::InvalidConfig(format!("{}: {}", file.display(), err)))
+}
+
+// `DONO_` env vars win over the file, and flags win over both
+fn apply_overrides(config: Config, args: &args::Args) -> std::result::Result<Config, Vec<String>> {
+    let mut config = config.with_env_overrides().map_err(|err| vec![err])?;
+    args.apply(&mut config);
+    config.validate().map(|_| config)
+}
+
+// re-reads the config file on SIGHUP, a bad file keeps the current config
+fn watch_config(file: std::path::PathBuf, args: args::Args) -> std::io::Result<()> {
+    let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGHUP])?;
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            info!("received SIGHUP, reloading {}", file.display());
+            let config = match read_config(&file) {
+                Ok(Some(config)) => config,
+                Ok(None) => {
+                    warn!("{} is missing, keeping the current config", file.display());
+                    continue;
+                }
+                Err(err) => {
+                    warn!("{}, keeping the current config", err);
+                    continue;
+                }
+            };
+            let config = match apply_overrides(config, &args) {
+                Ok(config) => config,
+                Err(problems) => {
+                    for problem in &problems {
+                        warn!("{}", problem);
+                    }
+                    warn!("keeping the current config");
+                    continue;
+                }
+            };
+
+            let reload = config::reload(config);
+            if reload.applied.is_empty() && reload.restart_required.is_empty() {
+                info!("config is unchanged");
+            }
+            for key in &reload.applied {
+                info!("`{}` was reloaded", key);
+            }
+            for key in &reload.restart_required {
+                warn!("`{}` changed, it needs a restart to apply", key);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn database_source(path: Option<&std::path::Path>, data_dir: &std::path::Path) -> database::Source {
+    match path {
+        Some(path) if path.as_os_str() == ":memory:" => database::Source::Memory,
+        // relative paths are resolved against the working directory
+        Some(path) => database::Source::File(
+            std::env::current_dir()
+                .expect("must be able to get the working directory")
+                .join(path),
+        ),
+        None => database::Source::File(data_dir.join("videos.db")),
+    }
+}
+
+// the certificate chain and key to serve https with, if both are configured
+fn tls_config(config: &Config) -> std::result::Result<Option<tiny_http::SslConfig>, String> {
+    let (cert, key) = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) => return Ok(None),
+        _ => return Err("tls_cert and tls_key have to be set together".into()),
+    };
+    let read = |path: &std::path::Path| {
+        std::fs::read(path).map_err(|err| format!("cannot read {}: {}", path.display(), err))
+    };
+    Ok(Some(tiny_http::SslConfig {
+        certificate: read(cert)?,
+        private_key: read(key)?,
+    }))
+}
+
 pub trait Storage<T>
 where
     T: FromRow,
 {
-    fn insert(&self, item: &server::Item) -> Result<()>;
-    fn current(&self) -> Result<T>;
-    fn previous(&self) -> Result<T>;
-    fn all(&self) -> Result<Vec<T>>;
+    // validates and stores the song, `then` runs in the same transaction so its writes
+    // are committed along with the song or not at all
+    fn insert<F>(&self, item: &server::Item, then: F) -> Result<T>
+    where
+        F: FnOnce(&rusqlite::Transaction<'_>, &T) -> Result<()>,
+    {
+        // lookups happen before a connection is held
+        let song = self.validate(item)?;
+        let mut conn = database::get_connection();
+        let tx = conn.transaction()?;
+        let song = self.store(&tx, &song)?;
+        then(&tx, &song)?;
+        tx.commit()?;
+        Ok(song)
+    }
+    // writes a validated song as part of `tx`, returning it as it was stored
+    fn store(&self, tx: &rusqlite::Transaction<'_>, song: &T) -> Result<T>;
+    // runs every check `insert` does and resolves the metadata, without storing anything
+    fn validate(&self, item: &server::Item) -> Result<T>;
+    // the song with this id, played or not
+    fn get(&self, id: i64) -> Result<Option<T>>;
+    fn current(&self) -> Result<Option<T>>;
+    fn previous(&self) -> Result<Option<T>>;
+    // the `n` most recently played songs, newest first
+    fn history(&self, n: u32) -> Result<Vec<T>>;
+    // marks the current song as played and returns the one after it
+    fn advance(&self) -> Result<Option<T>>;
+    fn all(&self, limit: Option<u32>, offset: Option<u32>) -> Result<Vec<T>>;
+    fn between(&self, start: i64, end: i64) -> Result<Vec<T>>;
+    // unplayed songs, in the order they'll be played
+    fn pending(&self) -> Result<Vec<T>>;
+    // randomizes the order of the unplayed songs after the first `skip`
+    fn shuffle<R: rand::Rng + ?Sized>(&self, rng: &mut R, skip: u32) -> Result<()>;
+    // moves an unplayed song to index `pos` of the unplayed songs, past the end is the end
+    fn move_to(&self, id: i64, pos: u32) -> Result<()>;
+    // marks the song as unplayed at `position`, false when there's no such song
+    fn requeue(&self, id: i64, position: i64) -> Result<bool>;
+    fn count(&self) -> Result<i64>;
+    fn delete(&self, id: i64) -> Result<bool>;
+    // deletes every unplayed song, returning how many there were
+    fn clear_pending(&self) -> Result<u64>;
+    // deletes every played song, returning how many there were
+    fn clear_history(&self) -> Result<u64>;
+    // deletes the songs played before `before`, returning how many there were
+    fn prune(&self, before: i64) -> Result<u64>;
 }
 
 pub trait FromRow {
     fn from_row(row: &rusqlite::Row<'_, '_>) -> Self;
-    fn timestamp(&self) -> i64;
+    // the row id, ids are only unique within a kind
+    fn id(&self) -> i64;
+    // where the song sits in the queue, the request timestamp unless it was shuffled
+    fn position(&self) -> i64;
+    // unix time, `None` until the song has played
+    fn played_at(&self) -> Option<i64>;
+}
+
+pub fn unix_time() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+// looked up by name, rows from before the column existed don't have one
+fn requested_by(row: &rusqlite::Row<'_, '_>) -> String {
+    row.get_checked::<_, Option<String>>("requested_by")
+        .ok()
+        .and_then(|s| s)
+        .unwrap_or_default()
+}
+
+// moves `id` to index `pos` of the `(id, position)` pairs in queue order and returns the
+// pairs whose position changed. the songs keep the same set of positions, only who has
+// which changes, so the other kinds are left where they are
+fn reorder(mut pending: Vec<(i64, i64)>, id: i64, pos: u32) -> Result<Vec<(i64, i64)>> {
+    let from = pending
+        .iter()
+        .position(|(song, _)| *song == id)
+        .ok_or_else(|| Error::SongNotFound(id))?;
+    let positions = pending.iter().map(|(_, pos)| *pos).collect::<Vec<_>>();
+    let song = pending.remove(from);
+    pending.insert((pos as usize).min(pending.len()), song);
+
+    Ok(pending
+        .into_iter()
+        .zip(positions)
+        .filter(|((_, old), new)| old != new)
+        .map(|((id, _), new)| (id, new))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+
+    #[test]
+    fn database_paths() {
+        let file =
+            |path: Option<&str>| match database_source(path.map(Path::new), Path::new("/data")) {
+                database::Source::File(path) => Some(path),
+                database::Source::Memory => None,
+            };
+        assert_eq!(file(None), Some(PathBuf::from("/data/videos.db")));
+        assert_eq!(
+            file(Some("/mnt/dono.db")),
+            Some(PathBuf::from("/mnt/dono.db"))
+        );
+        assert_eq!(
+            file(Some("db/dono.db")),
+            Some(std::env::current_dir().unwrap().join("db/dono.db"))
+        );
+        assert_eq!(file(Some(":memory:")), None);
+    }
+
+    #[test]
+    fn requested_by_missing() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let requested_by = |sql: &str| {
+            conn.query_row(sql, rusqlite::NO_PARAMS, requested_by)
+                .unwrap()
+        };
+        assert_eq!(requested_by("SELECT 'someone' AS requested_by"), "someone");
+        assert_eq!(requested_by("SELECT NULL AS requested_by"), "");
+        // from before the column existed
+        assert_eq!(requested_by("SELECT 1 AS id"), "");
+    }
+
+    #[test]
+    fn tls_files() {
+        assert!(tls_config(&Config::default()).unwrap().is_none());
+
+        let half = Config {
+            tls_cert: Some("cert.pem".into()),
+            ..Config::default()
+        };
+        assert_eq!(
+            tls_config(&half).err().as_deref(),
+            Some("tls_cert and tls_key have to be set together")
+        );
+
+        let missing = Config {
+            tls_cert: Some("/nonexistent/cert.pem".into()),
+            tls_key: Some("/nonexistent/key.pem".into()),
+            ..Config::default()
+        };
+        let err = tls_config(&missing).err().unwrap();
+        assert!(
+            err.starts_with("cannot read /nonexistent/cert.pem"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn reorder_pending() {
+        let pending = vec![(1, 10), (2, 20), (3, 30), (4, 40)];
+        assert_eq!(
+            reorder(pending.clone(), 4, 0).unwrap(),
+            [(4, 10), (1, 20), (2, 30), (3, 40)]
+        );
+        // past the end is the end, only the songs that moved are returned
+        assert_eq!(
+            reorder(pending.clone(), 2, 100).unwrap(),
+            [(3, 20), (4, 30), (2, 40)]
+        );
+        assert_eq!(reorder(pending.clone(), 2, 1).unwrap(), []);
+        assert!(matches!(
+            reorder(pending, 5, 0),
+            Err(Error::SongNotFound(5))
+        ));
+    }
+
+    #[test]
+    fn log_level() {
+        use log::LevelFilter;
+
+        let _guard = database::test::setup();
+        let outer = std::env::var_os("RUST_LOG");
+        std::env::remove_var("RUST_LOG");
+        let filter = |level| logger(level, config::LogFormat::Compact).build().filter();
+
+        assert_eq!(filter(Some("debug")), LevelFilter::Debug);
+        assert_eq!(filter(Some("warn,dono_server=trace")), LevelFilter::Trace);
+        // env_logger's own default
+        assert_eq!(filter(None), LevelFilter::Error);
+
+        std::env::set_var("RUST_LOG", "info");
+        assert_eq!(filter(Some("debug")), LevelFilter::Info);
+        assert_eq!(filter(None), LevelFilter::Info);
+
+        match outer {
+            Some(outer) => std::env::set_var("RUST_LOG", outer),
+            None => std::env::remove_var("RUST_LOG"),
+        }
+    }
+
+    #[test]
+    fn json_logs() {
+        let line = json_line(
+            &log::Record::builder()
+                .level(log::Level::Warn)
+                .target("dono_server::youtube")
+                .args(format_args!("video {} is \"private\"", "aaaaaaaaaaa"))
+                .build(),
+        );
+        // what's written is a line of json
+        let line = serde_json::from_str::<serde_json::Value>(&line.to_string()).unwrap();
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["target"], "dono_server::youtube");
+        assert_eq!(line["message"], "video aaaaaaaaaaa is \"private\"");
+        let ts = line["timestamp"].as_str().unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(ts).is_ok(), "{}", ts);
+        // outside of a request there's no id
+        assert!(line.get("request_id").is_none());
+        assert!(!line.to_string().contains('\n'));
+    }
+
+    #[test]
+    fn wrong_item_kind() {
+        let _guard = database::test::setup();
+        let item = |kind| server::Item {
+            kind,
+            ts: 100,
+            version: 1,
+            requested_by: "someone".into(),
+        };
+        let youtube = || {
+            item(server::ItemKind::Youtube(
+                "https://youtu.be/aaaaaaaaaaa".into(),
+            ))
+        };
+        let generic = || {
+            item(server::ItemKind::Generic {
+                url: "http://a/1".into(),
+                title: "a".into(),
+                duration: 1,
+            })
+        };
+        let kind = |res: Result<()>| match res {
+            Err(Error::WrongItemKind(kind)) => kind,
+            other => panic!("{:?}", other.map_err(|e| e.to_string())),
+        };
+
+        // handled like any other error rather than a panic
+        assert_eq!(
+            kind(
+                youtube::Youtube
+                    .insert(&generic(), |_, _| Ok(()))
+                    .map(|_| ())
+            ),
+            "youtube"
+        );
+        assert_eq!(
+            kind(local::Local.insert(&youtube(), |_, _| Ok(())).map(|_| ())),
+            "local"
+        );
+        assert_eq!(
+            kind(spotify::Spotify.validate(&youtube()).map(|_| ())),
+            "spotify"
+        );
+        assert_eq!(
+            kind(soundcloud::SoundCloud.validate(&youtube()).map(|_| ())),
+            "soundcloud"
+        );
+        assert_eq!(
+            kind(generic::Generic.validate(&youtube()).map(|_| ())),
+            "generic"
+        );
+        assert_eq!(Error::WrongItemKind("local").status(), 500);
+
+        let counts = [
+            youtube::Youtube.count().unwrap(),
+            local::Local.count().unwrap(),
+            generic::Generic.count().unwrap(),
+        ];
+        assert_eq!(counts, [0, 0, 0]);
+    }
+
+    #[test]
+    fn inserts_roll_back() {
+        let _guard = database::test::setup();
+        let item = server::Item {
+            kind: server::ItemKind::Generic {
+                url: "http://a/1".into(),
+                title: "a".into(),
+                duration: 1,
+            },
+            ts: 100,
+            version: 1,
+            requested_by: "someone".into(),
+        };
+        let keys = || {
+            database::get_connection()
+                .query_row(
+                    "SELECT COUNT(*) FROM idempotency_keys",
+                    rusqlite::NO_PARAMS,
+                    |row| row.get::<_, i64>(0),
+                )
+                .unwrap()
+        };
+
+        // the second write fails after the song and the key were written
+        let res = generic::Generic.insert(&item, |tx, _| {
+            idempotency::record(tx, "key", 200, &[])?;
+            Err(Error::QueueFull { max: 0 })
+        });
+        assert!(matches!(res.err(), Some(Error::QueueFull { .. })));
+        assert_eq!(generic::Generic.count().unwrap(), 0);
+        assert_eq!(keys(), 0);
+
+        generic::Generic
+            .insert(&item, |tx, _| idempotency::record(tx, "key", 200, &[]))
+            .unwrap();
+        assert_eq!(generic::Generic.count().unwrap(), 1);
+        assert_eq!(keys(), 1);
+    }
+
+    #[test]
+    fn missing_or_invalid_config() {
+        let dir = std::env::temp_dir().join(format!("dono-server-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("config.toml");
+        let write = |data: &[u8]| std::fs::write(&file, data).unwrap();
+
+        assert!(read_config(&file).unwrap().is_none());
+
+        write(b"port = 1234\naddress = \"127.0.0.1\"\n");
+        let config = read_config(&file).unwrap().unwrap();
+        assert_eq!((config.address.as_str(), config.port), ("127.0.0.1", 1234));
+
+        // a broken file is reported with where it went wrong, and left as it was
+        let broken = b"port = 1234\naddress = 127.0.0.1\n";
+        write(broken);
+        match read_config(&file) {
+            Err(err @ Error::InvalidConfig(..)) => {
+                let message = err.to_string();
+                assert!(message.starts_with("invalid config: "), "{}", message);
+                assert!(message.contains(&file.display().to_string()), "{}", message);
+                assert!(message.contains("line 2"), "{}", message);
+            }
+            other => panic!("{:?}", other.map_err(|e| e.to_string())),
+        }
+        assert_eq!(std::fs::read(&file).unwrap(), broken);
+
+        write(b"port = \"not a number\"\n");
+        assert!(matches!(read_config(&file), Err(Error::InvalidConfig(..))));
+        assert!(matches!(read_config(&dir), Err(Error::Io(..))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
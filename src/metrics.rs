@@ -0,0 +1,108 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use once_cell::sync_lazy;
+
+// upper bounds, in seconds
+const LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+static INSERTS: AtomicU64 = AtomicU64::new(0);
+static YOUTUBE_API_CALLS: AtomicU64 = AtomicU64::new(0);
+
+static INSERT_ERRORS: Lazy<Mutex<BTreeMap<&'static str, u64>>> = sync_lazy! {
+    Mutex::new(BTreeMap::new())
+};
+
+static LATENCY: Lazy<Mutex<Histogram>> = sync_lazy! {
+    Mutex::new(Histogram::default())
+};
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+pub fn insert() {
+    INSERTS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn insert_error(kind: &'static str) {
+    *INSERT_ERRORS.lock().unwrap().entry(kind).or_default() += 1;
+}
+
+pub fn youtube_api_call() {
+    YOUTUBE_API_CALLS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn request_latency(elapsed: Duration) {
+    let secs = elapsed.as_secs_f64();
+    let mut histogram = LATENCY.lock().unwrap();
+    for (bucket, le) in histogram.buckets.iter_mut().zip(LATENCY_BUCKETS.iter()) {
+        if secs <= *le {
+            *bucket += 1
+        }
+    }
+    histogram.count += 1;
+    histogram.sum += secs;
+}
+
+// the prometheus text exposition format
+pub fn render() -> String {
+    let mut out = String::new();
+
+    out.push_str("# HELP dono_inserts_total Songs added to the queue.\n");
+    out.push_str("# TYPE dono_inserts_total counter\n");
+    let _ = writeln!(
+        out,
+        "dono_inserts_total {}",
+        INSERTS.load(Ordering::Relaxed)
+    );
+
+    out.push_str("# HELP dono_insert_errors_total Requests that could not be added.\n");
+    out.push_str("# TYPE dono_insert_errors_total counter\n");
+    for (kind, count) in INSERT_ERRORS.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "dono_insert_errors_total{{kind=\"{}\"}} {}",
+            kind, count
+        );
+    }
+
+    out.push_str("# HELP dono_youtube_api_calls_total Requests made to the youtube api.\n");
+    out.push_str("# TYPE dono_youtube_api_calls_total counter\n");
+    let _ = writeln!(
+        out,
+        "dono_youtube_api_calls_total {}",
+        YOUTUBE_API_CALLS.load(Ordering::Relaxed)
+    );
+
+    let histogram = LATENCY.lock().unwrap();
+    out.push_str("# HELP dono_request_duration_seconds Time taken to handle a request.\n");
+    out.push_str("# TYPE dono_request_duration_seconds histogram\n");
+    for (count, le) in histogram.buckets.iter().zip(LATENCY_BUCKETS.iter()) {
+        let _ = writeln!(
+            out,
+            "dono_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+            le, count
+        );
+    }
+    let _ = writeln!(
+        out,
+        "dono_request_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+        histogram.count
+    );
+    let _ = writeln!(out, "dono_request_duration_seconds_sum {}", histogram.sum);
+    let _ = writeln!(
+        out,
+        "dono_request_duration_seconds_count {}",
+        histogram.count
+    );
+
+    out
+}
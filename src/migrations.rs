@@ -0,0 +1,125 @@
+use log::*;
+
+use crate::error::Result;
+
+// applied in order, `PRAGMA user_version` records how many have been applied.
+// never edit one that has shipped, append a new one instead
+const MIGRATIONS: &[&str] = &[
+    include_str!("../sql/migrations/001_initial.sql"),
+    include_str!("../sql/migrations/002_youtube_metadata.sql"),
+    include_str!("../sql/migrations/003_spotify.sql"),
+    include_str!("../sql/migrations/004_soundcloud.sql"),
+    include_str!("../sql/migrations/005_played.sql"),
+    include_str!("../sql/migrations/006_requested_by.sql"),
+    include_str!("../sql/migrations/007_blacklist.sql"),
+    include_str!("../sql/migrations/008_position.sql"),
+    include_str!("../sql/migrations/009_idempotency.sql"),
+    include_str!("../sql/migrations/010_played_at.sql"),
+    include_str!("../sql/migrations/011_generic.sql"),
+    include_str!("../sql/migrations/012_indexes.sql"),
+    include_str!("../sql/migrations/013_local_files.sql"),
+];
+
+pub fn run(conn: &mut rusqlite::Connection) -> Result<()> {
+    let version: i64 =
+        conn.query_row("PRAGMA user_version", rusqlite::NO_PARAMS, |row| row.get(0))?;
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.execute_batch(&format!("PRAGMA user_version = {}", i + 1))?;
+        tx.commit()?;
+        info!("applied database migration {}", i + 1);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(conn: &rusqlite::Connection) -> i64 {
+        conn.query_row("PRAGMA user_version", rusqlite::NO_PARAMS, |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn applied_once() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        run(&mut conn).unwrap();
+        assert_eq!(version(&conn), MIGRATIONS.len() as i64);
+        // nothing left to apply, the later ones would fail if they ran twice
+        run(&mut conn).unwrap();
+        assert_eq!(version(&conn), MIGRATIONS.len() as i64);
+    }
+
+    #[test]
+    fn upgrades_old_databases() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.execute_batch("PRAGMA user_version = 1").unwrap();
+        conn.execute_batch(
+            "INSERT INTO youtube_videos (vid, ts, duration, title) VALUES ('aaaaaaaaaaa', 1, 2, 'a')",
+        )
+        .unwrap();
+
+        run(&mut conn).unwrap();
+        assert_eq!(version(&conn), MIGRATIONS.len() as i64);
+        let (title, channel): (String, String) = conn
+            .query_row(
+                "SELECT title, channel FROM youtube_videos",
+                rusqlite::NO_PARAMS,
+                |row| (row.get(0), row.get(1)),
+            )
+            .unwrap();
+        assert_eq!((title.as_str(), channel.as_str()), ("a", ""));
+    }
+
+    #[test]
+    fn indexes() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        run(&mut conn).unwrap();
+        let indexes = conn
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'index' AND sql IS NOT NULL")
+            .unwrap()
+            .query_map(rusqlite::NO_PARAMS, |row| row.get::<_, String>(0))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap();
+        for table in &[
+            "youtube_videos",
+            "local_songs",
+            "spotify_tracks",
+            "soundcloud_tracks",
+            "generic_media",
+        ] {
+            for index in &["played_position", "ts", "requested_by"] {
+                let name = format!("{}_{}", table, index);
+                assert!(indexes.contains(&name), "{}", name);
+            }
+        }
+        for name in &[
+            "youtube_videos_vid_ts",
+            "spotify_tracks_tid_ts",
+            "soundcloud_tracks_permalink_ts",
+            "generic_media_url_ts",
+        ] {
+            assert!(indexes.iter().any(|i| i == name), "{}", name);
+        }
+
+        // and the queue is read through one
+        let plan = conn
+            .prepare(&format!(
+                "EXPLAIN QUERY PLAN {}",
+                include_str!("../sql/youtube/get_pending.sql")
+            ))
+            .unwrap()
+            .query_map(rusqlite::NO_PARAMS, |row| row.get::<_, String>(3))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap()
+            .join("\n");
+        assert!(plan.contains("youtube_videos_played_position"), "{}", plan);
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::config;
+use crate::error::{Error, Result};
+
+// tracks a per-requester cooldown and a global requests per minute cap
+#[derive(Default)]
+pub struct RateLimiter {
+    last: HashMap<String, Instant>,
+    recent: VecDeque<Instant>,
+}
+
+impl RateLimiter {
+    pub fn check(&mut self, who: &str, now: Instant) -> Result<()> {
+        const MINUTE: Duration = Duration::from_secs(60);
+
+        let config = config::get();
+        let cooldown = Duration::from_secs(config.request_cooldown_secs);
+
+        self.last
+            .retain(|_, last| now.duration_since(*last) < cooldown);
+        if let Some(last) = self.last.get(who) {
+            return Err(Error::RateLimited {
+                retry_after: retry_after(cooldown - now.duration_since(*last)),
+            });
+        }
+
+        while let Some(front) = self.recent.front() {
+            if now.duration_since(*front) < MINUTE {
+                break;
+            }
+            self.recent.pop_front();
+        }
+
+        let max = config.requests_per_minute as usize;
+        if max > 0 && self.recent.len() >= max {
+            let oldest = self.recent.front().expect("at least one request");
+            return Err(Error::RateLimited {
+                retry_after: retry_after(MINUTE - now.duration_since(*oldest)),
+            });
+        }
+
+        if !cooldown.is_zero() {
+            self.last.insert(who.to_string(), now);
+        }
+        if max > 0 {
+            self.recent.push_back(now);
+        }
+        Ok(())
+    }
+}
+
+// rounded up to whole seconds for the `Retry-After` header
+fn retry_after(remaining: Duration) -> u64 {
+    remaining.as_secs() + if remaining.subsec_nanos() > 0 { 1 } else { 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test::setup_with;
+
+    fn retry(res: Result<()>) -> Option<u64> {
+        match res {
+            Err(Error::RateLimited { retry_after }) => Some(retry_after),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn cooldown() {
+        let _guard = setup_with(|config| config.request_cooldown_secs = 10);
+        let (mut limiter, now) = (RateLimiter::default(), Instant::now());
+        assert!(limiter.check("a", now).is_ok());
+        assert!(limiter.check("b", now).is_ok());
+        let later = now + Duration::from_millis(2500);
+        assert_eq!(retry(limiter.check("a", later)), Some(8));
+        assert!(limiter.check("a", now + Duration::from_secs(10)).is_ok());
+    }
+
+    #[test]
+    fn requests_per_minute() {
+        let _guard = setup_with(|config| {
+            config.request_cooldown_secs = 0;
+            config.requests_per_minute = 2;
+        });
+        let (mut limiter, now) = (RateLimiter::default(), Instant::now());
+        assert!(limiter.check("a", now).is_ok());
+        assert!(limiter.check("a", now + Duration::from_secs(20)).is_ok());
+        assert_eq!(
+            retry(limiter.check("b", now + Duration::from_secs(30))),
+            Some(30)
+        );
+        // the first one has aged out
+        assert!(limiter.check("b", now + Duration::from_secs(60)).is_ok());
+    }
+
+    #[test]
+    fn whole_seconds() {
+        assert_eq!(retry_after(Duration::from_secs(3)), 3);
+        assert_eq!(retry_after(Duration::from_millis(3001)), 4);
+        assert_eq!(retry_after(Duration::from_millis(1)), 1);
+    }
+}
@@ -1,222 +1,4005 @@
-use std::fmt;
-use std::net::ToSocketAddrs;
-
-use log::*;
-use regex::Regex;
-use serde::{Deserialize, Serialize};
-
-use crate::error::{Error, Result};
-use crate::Storage;
-
-use crate::{local::Local, youtube::Youtube};
-
-pub struct HttpServer {
-    server: tiny_http::Server,
-    list_regex: Regex,
-}
-
-impl HttpServer {
-    pub fn new<A>(addr: A) -> Result<Self>
-    where
-        A: ToSocketAddrs + fmt::Debug + Clone,
-    {
-        let server = tiny_http::Server::http(addr.clone()).map_err(|err| {
-            error!("cannot bind http server at {:?}: {}", addr, err);
-            Error::BindHttp(format!("{:?}", addr))
-        })?;
-
-        info!(
-            "started http server at: {}",
-            addr.to_socket_addrs().unwrap().next().unwrap()
-        );
-
-        Ok(Self {
-            server,
-            list_regex: Regex::new(r#"/list/(?P<ty>\w.*?)(/|$)"#).expect("regex"),
-        })
-    }
-
-    pub fn run(mut self) {
-        loop {
-            let req = match self.server.recv() {
-                Ok(req) => req,
-                Err(err) => {
-                    error!("cannot recv request: {}", err);
-                    continue;
-                }
-            };
-
-            if let Err(err) = self.handle(req) {
-                error!("processing request failed: {}", err)
-            }
-        }
-    }
-
-    fn handle(&mut self, mut req: tiny_http::Request) -> Result<()> {
-        trace!("{} {}", req.method(), req.url());
-
-        macro_rules! err {
-            ($req:expr) => {{
-                debug!("unknown {} on {}", $req.method(), req.url());
-                $req.respond(tiny_http::Response::empty(400))
-                    .map_err(Error::Io)
-            }};
-        }
-
-        use tiny_http::Method::*;
-
-        match (req.method(), req.url()) {
-            (Get, "/current") => Self::compare(
-                Youtube.current().map(|t| (t, Kind::Youtube)),
-                Local.current().map(|t| (t, Kind::Local)),
-                req,
-                std::cmp::Ordering::Greater,
-            ),
-            (Get, "/previous") => Self::compare(
-                Youtube.current().map(|t| (t, Kind::Youtube)),
-                Local.current().map(|t| (t, Kind::Local)),
-                req,
-                std::cmp::Ordering::Less,
-            ),
-            (Get, other) => {
-                let namespace = self
-                    .list_regex
-                    .captures(other)
-                    .and_then(|c| c.name("ty"))
-                    .and_then(|s| Some(s.as_str()))
-                    .map(|s| s.to_lowercase());
-
-                match namespace.unwrap_or_else(|| "".into()).as_str() {
-                    "youtube" => Self::respond(Self::check(Youtube.all(), req)?),
-                    "local" => Self::respond(Self::check(Local.all(), req)?),
-                    _ => err!(req),
-                }
-            }
-
-            (Post, path @ "/youtube") | (Post, path @ "/local") => {
-                trace!("handling post at {}", path);
-
-                // TODO return a better error message for this (wrong version, etc)
-                let item: Item =
-                    serde_json::from_reader(req.as_reader()).map_err(Error::Serialize)?;
-
-                if item.version != 1 {
-                    return req
-                        .respond(tiny_http::Response::empty(400))
-                        .map_err(Error::Io);
-                }
-
-                match item.kind {
-                    ItemKind::Local { .. } => Local.insert(&item),
-                    ItemKind::Youtube(..) => Youtube.insert(&item),
-                }
-            }
-
-            _ => err!(req),
-        }
-    }
-
-    fn compare<L, R>(
-        left: Result<(L, Kind)>,
-        right: Result<(R, Kind)>,
-        req: tiny_http::Request,
-        op: std::cmp::Ordering,
-    ) -> Result<()>
-    where
-        L: Serialize + crate::FromRow,
-        R: Serialize + crate::FromRow,
-    {
-        #[derive(Serialize)]
-        #[serde(rename_all = "lowercase")]
-        struct Outgoing<T>
-        where
-            T: Serialize + crate::FromRow,
-        {
-            data: T,
-            kind: Kind,
-        }
-
-        impl<T> From<(T, Kind)> for Outgoing<T>
-        where
-            T: Serialize + crate::FromRow,
-        {
-            fn from((data, kind): (T, Kind)) -> Self {
-                Self { data, kind }
-            }
-        }
-
-        let left = left.map(Outgoing::from);
-        let right = right.map(Outgoing::from);
-
-        match (left.is_err(), right.is_err()) {
-            (true, true) => {
-                warn!("no songs in either table");
-                req.respond(tiny_http::Response::from_string("[]").with_status_code(204))
-                    .map_err(Error::Io)
-            }
-            (false, true) => Self::respond((vec![left.unwrap()], req)),
-            (true, false) => Self::respond((vec![right.unwrap()], req)),
-            (false, false) => {
-                let (left, right) = (left.unwrap(), right.unwrap());
-                if left.data.timestamp().cmp(&right.data.timestamp()) == op {
-                    Self::respond((vec![left], req))
-                } else {
-                    Self::respond((vec![right], req))
-                }
-            }
-        }
-    }
-
-    fn check<T>(res: Result<T>, req: tiny_http::Request) -> Result<(T, tiny_http::Request)> {
-        match res {
-            Ok(d) => Ok((d, req)),
-            Err(err) => req
-                .respond(tiny_http::Response::empty(500))
-                .map_err(Error::Io)
-                .and_then(|_| Err(err)),
-        }
-    }
-
-    fn respond<T>((res, req): (T, tiny_http::Request)) -> Result<()>
-    where
-        T: Serialize,
-    {
-        let data = match serde_json::to_vec(&res).map_err(Error::Serialize) {
-            Ok(data) => data,
-            Err(err) => {
-                return req
-                    .respond(tiny_http::Response::empty(400))
-                    .map_err(Error::Io)
-                    .and_then(|_| Err(err));
-            }
-        };
-        req.respond(tiny_http::Response::from_data(data))
-            .map_err(Error::Io)
-    }
-}
-
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub enum ItemKind {
-    Youtube(String),
-    Local {
-        artist: String,
-        title: String,
-        album: String,
-    },
-}
-
-#[derive(Serialize)]
-#[serde(rename_all = "lowercase")]
-enum Kind {
-    Youtube,
-    Local,
-}
-
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub struct Item {
-    pub kind: ItemKind,
-    pub ts: i64,
-    pub version: u32,
-}
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::*;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::backup;
+use crate::blacklist;
+use crate::conditional;
+use crate::config;
+use crate::database;
+use crate::error::{Error, Result};
+use crate::export;
+use crate::idempotency;
+use crate::metrics;
+use crate::ratelimit::RateLimiter;
+use crate::skip::SkipVotes;
+use crate::stats;
+use crate::subscribers::{Protocol, Subscribers};
+use crate::websocket;
+use crate::xml;
+use crate::Storage;
+
+use crate::{
+    generic::Generic, local::Local, soundcloud::SoundCloud, spotify::Spotify, youtube::Youtube,
+};
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+// how often played songs past `retention_days` are deleted
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+thread_local! {
+    // set while a request is handled, so its log lines can be told apart
+    static REQUEST_ID: Cell<Option<u64>> = const { Cell::new(None) };
+    // whatever `send` responded with, for the access log
+    static STATUS: Cell<Option<u16>> = const { Cell::new(None) };
+}
+
+// the id of the request being handled on this thread, if any
+pub fn request_id() -> Option<u64> {
+    REQUEST_ID.with(Cell::get)
+}
+
+pub struct HttpServer {
+    servers: Vec<tiny_http::Server>,
+    // a unix socket file is left behind by the listener, so it's removed once the server stops
+    socket: Option<PathBuf>,
+    list_regex: Regex,
+    // `/<kind>/<id>`, ids are only unique within a kind
+    song_regex: Regex,
+    stream_regex: Regex,
+    shutdown: ShutdownHandle,
+    limiter: RateLimiter,
+    skip_votes: SkipVotes<(Kind, i64)>,
+    subscribers: Subscribers,
+}
+
+#[derive(Clone, Default)]
+pub struct ShutdownHandle(Arc<AtomicBool>);
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.0.store(true, Ordering::SeqCst)
+    }
+
+    fn is_shutdown(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    fn register_signals(&self) -> std::io::Result<()> {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+        let mut signals = signal_hook::iterator::Signals::new([SIGINT, SIGTERM])?;
+        let handle = self.clone();
+        thread::spawn(move || {
+            if let Some(signal) = signals.forever().next() {
+                info!("received signal {}, shutting down", signal);
+                handle.shutdown()
+            }
+        });
+        Ok(())
+    }
+}
+
+impl HttpServer {
+    // with `tls` every listener serves https instead
+    pub fn new<A>(addrs: &[A], tls: Option<&tiny_http::SslConfig>) -> Result<Self>
+    where
+        A: ToSocketAddrs + fmt::Debug + Clone,
+    {
+        let servers = addrs
+            .iter()
+            .map(|addr| {
+                let server = tiny_http::ConfigListenAddr::from_socket_addrs(addr.clone())
+                    .map_err(Into::into)
+                    .and_then(|addr| bind(addr, tls))
+                    .map_err(|err| {
+                        error!("cannot bind http server at {:?}: {}", addr, err);
+                        Error::BindHttp(format!("{:?}", addr))
+                    })?;
+                info!("started http server at: {}", server.server_addr());
+                Ok(server)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self::with_servers(servers, None))
+    }
+
+    #[cfg(unix)]
+    pub fn unix(path: &Path, tls: Option<&tiny_http::SslConfig>) -> Result<Self> {
+        let addr = tiny_http::ConfigListenAddr::unix_from_path(path);
+        let server = bind(addr, tls).map_err(|err| {
+            error!("cannot bind http server at {}: {}", path.display(), err);
+            Error::BindHttp(path.display().to_string())
+        })?;
+        info!("started http server at: {}", path.display());
+        Ok(Self::with_servers(vec![server], Some(path.to_path_buf())))
+    }
+
+    fn with_servers(servers: Vec<tiny_http::Server>, socket: Option<PathBuf>) -> Self {
+        Self {
+            servers,
+            socket,
+            list_regex: Regex::new(r#"/list/(?P<ty>\w.*?)(/|$)"#).expect("regex"),
+            song_regex: Regex::new(r#"^/(?P<ty>\w+?)/(?P<id>\d+)/?$"#).expect("regex"),
+            stream_regex: Regex::new(r#"^/local/(?P<id>\d+)/stream/?$"#).expect("regex"),
+            shutdown: ShutdownHandle::default(),
+            limiter: RateLimiter::default(),
+            skip_votes: SkipVotes::default(),
+            subscribers: Subscribers::default(),
+        }
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    // returns once the shutdown handle is triggered, or on SIGINT/SIGTERM
+    pub fn run(mut self) {
+        if let Err(err) = self.shutdown_handle().register_signals() {
+            warn!("cannot install signal handlers: {}", err)
+        }
+
+        // every listener feeds the same queue, requests are still handled one at a time
+        let (tx, rx) = mpsc::channel();
+        let listeners = std::mem::take(&mut self.servers)
+            .into_iter()
+            .map(|server| {
+                let (tx, shutdown) = (tx.clone(), self.shutdown.clone());
+                thread::spawn(move || {
+                    // wake up periodically so a shutdown is noticed without a request
+                    while !shutdown.is_shutdown() {
+                        match server.recv_timeout(Duration::from_millis(250)) {
+                            Ok(Some(req)) => {
+                                if tx.send(req).is_err() {
+                                    break;
+                                }
+                            }
+                            Ok(None) => continue,
+                            Err(err) => error!("cannot recv request: {}", err),
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(tx);
+
+        let shutdown = self.shutdown.clone();
+        thread::spawn(move || {
+            // the first run is at startup
+            let mut last: Option<Instant> = None;
+            while !shutdown.is_shutdown() {
+                if last.is_some_and(|last| last.elapsed() < PRUNE_INTERVAL) {
+                    thread::sleep(Duration::from_millis(250));
+                    continue;
+                }
+                last = Some(Instant::now());
+                match Self::prune() {
+                    Ok(0) => {}
+                    Ok(n) => info!("pruned {} played songs", n),
+                    Err(err) => warn!("cannot prune played songs: {}", err),
+                }
+            }
+        });
+
+        while !self.shutdown.is_shutdown() {
+            let req = match rx.recv_timeout(Duration::from_millis(250)) {
+                Ok(req) => req,
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            };
+
+            let id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+            REQUEST_ID.with(|s| s.set(Some(id)));
+            STATUS.with(|s| s.set(None));
+
+            let method = req.method().clone();
+            let url = req.url().to_string();
+            // unix socket clients don't have an address
+            let addr = req
+                .remote_addr()
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_else(|| "-".into());
+
+            let start = Instant::now();
+            let res = self.handle(req);
+            let elapsed = start.elapsed();
+            metrics::request_latency(elapsed);
+
+            // rejected requests are already covered by their access log line
+            let status = STATUS.with(Cell::get);
+            if let Err(err) = res {
+                match status {
+                    Some(status) if status < 500 => debug!("request failed: {}", err),
+                    _ => error!("processing request failed: {}", err),
+                }
+            }
+
+            let level = match status {
+                Some(status) if status < 400 => Level::Info,
+                Some(status) if status < 500 => Level::Warn,
+                _ => Level::Error,
+            };
+            log!(
+                level,
+                "{} \"{} {}\" {} {:.3}ms",
+                addr,
+                method,
+                url,
+                status.map(|s| s.to_string()).unwrap_or_else(|| "-".into()),
+                elapsed.as_secs_f64() * 1000.0
+            );
+            REQUEST_ID.with(|s| s.set(None));
+        }
+
+        for listener in listeners {
+            let _ = listener.join();
+        }
+        if let Some(path) = &self.socket {
+            if let Err(err) = std::fs::remove_file(path) {
+                warn!("cannot remove {}: {}", path.display(), err)
+            }
+        }
+
+        info!("http server stopped");
+        database::close();
+    }
+
+    fn handle(&mut self, mut req: tiny_http::Request) -> Result<()> {
+        trace!("{} {}", req.method(), req.url());
+
+        macro_rules! err {
+            ($req:expr) => {{
+                debug!("unknown {} on {}", $req.method(), req.url());
+                Self::empty($req, 400)
+            }};
+        }
+
+        use tiny_http::Method::*;
+
+        let url = req.url().to_string();
+        let (path, query) = split_url(&url);
+
+        let protected = match (req.method(), path) {
+            // moderation data is never public
+            (Post, _) | (Delete, _) | (Get, "/blacklist") => true,
+            // probes and preflights never carry a token
+            (Options, _) | (Get, "/health") | (Get, "/ready") => false,
+            (Get, "/metrics") => config::get().protect_metrics,
+            _ => config::get().protect_reads,
+        };
+        if protected {
+            if let Err(err) = authorize(&req) {
+                return Self::respond_error(err, req);
+            }
+        }
+
+        match (req.method(), path) {
+            (Get, "/current") => match Self::check(Self::current(), req)? {
+                (Some((_, song)), req) => Self::respond((vec![song], req)),
+                (None, req) => {
+                    debug!("no songs in any table");
+                    Self::empty(req, 204)
+                }
+            },
+            (Get, "/previous") => {
+                let n = param(&query, "n").unwrap_or(1);
+                match Self::check(Self::previous(n), req)? {
+                    (ref songs, req) if songs.is_empty() => {
+                        debug!("no played songs in any table");
+                        Self::empty(req, 204)
+                    }
+                    (songs, req) => Self::respond((songs, req)),
+                }
+            }
+            (Get, "/export.csv") => {
+                let (rows, req) = Self::check(Self::csv_rows(), req)?;
+                let csv = export::csv(
+                    &[
+                        "kind",
+                        "id",
+                        "vid",
+                        "title",
+                        "channel",
+                        "duration",
+                        "timestamp",
+                        "requested_by",
+                    ],
+                    rows.into_iter().map(|(_, row)| row),
+                );
+                Self::send(
+                    req,
+                    200,
+                    tiny_http::Response::from_data(csv).with_header(
+                        "Content-Type: text/csv; charset=utf-8"
+                            .parse::<tiny_http::Header>()
+                            .expect("valid header"),
+                    ),
+                )
+            }
+            (Get, "/export.json") => Self::respond(Self::check(backup::export(), req)?),
+            (Get, "/queue") => Self::respond(Self::check(Self::queue(), req)?),
+            (Get, "/queue/duration") => Self::respond(Self::check(stats::pending_duration(), req)?),
+            (Get, "/queue.m3u") => {
+                let (entries, req) = Self::check(Self::playlist(), req)?;
+                Self::send(
+                    req,
+                    200,
+                    tiny_http::Response::from_data(export::m3u(&entries)).with_header(
+                        "Content-Type: audio/x-mpegurl"
+                            .parse::<tiny_http::Header>()
+                            .expect("valid header"),
+                    ),
+                )
+            }
+            (Get, "/blacklist") => Self::respond(Self::check(blacklist::all(), req)?),
+            (Get, "/leaderboard") => {
+                const DEFAULT_LIMIT: u32 = 10;
+                // the window defaults to all time
+                let start = param(&query, "start").unwrap_or(i64::MIN);
+                let end = param(&query, "end").unwrap_or(i64::MAX);
+                let limit = param(&query, "limit").unwrap_or(DEFAULT_LIMIT);
+                Self::respond(Self::check(stats::leaderboard(start, end, limit), req)?)
+            }
+            (Get, "/health") => Self::send(req, 200, tiny_http::Response::from_string("OK")),
+            (Get, "/ready") => {
+                if database::ping() {
+                    Self::send(req, 200, tiny_http::Response::from_string("OK"))
+                } else {
+                    warn!("database is unreachable");
+                    Self::send(
+                        req,
+                        503,
+                        tiny_http::Response::from_string("database unavailable"),
+                    )
+                }
+            }
+            (Get, "/metrics") => Self::send(
+                req,
+                200,
+                tiny_http::Response::from_data(metrics::render()).with_header(
+                    "Content-Type: text/plain; version=0.0.4"
+                        .parse::<tiny_http::Header>()
+                        .expect("valid header"),
+                ),
+            ),
+            (Get, "/ws") => {
+                let accept = match header(&req, "Sec-WebSocket-Key") {
+                    Some(key) => websocket::accept_key(key),
+                    None => return err!(req),
+                };
+                let header = format!("Sec-WebSocket-Accept: {}", accept)
+                    .parse::<tiny_http::Header>()
+                    .expect("valid header");
+
+                let (data, req) = Self::check(Self::now_playing(), req)?;
+                STATUS.with(|s| s.set(Some(101)));
+                let stream = req.upgrade(
+                    "websocket",
+                    tiny_http::Response::empty(101).with_header(header),
+                );
+                self.subscribers.add(Protocol::WebSocket, stream, &data);
+                Ok(())
+            }
+            (Get, "/events") => {
+                let (data, req) = Self::check(Self::now_playing(), req)?;
+                // tiny_http buffers streamed bodies, so take over the socket like /ws does.
+                // this adds an `Upgrade` header to the response, which clients ignore
+                let resp = tiny_http::Response::empty(200)
+                    .with_header(
+                        "Content-Type: text/event-stream"
+                            .parse::<tiny_http::Header>()
+                            .expect("valid header"),
+                    )
+                    .with_header(
+                        "Cache-Control: no-cache"
+                            .parse::<tiny_http::Header>()
+                            .expect("valid header"),
+                    );
+                STATUS.with(|s| s.set(Some(200)));
+                let stream = req.upgrade("text/event-stream", resp);
+                self.subscribers.add(Protocol::EventStream, stream, &data);
+                Ok(())
+            }
+            (Get, "/count") => {
+                #[derive(Serialize)]
+                struct Count {
+                    youtube: i64,
+                    local: i64,
+                    spotify: i64,
+                    soundcloud: i64,
+                    generic: i64,
+                }
+
+                let count = (|| {
+                    Ok(Count {
+                        youtube: Youtube.count()?,
+                        local: Local.count()?,
+                        spotify: Spotify.count()?,
+                        soundcloud: SoundCloud.count()?,
+                        generic: Generic.count()?,
+                    })
+                })();
+                Self::respond(Self::check(count, req)?)
+            }
+            (Get, "/search") => {
+                const DEFAULT_LIMIT: u32 = 5;
+                const MAX_LIMIT: u32 = 25;
+
+                let q = match query.get("q").map(|q| decode(q)) {
+                    Some(q) if !q.trim().is_empty() => q,
+                    _ => return err!(req),
+                };
+                let limit = param(&query, "limit")
+                    .unwrap_or(DEFAULT_LIMIT)
+                    .clamp(1, MAX_LIMIT);
+
+                // each search costs 100 quota units
+                let results = match config::get().allow_search {
+                    true => crate::youtube::search(&q, limit),
+                    false => Err(Error::SearchDisabled),
+                };
+                Self::respond(Self::check(results, req)?)
+            }
+
+            (Get, "/range") => {
+                #[derive(Serialize)]
+                struct Range {
+                    youtube: Vec<crate::youtube::Song>,
+                    local: Vec<crate::local::Song>,
+                    spotify: Vec<crate::spotify::Track>,
+                    soundcloud: Vec<crate::soundcloud::Track>,
+                    generic: Vec<crate::generic::Media>,
+                }
+
+                let (start, end) = match (param(&query, "start"), param(&query, "end")) {
+                    (Some(start), Some(end)) => (start, end),
+                    _ => return err!(req),
+                };
+
+                let range = (|| {
+                    Ok(Range {
+                        youtube: Youtube.between(start, end)?,
+                        local: Local.between(start, end)?,
+                        spotify: Spotify.between(start, end)?,
+                        soundcloud: SoundCloud.between(start, end)?,
+                        generic: Generic.between(start, end)?,
+                    })
+                })();
+                Self::respond(Self::check(range, req)?)
+            }
+            (Get, path) if self.song_regex.is_match(path) => {
+                let song = match self.song(path) {
+                    Some((ref ty, id)) if ty == "youtube" => Youtube
+                        .get(id)
+                        .and_then(|s| Self::outgoing(s, Kind::Youtube)),
+                    Some((ref ty, id)) if ty == "local" => {
+                        Local.get(id).and_then(|s| Self::outgoing(s, Kind::Local))
+                    }
+                    Some((ref ty, id)) if ty == "spotify" => Spotify
+                        .get(id)
+                        .and_then(|s| Self::outgoing(s, Kind::Spotify)),
+                    Some((ref ty, id)) if ty == "soundcloud" => SoundCloud
+                        .get(id)
+                        .and_then(|s| Self::outgoing(s, Kind::SoundCloud)),
+                    Some((ref ty, id)) if ty == "generic" => Generic
+                        .get(id)
+                        .and_then(|s| Self::outgoing(s, Kind::Generic)),
+                    _ => return err!(req),
+                };
+
+                match Self::check(song, req)? {
+                    (Some((_, _, song)), req) => Self::respond((song, req)),
+                    (None, req) => Self::empty(req, 404),
+                }
+            }
+
+            (Get, path) if self.stream_regex.is_match(path) => {
+                let id = self
+                    .stream_regex
+                    .captures(path)
+                    .and_then(|c| c["id"].parse::<i64>().ok());
+                let id = match id {
+                    Some(id) => id,
+                    None => return err!(req),
+                };
+                // checked again, the file or `local_root` may have changed since it was queued.
+                // without a root nothing is served, whatever was stored before it was unset
+                let file = Local
+                    .get(id)
+                    .map(|song| song.and_then(|song| song.path))
+                    .and_then(|path| path.as_deref().map(crate::local::resolve).transpose());
+                match Self::check(file, req)? {
+                    (Some(file), req) => Self::stream(req, &file),
+                    (None, req) => Self::empty(req, 404),
+                }
+            }
+
+            (Get, other) => {
+                let namespace = self
+                    .list_regex
+                    .captures(other)
+                    .and_then(|c| c.name("ty"))
+                    .and_then(|s| Some(s.as_str()))
+                    .map(|s| s.to_lowercase());
+
+                const DEFAULT_PAGE_SIZE: u32 = 100;
+                let limit = param(&query, "limit").unwrap_or(DEFAULT_PAGE_SIZE);
+                let offset = param(&query, "offset");
+
+                match namespace.unwrap_or_else(|| "".into()).as_str() {
+                    "youtube" if param(&query, "with_counts").unwrap_or(false) => Self::respond(
+                        Self::check(Youtube.all_with_counts(Some(limit), offset), req)?,
+                    ),
+                    "youtube" => Self::respond(Self::check(Youtube.all(Some(limit), offset), req)?),
+                    "local" => Self::respond(Self::check(Local.all(Some(limit), offset), req)?),
+                    "spotify" => Self::respond(Self::check(Spotify.all(Some(limit), offset), req)?),
+                    "soundcloud" => {
+                        Self::respond(Self::check(SoundCloud.all(Some(limit), offset), req)?)
+                    }
+                    "generic" => Self::respond(Self::check(Generic.all(Some(limit), offset), req)?),
+                    _ => err!(req),
+                }
+            }
+
+            (Post, path @ "/youtube")
+            | (Post, path @ "/local")
+            | (Post, path @ "/spotify")
+            | (Post, path @ "/soundcloud")
+            | (Post, path @ "/generic") => {
+                trace!("handling post at {}", path);
+
+                // a retry of a request that already went through gets the same response
+                let key = header(&req, "Idempotency-Key")
+                    .filter(|key| !key.is_empty())
+                    .map(str::to_string);
+                let replayed = key.as_ref().map(|key| idempotency::replay(key));
+                let (replayed, req) = Self::check(replayed.transpose(), req)?;
+                if let (Some(key), Some((status, body))) = (&key, replayed.and_then(|s| s)) {
+                    debug!("replaying the response for idempotency key {}", key);
+                    return Self::replay(req, status, body);
+                }
+
+                let (item, req) = Self::incoming(req)?;
+                let (_, req) = Self::check(Self::enabled(item.kind.kind()), req)?;
+
+                // anonymous requests are keyed by address, unix socket clients all share one
+                let who = match item.requested_by.as_str() {
+                    "" => req
+                        .remote_addr()
+                        .map(|addr| addr.ip().to_string())
+                        .unwrap_or_default(),
+                    name => name.to_string(),
+                };
+                let limited = self.limiter.check(&who, Instant::now());
+                let (_, req) = Self::check(limited, req)?;
+
+                let key = key.as_deref();
+                let (res, kind) = match item.kind {
+                    ItemKind::Local { .. } => {
+                        (Self::insert(Local, &item, Kind::Local, key), "local")
+                    }
+                    ItemKind::Youtube(..) => {
+                        (Self::insert(Youtube, &item, Kind::Youtube, key), "youtube")
+                    }
+                    ItemKind::Spotify(..) => {
+                        (Self::insert(Spotify, &item, Kind::Spotify, key), "spotify")
+                    }
+                    ItemKind::SoundCloud(..) => (
+                        Self::insert(SoundCloud, &item, Kind::SoundCloud, key),
+                        "soundcloud",
+                    ),
+                    ItemKind::Generic { .. } => {
+                        (Self::insert(Generic, &item, Kind::Generic, key), "generic")
+                    }
+                };
+                match res {
+                    Ok(..) => metrics::insert(),
+                    Err(..) => metrics::insert_error(kind),
+                }
+                let ((id, body), req) = Self::check(res, req)?;
+                let location = format!("Location: /{}/{}", kind, id)
+                    .parse::<tiny_http::Header>()
+                    .expect("valid header");
+                Self::send(
+                    req,
+                    201,
+                    tiny_http::Response::from_data(body)
+                        .with_header(json_header())
+                        .with_header(location),
+                )?;
+                self.notify();
+                Ok(())
+            }
+
+            (Post, "/validate") => {
+                let (item, req) = Self::incoming(req)?;
+                // a dry run, it doesn't count against the rate limit
+                let song = (|| {
+                    Self::enabled(item.kind.kind())?;
+                    let song = match &item.kind {
+                        ItemKind::Local { .. } => {
+                            Self::outgoing(Some(Local.validate(&item)?), Kind::Local)?
+                        }
+                        ItemKind::Youtube(..) => {
+                            Self::outgoing(Some(Youtube.validate(&item)?), Kind::Youtube)?
+                        }
+                        ItemKind::Spotify(..) => {
+                            Self::outgoing(Some(Spotify.validate(&item)?), Kind::Spotify)?
+                        }
+                        ItemKind::SoundCloud(..) => {
+                            Self::outgoing(Some(SoundCloud.validate(&item)?), Kind::SoundCloud)?
+                        }
+                        ItemKind::Generic { .. } => {
+                            Self::outgoing(Some(Generic.validate(&item)?), Kind::Generic)?
+                        }
+                    };
+                    Ok(song.map(|(_, _, song)| song))
+                })();
+                Self::respond(Self::check(song, req)?)
+            }
+
+            // youtube urls only, so they can share lookups
+            (Post, "/validate/bulk") => {
+                #[derive(Serialize)]
+                struct Failure {
+                    error: &'static str,
+                    message: String,
+                }
+
+                #[derive(Serialize)]
+                struct Checked {
+                    url: String,
+                    ok: bool,
+                    #[serde(skip_serializing_if = "Option::is_none")]
+                    error: Option<Failure>,
+                    #[serde(skip_serializing_if = "Option::is_none")]
+                    metadata: Option<crate::youtube::Song>,
+                }
+
+                let urls = Self::enabled(Kind::Youtube).and_then(|_| {
+                    serde_json::from_reader::<_, Vec<String>>(req.as_reader())
+                        .map_err(Error::Deserialize)
+                });
+                let (urls, req) = Self::check(urls, req)?;
+
+                let ts = crate::unix_time();
+                let items = urls
+                    .iter()
+                    .map(|url| Item {
+                        kind: ItemKind::Youtube(url.clone()),
+                        ts,
+                        version: 1,
+                        requested_by: String::new(),
+                    })
+                    .collect::<Vec<_>>();
+
+                let checked = Youtube.validate_each(&items).map(|results| {
+                    urls.into_iter()
+                        .zip(results)
+                        .map(|(url, res)| match res {
+                            Ok(song) => Checked {
+                                url,
+                                ok: true,
+                                error: None,
+                                metadata: Some(song),
+                            },
+                            Err(err) => Checked {
+                                url,
+                                ok: false,
+                                error: Some(Failure {
+                                    error: err.code(),
+                                    message: err.to_string(),
+                                }),
+                                metadata: None,
+                            },
+                        })
+                        .collect::<Vec<_>>()
+                });
+                Self::respond(Self::check(checked, req)?)
+            }
+
+            (Post, "/import/playlist") => {
+                #[derive(Deserialize)]
+                struct Playlist {
+                    url: String,
+                    #[serde(default)]
+                    requested_by: String,
+                }
+
+                let playlist = Self::enabled(Kind::Youtube)
+                    .and_then(|_| {
+                        serde_json::from_reader::<_, Playlist>(req.as_reader())
+                            .map_err(Error::Deserialize)
+                    })
+                    .and_then(|playlist| {
+                        blacklist::check(blacklist::EntryKind::User, &playlist.requested_by)?;
+                        Ok(playlist)
+                    });
+                let (playlist, req) = Self::check(playlist, req)?;
+
+                // the whole playlist counts as one request against the cooldown
+                let who = match playlist.requested_by.as_str() {
+                    "" => req
+                        .remote_addr()
+                        .map(|addr| addr.ip().to_string())
+                        .unwrap_or_default(),
+                    name => name.to_string(),
+                };
+                let limited = self.limiter.check(&who, Instant::now());
+                let (_, req) = Self::check(limited, req)?;
+
+                let (imported, req) = Self::check(
+                    Self::import_playlist(&playlist.url, &playlist.requested_by),
+                    req,
+                )?;
+                info!(
+                    "imported {} of {} videos from {}",
+                    imported.imported,
+                    imported.imported + imported.failed.len(),
+                    playlist.url
+                );
+                let notify = imported.imported > 0;
+                Self::respond((imported, req))?;
+                if notify {
+                    self.notify();
+                }
+                Ok(())
+            }
+
+            (Post, "/local/scan") => {
+                #[derive(Deserialize)]
+                struct Scan {
+                    path: String,
+                    #[serde(default)]
+                    requested_by: String,
+                }
+
+                let scan = Self::enabled(Kind::Local)
+                    .and_then(|_| {
+                        serde_json::from_reader::<_, Scan>(req.as_reader())
+                            .map_err(Error::Deserialize)
+                    })
+                    .and_then(|scan| {
+                        blacklist::check(blacklist::EntryKind::User, &scan.requested_by)?;
+                        Ok(scan)
+                    });
+                let (scan, req) = Self::check(scan, req)?;
+
+                // like a playlist, the whole scan counts as one request against the cooldown
+                let who = match scan.requested_by.as_str() {
+                    "" => req
+                        .remote_addr()
+                        .map(|addr| addr.ip().to_string())
+                        .unwrap_or_default(),
+                    name => name.to_string(),
+                };
+                let limited = self.limiter.check(&who, Instant::now());
+                let (_, req) = Self::check(limited, req)?;
+
+                let (scanned, req) =
+                    Self::check(Self::scan_local(&scan.path, &scan.requested_by), req)?;
+                info!(
+                    "added {} files from {}, skipped {}",
+                    scanned.added, scan.path, scanned.skipped
+                );
+                let notify = scanned.added > 0;
+                Self::respond((scanned, req))?;
+                if notify {
+                    self.notify();
+                }
+                Ok(())
+            }
+
+            (Post, "/advance") => {
+                match Self::check(Self::advance(), req)? {
+                    (Some((_, song)), req) => Self::respond((vec![song], req))?,
+                    (None, req) => {
+                        debug!("reached the end of the queue");
+                        Self::empty(req, 204)?
+                    }
+                }
+                self.notify();
+                Ok(())
+            }
+
+            (Post, "/prune") => {
+                #[derive(Serialize)]
+                struct Pruned {
+                    deleted: u64,
+                }
+
+                let (deleted, req) = Self::check(Self::prune(), req)?;
+                if deleted > 0 {
+                    info!("pruned {} played songs", deleted);
+                }
+                Self::respond((Pruned { deleted }, req))
+            }
+
+            (Post, "/maintenance") => {
+                let (done, req) = Self::check(database::maintenance(), req)?;
+                info!(
+                    "vacuumed the database, {} -> {} bytes",
+                    done.before.map_or_else(|| "-".into(), |n| n.to_string()),
+                    done.after.map_or_else(|| "-".into(), |n| n.to_string())
+                );
+                Self::respond((done, req))
+            }
+
+            (Post, "/shuffle") => {
+                // the song that's playing stays where it is. songs only trade places with
+                // others of their kind, so only the current kind has to skip its first song
+                let shuffled = Self::current().and_then(|current| {
+                    let current = current.map(|(kind, _)| kind);
+                    let skip = |kind| if current == Some(kind) { 1 } else { 0 };
+                    let mut rng = rand::thread_rng();
+                    Youtube.shuffle(&mut rng, skip(Kind::Youtube))?;
+                    Local.shuffle(&mut rng, skip(Kind::Local))?;
+                    Spotify.shuffle(&mut rng, skip(Kind::Spotify))?;
+                    SoundCloud.shuffle(&mut rng, skip(Kind::SoundCloud))?;
+                    Generic.shuffle(&mut rng, skip(Kind::Generic))?;
+                    Self::queue()
+                });
+                let (queue, req) = Self::check(shuffled, req)?;
+                Self::respond((queue, req))?;
+                self.notify();
+                Ok(())
+            }
+
+            (Post, "/queue/move") => {
+                #[derive(Deserialize)]
+                struct Move {
+                    kind: Kind,
+                    id: i64,
+                    // among the unplayed songs of the same kind, 0 is the front
+                    position: u32,
+                }
+
+                let moved = serde_json::from_reader::<_, Move>(req.as_reader())
+                    .map_err(Error::Deserialize)
+                    .and_then(|m| match m.kind {
+                        Kind::Youtube => Youtube.move_to(m.id, m.position),
+                        Kind::Local => Local.move_to(m.id, m.position),
+                        Kind::Spotify => Spotify.move_to(m.id, m.position),
+                        Kind::SoundCloud => SoundCloud.move_to(m.id, m.position),
+                        Kind::Generic => Generic.move_to(m.id, m.position),
+                    })
+                    .and_then(|_| Self::queue());
+                let (queue, req) = Self::check(moved, req)?;
+                Self::respond((queue, req))?;
+                self.notify();
+                Ok(())
+            }
+
+            // `/current/<kind>/<id>`, for when songs were played out of order
+            (Post, path) if path.starts_with("/current/") => {
+                let current = match self.song(&path["/current".len()..]) {
+                    Some((ref ty, id)) if ty == "youtube" => Self::set_current(Kind::Youtube, id),
+                    Some((ref ty, id)) if ty == "local" => Self::set_current(Kind::Local, id),
+                    Some((ref ty, id)) if ty == "spotify" => Self::set_current(Kind::Spotify, id),
+                    Some((ref ty, id)) if ty == "soundcloud" => {
+                        Self::set_current(Kind::SoundCloud, id)
+                    }
+                    Some((ref ty, id)) if ty == "generic" => Self::set_current(Kind::Generic, id),
+                    _ => return err!(req),
+                };
+
+                match Self::check(current, req)? {
+                    (Some((_, song)), req) => {
+                        Self::respond((vec![song], req))?;
+                        self.notify();
+                        Ok(())
+                    }
+                    (None, req) => Self::empty(req, 404),
+                }
+            }
+
+            (Post, "/skip") => {
+                #[derive(Deserialize)]
+                struct Vote {
+                    #[serde(default)]
+                    requested_by: String,
+                }
+
+                let threshold = config::get().skip_threshold;
+                if threshold == 0 {
+                    return Self::respond_error(Error::SkipDisabled, req);
+                }
+
+                let vote = serde_json::from_reader::<_, Vote>(req.as_reader())
+                    .map_err(Error::Deserialize)
+                    .and_then(|vote| {
+                        blacklist::check(blacklist::EntryKind::User, &vote.requested_by)
+                            .map(|_| vote)
+                    });
+                let (vote, req) = Self::check(vote, req)?;
+                let (current, req) = Self::check(Self::current(), req)?;
+                let song = match current {
+                    Some((kind, song)) => (kind, song["data"]["id"].as_i64().unwrap_or_default()),
+                    None => {
+                        debug!("nothing to skip");
+                        return Self::empty(req, 204);
+                    }
+                };
+
+                // anonymous votes are keyed by address, like requests
+                let who = match vote.requested_by.as_str() {
+                    "" => req
+                        .remote_addr()
+                        .map(|addr| addr.ip().to_string())
+                        .unwrap_or_default(),
+                    name => name.to_string(),
+                };
+                let tally = self.skip_votes.vote(song, &who, threshold);
+                if !tally.skipped {
+                    return Self::respond((tally, req));
+                }
+
+                info!("skipping the current song after {} votes", tally.votes);
+                let (_, req) = Self::check(Self::advance(), req)?;
+                Self::respond((tally, req))?;
+                self.notify();
+                Ok(())
+            }
+
+            // `?skip_duplicates=true` leaves out songs that are already stored
+            (Post, "/import.json") => {
+                let skip = param(&query, "skip_duplicates").unwrap_or(false);
+                let summary = serde_json::from_reader::<_, backup::Backup>(req.as_reader())
+                    .map_err(Error::Deserialize)
+                    .and_then(|backup| backup::import(&backup, skip));
+                let (summary, req) = Self::check(summary, req)?;
+                info!(
+                    "imported {} songs, skipped {}",
+                    summary.imported, summary.skipped
+                );
+                Self::respond((summary, req))?;
+                self.notify();
+                Ok(())
+            }
+
+            (Post, "/blacklist") => {
+                let entry = serde_json::from_reader::<_, blacklist::Entry>(req.as_reader())
+                    .map_err(Error::Deserialize)
+                    .and_then(|entry| blacklist::add(&entry));
+                let (_, req) = Self::check(entry, req)?;
+                Self::empty(req, 200)
+            }
+            (Delete, "/queue") => {
+                #[derive(Serialize)]
+                struct Cleared {
+                    pending: u64,
+                    history: u64,
+                }
+
+                let include_history = param(&query, "include_history").unwrap_or(false);
+                let cleared = (|| {
+                    let pending = Youtube.clear_pending()?
+                        + Local.clear_pending()?
+                        + Spotify.clear_pending()?
+                        + SoundCloud.clear_pending()?
+                        + Generic.clear_pending()?;
+                    // played songs are kept for /previous and the stats unless asked for
+                    let history = if include_history {
+                        Youtube.clear_history()?
+                            + Local.clear_history()?
+                            + Spotify.clear_history()?
+                            + SoundCloud.clear_history()?
+                            + Generic.clear_history()?
+                    } else {
+                        0
+                    };
+                    Ok(Cleared { pending, history })
+                })();
+                let (cleared, req) = Self::check(cleared, req)?;
+                info!(
+                    "cleared {} pending and {} played songs",
+                    cleared.pending, cleared.history
+                );
+                Self::respond((cleared, req))?;
+                self.notify();
+                Ok(())
+            }
+            (Delete, path) if path.starts_with("/blacklist/") => {
+                // a requester's name can have anything in it, so it arrives percent-encoded
+                let value = decode(path["/blacklist/".len()..].trim_end_matches('/'));
+                let (removed, req) = Self::check(blacklist::remove(&value), req)?;
+                Self::empty(req, if removed { 200 } else { 404 })
+            }
+
+            (Delete, other) => {
+                let deleted = match self.song(other) {
+                    Some((ref ty, id)) if ty == "youtube" => Youtube.delete(id),
+                    Some((ref ty, id)) if ty == "local" => Local.delete(id),
+                    Some((ref ty, id)) if ty == "spotify" => Spotify.delete(id),
+                    Some((ref ty, id)) if ty == "soundcloud" => SoundCloud.delete(id),
+                    Some((ref ty, id)) if ty == "generic" => Generic.delete(id),
+                    _ => return err!(req),
+                };
+
+                let (deleted, req) = Self::check(deleted, req)?;
+                Self::empty(req, if deleted { 200 } else { 404 })?;
+                if deleted {
+                    self.notify();
+                }
+                Ok(())
+            }
+
+            // cors preflight, the headers are added in `send`
+            (Options, _) => Self::empty(req, 204),
+
+            _ => err!(req),
+        }
+    }
+
+    // pushes the current song to every websocket and event stream subscriber
+    fn notify(&mut self) {
+        match Self::now_playing() {
+            Ok(data) => self.subscribers.broadcast(&data),
+            Err(err) => warn!("cannot get the current song: {}", err),
+        }
+    }
+
+    // the same body as /current, but an empty list when nothing is queued
+    fn now_playing() -> Result<Vec<u8>> {
+        let song = Self::current()?
+            .map(|(_, song)| vec![song])
+            .unwrap_or_default();
+        serde_json::to_vec(&song).map_err(Error::Serialize)
+    }
+
+    // the oldest unplayed song of every kind
+    fn current() -> Result<Option<(Kind, serde_json::Value)>> {
+        let candidates = vec![
+            Self::outgoing(Youtube.current()?, Kind::Youtube)?,
+            Self::outgoing(Local.current()?, Kind::Local)?,
+            Self::outgoing(Spotify.current()?, Kind::Spotify)?,
+            Self::outgoing(SoundCloud.current()?, Kind::SoundCloud)?,
+            Self::outgoing(Generic.current()?, Kind::Generic)?,
+        ];
+        Ok(Self::pick(candidates, std::cmp::Ordering::Less))
+    }
+
+    // every unplayed song, in the order they'll be played
+    fn queue() -> Result<Vec<serde_json::Value>> {
+        let mut queue = vec![];
+        for song in Youtube.pending()? {
+            queue.extend(Self::outgoing(Some(song), Kind::Youtube)?);
+        }
+        for song in Local.pending()? {
+            queue.extend(Self::outgoing(Some(song), Kind::Local)?);
+        }
+        for song in Spotify.pending()? {
+            queue.extend(Self::outgoing(Some(song), Kind::Spotify)?);
+        }
+        for song in SoundCloud.pending()? {
+            queue.extend(Self::outgoing(Some(song), Kind::SoundCloud)?);
+        }
+        for song in Generic.pending()? {
+            queue.extend(Self::outgoing(Some(song), Kind::Generic)?);
+        }
+
+        // stable, so songs with the same position keep their per-kind order
+        queue.sort_by_key(|(ts, ..)| *ts);
+        Ok(queue.into_iter().map(|(_, _, song)| song).collect())
+    }
+
+    // the queue as playable urls, local songs without a file have nothing to link to and are
+    // left out
+    fn playlist() -> Result<Vec<export::Entry>> {
+        let mut entries = vec![];
+        entries.extend(Youtube.pending()?.into_iter().map(|song| export::Entry {
+            position: song.position,
+            duration: song.duration,
+            url: song.url,
+            title: song.title,
+        }));
+        entries.extend(Local.pending()?.into_iter().filter_map(|song| {
+            Some(export::Entry {
+                position: song.position,
+                duration: song.duration,
+                url: song.path?,
+                title: match song.artist.as_str() {
+                    "" => song.title,
+                    artist => format!("{} - {}", artist, song.title),
+                },
+            })
+        }));
+        entries.extend(Spotify.pending()?.into_iter().map(|song| export::Entry {
+            position: song.position,
+            duration: song.duration,
+            url: song.url(),
+            title: format!("{} - {}", song.artist, song.title),
+        }));
+        entries.extend(SoundCloud.pending()?.into_iter().map(|song| export::Entry {
+            position: song.position,
+            duration: song.duration,
+            url: song.url(),
+            title: format!("{} - {}", song.artist, song.title),
+        }));
+        entries.extend(Generic.pending()?.into_iter().map(|song| export::Entry {
+            position: song.position,
+            duration: song.duration,
+            url: song.url,
+            title: song.title,
+        }));
+        entries.sort_by_key(|entry| entry.position);
+        Ok(entries)
+    }
+
+    // every song as csv fields, oldest first. `vid` is whatever id the kind uses
+    fn csv_rows() -> Result<Vec<(i64, Vec<String>)>> {
+        let mut rows = vec![];
+        rows.extend(Youtube.all(None, None)?.into_iter().map(|song| {
+            (
+                song.timestamp,
+                vec![
+                    "youtube".into(),
+                    song.id.to_string(),
+                    song.vid,
+                    song.title,
+                    song.channel,
+                    song.duration.to_string(),
+                    song.timestamp.to_string(),
+                    song.requested_by,
+                ],
+            )
+        }));
+        rows.extend(Local.all(None, None)?.into_iter().map(|song| {
+            (
+                song.timestamp,
+                vec![
+                    "local".into(),
+                    song.id.to_string(),
+                    song.path.unwrap_or_default(),
+                    song.title,
+                    song.artist,
+                    song.duration.to_string(),
+                    song.timestamp.to_string(),
+                    song.requested_by,
+                ],
+            )
+        }));
+        rows.extend(Spotify.all(None, None)?.into_iter().map(|song| {
+            (
+                song.timestamp,
+                vec![
+                    "spotify".into(),
+                    song.id.to_string(),
+                    song.tid,
+                    song.title,
+                    song.artist,
+                    song.duration.to_string(),
+                    song.timestamp.to_string(),
+                    song.requested_by,
+                ],
+            )
+        }));
+        rows.extend(SoundCloud.all(None, None)?.into_iter().map(|song| {
+            (
+                song.timestamp,
+                vec![
+                    "soundcloud".into(),
+                    song.id.to_string(),
+                    song.permalink,
+                    song.title,
+                    song.artist,
+                    song.duration.to_string(),
+                    song.timestamp.to_string(),
+                    song.requested_by,
+                ],
+            )
+        }));
+        rows.extend(Generic.all(None, None)?.into_iter().map(|song| {
+            (
+                song.timestamp,
+                vec![
+                    "generic".into(),
+                    song.id.to_string(),
+                    song.url,
+                    song.title,
+                    String::new(),
+                    song.duration.to_string(),
+                    song.timestamp.to_string(),
+                    song.requested_by,
+                ],
+            )
+        }));
+        rows.sort_by_key(|(ts, _)| *ts);
+        Ok(rows)
+    }
+
+    // stores the song, returning its id and the response body. the body is kept for the
+    // idempotency key in the same transaction, so only successes are kept and a failed
+    // request can be retried as is
+    fn insert<S, T>(
+        storage: S,
+        item: &Item,
+        kind: Kind,
+        key: Option<&str>,
+    ) -> Result<(i64, Vec<u8>)>
+    where
+        S: Storage<T>,
+        T: Serialize + crate::FromRow,
+    {
+        #[derive(Serialize)]
+        struct Inserted<'a, T> {
+            data: &'a T,
+            kind: Kind,
+        }
+
+        let mut body = vec![];
+        let song = storage.insert(item, |tx, song| {
+            body = serde_json::to_vec(&Inserted { data: song, kind }).map_err(Error::Serialize)?;
+            match key {
+                Some(key) => idempotency::record(tx, key, 201, &body),
+                None => Ok(()),
+            }
+        })?;
+        Ok((song.id(), body))
+    }
+
+    // every video goes through the same checks as a single request, including the queue
+    // limits, so a long playlist can fill the queue part way through
+    fn import_playlist(url: &str, requested_by: &str) -> Result<Imported> {
+        let ts = crate::unix_time();
+        let urls = crate::youtube::YoutubeItem::playlist(url)?
+            .into_iter()
+            .map(|id| format!("https://youtu.be/{}", id))
+            .collect::<Vec<_>>();
+        let items = urls
+            .iter()
+            .map(|url| Item {
+                kind: ItemKind::Youtube(url.clone()),
+                ts,
+                version: 1,
+                requested_by: requested_by.to_string(),
+            })
+            .collect::<Vec<_>>();
+
+        let mut imported = Imported::default();
+        for (url, res) in urls.into_iter().zip(Youtube.validate_each(&items)?) {
+            let res = res.and_then(|song| {
+                let mut conn = database::get_connection();
+                // taking the write lock up front keeps other requests from queueing past the caps
+                let tx =
+                    conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+                stats::check_queue_len(&tx, config::get().max_queue_len)?;
+                stats::check_user_queue_len(&tx, requested_by, config::get().max_per_user)?;
+                Youtube.store(&tx, &song)?;
+                tx.commit().map_err(Error::Sql)
+            });
+            match res {
+                Ok(..) => {
+                    metrics::insert();
+                    imported.imported += 1
+                }
+                Err(err) => {
+                    metrics::insert_error("youtube");
+                    imported.failed.push(Failed {
+                        url,
+                        error: err.code(),
+                        message: err.to_string(),
+                    })
+                }
+            }
+        }
+        Ok(imported)
+    }
+
+    // every file goes through the same checks as a single request, like a playlist import
+    fn scan_local(dir: &str, requested_by: &str) -> Result<Scanned> {
+        let ts = crate::unix_time();
+        let (files, skipped) = crate::local::scan(dir)?;
+
+        let mut scanned = Scanned {
+            skipped,
+            ..Scanned::default()
+        };
+        for file in files {
+            if crate::local::is_pending(&database::get_connection(), &file)? {
+                scanned.skipped += 1;
+                continue;
+            }
+            let path = file.to_string_lossy().into_owned();
+            let item = Item {
+                kind: ItemKind::Local {
+                    artist: String::new(),
+                    title: String::new(),
+                    album: String::new(),
+                    path: Some(path.clone()),
+                },
+                ts,
+                version: 1,
+                requested_by: requested_by.to_string(),
+            };
+            let res = Local.validate(&item).and_then(|song| {
+                let mut conn = database::get_connection();
+                // the same write lock as an import, so the caps hold against other requests
+                let tx =
+                    conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+                stats::check_queue_len(&tx, config::get().max_queue_len)?;
+                stats::check_user_queue_len(&tx, requested_by, config::get().max_per_user)?;
+                Local.store(&tx, &song)?;
+                tx.commit().map_err(Error::Sql)
+            });
+            match res {
+                Ok(..) => {
+                    metrics::insert();
+                    scanned.added += 1
+                }
+                Err(err) => {
+                    metrics::insert_error("local");
+                    scanned.failed.push(FailedFile {
+                        path,
+                        error: err.code(),
+                        message: err.to_string(),
+                    })
+                }
+            }
+        }
+        Ok(scanned)
+    }
+
+    // the kind and id from a `/<kind>/<id>` path
+    fn song(&self, path: &str) -> Option<(String, i64)> {
+        let c = self.song_regex.captures(path)?;
+        let ty = c.name("ty")?.as_str().to_lowercase();
+        let id = c.name("id")?.as_str().parse::<i64>().ok()?;
+        Some((ty, id))
+    }
+
+    // a kind left out of `enabled_kinds` is refused before anything else looks at the request
+    fn enabled(kind: Kind) -> Result<()> {
+        if config::get().enabled_kinds.contains(&kind) {
+            Ok(())
+        } else {
+            Err(Error::ItemKindDisabled(kind.name()))
+        }
+    }
+
+    // queues the song, played or not, just ahead of the current one of any kind. the songs
+    // that were ahead of it stay in the queue. `None` when there's no such song
+    fn set_current(kind: Kind, id: i64) -> Result<Option<(Kind, serde_json::Value)>> {
+        let position = match Self::current()? {
+            Some((_, song)) => song["data"]["position"]
+                .as_i64()
+                .unwrap_or_default()
+                .saturating_sub(1),
+            None => 0,
+        };
+        let found = match kind {
+            Kind::Youtube => Youtube.requeue(id, position)?,
+            Kind::Local => Local.requeue(id, position)?,
+            Kind::Spotify => Spotify.requeue(id, position)?,
+            Kind::SoundCloud => SoundCloud.requeue(id, position)?,
+            Kind::Generic => Generic.requeue(id, position)?,
+        };
+        if !found {
+            return Ok(None);
+        }
+        Self::current()
+    }
+
+    // marks the current song as played and returns the one after it
+    fn advance() -> Result<Option<(Kind, serde_json::Value)>> {
+        match Self::current()?.map(|(kind, _)| kind) {
+            Some(Kind::Youtube) => Youtube.advance().map(|_| ())?,
+            Some(Kind::Local) => Local.advance().map(|_| ())?,
+            Some(Kind::Spotify) => Spotify.advance().map(|_| ())?,
+            Some(Kind::SoundCloud) => SoundCloud.advance().map(|_| ())?,
+            Some(Kind::Generic) => Generic.advance().map(|_| ())?,
+            None => {}
+        }
+        Self::current()
+    }
+
+    // deletes the songs played more than `retention_days` ago
+    fn prune() -> Result<u64> {
+        let days = config::get().retention_days;
+        if days == 0 {
+            return Ok(0);
+        }
+        let before = crate::unix_time() - i64::from(days) * 60 * 60 * 24;
+        Ok(Youtube.prune(before)?
+            + Local.prune(before)?
+            + Spotify.prune(before)?
+            + SoundCloud.prune(before)?
+            + Generic.prune(before)?)
+    }
+
+    // the `n` most recently played songs across every kind, newest first
+    fn previous(n: u32) -> Result<Vec<serde_json::Value>> {
+        fn played<T: Serialize + crate::FromRow>(
+            song: T,
+            kind: Kind,
+        ) -> Result<Option<(Option<i64>, serde_json::Value)>> {
+            let at = song.played_at();
+            HttpServer::outgoing(Some(song), kind).map(|song| song.map(|(_, _, s)| (at, s)))
+        }
+
+        let mut songs = vec![];
+        // later kinds go first so they win ties, like they do in `pick`
+        for song in Generic.history(n)? {
+            songs.extend(played(song, Kind::Generic)?);
+        }
+        for song in SoundCloud.history(n)? {
+            songs.extend(played(song, Kind::SoundCloud)?);
+        }
+        for song in Spotify.history(n)? {
+            songs.extend(played(song, Kind::Spotify)?);
+        }
+        for song in Local.history(n)? {
+            songs.extend(played(song, Kind::Local)?);
+        }
+        for song in Youtube.history(n)? {
+            songs.extend(played(song, Kind::Youtube)?);
+        }
+        // by when they played rather than where they were queued, a song moved to the front
+        // plays after the ones queued before it. the sort is stable, so ties keep the order above
+        songs.sort_by(|(a, _), (b, _)| b.cmp(a));
+        Ok(songs
+            .into_iter()
+            .take(n as usize)
+            .map(|(_, song)| song)
+            .collect())
+    }
+
+    // picks the song whose position compares as `op` against the rest, later kinds win ties
+    fn pick(
+        candidates: Vec<Option<(i64, Kind, serde_json::Value)>>,
+        op: std::cmp::Ordering,
+    ) -> Option<(Kind, serde_json::Value)> {
+        candidates
+            .into_iter()
+            .flatten()
+            .fold(
+                None,
+                |best: Option<(i64, _, _)>, (pos, kind, song)| match best {
+                    Some((best_pos, ..)) if best_pos.cmp(&pos) == op => best,
+                    _ => Some((pos, kind, song)),
+                },
+            )
+            .map(|(_, kind, song)| (kind, song))
+    }
+
+    fn outgoing<T>(song: Option<T>, kind: Kind) -> Result<Option<(i64, Kind, serde_json::Value)>>
+    where
+        T: Serialize + crate::FromRow,
+    {
+        #[derive(Serialize)]
+        #[serde(rename_all = "lowercase")]
+        struct Outgoing<T>
+        where
+            T: Serialize + crate::FromRow,
+        {
+            data: T,
+            kind: Kind,
+        }
+
+        impl<T> From<(T, Kind)> for Outgoing<T>
+        where
+            T: Serialize + crate::FromRow,
+        {
+            fn from((data, kind): (T, Kind)) -> Self {
+                Self { data, kind }
+            }
+        }
+
+        let song = match song {
+            Some(song) => Outgoing::from((song, kind)),
+            None => return Ok(None),
+        };
+        let pos = song.data.position();
+        serde_json::to_value(song)
+            .map(|song| Some((pos, kind, song)))
+            .map_err(Error::Serialize)
+    }
+
+    // the checks that don't depend on the kind, done up front so a full queue
+    // doesn't spend api quota or a cooldown
+    fn incoming(mut req: tiny_http::Request) -> Result<(Item, tiny_http::Request)> {
+        let item = serde_json::from_reader::<_, Item>(req.as_reader())
+            .map_err(Error::Deserialize)
+            .and_then(|item| match item.version {
+                1 => Ok(item),
+                version => Err(Error::UnsupportedVersion(version)),
+            })
+            .and_then(|item| {
+                blacklist::check(blacklist::EntryKind::User, &item.requested_by)?;
+                let conn = database::get_connection();
+                stats::check_queue_len(&conn, config::get().max_queue_len)?;
+                stats::check_user_queue_len(&conn, &item.requested_by, config::get().max_per_user)?;
+                Ok(item)
+            });
+        Self::check(item, req)
+    }
+
+    fn check<T>(res: Result<T>, req: tiny_http::Request) -> Result<(T, tiny_http::Request)> {
+        match res {
+            Ok(d) => Ok((d, req)),
+            Err(err) => Self::respond_error(err, req),
+        }
+    }
+
+    fn respond_error<T>(err: Error, req: tiny_http::Request) -> Result<T> {
+        #[derive(Serialize)]
+        struct Outgoing {
+            error: &'static str,
+            message: String,
+        }
+
+        let data = serde_json::to_vec(&Outgoing {
+            error: err.code(),
+            message: err.to_string(),
+        })
+        .map_err(Error::Serialize)?;
+
+        let mut resp = tiny_http::Response::from_data(data).with_header(json_header());
+        if let Error::RateLimited { retry_after } = err {
+            if let Ok(header) = format!("Retry-After: {}", retry_after).parse::<tiny_http::Header>()
+            {
+                resp.add_header(header)
+            }
+        }
+
+        Self::send(req, err.status(), resp).and_then(|_| Err(err))
+    }
+
+    // json unless the client asks for xml
+    fn respond<T>((res, req): (T, tiny_http::Request)) -> Result<()>
+    where
+        T: Serialize,
+    {
+        let (data, content_type) = if wants_xml(&req) {
+            let data = serde_json::to_value(&res).map(|v| xml::render("response", &v).into_bytes());
+            (data, xml_header())
+        } else {
+            (serde_json::to_vec(&res), json_header())
+        };
+        let data = match data.map_err(Error::Serialize) {
+            Ok(data) => data,
+            Err(err) => {
+                return Self::empty(req, 400).and_then(|_| Err(err));
+            }
+        };
+        let vary = "Vary: Accept"
+            .parse::<tiny_http::Header>()
+            .expect("valid header");
+        if *req.method() != tiny_http::Method::Get {
+            return Self::send(
+                req,
+                200,
+                tiny_http::Response::from_data(data)
+                    .with_header(content_type)
+                    .with_header(vary),
+            );
+        }
+
+        // reads can be revalidated, an unchanged body is a 304 without it
+        let resource = format!("{} {}", content_type.value, req.url());
+        let validators = conditional::validators(&resource, &data);
+        let fresh = conditional::is_fresh(
+            &validators,
+            header(&req, "If-None-Match"),
+            header(&req, "If-Modified-Since"),
+        );
+        let (status, data) = if fresh { (304, vec![]) } else { (200, data) };
+        let mut resp = tiny_http::Response::from_data(data).with_header(vary);
+        if !fresh {
+            resp.add_header(content_type);
+        }
+        for header in &[
+            format!("ETag: {}", validators.etag),
+            format!("Last-Modified: {}", validators.last_modified_header()),
+            // caches have to check back before every use
+            "Cache-Control: no-cache".to_string(),
+        ] {
+            resp.add_header(header.parse::<tiny_http::Header>().expect("valid header"));
+        }
+        Self::send(req, status, resp)
+    }
+
+    fn replay(req: tiny_http::Request, status: u16, body: Vec<u8>) -> Result<()> {
+        if body.is_empty() {
+            return Self::empty(req, status);
+        }
+        Self::send(
+            req,
+            status,
+            tiny_http::Response::from_data(body).with_header(json_header()),
+        )
+    }
+
+    fn empty(req: tiny_http::Request, status: u16) -> Result<()> {
+        Self::send(req, status, tiny_http::Response::empty(status))
+    }
+
+    // the copy happens on its own thread so a slow client doesn't hold up every other request
+    fn stream(req: tiny_http::Request, path: &std::path::Path) -> Result<()> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let opened = std::fs::File::open(path)
+            .and_then(|file| file.metadata().map(|meta| (file, meta.len())))
+            .map_err(Error::Io);
+        let ((mut file, len), req) = Self::check(opened, req)?;
+
+        let content_type = crate::local::content_type(path).unwrap_or("application/octet-stream");
+        let mut headers = vec![
+            format!("Content-Type: {}", content_type),
+            "Accept-Ranges: bytes".to_string(),
+        ];
+        let (status, start, count) = match header(&req, "Range").and_then(|r| byte_range(r, len)) {
+            None => (200, 0, len),
+            Some(Some((start, end))) => {
+                headers.push(format!("Content-Range: bytes {}-{}/{}", start, end, len));
+                (206, start, end - start + 1)
+            }
+            Some(None) => {
+                headers.push(format!("Content-Range: bytes */{}", len));
+                (416, 0, 0)
+            }
+        };
+        file.seek(SeekFrom::Start(start))?;
+
+        let mut resp = tiny_http::Response::new(
+            tiny_http::StatusCode(status),
+            vec![],
+            file.take(count),
+            Some(count as usize),
+            None,
+        );
+        for header in headers {
+            resp.add_header(header.parse::<tiny_http::Header>().expect("valid header"));
+        }
+        for header in cors_headers(&req)
+            .into_iter()
+            .chain(request_id().map(request_id_header))
+        {
+            resp.add_header(header);
+        }
+        STATUS.with(|s| s.set(Some(status)));
+
+        thread::spawn(move || {
+            if let Err(err) = req.respond(resp) {
+                debug!("stream ended early: {}", err)
+            }
+        });
+        Ok(())
+    }
+
+    // every response goes through here
+    fn send<R>(req: tiny_http::Request, status: u16, resp: tiny_http::Response<R>) -> Result<()>
+    where
+        R: std::io::Read,
+    {
+        let mut resp = resp.with_status_code(status);
+        for header in cors_headers(&req) {
+            resp.add_header(header);
+        }
+        if let Some(id) = request_id() {
+            resp.add_header(request_id_header(id));
+        }
+        STATUS.with(|s| s.set(Some(status)));
+        req.respond(resp).map_err(Error::Io)
+    }
+}
+
+fn bind(
+    addr: tiny_http::ConfigListenAddr,
+    tls: Option<&tiny_http::SslConfig>,
+) -> std::result::Result<tiny_http::Server, Box<dyn std::error::Error + Send + Sync>> {
+    // the config is consumed by every server, so each listener gets its own copy
+    let ssl = tls.map(|tls| tiny_http::SslConfig {
+        certificate: tls.certificate.clone(),
+        private_key: tls.private_key.clone(),
+    });
+    tiny_http::Server::new(tiny_http::ServerConfig { addr, ssl })
+}
+
+fn cors_headers(req: &tiny_http::Request) -> Vec<tiny_http::Header> {
+    let allowed = &config::get().cors_allowed_origins;
+    let origin = match header(req, "Origin") {
+        Some(origin) => origin,
+        None => return vec![],
+    };
+
+    let origin = if allowed.iter().any(|s| s == "*") {
+        "*"
+    } else if allowed.iter().any(|s| s == origin) {
+        origin
+    } else {
+        return vec![];
+    };
+
+    [
+        format!("Access-Control-Allow-Origin: {}", origin),
+        "Access-Control-Allow-Methods: GET, POST, DELETE, OPTIONS".into(),
+        "Access-Control-Allow-Headers: Content-Type, Authorization, Idempotency-Key".into(),
+        "Access-Control-Max-Age: 86400".into(),
+        "Vary: Origin".into(),
+    ]
+    .iter()
+    .filter_map(|s| s.parse().ok())
+    .collect()
+}
+
+fn header<'a>(req: &'a tiny_http::Request, name: &'static str) -> Option<&'a str> {
+    req.headers()
+        .iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.as_str())
+}
+
+// no token configured means everything is allowed
+fn authorize(req: &tiny_http::Request) -> Result<()> {
+    let config = config::get();
+    let expected = match &config.api_token {
+        Some(token) => token,
+        None => return Ok(()),
+    };
+
+    let given = header(req, "Authorization")
+        .and_then(|s| s.strip_prefix("Bearer "))
+        .ok_or(Error::Unauthorized)?;
+
+    // compare every byte so the time taken doesn't leak the matching prefix
+    let matches = given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0;
+
+    if matches {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized)
+    }
+}
+
+fn request_id_header(id: u64) -> tiny_http::Header {
+    format!("X-Request-Id: {}", id)
+        .parse::<tiny_http::Header>()
+        .expect("valid header")
+}
+
+// the highest weighted media range that can be produced wins, anything unknown is json
+fn wants_xml(req: &tiny_http::Request) -> bool {
+    let accept = match header(req, "Accept") {
+        Some(accept) => accept,
+        None => return false,
+    };
+
+    let mut ranges = accept
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let media = parts.next()?.trim().to_lowercase();
+            let q = parts
+                .filter_map(|p| p.trim().strip_prefix("q=")?.parse::<f32>().ok())
+                .next()
+                .unwrap_or(1.0);
+            Some((media, q))
+        })
+        .filter(|(_, q)| *q > 0.0)
+        .collect::<Vec<_>>();
+    ranges.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+
+    ranges
+        .iter()
+        .find_map(|(media, _)| match media.as_str() {
+            "application/json" | "application/*" | "*/*" => Some(false),
+            "application/xml" | "text/xml" => Some(true),
+            _ => None,
+        })
+        .unwrap_or(false)
+}
+
+fn xml_header() -> tiny_http::Header {
+    "Content-Type: application/xml; charset=utf-8"
+        .parse()
+        .expect("valid header")
+}
+
+fn json_header() -> tiny_http::Header {
+    "Content-Type: application/json"
+        .parse()
+        .expect("valid header")
+}
+
+// splits a url into its path and its query parameters
+fn split_url(url: &str) -> (&str, HashMap<&str, &str>) {
+    let mut parts = url.splitn(2, '?');
+    let path = parts.next().unwrap_or_default();
+    let query = parts
+        .next()
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|pair| {
+            let mut pair = pair.splitn(2, '=');
+            Some((pair.next()?, pair.next().unwrap_or_default()))
+        })
+        .filter(|(k, _)| !k.is_empty())
+        .collect();
+    (path, query)
+}
+
+// a single `bytes=` range as inclusive offsets. `None` is the whole file, which is also what
+// a malformed header or several ranges get. `Some(None)` is a range past the end
+fn byte_range(range: &str, len: u64) -> Option<Option<(u64, u64)>> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    // `-n` is the last n bytes
+    if start.is_empty() {
+        let n = end.parse::<u64>().ok()?;
+        return Some(match n.min(len) {
+            0 => None,
+            n => Some((len - n, len - 1)),
+        });
+    }
+
+    let start = start.parse::<u64>().ok()?;
+    let end = match end {
+        "" => None,
+        end => Some(end.parse::<u64>().ok()?),
+    };
+    if end.is_some_and(|end| end < start) {
+        return None;
+    }
+    if start >= len {
+        return Some(None);
+    }
+    let end = end.map_or(len - 1, |end| end.min(len - 1));
+    Some(Some((start, end)))
+}
+
+// undoes percent-encoding, with `+` as a space like forms send it
+fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' => match s
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                Some(b) => {
+                    out.push(b);
+                    i += 2;
+                }
+                None => out.push(b'%'),
+            },
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn param<T: std::str::FromStr>(query: &HashMap<&str, &str>, key: &str) -> Option<T> {
+    query.get(key).and_then(|s| s.parse().ok())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ItemKind {
+    Youtube(String),
+    Spotify(String),
+    SoundCloud(String),
+    // anything else, with the metadata that can't be looked up
+    Generic {
+        url: String,
+        title: String,
+        duration: i64,
+    },
+    // a file to read the rest from, or just a description of the song
+    Local {
+        #[serde(default)]
+        artist: String,
+        #[serde(default)]
+        title: String,
+        #[serde(default)]
+        album: String,
+        #[serde(default)]
+        path: Option<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Youtube,
+    Local,
+    Spotify,
+    SoundCloud,
+    Generic,
+}
+
+impl Kind {
+    pub const ALL: [Kind; 5] = [
+        Kind::Youtube,
+        Kind::Local,
+        Kind::Spotify,
+        Kind::SoundCloud,
+        Kind::Generic,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Youtube => "youtube",
+            Kind::Local => "local",
+            Kind::Spotify => "spotify",
+            Kind::SoundCloud => "soundcloud",
+            Kind::Generic => "generic",
+        }
+    }
+}
+
+#[derive(Default, Serialize)]
+struct Imported {
+    imported: usize,
+    failed: Vec<Failed>,
+}
+
+#[derive(Serialize)]
+struct Failed {
+    url: String,
+    error: &'static str,
+    message: String,
+}
+
+// `skipped` counts files that aren't audio and ones that are already queued
+#[derive(Default, Serialize)]
+struct Scanned {
+    added: usize,
+    skipped: usize,
+    failed: Vec<FailedFile>,
+}
+
+#[derive(Serialize)]
+struct FailedFile {
+    path: String,
+    error: &'static str,
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub struct Item {
+    pub kind: ItemKind,
+    pub ts: i64,
+    pub version: u32,
+    #[serde(default)]
+    pub requested_by: String,
+}
+
+impl ItemKind {
+    fn kind(&self) -> Kind {
+        match self {
+            ItemKind::Youtube(..) => Kind::Youtube,
+            ItemKind::Local { .. } => Kind::Local,
+            ItemKind::Spotify(..) => Kind::Spotify,
+            ItemKind::SoundCloud(..) => Kind::SoundCloud,
+            ItemKind::Generic { .. } => Kind::Generic,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::{mpsc, Mutex};
+
+    use once_cell::sync::Lazy;
+    use once_cell::sync_lazy;
+
+    use super::*;
+    use crate::database::test::{setup, setup_with};
+    use crate::tags::test::{flac, TempDir};
+
+    struct Running {
+        addr: SocketAddr,
+        shutdown: ShutdownHandle,
+        handle: Option<thread::JoinHandle<()>>,
+    }
+
+    impl Drop for Running {
+        fn drop(&mut self) {
+            self.shutdown.shutdown();
+            if let Some(handle) = self.handle.take() {
+                let _ = handle.join();
+            }
+        }
+    }
+
+    // a server on an unused port, stopped when it's dropped
+    fn serve() -> Running {
+        let server = HttpServer::new(&["127.0.0.1:0"], None).unwrap();
+        let addr = server.servers[0].server_addr().to_ip().unwrap();
+        let shutdown = server.shutdown_handle();
+        Running {
+            addr,
+            shutdown,
+            handle: Some(thread::spawn(move || server.run())),
+        }
+    }
+
+    struct Response {
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    }
+
+    impl Response {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        }
+
+        fn json(&self) -> serde_json::Value {
+            serde_json::from_slice(&self.body).unwrap()
+        }
+    }
+
+    fn request(
+        server: &Running,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> Response {
+        let mut stream = TcpStream::connect(server.addr).unwrap();
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n",
+            method,
+            path,
+            body.len()
+        );
+        for (k, v) in headers {
+            head.push_str(&format!("{}: {}\r\n", k, v));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.write_all(body.as_bytes()).unwrap();
+
+        let mut data = vec![];
+        stream.read_to_end(&mut data).unwrap();
+        let split = data
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .expect("end of the head");
+        let head = String::from_utf8_lossy(&data[..split]).to_string();
+        let mut lines = head.split("\r\n");
+        let status = lines
+            .next()
+            .unwrap()
+            .split(' ')
+            .nth(1)
+            .unwrap()
+            .parse()
+            .unwrap();
+        let headers = lines
+            .filter_map(|line| {
+                let mut kv = line.splitn(2, ':');
+                Some((kv.next()?.to_string(), kv.next()?.trim().to_string()))
+            })
+            .collect();
+        Response {
+            status,
+            headers,
+            body: data[split + 4..].to_vec(),
+        }
+    }
+
+    fn get(server: &Running, path: &str) -> Response {
+        request(server, "GET", path, &[], "")
+    }
+
+    fn local(title: &str, ts: i64) -> Item {
+        Item {
+            kind: ItemKind::Local {
+                artist: "artist".into(),
+                title: title.into(),
+                album: "album".into(),
+                path: None,
+            },
+            ts,
+            version: 1,
+            requested_by: String::new(),
+        }
+    }
+
+    #[test]
+    fn count() {
+        let _guard = setup();
+        let server = serve();
+        assert_eq!(get(&server, "/count").json()["local"], 0);
+        for (i, title) in ["a", "b"].iter().enumerate() {
+            Local
+                .insert(&local(title, 100 + i as i64), |_, _| Ok(()))
+                .unwrap();
+        }
+        assert_eq!(Local.count().unwrap(), 2);
+        let res = get(&server, "/count");
+        assert_eq!(res.status, 200);
+        let count = res.json();
+        assert_eq!(count["local"], 2);
+        assert_eq!(count["youtube"], 0);
+    }
+
+    #[test]
+    fn range() {
+        let _guard = setup();
+        let server = serve();
+        for (i, title) in ["a", "b", "c"].iter().enumerate() {
+            Local
+                .insert(&local(title, 100 + i as i64), |_, _| Ok(()))
+                .unwrap();
+        }
+        let timestamps = |path: &str| {
+            get(&server, path).json()["local"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|song| song["timestamp"].as_i64().unwrap())
+                .collect::<Vec<_>>()
+        };
+        // inclusive at both ends
+        assert_eq!(timestamps("/range?start=101&end=102"), [101, 102]);
+        assert_eq!(timestamps("/range?start=100&end=100"), [100]);
+        assert!(timestamps("/range?start=200&end=300").is_empty());
+        assert_eq!(get(&server, "/range?start=100").status, 400);
+    }
+
+    #[test]
+    fn json_errors() {
+        let _guard = setup();
+        let server = serve();
+        let body = r#"{"kind":{"youtube":"https://example.com/watch"},"ts":1,"version":1}"#;
+        let res = request(&server, "POST", "/youtube", &[], body);
+        assert_eq!(res.status, 400);
+        assert!(res
+            .header("Content-Type")
+            .unwrap()
+            .starts_with("application/json"));
+        let body = res.json();
+        assert_eq!(body["error"], "invalid_youtube_url");
+        assert_eq!(
+            body["message"],
+            "invalid youtube url: https://example.com/watch"
+        );
+
+        let body = r#"{"kind":{"youtube":"https://youtu.be/dQw4w9WgXcQ"},"ts":1,"version":2}"#;
+        let res = request(&server, "POST", "/youtube", &[], body);
+        assert_eq!(res.status, 400);
+        assert_eq!(res.json()["error"], "unsupported_version");
+
+        let res = request(&server, "POST", "/youtube", &[], "not json");
+        assert_eq!(res.status, 400);
+        assert_eq!(res.json()["error"], "invalid_request");
+    }
+
+    #[test]
+    fn current() {
+        let _guard = setup();
+        let server = serve();
+        let empty = get(&server, "/current");
+        assert_eq!(empty.status, 204);
+        assert!(empty.body.is_empty());
+
+        Local.insert(&local("a", 100), |_, _| Ok(())).unwrap();
+        let current = get(&server, "/current");
+        assert_eq!(current.status, 200);
+        assert_eq!(current.json()[0]["kind"], "local");
+        assert_eq!(current.json()[0]["data"]["timestamp"], 100);
+    }
+
+    #[test]
+    fn shutdown() {
+        let _guard = setup();
+        let mut server = serve();
+        assert_eq!(get(&server, "/count").status, 200);
+
+        server.shutdown.shutdown();
+        let (tx, rx) = mpsc::channel();
+        let handle = server.handle.take().unwrap();
+        thread::spawn(move || tx.send(handle.join().is_ok()));
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(true));
+        // the listener is gone with it
+        assert!(TcpStream::connect(server.addr).is_err());
+    }
+
+    #[test]
+    fn cors() {
+        let _guard =
+            setup_with(|config| config.cors_allowed_origins = vec!["http://overlay.local".into()]);
+        let server = serve();
+        let preflight = request(
+            &server,
+            "OPTIONS",
+            "/youtube",
+            &[("Origin", "http://overlay.local")],
+            "",
+        );
+        assert_eq!(preflight.status, 204);
+        assert_eq!(
+            preflight.header("Access-Control-Allow-Origin"),
+            Some("http://overlay.local")
+        );
+        let methods = preflight.header("Access-Control-Allow-Methods").unwrap();
+        assert!(methods.contains("DELETE"), "{}", methods);
+
+        let other = request(&server, "GET", "/count", &[("Origin", "http://evil")], "");
+        assert_eq!(other.status, 200);
+        assert!(other.header("Access-Control-Allow-Origin").is_none());
+        // no origin, no cors
+        assert!(get(&server, "/count")
+            .header("Access-Control-Allow-Origin")
+            .is_none());
+
+        config::set(config::Config {
+            cors_allowed_origins: vec!["*".into()],
+            ..config::Config::default()
+        });
+        let any = request(&server, "GET", "/count", &[("Origin", "http://evil")], "");
+        assert_eq!(any.header("Access-Control-Allow-Origin"), Some("*"));
+    }
+
+    #[test]
+    fn api_token() {
+        let _guard = setup_with(|config| config.api_token = Some("secret".into()));
+        let server = serve();
+        let body =
+            r#"{"kind":{"local":{"artist":"a","title":"t","album":"b"}},"ts":1,"version":1}"#;
+        let post = |headers: &[(&str, &str)]| request(&server, "POST", "/local", headers, body);
+
+        let missing = post(&[]);
+        assert_eq!(missing.status, 401);
+        assert_eq!(missing.json()["error"], "unauthorized");
+        assert_eq!(post(&[("Authorization", "Bearer secreT")]).status, 401);
+        assert_eq!(post(&[("Authorization", "Bearer secret2")]).status, 401);
+        assert_eq!(post(&[("Authorization", "secret")]).status, 401);
+        assert_eq!(post(&[("Authorization", "Bearer secret")]).status, 201);
+        assert_eq!(request(&server, "DELETE", "/local/1", &[], "").status, 401);
+
+        // reads stay public unless asked otherwise
+        assert_eq!(get(&server, "/current").status, 200);
+        config::set(config::Config {
+            api_token: Some("secret".into()),
+            protect_reads: true,
+            ..config::Config::default()
+        });
+        assert_eq!(get(&server, "/current").status, 401);
+        let current = request(
+            &server,
+            "GET",
+            "/current",
+            &[("Authorization", "Bearer secret")],
+            "",
+        );
+        assert_eq!(current.status, 200);
+    }
+
+    // a connection that's kept open after the response head, which is returned with it
+    fn subscribe(server: &Running, path: &str, headers: &[(&str, &str)]) -> (TcpStream, String) {
+        let mut stream = TcpStream::connect(server.addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut head = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n", path);
+        for (k, v) in headers {
+            head.push_str(&format!("{}: {}\r\n", k, v));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes()).unwrap();
+
+        let mut head = vec![];
+        let mut byte = [0];
+        while !head.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        (stream, String::from_utf8(head).unwrap())
+    }
+
+    // the payload of the next unmasked text frame
+    fn read_frame(stream: &mut TcpStream) -> serde_json::Value {
+        let mut head = [0; 2];
+        stream.read_exact(&mut head).unwrap();
+        assert_eq!(head[0], 0x81);
+        let len = match head[1] {
+            126 => {
+                let mut len = [0; 2];
+                stream.read_exact(&mut len).unwrap();
+                u16::from_be_bytes(len) as usize
+            }
+            len => len as usize,
+        };
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).unwrap();
+        serde_json::from_slice(&payload).unwrap()
+    }
+
+    #[test]
+    fn websocket() {
+        let _guard = setup();
+        let server = serve();
+        let (mut ws, head) = subscribe(
+            &server,
+            "/ws",
+            &[
+                ("Connection", "Upgrade"),
+                ("Upgrade", "websocket"),
+                ("Sec-WebSocket-Version", "13"),
+                ("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="),
+            ],
+        );
+        assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        // nothing is playing yet
+        assert_eq!(read_frame(&mut ws), serde_json::json!([]));
+
+        let body =
+            r#"{"kind":{"local":{"artist":"a","title":"t","album":"b"}},"ts":100,"version":1}"#;
+        assert_eq!(request(&server, "POST", "/local", &[], body).status, 201);
+        assert_eq!(read_frame(&mut ws)[0]["data"]["timestamp"], 100);
+
+        assert_eq!(get(&server, "/ws").status, 400);
+    }
+
+    // the `data` of the next event
+    fn read_event(stream: &mut TcpStream) -> serde_json::Value {
+        let mut event = vec![];
+        let mut byte = [0];
+        while !event.ends_with(b"\n\n") {
+            stream.read_exact(&mut byte).unwrap();
+            event.push(byte[0]);
+        }
+        let event = String::from_utf8(event).unwrap();
+        let data = event
+            .strip_prefix("event: current\ndata: ")
+            .unwrap_or_else(|| panic!("{:?}", event));
+        serde_json::from_str(data.trim_end()).unwrap()
+    }
+
+    #[test]
+    fn event_stream() {
+        let _guard = setup();
+        let server = serve();
+        let (mut events, head) = subscribe(&server, "/events", &[]);
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        assert!(head.contains("Content-Type: text/event-stream"), "{}", head);
+        assert_eq!(read_event(&mut events), serde_json::json!([]));
+
+        let body =
+            r#"{"kind":{"local":{"artist":"a","title":"t","album":"b"}},"ts":100,"version":1}"#;
+        assert_eq!(request(&server, "POST", "/local", &[], body).status, 201);
+        assert_eq!(read_event(&mut events)[0]["data"]["timestamp"], 100);
+    }
+
+    #[test]
+    fn probes() {
+        let _guard = setup_with(|config| {
+            config.api_token = Some("secret".into());
+            config.protect_reads = true;
+        });
+        let server = serve();
+        // never behind the token
+        for path in &["/health", "/ready"] {
+            let res = get(&server, path);
+            assert_eq!(res.status, 200, "{}", path);
+            assert_eq!(res.body, b"OK");
+        }
+
+        // with every connection taken the database can't be reached
+        let held = (0..config::Config::default().pool_size)
+            .map(|_| database::get_connection())
+            .collect::<Vec<_>>();
+        let res = get(&server, "/ready");
+        assert_eq!(res.status, 503);
+        assert_eq!(res.body, b"database unavailable");
+        assert_eq!(get(&server, "/health").status, 200);
+        drop(held);
+        assert_eq!(get(&server, "/ready").status, 200);
+    }
+
+    #[test]
+    fn metrics() {
+        let _guard = setup();
+        let server = serve();
+        let scrape = |server: &Running| {
+            let res = get(server, "/metrics");
+            assert_eq!(res.status, 200);
+            let types = res
+                .headers
+                .iter()
+                .filter(|(k, _)| k.eq_ignore_ascii_case("Content-Type"))
+                .collect::<Vec<_>>();
+            assert_eq!(types.len(), 1, "{:?}", types);
+            assert!(types[0].1.starts_with("text/plain"));
+            String::from_utf8(res.body).unwrap()
+        };
+        let value = |metrics: &str, name: &str| {
+            metrics
+                .lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+                .map(|value| value.parse::<f64>().unwrap())
+                .unwrap_or_default()
+        };
+
+        let before = scrape(&server);
+        let body =
+            r#"{"kind":{"local":{"artist":"a","title":"t","album":"b"}},"ts":1,"version":1}"#;
+        assert_eq!(request(&server, "POST", "/local", &[], body).status, 201);
+        let body = r#"{"kind":{"youtube":"not a url"},"ts":1,"version":1}"#;
+        assert_eq!(request(&server, "POST", "/youtube", &[], body).status, 400);
+        let after = scrape(&server);
+
+        // other tests count too
+        let inserts = "dono_inserts_total";
+        assert!(value(&after, inserts) >= value(&before, inserts) + 1.0);
+        let errors = r#"dono_insert_errors_total{kind="youtube"}"#;
+        assert!(value(&after, errors) >= value(&before, errors) + 1.0);
+        let requests = "dono_request_duration_seconds_count";
+        assert!(value(&after, requests) >= value(&before, requests) + 3.0);
+        assert!(after.contains("# TYPE dono_request_duration_seconds histogram"));
+        assert!(after.contains(r#"dono_request_duration_seconds_bucket{le="+Inf"}"#));
+
+        config::set(config::Config {
+            api_token: Some("secret".into()),
+            protect_metrics: true,
+            ..config::Config::default()
+        });
+        assert_eq!(get(&server, "/metrics").status, 401);
+    }
+
+    #[test]
+    fn advance() {
+        let _guard = setup();
+        let server = serve();
+        Local.insert(&local("a", 100), |_, _| Ok(())).unwrap();
+        Local.insert(&local("b", 101), |_, _| Ok(())).unwrap();
+
+        let next = request(&server, "POST", "/advance", &[], "");
+        assert_eq!(next.status, 200);
+        assert_eq!(next.json()[0]["data"]["timestamp"], 101);
+        assert_eq!(Local.previous().unwrap().unwrap().timestamp, 100);
+        assert_eq!(get(&server, "/current").json()[0]["data"]["timestamp"], 101);
+        assert_eq!(
+            get(&server, "/previous").json()[0]["data"]["timestamp"],
+            100
+        );
+
+        // the last one played, nothing after it
+        assert_eq!(request(&server, "POST", "/advance", &[], "").status, 204);
+        assert_eq!(get(&server, "/current").status, 204);
+        assert_eq!(request(&server, "POST", "/advance", &[], "").status, 204);
+        assert_eq!(
+            get(&server, "/previous").json()[0]["data"]["timestamp"],
+            101
+        );
+    }
+
+    #[test]
+    fn pending_queue() {
+        let (_guard, _mock) = crate::youtube::test::setup();
+        let server = serve();
+        assert_eq!(get(&server, "/queue").json(), serde_json::json!([]));
+
+        for (url, ts) in &[
+            ("https://youtu.be/aaaaaaaaaaa", 100),
+            ("https://youtu.be/bbbbbbbbbbb", 102),
+        ] {
+            let mut item = crate::youtube::test::item(url);
+            item.ts = *ts;
+            Youtube.insert(&item, |_, _| Ok(())).unwrap();
+        }
+        Local.insert(&local("a", 101), |_, _| Ok(())).unwrap();
+        Youtube.advance().unwrap();
+
+        let queue = get(&server, "/queue")
+            .json()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|song| {
+                format!(
+                    "{} {}",
+                    song["kind"].as_str().unwrap(),
+                    song["data"]["timestamp"]
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(queue, ["local 101", "youtube 102"]);
+    }
+
+    #[test]
+    fn requester() {
+        let _guard = setup();
+        let server = serve();
+        for (ts, who) in &[(100, Some("someone")), (101, None)] {
+            let mut body = serde_json::json!({
+                "kind": {"local": {"artist": "a", "title": "t", "album": "b"}},
+                "ts": ts,
+                "version": 1,
+            });
+            if let Some(who) = who {
+                body["requested_by"] = (*who).into();
+            }
+            let res = request(&server, "POST", "/local", &[], &body.to_string());
+            assert_eq!(res.status, 201);
+        }
+        let queue = get(&server, "/queue").json();
+        assert_eq!(queue[0]["data"]["requested_by"], "someone");
+        assert_eq!(queue[1]["data"]["requested_by"], "");
+    }
+
+    #[test]
+    fn queue_limit() {
+        let _guard = setup_with(|config| config.max_queue_len = 2);
+        let server = serve();
+        let post = |ts: i64| {
+            let body = serde_json::json!({
+                "kind": {"local": {"artist": "a", "title": "t", "album": "b"}},
+                "ts": ts,
+                "version": 1,
+            });
+            request(&server, "POST", "/local", &[], &body.to_string())
+        };
+        assert_eq!(post(100).status, 201);
+        assert_eq!(post(101).status, 201);
+        let full = post(102);
+        assert_eq!(full.status, 409);
+        assert_eq!(full.json()["error"], "queue_full");
+        assert_eq!(Local.count().unwrap(), 2);
+
+        // playing one frees its slot
+        Local.advance().unwrap();
+        assert_eq!(post(102).status, 201);
+    }
+
+    fn post_local(server: &Running, ts: i64, requested_by: &str) -> u16 {
+        let body = serde_json::json!({
+            "kind": {"local": {"artist": "a", "title": "t", "album": "b"}},
+            "ts": ts,
+            "version": 1,
+            "requested_by": requested_by,
+        });
+        request(server, "POST", "/local", &[], &body.to_string()).status
+    }
+
+    #[test]
+    fn per_user_limit() {
+        let _guard = setup_with(|config| config.max_per_user = 1);
+        let server = serve();
+        assert_eq!(post_local(&server, 100, "a"), 201);
+        let body = serde_json::json!({
+            "kind": {"local": {"artist": "a", "title": "t", "album": "b"}},
+            "ts": 101,
+            "version": 1,
+            "requested_by": "a",
+        });
+        let full = request(&server, "POST", "/local", &[], &body.to_string());
+        assert_eq!(full.status, 409);
+        assert_eq!(full.json()["error"], "user_queue_full");
+        assert_eq!(post_local(&server, 102, "b"), 201);
+        // anonymous requests have no name to count by
+        assert_eq!(post_local(&server, 103, ""), 201);
+        assert_eq!(post_local(&server, 104, ""), 201);
+
+        // only unplayed songs count
+        Local.advance().unwrap();
+        assert_eq!(post_local(&server, 105, "a"), 201);
+    }
+
+    #[test]
+    fn blacklist_remove_encoded() {
+        let _guard = setup();
+        let server = serve();
+        let body = r#"{"kind":"user","value":"some one/else"}"#;
+        assert_eq!(
+            request(&server, "POST", "/blacklist", &[], body).status,
+            200
+        );
+        let listed = get(&server, "/blacklist");
+        assert!(listed
+            .header("Content-Type")
+            .unwrap()
+            .starts_with("application/json"));
+        assert_eq!(listed.json()[0]["value"], "some one/else");
+
+        let path = "/blacklist/some%20one%2Felse";
+        assert_eq!(request(&server, "DELETE", path, &[], "").status, 200);
+        assert_eq!(get(&server, "/blacklist").json(), serde_json::json!([]));
+        assert_eq!(request(&server, "DELETE", path, &[], "").status, 404);
+    }
+
+    #[test]
+    fn blacklisted_requester() {
+        let _guard = setup();
+        let server = serve();
+        let body = r#"{"kind":"user","value":"troll"}"#;
+        assert_eq!(
+            request(&server, "POST", "/blacklist", &[], body).status,
+            200
+        );
+
+        let body = serde_json::json!({
+            "kind": {"local": {"artist": "a", "title": "t", "album": "b"}},
+            "ts": 100,
+            "version": 1,
+            "requested_by": "troll",
+        });
+        let res = request(&server, "POST", "/local", &[], &body.to_string());
+        assert_eq!(res.status, 403);
+        assert_eq!(res.json()["error"], "blacklisted");
+        assert_eq!(post_local(&server, 100, "someone"), 201);
+    }
+
+    #[test]
+    fn m3u_playlist() {
+        let (_guard, _mock) = crate::youtube::test::setup();
+        let server = serve();
+        for (i, id) in ["aaaaaaaaaaa", "bbbbbbbbbbb", "ccccccccccc"]
+            .iter()
+            .enumerate()
+        {
+            let mut item = crate::youtube::test::item(&format!("https://youtu.be/{}", id));
+            item.ts = 100 + i as i64;
+            Youtube.insert(&item, |_, _| Ok(())).unwrap();
+        }
+        // nothing to link to
+        Local.insert(&local("a", 101), |_, _| Ok(())).unwrap();
+        Youtube.advance().unwrap();
+
+        let res = get(&server, "/queue.m3u");
+        assert_eq!(res.header("Content-Type"), Some("audio/x-mpegurl"));
+        let body = String::from_utf8(res.body).unwrap();
+        let lines = body.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                "#EXTM3U",
+                "#EXTINF:180,title bbbbbbbbbbb",
+                "https://youtu.be/bbbbbbbbbbb",
+                "#EXTINF:180,title ccccccccccc",
+                "https://youtu.be/ccccccccccc",
+            ]
+        );
+    }
+
+    #[test]
+    fn csv_export() {
+        let (_guard, _mock) = crate::youtube::test::setup_with(
+            |_| {},
+            |path| {
+                let videos = crate::youtube::test::videos(path, |item| {
+                    item["snippet"]["title"] = r#"Say "Hello", World"#.into();
+                    item["snippet"]["channelTitle"] = "uploader".into();
+                });
+                (200, videos)
+            },
+        );
+        let server = serve();
+        let mut item = crate::youtube::test::item("https://youtu.be/aaaaaaaaaaa");
+        item.ts = 100;
+        item.requested_by = "someone".into();
+        Youtube.insert(&item, |_, _| Ok(())).unwrap();
+
+        let res = get(&server, "/export.csv");
+        assert!(res.header("Content-Type").unwrap().starts_with("text/csv"));
+        let body = String::from_utf8(res.body).unwrap();
+        let mut lines = body.split("\r\n");
+        assert_eq!(
+            lines.next(),
+            Some("kind,id,vid,title,channel,duration,timestamp,requested_by")
+        );
+        let row = lines.next().unwrap();
+        assert!(
+            row.starts_with("youtube,")
+                && row.ends_with(r#",aaaaaaaaaaa,"Say ""Hello"", World",uploader,180,100,someone"#),
+            "{}",
+            row
+        );
+    }
+
+    #[test]
+    fn several_listeners() {
+        let _guard = setup();
+        let server = HttpServer::new(&[("127.0.0.1", 0), ("127.0.0.1", 0)], None).unwrap();
+        let addrs = server
+            .servers
+            .iter()
+            .map(|server| server.server_addr().to_ip().unwrap())
+            .collect::<Vec<_>>();
+        assert_ne!(addrs[0], addrs[1]);
+        let running = Running {
+            addr: addrs[0],
+            shutdown: server.shutdown_handle(),
+            handle: Some(thread::spawn(move || server.run())),
+        };
+        for addr in addrs {
+            // only the address is used to make requests
+            let server = Running {
+                addr,
+                shutdown: ShutdownHandle::default(),
+                handle: None,
+            };
+            assert_eq!(get(&server, "/health").body, b"OK");
+        }
+        drop(running);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket() {
+        use std::os::unix::net::UnixStream;
+
+        let _guard = setup();
+        let path = std::env::temp_dir().join(format!("dono-server-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let server = HttpServer::unix(&path, None).unwrap();
+        let shutdown = server.shutdown_handle();
+        let handle = thread::spawn(move || server.run());
+
+        Local.insert(&local("a", 100), |_, _| Ok(())).unwrap();
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream
+            .write_all(b"GET /current HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut data = String::new();
+        stream.read_to_string(&mut data).unwrap();
+        assert!(data.starts_with("HTTP/1.1 200"), "{}", data);
+        assert!(data.contains(r#""timestamp":100"#), "{}", data);
+
+        shutdown.shutdown();
+        handle.join().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn tls() {
+        use std::convert::TryInto;
+
+        let _guard = setup();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let ssl = tiny_http::SslConfig {
+            certificate: cert.cert.pem().into_bytes(),
+            private_key: cert.signing_key.serialize_pem().into_bytes(),
+        };
+        let server = HttpServer::new(&["127.0.0.1:0"], Some(&ssl)).unwrap();
+        let addr = server.servers[0].server_addr().to_ip().unwrap();
+        let _running = Running {
+            addr,
+            shutdown: server.shutdown_handle(),
+            handle: Some(thread::spawn(move || server.run())),
+        };
+        Local.insert(&local("a", 100), |_, _| Ok(())).unwrap();
+
+        // the self-signed certificate is the only one trusted
+        let mut roots = rustls::RootCertStore::empty();
+        roots
+            .add(&rustls::Certificate(cert.cert.der().to_vec()))
+            .unwrap();
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let conn = rustls::ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap())
+            .unwrap();
+        let tcp = TcpStream::connect(addr).unwrap();
+        tcp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut stream = rustls::StreamOwned::new(conn, tcp);
+
+        // kept alive, so both responses come back on the one connection
+        for _ in 0..2 {
+            stream
+                .write_all(b"GET /current HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            let mut head = vec![];
+            let mut byte = [0];
+            while !head.ends_with(b"\r\n\r\n") {
+                stream.read_exact(&mut byte).unwrap();
+                head.push(byte[0]);
+            }
+            let head = String::from_utf8(head).unwrap();
+            assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+            let len = head
+                .lines()
+                .find_map(|line| line.strip_prefix("Content-Length: "))
+                .unwrap()
+                .parse()
+                .unwrap();
+            let mut body = vec![0; len];
+            stream.read_exact(&mut body).unwrap();
+            let body = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+            assert_eq!(body[0]["data"]["timestamp"], 100);
+        }
+
+        // and plain http isn't understood
+        let mut plain = TcpStream::connect(addr).unwrap();
+        plain
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        plain
+            .write_all(b"GET /current HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut data = vec![];
+        let _ = plain.read_to_end(&mut data);
+        assert!(!data.starts_with(b"HTTP/1.1"));
+    }
+
+    // a log line and the request id it was logged under
+    type Line = (log::Level, String, Option<u64>);
+
+    static LOGS: Lazy<Mutex<Vec<Line>>> = sync_lazy! {
+        Mutex::new(vec![])
+    };
+
+    struct Capture;
+
+    impl log::Log for Capture {
+        fn enabled(&self, _: &log::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record<'_>) {
+            if record.target() == module_path!().trim_end_matches("::tests") {
+                let line = (record.level(), record.args().to_string(), request_id());
+                LOGS.lock().unwrap().push(line);
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn access_log(marker: &str) -> Option<Line> {
+        LOGS.lock()
+            .unwrap()
+            .iter()
+            .find(|(_, line, _)| line.contains(marker))
+            .cloned()
+    }
+
+    #[test]
+    fn access_logs() {
+        static CAPTURE: std::sync::Once = std::sync::Once::new();
+        CAPTURE.call_once(|| {
+            log::set_logger(&Capture).unwrap();
+            log::set_max_level(log::LevelFilter::Info);
+        });
+
+        let _guard = setup();
+        let server = serve();
+        let ok = get(&server, "/health?marker=ok");
+        let missing = get(&server, "/list/nothing?marker=missing");
+        assert_eq!(missing.status, 400);
+        drop(server);
+
+        let (level, line, id) = access_log("marker=ok").unwrap();
+        assert_eq!(level, log::Level::Info);
+        let pattern =
+            Regex::new(r#"^127\.0\.0\.1 "GET /health\?marker=ok" 200 \d+\.\d{3}ms$"#).unwrap();
+        assert!(pattern.is_match(&line), "{}", line);
+        // the same id the client was given
+        assert_eq!(
+            id.map(|id| id.to_string()).as_deref(),
+            ok.header("X-Request-Id")
+        );
+
+        let (level, line, id) = access_log("marker=missing").unwrap();
+        assert_eq!(level, log::Level::Warn);
+        assert!(
+            line.contains("\"GET /list/nothing?marker=missing\" 400 "),
+            "{}",
+            line
+        );
+        assert_ne!(
+            id.map(|id| id.to_string()).as_deref(),
+            ok.header("X-Request-Id")
+        );
+    }
+
+    #[test]
+    fn previous_endpoint() {
+        let _guard = setup();
+        let server = serve();
+        assert_eq!(get(&server, "/previous").status, 204);
+
+        for ts in 100..103 {
+            Local.insert(&local("song", ts), |_, _| Ok(())).unwrap();
+        }
+        Local.advance().unwrap();
+        Local.advance().unwrap();
+        let played = |path: &str| {
+            get(&server, path)
+                .json()
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|song| song["data"]["timestamp"].as_i64().unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(played("/previous"), [101]);
+        assert_eq!(played("/previous?n=5"), [101, 100]);
+        assert_eq!(played("/previous?n=1"), [101]);
+    }
+
+    #[test]
+    fn xml_negotiation() {
+        let _guard = setup();
+        let server = serve();
+        Local
+            .insert(
+                &Item {
+                    requested_by: "this & that".into(),
+                    ..local("song", 100)
+                },
+                |_, _| Ok(()),
+            )
+            .unwrap();
+
+        let xml = request(
+            &server,
+            "GET",
+            "/current",
+            &[("Accept", "application/xml")],
+            "",
+        );
+        assert_eq!(
+            xml.header("Content-Type"),
+            Some("application/xml; charset=utf-8")
+        );
+        let body = String::from_utf8(xml.body).unwrap();
+        assert!(body.starts_with(r#"<?xml version="1.0" encoding="UTF-8"?><response><item>"#));
+        assert!(
+            body.contains("<requested_by>this &amp; that</requested_by>"),
+            "{}",
+            body
+        );
+
+        // json unless xml is preferred
+        for accept in &[
+            None,
+            Some("*/*"),
+            Some("text/html"),
+            Some("application/xml;q=0.5, application/json"),
+        ] {
+            let headers = accept.map(|a| vec![("Accept", a)]).unwrap_or_default();
+            let res = request(&server, "GET", "/current", &headers, "");
+            assert!(
+                res.header("Content-Type")
+                    .unwrap()
+                    .starts_with("application/json"),
+                "{:?}",
+                accept
+            );
+        }
+    }
+
+    #[test]
+    fn skip_votes() {
+        let _guard = setup();
+        let server = serve();
+        let vote = |who: &str| {
+            let body = serde_json::json!({ "requested_by": who }).to_string();
+            request(&server, "POST", "/skip", &[], &body)
+        };
+        // turned off by default
+        assert_eq!(vote("a").status, 403);
+        drop(_guard);
+
+        let _guard = setup_with(|config| config.skip_threshold = 2);
+        assert_eq!(vote("a").status, 204);
+
+        Local.insert(&local("a", 100), |_, _| Ok(())).unwrap();
+        Local.insert(&local("b", 101), |_, _| Ok(())).unwrap();
+        let tally = vote("a").json();
+        assert_eq!(tally["votes"], 1);
+        assert_eq!(tally["skipped"], false);
+        // voting again doesn't count twice
+        assert_eq!(vote("a").json()["votes"], 1);
+
+        let tally = vote("b").json();
+        assert_eq!(tally["votes"], 2);
+        assert_eq!(tally["skipped"], true);
+        assert_eq!(get(&server, "/current").json()[0]["data"]["timestamp"], 101);
+
+        // the votes started over for the new song
+        assert_eq!(vote("a").json()["votes"], 1);
+    }
+
+    #[test]
+    fn shuffle() {
+        use rand::SeedableRng;
+
+        let _guard = setup();
+        for ts in 100..110 {
+            Local.insert(&local("song", ts), |_, _| Ok(())).unwrap();
+        }
+        Local.advance().unwrap();
+        let pending = || {
+            Local
+                .pending()
+                .unwrap()
+                .iter()
+                .map(|song| song.timestamp)
+                .collect::<Vec<_>>()
+        };
+        let queued = (101..110).collect::<Vec<_>>();
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        Local.shuffle(&mut rng, 1).unwrap();
+        let shuffled = pending();
+        assert_ne!(shuffled, queued);
+        // the one playing stays at the front
+        assert_eq!(shuffled[0], 101);
+        let mut sorted = shuffled.clone();
+        sorted.sort();
+        assert_eq!(sorted, queued);
+
+        // the played one wasn't touched
+        assert_eq!(Local.previous().unwrap().unwrap().timestamp, 100);
+
+        // the endpoint keeps the current song at the front too
+        let server = serve();
+        let res = request(&server, "POST", "/shuffle", &[], "");
+        assert_eq!(res.status, 200);
+        assert_eq!(res.json()[0]["data"]["timestamp"], 101);
+        let mut sorted = pending();
+        sorted.sort();
+        assert_eq!(sorted, queued);
+    }
+
+    #[test]
+    fn clear_queue() {
+        let _guard = setup();
+        let server = serve();
+        for ts in 100..103 {
+            Local.insert(&local("song", ts), |_, _| Ok(())).unwrap();
+        }
+        Local.advance().unwrap();
+
+        let cleared = request(&server, "DELETE", "/queue", &[], "").json();
+        assert_eq!(cleared, serde_json::json!({"pending": 2, "history": 0}));
+        assert_eq!(get(&server, "/queue").json(), serde_json::json!([]));
+        // what already played is still there
+        assert_eq!(Local.count().unwrap(), 1);
+        assert!(Local.previous().unwrap().is_some());
+
+        Local.insert(&local("song", 103), |_, _| Ok(())).unwrap();
+        let cleared = request(&server, "DELETE", "/queue?include_history=true", &[], "").json();
+        assert_eq!(cleared, serde_json::json!({"pending": 1, "history": 1}));
+        assert_eq!(Local.count().unwrap(), 0);
+    }
+
+    #[test]
+    fn validate_dry_run() {
+        let (_guard, _mock) = crate::youtube::test::setup_with(
+            |config| config.max_duration_secs = 240,
+            |path| {
+                let body = crate::youtube::test::videos(path, |item| {
+                    if item["id"] == "bbbbbbbbbbb" {
+                        item["contentDetails"]["duration"] = "PT1H".into();
+                    }
+                });
+                (200, body)
+            },
+        );
+        let server = serve();
+        let body = |url: &str| {
+            serde_json::json!({"kind": {"youtube": url}, "ts": 100, "version": 1}).to_string()
+        };
+
+        let res = request(
+            &server,
+            "POST",
+            "/validate",
+            &[],
+            &body("https://youtu.be/aaaaaaaaaaa"),
+        );
+        assert_eq!(res.status, 200);
+        let song = res.json();
+        assert_eq!(song["data"]["vid"], "aaaaaaaaaaa");
+        assert_eq!(song["data"]["title"], "title aaaaaaaaaaa");
+        assert_eq!(song["data"]["duration"], 180);
+
+        let res = request(
+            &server,
+            "POST",
+            "/validate",
+            &[],
+            &body("https://youtu.be/bbbbbbbbbbb"),
+        );
+        assert_eq!(res.status, 400);
+        assert_eq!(res.json()["error"], "duration_too_long");
+
+        // neither was stored
+        assert_eq!(Youtube.count().unwrap(), 0);
+        assert_eq!(get(&server, "/queue").json(), serde_json::json!([]));
+    }
+
+    #[test]
+    fn idempotency_key() {
+        let _guard = setup();
+        let server = serve();
+        let body = serde_json::json!({
+            "kind": {"local": {"artist": "a", "title": "t", "album": "b"}},
+            "ts": 100,
+            "version": 1,
+        })
+        .to_string();
+        let post = |key| {
+            request(
+                &server,
+                "POST",
+                "/local",
+                &[("Idempotency-Key", key)],
+                &body,
+            )
+        };
+
+        let first = post("retry-1");
+        assert_eq!(first.status, 201);
+        let again = post("retry-1");
+        assert_eq!(again.status, 201);
+        assert_eq!(again.body, first.body);
+        assert_eq!(Local.count().unwrap(), 1);
+
+        // a new key is a new request
+        assert_eq!(post("retry-2").status, 201);
+        assert_eq!(Local.count().unwrap(), 2);
+
+        // once the key has expired the request goes through again
+        database::get_connection()
+            .execute_batch("UPDATE idempotency_keys SET ts = 0")
+            .unwrap();
+        assert_eq!(post("retry-1").status, 201);
+        assert_eq!(Local.count().unwrap(), 3);
+    }
+
+    #[test]
+    fn move_in_queue() {
+        let _guard = setup();
+        let server = serve();
+        for ts in 100..104 {
+            Local.insert(&local("song", ts), |_, _| Ok(())).unwrap();
+        }
+        let ids = Local
+            .pending()
+            .unwrap()
+            .iter()
+            .map(|s| s.id)
+            .collect::<Vec<_>>();
+        let move_to = |id: i64, position: u32| {
+            let body = serde_json::json!({"kind": "local", "id": id, "position": position});
+            request(&server, "POST", "/queue/move", &[], &body.to_string())
+        };
+        let pending = || {
+            Local
+                .pending()
+                .unwrap()
+                .iter()
+                .map(|s| s.id)
+                .collect::<Vec<_>>()
+        };
+
+        let res = move_to(ids[3], 0);
+        assert_eq!(res.status, 200);
+        assert_eq!(res.json()[0]["data"]["id"], ids[3]);
+        assert_eq!(pending(), [ids[3], ids[0], ids[1], ids[2]]);
+        assert_eq!(move_to(ids[0], 2).status, 200);
+        assert_eq!(pending(), [ids[3], ids[1], ids[0], ids[2]]);
+
+        // only unplayed songs can move
+        Local.advance().unwrap();
+        assert_eq!(move_to(ids[3], 1).status, 404);
+    }
+
+    #[test]
+    fn played_at() {
+        let _guard = setup();
+        let server = serve();
+        Local.insert(&local("a", 100), |_, _| Ok(())).unwrap();
+        Local.insert(&local("b", 101), |_, _| Ok(())).unwrap();
+        assert_eq!(Local.current().unwrap().unwrap().played_at, None);
+
+        let before = crate::unix_time();
+        Local.advance().unwrap();
+        Local.advance().unwrap();
+        let played = Local.history(10).unwrap();
+        for song in &played {
+            let at = song.played_at.unwrap();
+            assert!(at >= before && at <= crate::unix_time());
+        }
+        // requested at is left alone
+        assert_eq!(played[0].timestamp, 101);
+
+        // the history goes by when songs played, not where they were queued
+        database::get_connection()
+            .execute_batch("UPDATE local_songs SET played_at = 1 WHERE ts = 101")
+            .unwrap();
+        let previous = get(&server, "/previous?n=2").json();
+        assert_eq!(previous[0]["data"]["timestamp"], 100);
+        assert_eq!(previous[1]["data"]["played_at"], 1);
+    }
+
+    #[test]
+    fn prune_played() {
+        // the first played 40 days ago, the second just now and the third not yet
+        fn played() -> Vec<i64> {
+            for ts in 100..103 {
+                Local.insert(&local("song", ts), |_, _| Ok(())).unwrap();
+            }
+            Local.advance().unwrap();
+            Local.advance().unwrap();
+            let songs = Local
+                .all(None, None)
+                .unwrap()
+                .iter()
+                .map(|s| s.id)
+                .collect::<Vec<_>>();
+            database::get_connection()
+                .execute_named(
+                    "UPDATE local_songs SET played_at = :at WHERE id = :id",
+                    &[
+                        (":at", &(crate::unix_time() - 40 * 60 * 60 * 24)),
+                        (":id", &songs[0]),
+                    ],
+                )
+                .unwrap();
+            songs
+        }
+        let ids = || {
+            Local
+                .all(None, None)
+                .unwrap()
+                .iter()
+                .map(|s| s.id)
+                .collect::<Vec<_>>()
+        };
+
+        let _guard = setup();
+        let server = serve();
+        played();
+
+        // a retention of 0 keeps everything
+        let pruned = request(&server, "POST", "/prune", &[], "").json();
+        assert_eq!(pruned, serde_json::json!({"deleted": 0}));
+        assert_eq!(Local.count().unwrap(), 3);
+        drop(_guard);
+
+        let _guard = setup_with(|config| config.retention_days = 30);
+        let songs = played();
+        let pruned = request(&server, "POST", "/prune", &[], "").json();
+        assert_eq!(pruned, serde_json::json!({"deleted": 1}));
+        assert_eq!(ids(), songs[1..]);
+    }
+
+    #[test]
+    fn maintenance() {
+        let _guard = setup_with(|config| config.api_token = Some("secret".into()));
+        let server = serve();
+        let auth = [("Authorization", "Bearer secret")];
+        for ts in 100..103 {
+            Local.insert(&local("song", ts), |_, _| Ok(())).unwrap();
+        }
+        let ids = Local
+            .pending()
+            .unwrap()
+            .iter()
+            .map(|s| s.id)
+            .collect::<Vec<_>>();
+        Local.delete(ids[1]).unwrap();
+
+        assert_eq!(
+            request(&server, "POST", "/maintenance", &[], "").status,
+            401
+        );
+        let res = request(&server, "POST", "/maintenance", &auth, "");
+        assert_eq!(res.status, 200);
+        // the tests' database has no file
+        assert_eq!(
+            res.json(),
+            serde_json::json!({"before": null, "after": null})
+        );
+
+        // and nothing was lost
+        let queued = get(&server, "/queue")
+            .json()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|song| song["data"]["id"].as_i64().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(queued, [ids[0], ids[2]]);
+    }
+
+    #[test]
+    fn song_by_id() {
+        let _guard = setup();
+        let server = serve();
+        for (i, url) in ["http://a/1", "http://a/2"].iter().enumerate() {
+            let item = Item {
+                kind: ItemKind::Generic {
+                    url: url.to_string(),
+                    title: format!("song {}", i),
+                    duration: 60,
+                },
+                ts: 100 + i as i64,
+                version: 1,
+                requested_by: "someone".into(),
+            };
+            Generic.insert(&item, |_, _| Ok(())).unwrap();
+        }
+        let songs = Generic.all(None, None).unwrap();
+        Generic.advance().unwrap();
+
+        for song in &songs {
+            let res = get(&server, &format!("/generic/{}", song.id));
+            assert_eq!(res.status, 200);
+            let data = &res.json()["data"];
+            assert_eq!(data["id"], song.id);
+            assert_eq!(data["url"], song.url.as_str());
+            assert_eq!(data["title"], song.title.as_str());
+            assert_eq!(data["duration"], song.duration);
+            assert_eq!(data["timestamp"], song.timestamp);
+        }
+        // played ones too
+        assert!(
+            get(&server, &format!("/generic/{}/", songs[0].id)).json()["data"]["played_at"]
+                .is_i64()
+        );
+
+        assert_eq!(get(&server, "/generic/9999").status, 404);
+        assert!(get(&server, "/generic/9999").body.is_empty());
+        // ids are only unique within a kind
+        assert_eq!(
+            get(&server, &format!("/youtube/{}", songs[0].id)).status,
+            404
+        );
+        assert!(Generic.get(9999).unwrap().is_none());
+    }
+
+    #[test]
+    fn created_song() {
+        let _guard = setup();
+        let server = serve();
+        let post = |url: &str| {
+            let body = serde_json::json!({
+                "kind": {"generic": {"url": url, "title": "a song", "duration": 60}},
+                "ts": 100,
+                "version": 1,
+                "requested_by": "someone",
+            });
+            request(&server, "POST", "/generic", &[], &body.to_string())
+        };
+        let res = post("http://a/1");
+        assert_eq!(res.status, 201);
+        let song = res.json();
+        let id = song["data"]["id"].as_i64().unwrap();
+        assert_eq!(song["kind"], "generic");
+        assert_eq!(song["data"]["title"], "a song");
+        assert_eq!(song["data"]["duration"], 60);
+        assert_eq!(song["data"]["requested_by"], "someone");
+
+        let location = res.header("Location").unwrap().to_string();
+        assert_eq!(location, format!("/generic/{}", id));
+        // and it points at the same song
+        assert_eq!(get(&server, &location).json(), song);
+
+        let second = post("http://a/2");
+        assert_eq!(
+            second.header("Location").unwrap(),
+            format!("/generic/{}", id + 1)
+        );
+    }
+
+    #[test]
+    fn bulk_validate() {
+        let (_guard, mock) = crate::youtube::test::setup_with(
+            |_| {},
+            |path| {
+                // private or deleted videos are left out by the api
+                let mut body = serde_json::from_str::<serde_json::Value>(
+                    &crate::youtube::test::videos(path, |_| {}),
+                )
+                .unwrap();
+                body["items"]
+                    .as_array_mut()
+                    .unwrap()
+                    .retain(|item| item["id"] != "ccccccccccc");
+                (200, body.to_string())
+            },
+        );
+        let server = serve();
+        let urls = serde_json::json!([
+            "https://youtu.be/aaaaaaaaaaa",
+            "https://example.com/not-youtube",
+            "https://youtu.be/ccccccccccc",
+            "https://www.youtube.com/watch?v=bbbbbbbbbbb",
+        ]);
+        let res = request(&server, "POST", "/validate/bulk", &[], &urls.to_string());
+        assert_eq!(res.status, 200);
+        let checked = res.json();
+        let checked = checked.as_array().unwrap();
+        assert_eq!(checked.len(), 4);
+
+        for (i, vid) in &[(0, "aaaaaaaaaaa"), (3, "bbbbbbbbbbb")] {
+            assert_eq!(checked[*i]["url"], urls[*i]);
+            assert_eq!(checked[*i]["ok"], true);
+            assert_eq!(checked[*i]["metadata"]["vid"], *vid);
+            assert!(checked[*i].get("error").is_none());
+        }
+        assert_eq!(checked[1]["ok"], false);
+        assert_eq!(checked[1]["error"]["error"], "invalid_youtube_url");
+        assert!(checked[1].get("metadata").is_none());
+        assert_eq!(checked[2]["ok"], false);
+        assert_eq!(checked[2]["error"]["error"], "video_unavailable");
+
+        // one lookup for all of them, and nothing stored
+        assert_eq!(mock.requests().len(), 1);
+        assert_eq!(Youtube.count().unwrap(), 0);
+    }
+
+    #[test]
+    fn import_playlist() {
+        use crate::youtube::test::{param, videos};
+
+        let (_guard, mock) = crate::youtube::test::setup_with(
+            |config| {
+                config.max_queue_len = 2;
+                config.request_cooldown_secs = 60;
+            },
+            |path| {
+                if !path.starts_with("/playlistItems") {
+                    return (200, videos(path, |_| {}));
+                }
+                let item = |id: &str| serde_json::json!({"contentDetails": {"videoId": id}});
+                let page = match param(path, "pageToken").as_deref() {
+                    None => serde_json::json!({
+                        "nextPageToken": "page2",
+                        "items": [item("aaaaaaaaaaa"), item("bbbbbbbbbbb")],
+                    }),
+                    // the same video twice is only imported once
+                    Some("page2") => serde_json::json!({
+                        "items": [item("bbbbbbbbbbb"), item("ccccccccccc")],
+                    }),
+                    Some(other) => panic!("{}", other),
+                };
+                (200, page.to_string())
+            },
+        );
+        let server = serve();
+        let body = serde_json::json!({
+            "url": "https://www.youtube.com/playlist?list=PL0123456789",
+            "requested_by": "someone",
+        });
+        let res = request(&server, "POST", "/import/playlist", &[], &body.to_string());
+        assert_eq!(res.status, 200);
+        let imported = res.json();
+        assert_eq!(imported["imported"], 2);
+        // past `max_queue_len`
+        assert_eq!(imported["failed"][0]["url"], "https://youtu.be/ccccccccccc");
+        assert_eq!(imported["failed"][0]["error"], "queue_full");
+
+        let pages = mock
+            .requests()
+            .into_iter()
+            .filter(|path| path.starts_with("/playlistItems"))
+            .map(|path| param(&path, "pageToken"))
+            .collect::<Vec<_>>();
+        assert_eq!(pages, [None, Some("page2".to_string())]);
+        let vids = Youtube
+            .pending()
+            .unwrap()
+            .into_iter()
+            .map(|song| song.vid)
+            .collect::<Vec<_>>();
+        assert_eq!(vids, ["aaaaaaaaaaa", "bbbbbbbbbbb"]);
+
+        // the import was one request, the next one has to wait out the cooldown
+        let requests = mock.requests().len();
+        let res = request(&server, "POST", "/import/playlist", &[], &body.to_string());
+        assert_eq!(res.status, 429);
+        assert_eq!(mock.requests().len(), requests);
+
+        let body = serde_json::json!({"url": "https://www.youtube.com/watch?v=aaaaaaaaaaa"});
+        let res = request(&server, "POST", "/import/playlist", &[], &body.to_string());
+        assert_eq!(res.status, 400);
+    }
+
+    #[test]
+    fn search_endpoint() {
+        use crate::youtube::test::param;
+
+        let (_guard, mock) = crate::youtube::test::setup_with(
+            |config| config.allow_search = true,
+            |path| {
+                let q = param(path, "q").unwrap();
+                let results = serde_json::json!({"items": [
+                    {
+                        "id": {"kind": "youtube#video", "videoId": "aaaaaaaaaaa"},
+                        "snippet": {
+                            "title": format!("first for {}", q),
+                            "channelTitle": "a channel",
+                            "thumbnails": {
+                                "default": {"url": "http://i/a/default.jpg"},
+                                "medium": {"url": "http://i/a/medium.jpg"},
+                            },
+                        },
+                    },
+                    {
+                        "id": {"kind": "youtube#video", "videoId": "bbbbbbbbbbb"},
+                        "snippet": {"title": "second", "channelTitle": "b channel", "thumbnails": {}},
+                    },
+                ]});
+                (200, results.to_string())
+            },
+        );
+        let server = serve();
+
+        let res = get(&server, "/search?q=lo-fi%20beats%20%C3%BC&limit=2");
+        assert_eq!(res.status, 200);
+        assert_eq!(
+            res.json(),
+            serde_json::json!([
+                {
+                    "vid": "aaaaaaaaaaa",
+                    "title": "first for lo-fi beats ü",
+                    "channel": "a channel",
+                    "thumbnail": "http://i/a/medium.jpg",
+                },
+                {"vid": "bbbbbbbbbbb", "title": "second", "channel": "b channel", "thumbnail": ""},
+            ])
+        );
+        let requests = mock.requests();
+        assert_eq!(param(&requests[0], "q").as_deref(), Some("lo-fi beats ü"));
+        assert_eq!(param(&requests[0], "maxResults").as_deref(), Some("2"));
+
+        // cached by query, case and surrounding space aside
+        get(&server, "/search?q=%20LO-FI%20beats%20%C3%9C&limit=2");
+        assert_eq!(mock.requests().len(), 1);
+        get(&server, "/search?q=lo-fi%20beats%20%C3%BC&limit=100");
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(param(&requests[1], "maxResults").as_deref(), Some("25"));
+
+        assert_eq!(get(&server, "/search?q=%20").status, 400);
+    }
+
+    #[test]
+    fn request_counts() {
+        let (_guard, _mock) = crate::youtube::test::setup_with(
+            |config| config.dedupe_window_secs = 10,
+            |path| (200, crate::youtube::test::videos(path, |_| {})),
+        );
+        let server = serve();
+        for (vid, ts) in &[
+            ("aaaaaaaaaaa", 100),
+            ("bbbbbbbbbbb", 100),
+            ("aaaaaaaaaaa", 200),
+            ("ccccccccccc", 200),
+            ("aaaaaaaaaaa", 300),
+            ("bbbbbbbbbbb", 300),
+        ] {
+            let mut item = crate::youtube::test::item(&format!("https://youtu.be/{}", vid));
+            item.ts = *ts;
+            Youtube.insert(&item, |_, _| Ok(())).unwrap();
+        }
+        // played ones count too
+        Youtube.advance().unwrap();
+
+        let counts = get(&server, "/list/youtube?with_counts=true")
+            .json()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|song| {
+                (
+                    song["vid"].as_str().unwrap().to_string(),
+                    song["requested"].as_i64().unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let want = [
+            ("aaaaaaaaaaa", 3),
+            ("bbbbbbbbbbb", 2),
+            ("aaaaaaaaaaa", 3),
+            ("ccccccccccc", 1),
+            ("aaaaaaaaaaa", 3),
+            ("bbbbbbbbbbb", 2),
+        ];
+        assert_eq!(
+            counts,
+            want.iter()
+                .map(|(v, n)| (v.to_string(), *n))
+                .collect::<Vec<_>>()
+        );
+
+        // paged like the plain list
+        let page = get(&server, "/list/youtube?with_counts=true&limit=2&offset=2").json();
+        assert_eq!(page[0]["vid"], "aaaaaaaaaaa");
+        assert_eq!(page[1]["requested"], 1);
+        // and only there when asked for
+        let plain = get(&server, "/list/youtube").json();
+        assert!(plain[0].get("requested").is_none());
+    }
+
+    // generic songs need nothing looked up, they're queued in order
+    fn queue(urls: &[&str]) -> Vec<crate::generic::Media> {
+        urls.iter()
+            .enumerate()
+            .map(|(i, url)| {
+                let item = Item {
+                    kind: ItemKind::Generic {
+                        url: url.to_string(),
+                        title: format!("song {}", i),
+                        duration: 60,
+                    },
+                    ts: 100 + i as i64,
+                    version: 1,
+                    requested_by: "someone".into(),
+                };
+                Generic.insert(&item, |_, _| Ok(())).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn set_current() {
+        let _guard = setup();
+        let server = serve();
+        let songs = queue(&["http://a/1", "http://a/2", "http://a/3"]);
+        Generic.advance().unwrap();
+        let urls = |server: &Running| {
+            get(server, "/queue")
+                .json()
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|song| song["data"]["url"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        // picked out of order, the rest wait behind it
+        let res = request(
+            &server,
+            "POST",
+            &format!("/current/generic/{}", songs[2].id),
+            &[],
+            "",
+        );
+        assert_eq!(res.status, 200);
+        assert_eq!(res.json()[0]["data"]["url"], "http://a/3");
+        assert_eq!(
+            get(&server, "/current").json()[0]["data"]["url"],
+            "http://a/3"
+        );
+        assert_eq!(urls(&server), ["http://a/3", "http://a/2"]);
+
+        // one that already played comes back
+        let path = format!("/current/generic/{}", songs[0].id);
+        assert_eq!(request(&server, "POST", &path, &[], "").status, 200);
+        assert_eq!(urls(&server), ["http://a/1", "http://a/3", "http://a/2"]);
+        assert_eq!(Generic.get(songs[0].id).unwrap().unwrap().played_at, None);
+
+        request(&server, "POST", "/advance", &[], "");
+        assert_eq!(
+            get(&server, "/current").json()[0]["data"]["url"],
+            "http://a/3"
+        );
+
+        assert_eq!(
+            request(&server, "POST", "/current/generic/9999", &[], "").status,
+            404
+        );
+        assert_eq!(
+            request(&server, "POST", "/current/nothing/1", &[], "").status,
+            400
+        );
+        assert_eq!(urls(&server), ["http://a/3", "http://a/2"]);
+    }
+
+    #[test]
+    fn disabled_kinds() {
+        let _guard = setup_with(|config| config.enabled_kinds = vec![Kind::Youtube, Kind::Local]);
+        let server = serve();
+        let item = serde_json::json!({
+            "kind": {"generic": {"url": "http://a/1", "title": "a", "duration": 60}},
+            "ts": 100,
+            "version": 1,
+        })
+        .to_string();
+
+        let res = request(&server, "POST", "/generic", &[], &item);
+        assert_eq!(res.status, 400);
+        let body = res.json();
+        assert_eq!(body["error"], "item_kind_disabled");
+        assert_eq!(body["message"], "generic requests are disabled");
+
+        let res = request(&server, "POST", "/validate", &[], &item);
+        assert_eq!(res.json()["error"], "item_kind_disabled");
+        assert_eq!(Generic.count().unwrap(), 0);
+        drop(_guard);
+
+        // everything is on by default
+        let _guard = setup();
+        assert_eq!(request(&server, "POST", "/generic", &[], &item).status, 201);
+    }
+
+    #[test]
+    fn local_requests() {
+        let dir = TempDir::new();
+        dir.file("music/song.flac", &flac(&["TITLE=a song"], 60));
+        std::fs::create_dir(dir.path().join("music/album.flac")).unwrap();
+        dir.file("secret.flac", b"");
+        let root = dir.path().join("music");
+        let _guard = setup_with(|config| config.local_root = Some(root));
+        let server = serve();
+        let post = |path: &str| {
+            let body = serde_json::json!({
+                "kind": {"local": {"path": path}},
+                "ts": 100,
+                "version": 1,
+            });
+            request(&server, "POST", "/local", &[], &body.to_string())
+        };
+
+        let res = post("song.flac");
+        assert_eq!(res.status, 201);
+        assert_eq!(res.json()["data"]["title"], "a song");
+
+        for (path, status, error) in &[
+            ("missing.flac", 404, "local_file_not_found"),
+            ("album.flac", 400, "not_a_file"),
+            ("../secret.flac", 403, "path_not_allowed"),
+        ] {
+            let res = post(path);
+            assert_eq!(res.status, *status, "{}", path);
+            assert_eq!(res.json()["error"], *error, "{}", path);
+        }
+        // only the one that could be played was queued
+        assert_eq!(Local.count().unwrap(), 1);
+    }
+
+    #[test]
+    fn scan_local() {
+        let dir = TempDir::new();
+        dir.file("music/b.flac", &flac(&["TITLE=b"], 60));
+        dir.file("music/a.flac", &flac(&["TITLE=a"], 60));
+        dir.file("music/album/c.flac", &flac(&["TITLE=c"], 60));
+        dir.file("music/album/long.flac", &flac(&["TITLE=long"], 600));
+        dir.file("music/album/cover.jpg", b"");
+        dir.file("music/notes.txt", b"");
+        let root = dir.path().join("music");
+        let _guard = setup_with(|config| {
+            config.local_root = Some(root);
+            config.max_duration_secs = 300;
+        });
+        let server = serve();
+        let scan = |path: &str| {
+            let body = serde_json::json!({"path": path, "requested_by": "someone"});
+            request(&server, "POST", "/local/scan", &[], &body.to_string())
+        };
+
+        let res = scan(".");
+        assert_eq!(res.status, 200);
+        let scanned = res.json();
+        assert_eq!(scanned["added"], 3);
+        // the two that aren't audio
+        assert_eq!(scanned["skipped"], 2);
+        let failed = scanned["failed"].as_array().unwrap();
+        assert_eq!(failed.len(), 1);
+        assert!(failed[0]["path"].as_str().unwrap().ends_with("long.flac"));
+        assert_eq!(failed[0]["error"], "duration_too_long");
+
+        let titles = Local
+            .pending()
+            .unwrap()
+            .into_iter()
+            .map(|song| song.title)
+            .collect::<Vec<_>>();
+        // queued in path order, `album/` sorts before `b.flac`
+        assert_eq!(titles, ["a", "c", "b"]);
+
+        // what's already queued is skipped, not an error
+        let again = scan("album").json();
+        assert_eq!(again["added"], 0);
+        assert_eq!(again["skipped"], 2);
+
+        assert_eq!(scan("a.flac").json()["error"], "not_a_directory");
+        assert_eq!(scan("..").status, 403);
+        assert_eq!(Local.count().unwrap(), 3);
+    }
+
+    #[test]
+    fn scan_limits() {
+        let dir = TempDir::new();
+        for name in &["a", "b", "c"] {
+            dir.file(&format!("{}.flac", name), &flac(&[], 60));
+        }
+        let root = dir.path().to_path_buf();
+        let _guard = setup_with(|config| {
+            config.local_root = Some(root);
+            config.max_queue_len = 2;
+            config.request_cooldown_secs = 60;
+        });
+        let server = serve();
+        let scan = || {
+            let body = serde_json::json!({"path": ".", "requested_by": "someone"});
+            request(&server, "POST", "/local/scan", &[], &body.to_string())
+        };
+
+        let scanned = scan().json();
+        assert_eq!(scanned["added"], 2);
+        // past `max_queue_len`
+        assert!(scanned["failed"][0]["path"]
+            .as_str()
+            .unwrap()
+            .ends_with("c.flac"));
+        assert_eq!(scanned["failed"][0]["error"], "queue_full");
+
+        // the whole scan was one request against the cooldown
+        assert_eq!(scan().status, 429);
+        assert_eq!(Local.count().unwrap(), 2);
+    }
+
+    // a local song for `path`, stored as is
+    fn local_song(path: &str) -> i64 {
+        let conn = database::get_connection();
+        conn.execute_named(
+            "INSERT INTO local_songs (ts, title, artist, album, path, position)
+                VALUES (1, 'a song', '', '', :path, 1)",
+            &[(":path", &path)],
+        )
+        .unwrap();
+        conn.last_insert_rowid()
+    }
+
+    #[test]
+    fn stream_local() {
+        let dir = TempDir::new();
+        let data = (0..=255u8).cycle().take(10_000).collect::<Vec<_>>();
+        let file = dir.file("song.mp3", &data);
+        let root = dir.path().to_path_buf();
+        let _guard = setup_with(|config| config.local_root = Some(root));
+        let id = local_song(file.to_str().unwrap());
+        let server = serve();
+        let path = format!("/local/{}/stream", id);
+
+        let full = get(&server, &path);
+        assert_eq!(full.status, 200);
+        assert_eq!(full.header("Content-Type"), Some("audio/mpeg"));
+        assert_eq!(full.header("Accept-Ranges"), Some("bytes"));
+        assert_eq!(full.body, data);
+
+        let range = request(&server, "GET", &path, &[("Range", "bytes=100-199")], "");
+        assert_eq!(range.status, 206);
+        assert_eq!(range.header("Content-Range"), Some("bytes 100-199/10000"));
+        assert_eq!(range.body, &data[100..200]);
+
+        let tail = request(&server, "GET", &path, &[("Range", "bytes=-10")], "");
+        assert_eq!(tail.status, 206);
+        assert_eq!(tail.body, &data[9990..]);
+
+        let past = request(&server, "GET", &path, &[("Range", "bytes=10000-")], "");
+        assert_eq!(past.status, 416);
+        assert_eq!(past.header("Content-Range"), Some("bytes */10000"));
+        assert!(past.body.is_empty());
+
+        assert_eq!(get(&server, "/local/12345/stream").status, 404);
+    }
+
+    #[test]
+    fn stream_outside_root() {
+        let dir = TempDir::new();
+        let root = dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        let outside = dir.file("outside.mp3", b"secret");
+        let inside = dir.file("root/song.mp3", b"song");
+
+        let _guard = setup_with(|config| config.local_root = Some(root));
+        // stored before the root was moved, or by an older version
+        let outside = local_song(outside.to_str().unwrap());
+        let server = serve();
+        let res = get(&server, &format!("/local/{}/stream", outside));
+        assert_eq!(res.status, 403);
+        assert_eq!(res.json()["error"], "path_not_allowed");
+
+        // and nothing at all without a root
+        let inside = local_song(inside.to_str().unwrap());
+        config::set(config::Config::default());
+        let res = get(&server, &format!("/local/{}/stream", inside));
+        assert_eq!(res.status, 403);
+        assert_eq!(res.json()["error"], "path_not_allowed");
+    }
+
+    #[test]
+    fn byte_ranges() {
+        for (range, want) in &[
+            ("bytes=0-99", Some(Some((0, 99)))),
+            ("bytes=100-", Some(Some((100, 999)))),
+            ("bytes=900-5000", Some(Some((900, 999)))),
+            ("bytes=-100", Some(Some((900, 999)))),
+            ("bytes=-5000", Some(Some((0, 999)))),
+            (" bytes= 5 - 9 ", Some(Some((5, 9)))),
+            // unsatisfiable
+            ("bytes=1000-", Some(None)),
+            ("bytes=-0", Some(None)),
+            // ignored, the whole file is sent
+            ("bytes=9-5", None),
+            ("bytes=0-1,5-6", None),
+            ("bytes=a-b", None),
+            ("items=0-5", None),
+            ("bytes=5", None),
+        ] {
+            assert_eq!(byte_range(range, 1000), *want, "{}", range);
+        }
+    }
+
+    #[test]
+    fn not_modified() {
+        let _guard = setup();
+        let server = serve();
+        queue(&["http://a/1"]);
+
+        let first = get(&server, "/queue");
+        assert_eq!(first.status, 200);
+        assert_eq!(first.header("Cache-Control"), Some("no-cache"));
+        let etag = first.header("ETag").unwrap().to_string();
+        let date = first.header("Last-Modified").unwrap().to_string();
+
+        let res = request(&server, "GET", "/queue", &[("If-None-Match", &etag)], "");
+        assert_eq!(res.status, 304);
+        assert!(res.body.is_empty());
+        assert_eq!(res.header("ETag"), Some(etag.as_str()));
+        let res = request(
+            &server,
+            "GET",
+            "/queue",
+            &[("If-Modified-Since", &date)],
+            "",
+        );
+        assert_eq!(res.status, 304);
+
+        // the xml for the same queue is a different resource
+        let res = request(
+            &server,
+            "GET",
+            "/queue",
+            &[("Accept", "application/xml"), ("If-None-Match", &etag)],
+            "",
+        );
+        assert_eq!(res.status, 200);
+
+        queue(&["http://a/2"]);
+        for headers in &[
+            [("If-None-Match", etag.as_str())],
+            [("If-Modified-Since", date.as_str())],
+        ] {
+            let res = request(&server, "GET", "/queue", headers, "");
+            assert_eq!(res.status, 200);
+            assert_eq!(res.json().as_array().unwrap().len(), 2);
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use std::collections::HashSet;
+
+use serde::Serialize;
+
+// votes to skip the current song, they're reset whenever the current song changes
+pub struct SkipVotes<K> {
+    song: Option<K>,
+    voters: HashSet<String>,
+}
+
+impl<K> Default for SkipVotes<K> {
+    fn default() -> Self {
+        Self {
+            song: None,
+            voters: HashSet::new(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Tally {
+    pub votes: usize,
+    pub threshold: u32,
+    pub skipped: bool,
+}
+
+impl<K: PartialEq> SkipVotes<K> {
+    // voting twice for the same song only counts once
+    pub fn vote(&mut self, song: K, who: &str, threshold: u32) -> Tally {
+        if self.song.as_ref() != Some(&song) {
+            self.song = Some(song);
+            self.voters.clear();
+        }
+        self.voters.insert(who.to_string());
+
+        let votes = self.voters.len();
+        let skipped = votes >= threshold as usize;
+        if skipped {
+            self.song = None;
+            self.voters.clear();
+        }
+        Tally {
+            votes,
+            threshold,
+            skipped,
+        }
+    }
+}
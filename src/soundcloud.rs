@@ -0,0 +1,535 @@
+use std::borrow::Cow;
+
+use log::*;
+use once_cell::sync::Lazy;
+use once_cell::sync_lazy;
+use rand::seq::SliceRandom;
+use regex::Regex;
+use rusqlite::OptionalExtension;
+use serde::{Deserialize, Serialize};
+
+use crate::blacklist;
+use crate::config;
+use crate::database;
+use crate::error::{Error, Result};
+use crate::server;
+use crate::title;
+use crate::youtube::encode;
+use crate::FromRow;
+
+// playlists (`/user/sets/name`) have an extra segment and don't match
+static PATTERN: Lazy<Regex> = sync_lazy! {
+    Regex::new(
+        r#"^(?:https?://)?(?:(?:www|m)\.)?soundcloud\.com/(?P<permalink>[A-Za-z0-9_-]+/[A-Za-z0-9_-]+)/?(?:[?#].*)?$"#,
+    ).expect("valid regex")
+};
+
+#[derive(Serialize)]
+pub struct Track {
+    pub id: i64,
+    pub permalink: String,
+    pub timestamp: i64,
+    pub duration: i64,
+    pub title: String,
+    pub artist: String,
+    pub requested_by: String,
+    pub position: i64,
+    // unix time, null until the song has played
+    pub played_at: Option<i64>,
+}
+
+impl FromRow for Track {
+    fn from_row(row: &rusqlite::Row<'_, '_>) -> Self {
+        Self {
+            id: row.get(0),
+            permalink: row.get(1),
+            timestamp: row.get(2),
+            duration: row.get(3),
+            title: row.get(4),
+            artist: row.get(5),
+            requested_by: crate::requested_by(row),
+            position: row.get("position"),
+            played_at: row.get("played_at"),
+        }
+    }
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+
+    fn position(&self) -> i64 {
+        self.position
+    }
+
+    fn played_at(&self) -> Option<i64> {
+        self.played_at
+    }
+}
+
+impl Track {
+    pub fn url(&self) -> String {
+        format!("https://soundcloud.com/{}", self.permalink)
+    }
+}
+
+#[derive(Default)]
+pub struct SoundCloud;
+
+impl crate::Storage<Track> for SoundCloud {
+    fn store(&self, tx: &rusqlite::Transaction<'_>, track: &Track) -> Result<Track> {
+        tx.execute_named(
+            include_str!("../sql/soundcloud/add_track.sql"),
+            &[
+                (":permalink", &track.permalink),
+                (":ts", &track.timestamp),
+                (":duration", &track.duration),
+                (":title", &track.title),
+                (":artist", &track.artist),
+                (":requested_by", &track.requested_by),
+            ],
+        )?;
+        tx.query_row_named(
+            include_str!("../sql/soundcloud/get.sql"),
+            &[(":id", &tx.last_insert_rowid())],
+            Track::from_row,
+        )
+        .map_err(Error::Sql)
+    }
+
+    fn validate(&self, item: &server::Item) -> Result<Track> {
+        let url = match &item.kind {
+            server::ItemKind::SoundCloud(url) => url,
+            _ => return Err(Error::WrongItemKind("soundcloud")),
+        };
+
+        // permalinks are case insensitive
+        let permalink = PATTERN
+            .captures(url)
+            .and_then(|s| s.name("permalink"))
+            .map(|s| s.as_str().to_lowercase())
+            .ok_or_else(|| Error::InvalidSoundCloudUrl(url.to_string()))?;
+
+        // before anything else so known-bad ids don't cost an api call
+        blacklist::check(blacklist::EntryKind::Video, &permalink)?;
+
+        let conn = database::get_connection();
+
+        // a window of 0 means a track can only ever be requested once
+        let since = match config::get().dedupe_window_secs {
+            0 => i64::MIN,
+            window => item.ts - window,
+        };
+        let exists: bool = conn.query_row_named(
+            include_str!("../sql/soundcloud/exists.sql"),
+            &[(":permalink", &permalink), (":since", &since)],
+            |row| row.get(0),
+        )?;
+        if exists {
+            return Err(Error::DuplicateSong { vid: permalink });
+        }
+
+        let mut info = SoundCloudItem::fetch(&permalink)?;
+        info.title = title::sanitize(&info.title, config::get().max_title_len);
+        title::check_words(&[&info.title, &info.artist])?;
+
+        let max = config::get().max_duration_secs;
+        if max > 0 && info.duration > max {
+            return Err(Error::DurationTooLong {
+                got: info.duration,
+                max,
+            });
+        }
+
+        if config::get().dedupe_titles {
+            let title = title::normalize(&info.title);
+            if self
+                .pending()?
+                .iter()
+                .any(|song| title::normalize(&song.title) == title)
+            {
+                return Err(Error::DuplicateTitle(info.title));
+            }
+        }
+
+        // not stored yet, so there's no row id
+        Ok(Track {
+            id: 0,
+            permalink,
+            timestamp: item.ts,
+            duration: info.duration,
+            title: info.title,
+            artist: info.artist,
+            requested_by: item.requested_by.clone(),
+            position: item.ts,
+            played_at: None,
+        })
+    }
+
+    fn get(&self, id: i64) -> Result<Option<Track>> {
+        database::get_connection()
+            .query_row_named(
+                include_str!("../sql/soundcloud/get.sql"),
+                &[(":id", &id)],
+                Track::from_row,
+            )
+            .optional()
+            .map_err(Error::Sql)
+    }
+
+    fn current(&self) -> Result<Option<Track>> {
+        database::get_connection()
+            .query_row(
+                include_str!("../sql/soundcloud/get_current.sql"),
+                rusqlite::NO_PARAMS,
+                Track::from_row,
+            )
+            .optional()
+            .map_err(Error::Sql)
+    }
+
+    fn previous(&self) -> Result<Option<Track>> {
+        database::get_connection()
+            .query_row(
+                include_str!("../sql/soundcloud/get_previous.sql"),
+                rusqlite::NO_PARAMS,
+                Track::from_row,
+            )
+            .optional()
+            .map_err(Error::Sql)
+    }
+
+    fn history(&self, n: u32) -> Result<Vec<Track>> {
+        Ok(database::get_connection()
+            .prepare(include_str!("../sql/soundcloud/get_history.sql"))?
+            .query_map_named(&[(":limit", &n)], Track::from_row)
+            .map_err(Error::Sql)?
+            .filter_map(|s| s.ok())
+            .collect::<Vec<_>>())
+    }
+
+    fn shuffle<R: rand::Rng + ?Sized>(&self, rng: &mut R, skip: u32) -> Result<()> {
+        let mut conn = database::get_connection();
+        let tx = conn.transaction()?;
+        let pending = tx
+            .prepare(include_str!("../sql/soundcloud/get_pending_positions.sql"))?
+            .query_map_named(&[(":skip", &skip)], |row| {
+                (row.get::<_, i64>(0), row.get::<_, i64>(1))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut positions = pending.iter().map(|(_, pos)| *pos).collect::<Vec<_>>();
+        positions.shuffle(rng);
+        for ((id, _), position) in pending.iter().zip(positions) {
+            tx.execute_named(
+                include_str!("../sql/soundcloud/set_position.sql"),
+                &[(":id", id), (":position", &position)],
+            )?;
+        }
+        tx.commit().map_err(Error::Sql)
+    }
+
+    fn move_to(&self, id: i64, pos: u32) -> Result<()> {
+        let mut conn = database::get_connection();
+        let tx = conn.transaction()?;
+        let pending = tx
+            .prepare(include_str!("../sql/soundcloud/get_pending_positions.sql"))?
+            .query_map_named(&[(":skip", &0)], |row| {
+                (row.get::<_, i64>(0), row.get::<_, i64>(1))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        for (id, position) in crate::reorder(pending, id, pos)? {
+            tx.execute_named(
+                include_str!("../sql/soundcloud/set_position.sql"),
+                &[(":id", &id), (":position", &position)],
+            )?;
+        }
+        tx.commit().map_err(Error::Sql)
+    }
+
+    fn requeue(&self, id: i64, position: i64) -> Result<bool> {
+        database::get_connection()
+            .execute_named(
+                include_str!("../sql/soundcloud/requeue.sql"),
+                &[(":id", &id), (":position", &position)],
+            )
+            .map(|n| n > 0)
+            .map_err(Error::Sql)
+    }
+
+    fn advance(&self) -> Result<Option<Track>> {
+        database::get_connection()
+            .execute_named(
+                include_str!("../sql/soundcloud/advance.sql"),
+                &[(":now", &crate::unix_time())],
+            )
+            .map_err(Error::Sql)?;
+        self.current()
+    }
+
+    fn all(&self, limit: Option<u32>, offset: Option<u32>) -> Result<Vec<Track>> {
+        // a negative limit is unbounded in sqlite
+        let limit = limit.map(i64::from).unwrap_or(-1);
+        let offset = offset.map(i64::from).unwrap_or(0);
+        Ok(database::get_connection()
+            .prepare(include_str!("../sql/soundcloud/get_all.sql"))?
+            .query_map_named(&[(":limit", &limit), (":offset", &offset)], Track::from_row)
+            .map_err(Error::Sql)?
+            .filter_map(|s| s.ok())
+            .collect::<Vec<_>>())
+    }
+
+    fn pending(&self) -> Result<Vec<Track>> {
+        Ok(database::get_connection()
+            .prepare(include_str!("../sql/soundcloud/get_pending.sql"))?
+            .query_map(rusqlite::NO_PARAMS, Track::from_row)
+            .map_err(Error::Sql)?
+            .filter_map(|s| s.ok())
+            .collect::<Vec<_>>())
+    }
+
+    fn between(&self, start: i64, end: i64) -> Result<Vec<Track>> {
+        Ok(database::get_connection()
+            .prepare(include_str!("../sql/soundcloud/get_between.sql"))?
+            .query_map_named(&[(":start", &start), (":end", &end)], Track::from_row)
+            .map_err(Error::Sql)?
+            .filter_map(|s| s.ok())
+            .collect::<Vec<_>>())
+    }
+
+    fn count(&self) -> Result<i64> {
+        database::get_connection()
+            .query_row(
+                include_str!("../sql/soundcloud/count.sql"),
+                rusqlite::NO_PARAMS,
+                |row| row.get(0),
+            )
+            .map_err(Error::Sql)
+    }
+
+    fn clear_pending(&self) -> Result<u64> {
+        database::get_connection()
+            .execute(
+                include_str!("../sql/soundcloud/clear_pending.sql"),
+                rusqlite::NO_PARAMS,
+            )
+            .map_err(Error::Sql)
+            .map(|n| n as u64)
+    }
+
+    fn clear_history(&self) -> Result<u64> {
+        database::get_connection()
+            .execute(
+                include_str!("../sql/soundcloud/clear_history.sql"),
+                rusqlite::NO_PARAMS,
+            )
+            .map_err(Error::Sql)
+            .map(|n| n as u64)
+    }
+
+    fn prune(&self, before: i64) -> Result<u64> {
+        database::get_connection()
+            .execute_named(
+                include_str!("../sql/soundcloud/prune.sql"),
+                &[(":before", &before)],
+            )
+            .map_err(Error::Sql)
+            .map(|n| n as u64)
+    }
+
+    fn delete(&self, id: i64) -> Result<bool> {
+        database::get_connection()
+            .execute_named(
+                include_str!("../sql/soundcloud/delete.sql"),
+                &[(":id", &id)],
+            )
+            .map_err(Error::Sql)
+            .map(|n| n > 0)
+    }
+}
+
+pub struct SoundCloudItem {
+    pub title: String,
+    pub artist: String,
+    pub duration: i64,
+}
+
+impl SoundCloudItem {
+    pub fn fetch(permalink: &str) -> Result<Self> {
+        let config = config::get();
+        let client_id = config
+            .soundcloud_client_id
+            .as_ref()
+            .ok_or(Error::SoundCloudNotConfigured)?;
+
+        let mut url = format!(
+            "{}/resolve?url={}&client_id={}",
+            config.soundcloud_base_url.trim_end_matches('/'),
+            encode(&format!("https://soundcloud.com/{}", permalink)),
+            encode(client_id)
+        );
+
+        // resolve answers with a redirect to the track itself
+        const MAX_REDIRECTS: usize = 3;
+        for _ in 0..=MAX_REDIRECTS {
+            let mut data = vec![];
+            let resp = http_req::request::get(&url, &mut data).map_err(Error::HttpClient)?;
+
+            let code = resp.status_code();
+            if code.is_success() {
+                return Self::serialize(&data, permalink);
+            }
+
+            if !code.is_redirect() {
+                return Err(match code.into() {
+                    404 => Error::VideoUnavailable("track not found".into()),
+                    code => Error::HttpResponse(code, resp.reason().to_string()),
+                });
+            }
+
+            url = resp
+                .headers()
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case("location"))
+                .map(|(_, v)| v.to_string())
+                .ok_or_else(|| Error::HttpResponse(code.into(), resp.reason().to_string()))?;
+            debug!("following soundcloud redirect to {}", url);
+        }
+
+        Err(Error::HttpResponse(302, "too many redirects".into()))
+    }
+
+    fn serialize(data: &[u8], permalink: &str) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct Response<'a> {
+            kind: &'a str,
+            // borrowed unless there's something escaped in it
+            #[serde(borrow)]
+            title: Cow<'a, str>,
+            // in milliseconds
+            duration: i64,
+            #[serde(borrow)]
+            user: User<'a>,
+        }
+        #[derive(Deserialize)]
+        struct User<'a> {
+            #[serde(borrow)]
+            username: Cow<'a, str>,
+        }
+
+        let data = serde_json::from_slice::<Response>(data).map_err(Error::Serialize)?;
+        // user pages and the like resolve too
+        if data.kind != "track" {
+            return Err(Error::InvalidSoundCloudUrl(permalink.to_string()));
+        }
+
+        Ok(Self {
+            title: data.title.to_string(),
+            artist: data.user.username.to_string(),
+            duration: data.duration / 1000,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::youtube::test::{mock, param};
+    use crate::Storage;
+
+    fn permalink(url: &str) -> Option<String> {
+        PATTERN
+            .captures(url)
+            .and_then(|c| c.name("permalink"))
+            .map(|p| p.as_str().to_string())
+    }
+
+    #[test]
+    fn url_forms() {
+        for url in &[
+            "https://soundcloud.com/someone/a-song",
+            "http://www.soundcloud.com/someone/a-song/",
+            "m.soundcloud.com/someone/a-song?in=playlist",
+            "soundcloud.com/someone/a-song#t=1:00",
+        ] {
+            assert_eq!(permalink(url).as_deref(), Some("someone/a-song"), "{}", url);
+        }
+        for url in &[
+            "https://soundcloud.com/someone",
+            "https://soundcloud.com/someone/sets/a-playlist",
+            "https://example.com/someone/a-song",
+            "https://soundcloud.com/some one/a-song",
+        ] {
+            assert_eq!(permalink(url), None, "{}", url);
+        }
+    }
+
+    #[test]
+    fn metadata() {
+        let data =
+            br#"{"kind":"track","title":"a song","duration":183500,"user":{"username":"someone"}}"#;
+        let track = SoundCloudItem::serialize(data, "someone/a-song").unwrap();
+        assert_eq!(track.title, "a song");
+        assert_eq!(track.artist, "someone");
+        assert_eq!(track.duration, 183);
+
+        let data = br#"{"kind":"track","title":"a \"song\"\n","duration":0,"user":{"username":"some\u00f6ne"}}"#;
+        let track = SoundCloudItem::serialize(data, "someone/a-song").unwrap();
+        assert_eq!(track.title, "a \"song\"\n");
+        assert_eq!(track.artist, "someöne");
+
+        let user = br#"{"kind":"user","title":"","duration":0,"user":{"username":"someone"}}"#;
+        assert!(matches!(
+            SoundCloudItem::serialize(user, "someone/a-song"),
+            Err(Error::InvalidSoundCloudUrl(..))
+        ));
+        assert!(matches!(
+            SoundCloudItem::serialize(b"{}", "someone/a-song"),
+            Err(Error::Serialize(..))
+        ));
+    }
+
+    #[test]
+    fn resolve() {
+        let (url, mock) = mock(|path| {
+            match param(path, "url").as_deref() {
+            Some("https://soundcloud.com/someone/a-song") => (
+                200,
+                r#"{"kind":"track","title":"a song","duration":60000,"user":{"username":"someone"}}"#
+                    .into(),
+            ),
+            _ => (404, "{}".into()),
+        }
+        });
+        let _guard = database::test::setup_with(|config| {
+            config.soundcloud_base_url = url;
+            config.soundcloud_client_id = Some("id".into());
+        });
+
+        let item = |url: &str| server::Item {
+            kind: server::ItemKind::SoundCloud(url.to_string()),
+            ts: 1,
+            version: 1,
+            requested_by: String::new(),
+        };
+        // permalinks are stored lowercased
+        SoundCloud
+            .insert(
+                &item("https://soundcloud.com/Someone/A-Song"),
+                |_, _| Ok(()),
+            )
+            .unwrap();
+        let track = SoundCloud.current().unwrap().unwrap();
+        assert_eq!(track.permalink, "someone/a-song");
+        assert_eq!((track.title.as_str(), track.duration), ("a song", 60));
+        assert_eq!(
+            param(&mock.requests()[0], "client_id").as_deref(),
+            Some("id")
+        );
+
+        let missing =
+            SoundCloud.insert(&item("https://soundcloud.com/someone/gone"), |_, _| Ok(()));
+        assert!(matches!(missing.err(), Some(Error::VideoUnavailable(..))));
+        assert_eq!(SoundCloud.count().unwrap(), 1);
+    }
+}
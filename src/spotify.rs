@@ -0,0 +1,680 @@
+use std::borrow::Cow;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use http_req::request::Method;
+use log::*;
+use once_cell::sync::Lazy;
+use once_cell::sync_lazy;
+use rand::seq::SliceRandom;
+use regex::Regex;
+use rusqlite::OptionalExtension;
+use serde::{Deserialize, Serialize};
+
+use crate::blacklist;
+use crate::config;
+use crate::database;
+use crate::error::{Error, Result};
+use crate::server;
+use crate::title;
+use crate::youtube::{connect, timed_out};
+use crate::FromRow;
+
+static PATTERN: Lazy<Regex> = sync_lazy! {
+    Regex::new(
+        r#"^(?:spotify:track:|(?:https?://)?open\.spotify\.com/(?:intl-[a-z]{2}(?:-[A-Za-z]{2})?/)?(?:embed/)?track/)(?P<id>[A-Za-z0-9]{22})"#,
+    ).expect("valid regex")
+};
+
+// access token and when it stops being valid
+static TOKEN: Lazy<Mutex<Option<(String, Instant)>>> = sync_lazy! {
+    Mutex::new(None)
+};
+
+#[derive(Serialize)]
+pub struct Track {
+    pub id: i64,
+    pub tid: String,
+    pub timestamp: i64,
+    pub duration: i64,
+    pub title: String,
+    pub artist: String,
+    pub requested_by: String,
+    pub position: i64,
+    // unix time, null until the song has played
+    pub played_at: Option<i64>,
+}
+
+impl FromRow for Track {
+    fn from_row(row: &rusqlite::Row<'_, '_>) -> Self {
+        Self {
+            id: row.get(0),
+            tid: row.get(1),
+            timestamp: row.get(2),
+            duration: row.get(3),
+            title: row.get(4),
+            artist: row.get(5),
+            requested_by: crate::requested_by(row),
+            position: row.get("position"),
+            played_at: row.get("played_at"),
+        }
+    }
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+
+    fn position(&self) -> i64 {
+        self.position
+    }
+
+    fn played_at(&self) -> Option<i64> {
+        self.played_at
+    }
+}
+
+impl Track {
+    pub fn url(&self) -> String {
+        format!("https://open.spotify.com/track/{}", self.tid)
+    }
+}
+
+#[derive(Default)]
+pub struct Spotify;
+
+impl crate::Storage<Track> for Spotify {
+    fn store(&self, tx: &rusqlite::Transaction<'_>, track: &Track) -> Result<Track> {
+        tx.execute_named(
+            include_str!("../sql/spotify/add_track.sql"),
+            &[
+                (":tid", &track.tid),
+                (":ts", &track.timestamp),
+                (":duration", &track.duration),
+                (":title", &track.title),
+                (":artist", &track.artist),
+                (":requested_by", &track.requested_by),
+            ],
+        )?;
+        tx.query_row_named(
+            include_str!("../sql/spotify/get.sql"),
+            &[(":id", &tx.last_insert_rowid())],
+            Track::from_row,
+        )
+        .map_err(Error::Sql)
+    }
+
+    fn validate(&self, item: &server::Item) -> Result<Track> {
+        let url = match &item.kind {
+            server::ItemKind::Spotify(url) => url,
+            _ => return Err(Error::WrongItemKind("spotify")),
+        };
+
+        let id = PATTERN
+            .captures(url)
+            .and_then(|s| s.name("id"))
+            .map(|s| s.as_str())
+            .ok_or_else(|| Error::InvalidSpotifyUrl(url.to_string()))?;
+
+        // before anything else so known-bad ids don't cost an api call
+        blacklist::check(blacklist::EntryKind::Video, id)?;
+
+        // a window of 0 means a track can only ever be requested once
+        let since = match config::get().dedupe_window_secs {
+            0 => i64::MIN,
+            window => item.ts - window,
+        };
+        // the connection goes back to the pool before the lookup, which can take a while
+        let exists: bool = database::get_connection().query_row_named(
+            include_str!("../sql/spotify/exists.sql"),
+            &[(":tid", &id), (":since", &since)],
+            |row| row.get(0),
+        )?;
+        if exists {
+            return Err(Error::DuplicateSong {
+                vid: id.to_string(),
+            });
+        }
+
+        let mut info = SpotifyItem::fetch(id)?;
+        info.title = title::sanitize(&info.title, config::get().max_title_len);
+        title::check_words(&[&info.title, &info.artist])?;
+
+        let max = config::get().max_duration_secs;
+        if max > 0 && info.duration > max {
+            return Err(Error::DurationTooLong {
+                got: info.duration,
+                max,
+            });
+        }
+
+        if config::get().dedupe_titles {
+            let title = title::normalize(&info.title);
+            if self
+                .pending()?
+                .iter()
+                .any(|song| title::normalize(&song.title) == title)
+            {
+                return Err(Error::DuplicateTitle(info.title));
+            }
+        }
+
+        // not stored yet, so there's no row id
+        Ok(Track {
+            id: 0,
+            tid: id.to_string(),
+            timestamp: item.ts,
+            duration: info.duration,
+            title: info.title,
+            artist: info.artist,
+            requested_by: item.requested_by.clone(),
+            position: item.ts,
+            played_at: None,
+        })
+    }
+
+    fn get(&self, id: i64) -> Result<Option<Track>> {
+        database::get_connection()
+            .query_row_named(
+                include_str!("../sql/spotify/get.sql"),
+                &[(":id", &id)],
+                Track::from_row,
+            )
+            .optional()
+            .map_err(Error::Sql)
+    }
+
+    fn current(&self) -> Result<Option<Track>> {
+        database::get_connection()
+            .query_row(
+                include_str!("../sql/spotify/get_current.sql"),
+                rusqlite::NO_PARAMS,
+                Track::from_row,
+            )
+            .optional()
+            .map_err(Error::Sql)
+    }
+
+    fn previous(&self) -> Result<Option<Track>> {
+        database::get_connection()
+            .query_row(
+                include_str!("../sql/spotify/get_previous.sql"),
+                rusqlite::NO_PARAMS,
+                Track::from_row,
+            )
+            .optional()
+            .map_err(Error::Sql)
+    }
+
+    fn history(&self, n: u32) -> Result<Vec<Track>> {
+        Ok(database::get_connection()
+            .prepare(include_str!("../sql/spotify/get_history.sql"))?
+            .query_map_named(&[(":limit", &n)], Track::from_row)
+            .map_err(Error::Sql)?
+            .filter_map(|s| s.ok())
+            .collect::<Vec<_>>())
+    }
+
+    fn shuffle<R: rand::Rng + ?Sized>(&self, rng: &mut R, skip: u32) -> Result<()> {
+        let mut conn = database::get_connection();
+        let tx = conn.transaction()?;
+        let pending = tx
+            .prepare(include_str!("../sql/spotify/get_pending_positions.sql"))?
+            .query_map_named(&[(":skip", &skip)], |row| {
+                (row.get::<_, i64>(0), row.get::<_, i64>(1))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut positions = pending.iter().map(|(_, pos)| *pos).collect::<Vec<_>>();
+        positions.shuffle(rng);
+        for ((id, _), position) in pending.iter().zip(positions) {
+            tx.execute_named(
+                include_str!("../sql/spotify/set_position.sql"),
+                &[(":id", id), (":position", &position)],
+            )?;
+        }
+        tx.commit().map_err(Error::Sql)
+    }
+
+    fn move_to(&self, id: i64, pos: u32) -> Result<()> {
+        let mut conn = database::get_connection();
+        let tx = conn.transaction()?;
+        let pending = tx
+            .prepare(include_str!("../sql/spotify/get_pending_positions.sql"))?
+            .query_map_named(&[(":skip", &0)], |row| {
+                (row.get::<_, i64>(0), row.get::<_, i64>(1))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        for (id, position) in crate::reorder(pending, id, pos)? {
+            tx.execute_named(
+                include_str!("../sql/spotify/set_position.sql"),
+                &[(":id", &id), (":position", &position)],
+            )?;
+        }
+        tx.commit().map_err(Error::Sql)
+    }
+
+    fn requeue(&self, id: i64, position: i64) -> Result<bool> {
+        database::get_connection()
+            .execute_named(
+                include_str!("../sql/spotify/requeue.sql"),
+                &[(":id", &id), (":position", &position)],
+            )
+            .map(|n| n > 0)
+            .map_err(Error::Sql)
+    }
+
+    fn advance(&self) -> Result<Option<Track>> {
+        database::get_connection()
+            .execute_named(
+                include_str!("../sql/spotify/advance.sql"),
+                &[(":now", &crate::unix_time())],
+            )
+            .map_err(Error::Sql)?;
+        self.current()
+    }
+
+    fn all(&self, limit: Option<u32>, offset: Option<u32>) -> Result<Vec<Track>> {
+        // a negative limit is unbounded in sqlite
+        let limit = limit.map(i64::from).unwrap_or(-1);
+        let offset = offset.map(i64::from).unwrap_or(0);
+        Ok(database::get_connection()
+            .prepare(include_str!("../sql/spotify/get_all.sql"))?
+            .query_map_named(&[(":limit", &limit), (":offset", &offset)], Track::from_row)
+            .map_err(Error::Sql)?
+            .filter_map(|s| s.ok())
+            .collect::<Vec<_>>())
+    }
+
+    fn pending(&self) -> Result<Vec<Track>> {
+        Ok(database::get_connection()
+            .prepare(include_str!("../sql/spotify/get_pending.sql"))?
+            .query_map(rusqlite::NO_PARAMS, Track::from_row)
+            .map_err(Error::Sql)?
+            .filter_map(|s| s.ok())
+            .collect::<Vec<_>>())
+    }
+
+    fn between(&self, start: i64, end: i64) -> Result<Vec<Track>> {
+        Ok(database::get_connection()
+            .prepare(include_str!("../sql/spotify/get_between.sql"))?
+            .query_map_named(&[(":start", &start), (":end", &end)], Track::from_row)
+            .map_err(Error::Sql)?
+            .filter_map(|s| s.ok())
+            .collect::<Vec<_>>())
+    }
+
+    fn count(&self) -> Result<i64> {
+        database::get_connection()
+            .query_row(
+                include_str!("../sql/spotify/count.sql"),
+                rusqlite::NO_PARAMS,
+                |row| row.get(0),
+            )
+            .map_err(Error::Sql)
+    }
+
+    fn clear_pending(&self) -> Result<u64> {
+        database::get_connection()
+            .execute(
+                include_str!("../sql/spotify/clear_pending.sql"),
+                rusqlite::NO_PARAMS,
+            )
+            .map_err(Error::Sql)
+            .map(|n| n as u64)
+    }
+
+    fn clear_history(&self) -> Result<u64> {
+        database::get_connection()
+            .execute(
+                include_str!("../sql/spotify/clear_history.sql"),
+                rusqlite::NO_PARAMS,
+            )
+            .map_err(Error::Sql)
+            .map(|n| n as u64)
+    }
+
+    fn prune(&self, before: i64) -> Result<u64> {
+        database::get_connection()
+            .execute_named(
+                include_str!("../sql/spotify/prune.sql"),
+                &[(":before", &before)],
+            )
+            .map_err(Error::Sql)
+            .map(|n| n as u64)
+    }
+
+    fn delete(&self, id: i64) -> Result<bool> {
+        database::get_connection()
+            .execute_named(include_str!("../sql/spotify/delete.sql"), &[(":id", &id)])
+            .map_err(Error::Sql)
+            .map(|n| n > 0)
+    }
+}
+
+pub struct SpotifyItem {
+    pub title: String,
+    pub artist: String,
+    pub duration: i64,
+}
+
+impl SpotifyItem {
+    pub fn fetch(id: &str) -> Result<Self> {
+        let url = format!(
+            "{}/tracks/{}",
+            config::get().spotify_base_url.trim_end_matches('/'),
+            id
+        );
+
+        let token = Self::token()?;
+        let auth = ("Authorization", format!("Bearer {}", token));
+        match send(Method::GET, &url, &[auth], &[]) {
+            Err(Error::HttpResponse(401, message)) => {
+                // the token was revoked early, the next request will get a new one
+                TOKEN.lock().unwrap().take();
+                Err(Error::HttpResponse(401, message))
+            }
+            res => res.and_then(|data| Self::serialize(&data)),
+        }
+    }
+
+    // client credentials tokens are only valid for an hour
+    fn token() -> Result<String> {
+        let mut token = TOKEN.lock().unwrap();
+        if let Some((token, expires)) = &*token {
+            if Instant::now() < *expires {
+                return Ok(token.clone());
+            }
+        }
+
+        #[derive(Deserialize)]
+        struct Response {
+            access_token: String,
+            expires_in: u64,
+        }
+
+        let config = config::get();
+        let (id, secret) = match (&config.spotify_client_id, &config.spotify_client_secret) {
+            (Some(id), Some(secret)) => (id, secret),
+            _ => return Err(Error::SpotifyNotConfigured),
+        };
+
+        let url = format!(
+            "{}/api/token",
+            config.spotify_accounts_url.trim_end_matches('/')
+        );
+        let body = b"grant_type=client_credentials";
+        let auth = base64::encode(format!("{}:{}", id, secret));
+        let data = send(
+            Method::POST,
+            &url,
+            &[
+                ("Authorization", format!("Basic {}", auth)),
+                ("Content-Type", "application/x-www-form-urlencoded".into()),
+            ],
+            body,
+        )?;
+
+        let resp = serde_json::from_slice::<Response>(&data).map_err(Error::Serialize)?;
+        debug!("got a spotify token valid for {}s", resp.expires_in);
+
+        // refresh a little early so a token doesn't expire mid-request
+        let expires = Instant::now() + Duration::from_secs(resp.expires_in.saturating_sub(60));
+        *token = Some((resp.access_token.clone(), expires));
+        Ok(resp.access_token)
+    }
+
+    fn api_error(code: u16, reason: &str, data: &[u8]) -> Error {
+        #[derive(Deserialize)]
+        struct Response {
+            error: Inner,
+        }
+        #[derive(Deserialize)]
+        struct Inner {
+            #[serde(default)]
+            message: String,
+        }
+
+        let message = serde_json::from_slice::<Response>(data)
+            .map(|resp| resp.error.message)
+            .unwrap_or_else(|_| reason.to_string());
+
+        match code {
+            400 | 404 => Error::VideoUnavailable(message),
+            _ => Error::HttpResponse(code, message),
+        }
+    }
+
+    fn serialize(data: &[u8]) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct Response<'a> {
+            // borrowed unless there's something escaped in it
+            #[serde(borrow)]
+            name: Cow<'a, str>,
+            duration_ms: i64,
+            #[serde(borrow, default)]
+            artists: Vec<Artist<'a>>,
+        }
+        #[derive(Deserialize)]
+        struct Artist<'a> {
+            #[serde(borrow)]
+            name: Cow<'a, str>,
+        }
+
+        let data = serde_json::from_slice::<Response>(data).map_err(Error::Serialize)?;
+        Ok(Self {
+            title: data.name.to_string(),
+            artist: data
+                .artists
+                .iter()
+                .map(|artist| &*artist.name)
+                .collect::<Vec<_>>()
+                .join(", "),
+            duration: data.duration_ms / 1000,
+        })
+    }
+}
+
+// http_req can only send a body and set timeouts through the lower level builder
+fn send(method: Method, url: &str, headers: &[(&str, String)], body: &[u8]) -> Result<Vec<u8>> {
+    use http_req::request::RequestBuilder;
+    use std::io::{Read, Write};
+
+    fn send<S: Read + Write>(builder: &RequestBuilder, mut stream: S) -> Result<Vec<u8>> {
+        let mut data = vec![];
+        let resp = builder
+            .send(&mut stream, &mut data)
+            .map_err(|err| match err {
+                http_req::error::Error::IO(err) => timed_out(err),
+                err => Error::HttpClient(err),
+            })?;
+        if !resp.status_code().is_success() {
+            return Err(SpotifyItem::api_error(
+                resp.status_code().into(),
+                resp.reason(),
+                &data,
+            ));
+        }
+        Ok(data)
+    }
+
+    let uri = url.parse::<http_req::uri::Uri>()?;
+    let host = uri.host().unwrap_or_default();
+
+    let mut builder = RequestBuilder::new(&uri);
+    builder
+        .method(method)
+        .header("Connection", "Close")
+        .header("Content-Length", &body.len())
+        .body(body);
+    for (key, val) in headers {
+        builder.header(*key, val);
+    }
+
+    let timeout = match config::get().spotify_timeout_secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    let stream = connect(host, uri.corr_port(), timeout).map_err(timed_out)?;
+    if uri.scheme() == "https" {
+        send(
+            &builder,
+            http_req::tls::Config::default().connect(host, stream)?,
+        )
+    } else {
+        send(&builder, stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::MutexGuard;
+
+    use super::*;
+    use crate::database::test;
+    use crate::youtube::test::{mock, Mock};
+    use crate::Storage;
+
+    const TRACK: &str = "https://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC";
+
+    // spotify requests go to `handler`, tokens are always handed out
+    fn setup(
+        f: impl FnOnce(&mut config::Config),
+        handler: impl Fn(&str) -> (u16, String) + Send + Sync + 'static,
+    ) -> (MutexGuard<'static, ()>, Mock) {
+        let (url, mock) = mock(move |path| match path {
+            "/api/token" => (200, r#"{"access_token":"token","expires_in":3600}"#.into()),
+            path => handler(path),
+        });
+        let guard = test::setup_with(|config| {
+            config.spotify_client_id = Some("id".into());
+            config.spotify_client_secret = Some("secret".into());
+            config.spotify_base_url = url.clone();
+            config.spotify_accounts_url = url;
+            f(config)
+        });
+        TOKEN.lock().unwrap().take();
+        (guard, mock)
+    }
+
+    fn item(url: &str) -> server::Item {
+        server::Item {
+            kind: server::ItemKind::Spotify(url.to_string()),
+            ts: 1,
+            version: 1,
+            requested_by: String::new(),
+        }
+    }
+
+    #[test]
+    fn url_forms() {
+        let id = |url: &str| {
+            PATTERN
+                .captures(url)
+                .and_then(|c| c.name("id"))
+                .map(|id| id.as_str().to_string())
+        };
+        for url in &[
+            TRACK,
+            "http://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC?si=abcdef",
+            "open.spotify.com/intl-de/track/4uLU6hMCjMI75M1A2tKUQC",
+            "https://open.spotify.com/intl-pt-BR/track/4uLU6hMCjMI75M1A2tKUQC",
+            "https://open.spotify.com/embed/track/4uLU6hMCjMI75M1A2tKUQC",
+            "spotify:track:4uLU6hMCjMI75M1A2tKUQC",
+        ] {
+            assert_eq!(
+                id(url).as_deref(),
+                Some("4uLU6hMCjMI75M1A2tKUQC"),
+                "{}",
+                url
+            );
+        }
+        for url in &[
+            "https://open.spotify.com/album/4uLU6hMCjMI75M1A2tKUQC",
+            "https://open.spotify.com/track/short",
+            "https://example.com/track/4uLU6hMCjMI75M1A2tKUQC",
+            "spotify:album:4uLU6hMCjMI75M1A2tKUQC",
+        ] {
+            assert_eq!(id(url), None, "{}", url);
+        }
+    }
+
+    #[test]
+    fn lookup() {
+        let (_guard, mock) = setup(
+            |_| {},
+            |_| {
+                let track = serde_json::json!({
+                    "name": "a \"song\"",
+                    "duration_ms": 201_999,
+                    "artists": [{"name": "one\\"}, {"name": "two"}],
+                });
+                (200, track.to_string())
+            },
+        );
+        Spotify.insert(&item(TRACK), |_, _| Ok(())).unwrap();
+        let track = Spotify.current().unwrap().unwrap();
+        assert_eq!(track.tid, "4uLU6hMCjMI75M1A2tKUQC");
+        assert_eq!(track.title, "a \"song\"");
+        assert_eq!(track.artist, "one\\, two");
+        assert_eq!(track.duration, 201);
+        assert_eq!(
+            mock.requests(),
+            ["/api/token", "/tracks/4uLU6hMCjMI75M1A2tKUQC"]
+        );
+
+        // duplicates are caught before a lookup, and the token is reused
+        let res = Spotify.insert(&item(TRACK), |_, _| Ok(()));
+        assert!(matches!(res.err(), Some(Error::DuplicateSong { .. })));
+        Spotify
+            .insert(&item("spotify:track:0000000000000000000000"), |_, _| Ok(()))
+            .unwrap();
+        assert_eq!(mock.requests().len(), 3);
+        assert_eq!(Spotify.count().unwrap(), 2);
+    }
+
+    #[test]
+    fn unknown_track() {
+        let (_guard, _mock) = setup(
+            |_| {},
+            |_| {
+                (
+                    404,
+                    r#"{"error":{"status":404,"message":"non existing id"}}"#.into(),
+                )
+            },
+        );
+        let res = Spotify.insert(&item(TRACK), |_, _| Ok(()));
+        assert!(
+            matches!(res.err(), Some(Error::VideoUnavailable(ref m)) if m == "non existing id")
+        );
+        assert!(matches!(
+            Spotify
+                .insert(
+                    &item("https://open.spotify.com/album/4uLU6hMCjMI75M1A2tKUQC"),
+                    |_, _| Ok(())
+                )
+                .err(),
+            Some(Error::InvalidSpotifyUrl(..))
+        ));
+        assert_eq!(Spotify.count().unwrap(), 0);
+    }
+
+    #[test]
+    fn lookup_times_out() {
+        let (_guard, mock) = setup(
+            |config| config.spotify_timeout_secs = 1,
+            |_| {
+                std::thread::sleep(Duration::from_secs(3));
+                (200, "{}".into())
+            },
+        );
+        let start = Instant::now();
+        assert!(matches!(
+            Spotify.insert(&item(TRACK), |_, _| Ok(())).err(),
+            Some(Error::Timeout)
+        ));
+        assert!(start.elapsed() < Duration::from_secs(3));
+        assert_eq!(mock.requests().len(), 2);
+    }
+}
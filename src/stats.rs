@@ -0,0 +1,171 @@
+use serde::Serialize;
+
+use crate::database;
+use crate::error::{Error, Result};
+
+#[derive(Serialize)]
+pub struct LeaderboardEntry {
+    pub name: String,
+    pub count: i64,
+    // local files whose length couldn't be read don't add to this
+    pub total_duration: i64,
+}
+
+// anonymous requests aren't counted
+pub fn leaderboard(start: i64, end: i64, limit: u32) -> Result<Vec<LeaderboardEntry>> {
+    Ok(database::get_connection()
+        .prepare(include_str!("../sql/stats/leaderboard.sql"))?
+        .query_map_named(
+            &[(":start", &start), (":end", &end), (":limit", &limit)],
+            |row| LeaderboardEntry {
+                name: row.get(0),
+                count: row.get(1),
+                total_duration: row.get(2),
+            },
+        )
+        .map_err(Error::Sql)?
+        .filter_map(|s| s.ok())
+        .collect::<Vec<_>>())
+}
+
+// unplayed songs of every kind
+pub fn pending_count(conn: &rusqlite::Connection) -> Result<i64> {
+    conn.query_row(
+        include_str!("../sql/stats/pending_count.sql"),
+        rusqlite::NO_PARAMS,
+        |row| row.get(0),
+    )
+    .map_err(Error::Sql)
+}
+
+#[derive(Serialize)]
+pub struct QueueDuration {
+    // local files whose length couldn't be read don't add to this
+    pub seconds: i64,
+}
+
+// 0 for an empty queue
+pub fn pending_duration() -> Result<QueueDuration> {
+    database::get_connection()
+        .query_row(
+            include_str!("../sql/stats/pending_duration.sql"),
+            rusqlite::NO_PARAMS,
+            |row| QueueDuration {
+                seconds: row.get(0),
+            },
+        )
+        .map_err(Error::Sql)
+}
+
+// 0 means unlimited. these take the connection so they can run in the transaction
+// that stores the song, where nothing can be queued between the count and the insert
+pub fn check_queue_len(conn: &rusqlite::Connection, max: i64) -> Result<()> {
+    if max > 0 && pending_count(conn)? >= max {
+        return Err(Error::QueueFull { max });
+    }
+    Ok(())
+}
+
+// anonymous requests have no name to count by, so they're only held to `max_queue_len`
+pub fn check_user_queue_len(
+    conn: &rusqlite::Connection,
+    requested_by: &str,
+    max: i64,
+) -> Result<()> {
+    if max == 0 || requested_by.is_empty() {
+        return Ok(());
+    }
+
+    let pending: i64 = conn.query_row_named(
+        include_str!("../sql/stats/pending_count_for.sql"),
+        &[(":requested_by", &requested_by)],
+        |row| row.get(0),
+    )?;
+    if pending >= max {
+        return Err(Error::UserQueueFull { max });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test::setup;
+
+    fn request(name: &str, ts: i64, duration: i64) {
+        database::get_connection()
+            .execute_named(
+                "INSERT INTO spotify_tracks (tid, ts, duration, title, artist, requested_by)
+                    VALUES ('track' || :ts, :ts, :duration, 'a', 'b', :name)",
+                &[(":name", &name), (":ts", &ts), (":duration", &duration)],
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn local_songs_count() {
+        let _guard = setup();
+        request("someone", 20, 60);
+        database::get_connection()
+            .execute_batch(
+                "INSERT INTO local_songs (ts, title, artist, album, requested_by)
+                    VALUES (10, 'a', '', '', 'someone');",
+            )
+            .unwrap();
+
+        let leaderboard = leaderboard(0, 100, 10).unwrap();
+        assert_eq!(leaderboard.len(), 1);
+        assert_eq!(leaderboard[0].count, 2);
+        assert_eq!(leaderboard[0].total_duration, 60);
+    }
+
+    #[test]
+    fn leaderboard_order_and_window() {
+        let _guard = setup();
+        request("b", 10, 60);
+        request("b", 20, 50);
+        request("a", 30, 100);
+        request("a", 40, 20);
+        request("c", 50, 500);
+        request("", 60, 60);
+
+        let names = |start, end, limit| {
+            leaderboard(start, end, limit)
+                .unwrap()
+                .into_iter()
+                .map(|entry| (entry.name, entry.count, entry.total_duration))
+                .collect::<Vec<_>>()
+        };
+        // ties on count go to the longer total, anonymous requests aren't counted
+        assert_eq!(
+            names(i64::MIN, i64::MAX, 10),
+            [
+                ("a".to_string(), 2, 120),
+                ("b".to_string(), 2, 110),
+                ("c".to_string(), 1, 500)
+            ]
+        );
+        assert_eq!(names(i64::MIN, i64::MAX, 1).len(), 1);
+        // inclusive
+        assert_eq!(names(20, 50, 10)[0], ("a".to_string(), 2, 120));
+        assert_eq!(names(50, 50, 10), [("c".to_string(), 1, 500)]);
+        assert!(names(100, 200, 10).is_empty());
+    }
+
+    #[test]
+    fn queue_duration() {
+        let _guard = setup();
+        assert_eq!(pending_duration().unwrap().seconds, 0);
+
+        request("a", 10, 60);
+        request("b", 20, 200);
+        request("a", 30, 45);
+        assert_eq!(pending_duration().unwrap().seconds, 305);
+
+        // played songs aren't queued anymore
+        database::get_connection()
+            .execute_batch("UPDATE spotify_tracks SET played = 1 WHERE ts = 20")
+            .unwrap();
+        assert_eq!(pending_duration().unwrap().seconds, 105);
+    }
+}
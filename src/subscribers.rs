@@ -0,0 +1,179 @@
+use std::io::Write;
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+use log::*;
+
+use crate::websocket;
+
+// how many pushes a client can fall behind before it's dropped
+const BACKLOG: usize = 16;
+// idle connections get dropped by proxies, and dead clients are only noticed on a write
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Protocol {
+    WebSocket,
+    EventStream,
+}
+
+// every client gets a thread, so a slow or stuck one can't hold up the server
+pub struct Subscribers {
+    clients: Vec<SyncSender<Vec<u8>>>,
+    keep_alive: Duration,
+}
+
+impl Default for Subscribers {
+    fn default() -> Self {
+        Self {
+            clients: vec![],
+            keep_alive: KEEP_ALIVE,
+        }
+    }
+}
+
+impl Subscribers {
+    // the client is sent `data` straight away so it doesn't have to wait for a change
+    pub fn add(&mut self, protocol: Protocol, stream: websocket::Stream, data: &[u8]) {
+        let (tx, rx) = mpsc::sync_channel(BACKLOG);
+        tx.try_send(data.to_vec()).expect("empty channel");
+        let keep_alive = self.keep_alive;
+        thread::spawn(move || Client { protocol, stream }.run(&rx, keep_alive));
+        self.clients.push(tx)
+    }
+
+    // clients that have gone away, or that fell too far behind, are dropped
+    pub fn broadcast(&mut self, data: &[u8]) {
+        let before = self.clients.len();
+        self.clients
+            .retain(|client| match client.try_send(data.to_vec()) {
+                Ok(..) => true,
+                Err(TrySendError::Full(..)) => {
+                    debug!("subscriber fell behind");
+                    false
+                }
+                Err(TrySendError::Disconnected(..)) => false,
+            });
+        if self.clients.len() < before {
+            debug!("pruned {} subscribers", before - self.clients.len())
+        }
+    }
+}
+
+struct Client {
+    protocol: Protocol,
+    stream: websocket::Stream,
+}
+
+impl Client {
+    fn run(mut self, rx: &mpsc::Receiver<Vec<u8>>, keep_alive: Duration) {
+        loop {
+            let res = match rx.recv_timeout(keep_alive) {
+                Ok(data) => self.write(&data).map(|_| true),
+                Err(RecvTimeoutError::Timeout) => self.ping(),
+                // dropped by `Subscribers`, either it's gone or this client fell behind
+                Err(RecvTimeoutError::Disconnected) => {
+                    if self.protocol == Protocol::WebSocket {
+                        let _ = websocket::close(&mut self.stream);
+                    }
+                    return;
+                }
+            };
+            match res {
+                Ok(true) => {}
+                Ok(false) => return,
+                Err(err) => {
+                    debug!("subscriber went away: {}", err);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        let data = match self.protocol {
+            Protocol::WebSocket => websocket::text_frame(data),
+            Protocol::EventStream => {
+                let mut event = b"event: current\ndata: ".to_vec();
+                event.extend_from_slice(data);
+                event.extend_from_slice(b"\n\n");
+                event
+            }
+        };
+        self.write_raw(&data)
+    }
+
+    // false once the client wants to close
+    fn ping(&mut self) -> std::io::Result<bool> {
+        match self.protocol {
+            Protocol::WebSocket => websocket::ping(&mut self.stream),
+            Protocol::EventStream => self.write_raw(b": keep-alive\n\n").map(|_| true),
+        }
+    }
+
+    fn write_raw(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.stream
+            .write_all(data)
+            .and_then(|_| self.stream.flush())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Instant;
+
+    use super::*;
+
+    // the server's end of a connection made to the returned client
+    fn connect(subscribers: &mut Subscribers, protocol: Protocol) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (server, _) = listener.accept().unwrap();
+        subscribers.add(protocol, Box::new(server), b"[]");
+        client
+    }
+
+    #[test]
+    fn keep_alive() {
+        let mut subscribers = Subscribers {
+            keep_alive: Duration::from_millis(10),
+            ..Subscribers::default()
+        };
+        let mut events = connect(&mut subscribers, Protocol::EventStream);
+        let expected = b"event: current\ndata: []\n\n: keep-alive\n\n";
+        let mut data = vec![0; expected.len()];
+        events.read_exact(&mut data).unwrap();
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn drops_clients_that_fall_behind() {
+        let mut subscribers = Subscribers::default();
+        // never reads, so its socket buffers fill up and the thread writing to it blocks
+        let _stuck = connect(&mut subscribers, Protocol::EventStream);
+        let mut live = connect(&mut subscribers, Protocol::EventStream);
+
+        let data = vec![b'a'; 64 * 1024];
+        let event = data.len() + b"event: current\ndata: \n\n".len();
+        let mut buf = vec![0; event];
+        live.read_exact(&mut buf[..b"event: current\ndata: []\n\n".len()])
+            .unwrap();
+
+        let start = Instant::now();
+        for _ in 0..BACKLOG * 64 {
+            subscribers.broadcast(&data);
+            live.read_exact(&mut buf).unwrap();
+            if subscribers.clients.len() == 1 {
+                break;
+            }
+        }
+        assert_eq!(subscribers.clients.len(), 1);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,159 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+use lofty::prelude::*;
+use lofty::probe::Probe;
+use log::*;
+
+// whatever the file says about itself, anything it doesn't say is `None`
+#[derive(Debug, Default)]
+pub struct Tags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    // whole seconds
+    pub duration: Option<i64>,
+}
+
+// the format comes from the file's contents, not its name. only io errors are errors, a file
+// lofty can't make sense of is the same as one without tags
+pub fn read(path: &Path) -> io::Result<Tags> {
+    let file = Probe::new(BufReader::new(File::open(path)?)).guess_file_type()?;
+    let file = match file.read() {
+        Ok(file) => file,
+        Err(err) => {
+            debug!("no tags in {}: {}", path.display(), err);
+            return Ok(Tags::default());
+        }
+    };
+
+    let text = |field: Option<std::borrow::Cow<'_, str>>| {
+        field
+            .map(|field| field.trim().to_string())
+            .filter(|field| !field.is_empty())
+    };
+    let tag = file.primary_tag().or_else(|| file.first_tag());
+    let duration = file.properties().duration().as_secs() as i64;
+    Ok(Tags {
+        title: tag.and_then(|tag| text(tag.title())),
+        artist: tag.and_then(|tag| text(tag.artist())),
+        album: tag.and_then(|tag| text(tag.album())),
+        duration: Some(duration).filter(|&secs| secs > 0),
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // a directory in the temp dir, removed along with everything in it when dropped
+    pub struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub fn new() -> Self {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let name = format!(
+                "dono-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            );
+            let path = std::env::temp_dir().join(name);
+            std::fs::create_dir_all(&path).unwrap();
+            TempDir(path.canonicalize().unwrap())
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.0
+        }
+
+        // writes `data` to `name`, creating the directories on the way
+        pub fn file(&self, name: &str, data: &[u8]) -> PathBuf {
+            let path = self.0.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, data).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // a flac file that's `secs` long at 44.1khz, with `KEY=value` comments and no audio
+    pub fn flac(comments: &[&str], secs: u64) -> Vec<u8> {
+        const RATE: u64 = 44100;
+        let samples = secs * RATE;
+
+        let mut data = b"fLaC".to_vec();
+        data.extend_from_slice(&[0, 0, 0, 34]);
+        let mut info = [0u8; 34];
+        info[10] = (RATE >> 12) as u8;
+        info[11] = (RATE >> 4) as u8;
+        // stereo, 16 bits per sample
+        info[12] = ((RATE & 0x0f) << 4) as u8 | 0x02;
+        info[13] = 0xf0 | ((samples >> 32) & 0x0f) as u8;
+        info[14..18].copy_from_slice(&(samples as u32).to_be_bytes());
+        data.extend_from_slice(&info);
+
+        let mut block = vec![];
+        block.extend_from_slice(&4u32.to_le_bytes());
+        block.extend_from_slice(b"test");
+        block.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            block.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            block.extend_from_slice(comment.as_bytes());
+        }
+        data.push(0x84);
+        data.extend_from_slice(&(block.len() as u32).to_be_bytes()[1..]);
+        data.extend(block);
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test::*;
+    use super::*;
+
+    #[test]
+    fn flac_tags() {
+        let dir = TempDir::new();
+        let data = flac(
+            &[
+                "title=a song",
+                "ARTIST=an artist",
+                "Album=an album",
+                "GENRE=x",
+            ],
+            185,
+        );
+        let tags = read(&dir.file("song.flac", &data)).unwrap();
+        assert_eq!(tags.title.as_deref(), Some("a song"));
+        assert_eq!(tags.artist.as_deref(), Some("an artist"));
+        assert_eq!(tags.album.as_deref(), Some("an album"));
+        assert_eq!(tags.duration, Some(185));
+
+        // the length is there even without comments, and the name doesn't matter
+        let tags = read(&dir.file("bare.mp3", &flac(&["TITLE= "], 30))).unwrap();
+        assert!(tags.title.is_none());
+        assert_eq!(tags.duration, Some(30));
+    }
+
+    #[test]
+    fn untagged() {
+        let dir = TempDir::new();
+        for (name, data) in &[
+            ("notes.txt", &b"not audio at all"[..]),
+            ("empty.mp3", &b""[..]),
+            ("short.flac", &b"fLaC\x00\x00"[..]),
+        ] {
+            let tags = read(&dir.file(name, data)).unwrap();
+            assert!(tags.title.is_none() && tags.duration.is_none(), "{}", name);
+        }
+        assert!(read(&dir.path().join("missing.mp3")).is_err());
+    }
+}
@@ -0,0 +1,192 @@
+use once_cell::sync::Lazy;
+use once_cell::sync_lazy;
+use regex::Regex;
+
+use crate::config;
+use crate::error::{Error, Result};
+
+// the usual upload decorations, matched after lowercasing
+static NOISE: Lazy<Regex> = sync_lazy! {
+    Regex::new(
+        r#"\b(?:official\s+(?:music\s+|lyric\s+)?(?:video|audio|visualizer)|(?:music|lyric)\s+video|lyrics?|hd|hq|4k)\b"#,
+    ).expect("valid regex")
+};
+
+// control characters are dropped and runs of whitespace, newlines included, become a single
+// space. above 0, `max_len` is the most characters that are kept
+pub fn sanitize(title: &str, max_len: usize) -> String {
+    let title = title
+        .chars()
+        .filter(|c| c.is_whitespace() || !c.is_control())
+        .collect::<String>();
+    let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+    match max_len {
+        0 => title,
+        max => title
+            .chars()
+            .take(max)
+            .collect::<String>()
+            .trim_end()
+            .to_string(),
+    }
+}
+
+// refuses the song when any of `fields`, the title and channel or artist, has a blocked word
+pub fn check_words(fields: &[&str]) -> Result<()> {
+    let config = config::get();
+    if config.blocked_words.is_empty() {
+        return Ok(());
+    }
+    for field in fields {
+        if let Some(word) = blocked_word(
+            field,
+            &config.blocked_words,
+            &config.allowed_words,
+            config.filter_whole_words,
+        ) {
+            return Err(Error::FilteredContent(word.to_string()));
+        }
+    }
+    Ok(())
+}
+
+// the first of `blocked` found in `text`, ignoring case. both are split into words on anything
+// that isn't alphanumeric, so a blocked phrase matches across punctuation. with `whole_words`
+// the words have to be equal, otherwise a blocked word matches inside a longer one unless
+// that one is in `allowed`
+pub fn blocked_word<'a>(
+    text: &str,
+    blocked: &'a [String],
+    allowed: &[String],
+    whole_words: bool,
+) -> Option<&'a str> {
+    fn words(s: &str) -> Vec<String> {
+        s.to_lowercase()
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    let text = words(text);
+    let allowed = allowed
+        .iter()
+        .map(|word| words(word).join(" "))
+        .collect::<Vec<_>>();
+
+    blocked.iter().map(|word| word.trim()).find(|word| {
+        let needle = words(word);
+        if needle.is_empty() {
+            return false;
+        }
+        text.windows(needle.len()).any(|window| {
+            if whole_words {
+                return window == needle.as_slice();
+            }
+            let window = window.join(" ");
+            window.contains(&needle.join(" ")) && !allowed.contains(&window)
+        })
+    })
+}
+
+// "Artist - Song (Official Video) [HD]" and "artist   song lyrics" both become "artist song".
+// punctuation is dropped so brackets and dashes left behind by the noise don't matter
+pub fn normalize(title: &str) -> String {
+    let title = title.to_lowercase();
+    NOISE
+        .replace_all(&title, " ")
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_titles() {
+        for (title, want) in &[
+            ("Artist - Song (Official Video) [HD]", "artist song"),
+            ("artist   song lyrics", "artist song"),
+            ("ARTIST - SONG (Official Music Video)", "artist song"),
+            ("Artist - Song [Official Lyric Video] 4K", "artist song"),
+            ("Artist - Song (Official Audio)", "artist song"),
+            ("Artist\t-\nSong (Lyric Video) HQ", "artist song"),
+            // only whole words are noise
+            ("Hdmi Lyricist - Video Games", "hdmi lyricist video games"),
+            ("Official", "official"),
+            ("", ""),
+        ] {
+            assert_eq!(normalize(title), *want, "{}", title);
+        }
+    }
+
+    #[test]
+    fn sanitize_titles() {
+        for (title, max, want) in &[
+            ("a song", 0, "a song"),
+            ("  a\n\nsong\r\n(live)\t", 0, "a song (live)"),
+            ("a\u{0}so\u{1b}ng\u{7f}", 0, "asong"),
+            ("a very long title", 6, "a very"),
+            // what's cut off doesn't leave a trailing space
+            ("a very long title", 7, "a very"),
+            ("ünïcödé títle", 6, "ünïcöd"),
+            ("short", 100, "short"),
+            ("\n\t", 0, ""),
+        ] {
+            assert_eq!(sanitize(title, *max), *want, "{:?}", title);
+        }
+    }
+
+    #[test]
+    fn blocked_words() {
+        let list = |words: &[&str]| words.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+        let blocked = list(&["bad", "Straße", "two words"]);
+        let allowed = list(&["badminton"]);
+        for (text, whole_words, want) in &[
+            ("a good song", false, None),
+            ("a BAD song", false, Some("bad")),
+            ("a bad-song", true, Some("bad")),
+            // inside a longer word, unless that word is allowed
+            ("forbade", false, Some("bad")),
+            ("forbade", true, None),
+            ("badminton highlights", false, None),
+            ("BADMINTON highlights", false, None),
+            ("badly", false, Some("bad")),
+            // unicode case folding
+            ("STRASSE", false, None),
+            ("STRAẞE", false, Some("Straße")),
+            ("Ünïcode straße", true, Some("Straße")),
+            // phrases match across punctuation but need every word
+            ("two... words", true, Some("two words")),
+            ("two", false, None),
+            ("words two", false, None),
+        ] {
+            assert_eq!(
+                blocked_word(text, &blocked, &allowed, *whole_words),
+                *want,
+                "{} {}",
+                text,
+                whole_words
+            );
+        }
+        assert_eq!(
+            blocked_word("anything", &list(&["", " "]), &[], false),
+            None
+        );
+    }
+
+    #[test]
+    fn check_fields() {
+        let _guard = crate::database::test::setup_with(|config| {
+            config.blocked_words = vec!["nope".into()];
+        });
+        assert!(check_words(&["a title", "a channel"]).is_ok());
+        assert!(matches!(
+            check_words(&["a title", "the nope channel"]),
+            Err(Error::FilteredContent(word)) if word == "nope"
+        ));
+    }
+}
@@ -0,0 +1,221 @@
+use std::io::{self, Read, Write};
+
+// from rfc 6455, appended to the client's key before hashing
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// clients only send control frames, anything bigger than this is a broken client
+const MAX_FRAME: usize = 64 * 1024;
+
+const TEXT: u8 = 0x1;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+// close status codes
+const NORMAL: u16 = 1000;
+const GOING_AWAY: u16 = 1001;
+
+pub type Stream = Box<dyn tiny_http::ReadWrite + Send>;
+
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(key.trim().as_bytes());
+    sha1.update(GUID.as_bytes());
+    base64::encode(sha1.digest().bytes())
+}
+
+pub fn text_frame(payload: &[u8]) -> Vec<u8> {
+    frame(TEXT, payload)
+}
+
+// server to client frames are never masked
+fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode); // fin + opcode
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= 0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes())
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes())
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+// the opcode and the unmasked payload of the next frame from the client
+fn read_frame(stream: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut head = [0; 2];
+    stream.read_exact(&mut head)?;
+    let len = match head[1] & 0x7F {
+        126 => {
+            let mut len = [0; 2];
+            stream.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as u64
+        }
+        127 => {
+            let mut len = [0; 8];
+            stream.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => len as u64,
+    };
+    if len > MAX_FRAME as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("websocket frame of {} bytes", len),
+        ));
+    }
+
+    let mut mask = [0; 4];
+    if head[1] & 0x80 != 0 {
+        stream.read_exact(&mut mask)?;
+    }
+    let mut payload = vec![0; len as usize];
+    stream.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok((head[0] & 0x0F, payload))
+}
+
+// tiny_http doesn't split the stream into halves that can be used from different threads,
+// so the client's frames are read here, up to its reply. false if it wants to close
+pub fn ping(stream: &mut Stream) -> io::Result<bool> {
+    write(stream, &frame(PING, &[]))?;
+    loop {
+        match read_frame(stream)? {
+            (PONG, _) => return Ok(true),
+            (PING, payload) => write(stream, &frame(PONG, &payload))?,
+            (CLOSE, payload) => {
+                let code = match payload.get(..2) {
+                    Some(&[hi, lo]) => u16::from_be_bytes([hi, lo]),
+                    _ => NORMAL,
+                };
+                write(stream, &frame(CLOSE, &code.to_be_bytes()))?;
+                return Ok(false);
+            }
+            // nothing is expected from the client, so anything else is ignored
+            _ => {}
+        }
+    }
+}
+
+// waits for the client to answer, so the connection isn't torn down with its reply unread
+pub fn close(stream: &mut Stream) -> io::Result<()> {
+    write(stream, &frame(CLOSE, &GOING_AWAY.to_be_bytes()))?;
+    while read_frame(stream)?.0 != CLOSE {}
+    Ok(())
+}
+
+fn write(stream: &mut Stream, data: &[u8]) -> io::Result<()> {
+    stream.write_all(data).and_then(|_| stream.flush())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    // the server's end of a connection, and the client's
+    fn connect() -> (Stream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (Box::new(server), client)
+    }
+
+    // clients have to mask their frames
+    fn client_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn accept() {
+        // the example from the rfc
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        assert_eq!(
+            accept_key(" dGhlIHNhbXBsZSBub25jZQ== "),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn frame_lengths() {
+        assert_eq!(text_frame(b"hi"), [0x81, 2, b'h', b'i']);
+        let medium = text_frame(&[b'a'; 300]);
+        assert_eq!(medium[..4], [0x81, 126, 0x01, 0x2C]);
+        assert_eq!(medium.len(), 4 + 300);
+        let large = text_frame(&vec![b'a'; 70_000]);
+        assert_eq!(large[..2], [0x81, 127]);
+        assert_eq!(large[2..10], 70_000u64.to_be_bytes());
+        assert_eq!(large.len(), 10 + 70_000);
+    }
+
+    #[test]
+    fn masked_frames() {
+        let frame = client_frame(TEXT, b"hello");
+        assert_eq!(
+            read_frame(&mut frame.as_slice()).unwrap(),
+            (TEXT, b"hello".to_vec())
+        );
+        let mut huge = vec![0x82, 127];
+        huge.extend_from_slice(&(1u64 << 40).to_be_bytes());
+        assert!(read_frame(&mut huge.as_slice()).is_err());
+    }
+
+    #[test]
+    fn answers_pings_and_close() {
+        let (mut server, mut client) = connect();
+        let pings = thread::spawn(move || (ping(&mut server).unwrap(), ping(&mut server).unwrap()));
+
+        // the client's own ping is answered while waiting for its pong
+        assert_eq!(read_frame(&mut client).unwrap(), (PING, vec![]));
+        client.write_all(&client_frame(PING, b"ok")).unwrap();
+        client.write_all(&client_frame(TEXT, b"ignored")).unwrap();
+        client.write_all(&client_frame(PONG, &[])).unwrap();
+        assert_eq!(read_frame(&mut client).unwrap(), (PONG, b"ok".to_vec()));
+
+        // and its close is echoed back
+        assert_eq!(read_frame(&mut client).unwrap(), (PING, vec![]));
+        client
+            .write_all(&client_frame(CLOSE, &1000u16.to_be_bytes()))
+            .unwrap();
+        assert_eq!(
+            read_frame(&mut client).unwrap(),
+            (CLOSE, 1000u16.to_be_bytes().to_vec())
+        );
+        assert_eq!(pings.join().unwrap(), (true, false));
+    }
+
+    #[test]
+    fn closes() {
+        let (mut server, mut client) = connect();
+        let closed = thread::spawn(move || close(&mut server).is_ok());
+        assert_eq!(
+            read_frame(&mut client).unwrap(),
+            (CLOSE, GOING_AWAY.to_be_bytes().to_vec())
+        );
+        client
+            .write_all(&client_frame(CLOSE, &GOING_AWAY.to_be_bytes()))
+            .unwrap();
+        assert!(closed.join().unwrap());
+    }
+}
@@ -0,0 +1,96 @@
+use serde_json::Value;
+
+// json shaped xml: objects become child elements named after their keys,
+// array elements are each wrapped in an `<item>`, null is an empty element
+pub fn render(root: &str, value: &Value) -> String {
+    let mut out = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    element(&mut out, root, value);
+    out
+}
+
+fn element(out: &mut String, name: &str, value: &Value) {
+    // keys are field names, this keeps anything else from producing broken xml
+    let name = match name.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => name
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' | '.' => c,
+                _ => '_',
+            })
+            .collect::<String>(),
+        _ => format!("_{}", name),
+    };
+
+    match value {
+        Value::Null => out.push_str(&format!("<{}/>", name)),
+        Value::Bool(b) => out.push_str(&format!("<{0}>{1}</{0}>", name, b)),
+        Value::Number(n) => out.push_str(&format!("<{0}>{1}</{0}>", name, n)),
+        Value::String(s) => out.push_str(&format!("<{0}>{1}</{0}>", name, escape(s))),
+        Value::Array(items) => {
+            out.push_str(&format!("<{}>", name));
+            for item in items {
+                element(out, "item", item);
+            }
+            out.push_str(&format!("</{}>", name));
+        }
+        Value::Object(map) => {
+            out.push_str(&format!("<{}>", name));
+            for (key, value) in map {
+                element(out, key, value);
+            }
+            out.push_str(&format!("</{}>", name));
+        }
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            // not allowed anywhere in xml 1.0
+            c if (c as u32) < 0x20 && !matches!(c, '\t' | '\n' | '\r') => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_shaped() {
+        let value = serde_json::json!({
+            "kind": "youtube",
+            "data": {"id": 1, "title": "<b> & \"q\"", "played_at": null, "live": false},
+            "tags": ["a", 2],
+        });
+        assert_eq!(
+            render("response", &value),
+            concat!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+                "<response>",
+                "<data><id>1</id><live>false</live><played_at/>",
+                "<title>&lt;b&gt; &amp; &quot;q&quot;</title></data>",
+                "<kind>youtube</kind>",
+                "<tags><item>a</item><item>2</item></tags>",
+                "</response>",
+            )
+        );
+    }
+
+    #[test]
+    fn names_and_text_stay_valid() {
+        let value = serde_json::json!({"1st": "a\u{0}b\tc", "a b": "x"});
+        assert_eq!(
+            render("r", &value),
+            r#"<?xml version="1.0" encoding="UTF-8"?><r><_1st>ab	c</_1st><a_b>x</a_b></r>"#
+        );
+    }
+}
@@ -1,211 +1,2423 @@
-use log::*;
-use once_cell::sync::Lazy;
-use once_cell::sync_lazy;
-use regex::Regex;
-use serde::{Deserialize, Serialize};
-
-use crate::database;
-use crate::error::{Error, Result};
-use crate::server;
-use crate::FromRow;
-
-static PATTERN: Lazy<Regex> = sync_lazy! {
-    Regex::new(
-        r#"(:?^(:?http?.*?youtu(:?\.be|be.com))(:?/|.*?v=))(?P<id>[A-Za-z0-9_-]{11})"#,
-    ).expect("valid regex")
-};
-
-static API_KEY: Lazy<String> = sync_lazy! {
-    const YOUTUBE_API_KEY: &str = "SHAKEN_YOUTUBE_API_KEY";
-    std::env::var(YOUTUBE_API_KEY).map_err(|_| {
-        error!("environment var `{}` must be set",YOUTUBE_API_KEY );
-        std::process::exit(1);
-    }).unwrap()
-};
-
-#[derive(Serialize)]
-pub struct Song {
-    pub id: i64,
-    pub vid: String,
-    pub timestamp: i64,
-    pub duration: i64,
-    pub title: String,
-}
-
-impl FromRow for Song {
-    fn from_row(row: &rusqlite::Row<'_, '_>) -> Self {
-        Self {
-            id: row.get(0),
-            vid: row.get(1),
-            timestamp: row.get(2),
-            duration: row.get(3),
-            title: row.get(4),
-        }
-    }
-
-    fn timestamp(&self) -> i64 {
-        self.timestamp
-    }
-}
-
-#[derive(Default)]
-pub struct Youtube;
-
-impl crate::Storage<Song> for Youtube {
-    fn insert(&self, item: &server::Item) -> Result<()> {
-        let url = match &item.kind {
-            server::ItemKind::Youtube(url) => url,
-            _ => unreachable!("expected a youtube item"),
-        };
-
-        let id = PATTERN
-            .captures(&url)
-            .and_then(|s| s.name("id"))
-            .map(|s| s.as_str())
-            .ok_or_else(|| Error::InvalidYoutubeUrl(url.to_string()))?;
-
-        let info = YoutubeItem::fetch(id)?;
-
-        database::get_connection()
-            .execute_named(
-                include_str!("../sql/youtube/add_video.sql"),
-                &[
-                    (":vid", &id),
-                    (":ts", &item.ts),
-                    (":duration", &info.duration),
-                    (":title", &info.title),
-                ],
-            )
-            .map_err(Error::Sql)
-            .map(|_| ())
-    }
-
-    fn current(&self) -> Result<Song> {
-        database::get_connection()
-            .query_row(
-                include_str!("../sql/youtube/get_current.sql"),
-                rusqlite::NO_PARAMS,
-                Song::from_row,
-            )
-            .map_err(Error::Sql)
-    }
-
-    fn previous(&self) -> Result<Song> {
-        database::get_connection()
-            .query_row(
-                include_str!("../sql/youtube/get_previous.sql"),
-                rusqlite::NO_PARAMS,
-                Song::from_row,
-            )
-            .map_err(Error::Sql)
-    }
-
-    fn all(&self) -> Result<Vec<Song>> {
-        Ok(database::get_connection()
-            .prepare(include_str!("../sql/youtube/get_all.sql"))?
-            .query_map(rusqlite::NO_PARAMS, Song::from_row)
-            .map_err(Error::Sql)?
-            .filter_map(|s| s.ok())
-            .collect::<Vec<_>>())
-    }
-}
-
-pub struct YoutubeItem {
-    pub title: String,
-    pub duration: i64,
-}
-
-impl YoutubeItem {
-    pub fn fetch(id: &str) -> Result<Self> {
-        const BASE: &str = "https://www.googleapis.com/youtube/v3";
-        let query = Self::build_query(id)?;
-
-        let mut data = vec![];
-        let resp = http_req::request::get(format!("{}/videos/?{}", BASE, query), &mut data)
-            .map_err(Error::HttpClient)?;
-
-        if !resp.status_code().is_success() {
-            return Err(Error::HttpResponse(
-                resp.status_code().into(),
-                resp.reason().to_string(),
-            ));
-        }
-
-        Self::serialize(&data)
-    }
-
-    fn serialize(data: &[u8]) -> Result<Self> {
-        #[derive(Deserialize)]
-        struct Response<'a> {
-            #[serde(borrow)]
-            items: Vec<Item<'a>>,
-        }
-        #[derive(Deserialize)]
-        struct Item<'a> {
-            #[serde(borrow)]
-            snippet: Snippet<'a>,
-            #[serde(borrow, rename = "contentDetails")]
-            details: ContentDetails<'a>,
-        }
-        #[derive(Deserialize)]
-        struct Snippet<'a> {
-            title: &'a str,
-        }
-        #[derive(Deserialize)]
-        struct ContentDetails<'a> {
-            duration: &'a str,
-        }
-
-        let data = serde_json::from_slice::<Response>(&data).map_err(Error::Serialize)?;
-        let item = &data.items.get(0).ok_or_else(|| Error::InvalidYoutubeData)?;
-        Ok(Self {
-            title: item.snippet.title.to_string(),
-            duration: from_iso8601(item.details.duration),
-        })
-    }
-
-    fn build_query(id: &str) -> Result<String> {
-        let map = &[
-            ("id", id),
-            ("part", "snippet,contentDetails"),
-            (
-                "fields",
-                "items(id, snippet(title), contentDetails(duration))",
-            ),
-            ("key", API_KEY.as_str()),
-        ];
-
-        Ok(map
-            .as_ref()
-            .iter()
-            .map(|(k, v)| format!("{}={}&", encode(k), encode(v)))
-            .collect())
-    }
-}
-
-#[inline]
-fn encode(data: &str) -> String {
-    data.chars().fold(String::new(), |mut a, ch| {
-        match ch {
-            'A'..='Z' | 'a'..='z' | '0'..='9' | '-' | '_' | '.' | '~' => a.push(ch),
-            ch => a.push_str(&format!("%{:02X}", ch as u32)),
-        }
-        a
-    })
-}
-
-#[inline]
-fn from_iso8601(period: &str) -> i64 {
-    let parse = |s, e| period[s + 1..e].parse::<i64>().unwrap_or(0);
-    period
-        .chars()
-        .enumerate()
-        .fold((0, 0), |(a, p), (i, c)| match c {
-            c if c.is_numeric() => (a, p),
-            'H' => (a + parse(p, i) * 60 * 60, i),
-            'M' => (a + parse(p, i) * 60, i),
-            'S' => (a + parse(p, i), i),
-            'P' | 'T' | _ => (a, i),
-        })
-        .0 as i64
-}
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::*;
+use once_cell::sync::Lazy;
+use once_cell::sync_lazy;
+use rand::seq::SliceRandom;
+use regex::Regex;
+use rusqlite::OptionalExtension;
+use serde::{Deserialize, Serialize};
+
+use crate::blacklist;
+use crate::config;
+use crate::database;
+use crate::error::{Error, Result};
+use crate::metrics;
+use crate::server;
+use crate::title;
+use crate::FromRow;
+use crate::Storage;
+
+static PATTERN: Lazy<Regex> = sync_lazy! {
+    Regex::new(
+        r#"^(?:https?://)?(?:(?:www|m|music)\.)?(?:youtu\.be/|youtube\.com/(?:(?:shorts|embed|v|live)/|.*?[?&]v=))(?P<id>[A-Za-z0-9_-]{11})"#,
+    ).expect("valid regex")
+};
+
+static PLAYLIST: Lazy<Regex> = sync_lazy! {
+    Regex::new(
+        r#"^(?:https?://)?(?:(?:www|m|music)\.)?youtube\.com/(?:playlist|watch)\?(?:.*?&)?list=(?P<list>[A-Za-z0-9_-]+)"#,
+    ).expect("valid regex")
+};
+
+static START: Lazy<Regex> = sync_lazy! {
+    Regex::new(r#"[?&#](?:t|start)=(?P<start>[0-9hms]+)"#).expect("valid regex")
+};
+
+static CACHE: Lazy<Mutex<Cache<YoutubeItem>>> = sync_lazy! {
+    Mutex::new(Cache::default())
+};
+
+// lookups that are running, with everyone waiting on each
+type Waiters = Vec<mpsc::Sender<Result<YoutubeItem>>>;
+
+static IN_FLIGHT: Lazy<Mutex<HashMap<String, Waiters>>> = sync_lazy! {
+    Mutex::new(HashMap::new())
+};
+
+// keyed by limit and query, with when the results were fetched
+type Searched = (Instant, Vec<SearchResult>);
+
+static SEARCHES: Lazy<Mutex<Cache<Searched>>> = sync_lazy! {
+    Mutex::new(Cache::default())
+};
+
+static API_KEYS: Lazy<Mutex<KeyPool>> = sync_lazy! {
+    Mutex::new(KeyPool::new(api_keys().unwrap_or_default()))
+};
+
+const YOUTUBE_API_KEY: &str = "SHAKEN_YOUTUBE_API_KEY";
+
+// the env var overrides `youtube_api_key`, `youtube_api_keys` are rotated in after either
+pub fn api_keys() -> Result<Vec<String>> {
+    let config = config::get();
+    let mut keys = std::env::var(YOUTUBE_API_KEY)
+        .ok()
+        .or_else(|| config.youtube_api_key.clone())
+        .into_iter()
+        .collect::<Vec<_>>();
+    keys.extend(config.youtube_api_keys.iter().cloned());
+    keys.retain(|key| !key.is_empty());
+    if keys.is_empty() {
+        return Err(Error::YoutubeNotConfigured);
+    }
+    Ok(keys)
+}
+
+#[derive(Serialize)]
+pub struct Song {
+    pub id: i64,
+    pub vid: String,
+    pub timestamp: i64,
+    pub duration: i64,
+    pub title: String,
+    pub start: i64,
+    pub channel: String,
+    pub thumbnail: String,
+    pub requested_by: String,
+    pub position: i64,
+    // unix time, null until the song has played
+    pub played_at: Option<i64>,
+    // derived from `vid` and `start`, so clients don't have to build it
+    pub url: String,
+    // how many times `vid` has been requested, only looked up when asked for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requested: Option<i64>,
+}
+
+impl FromRow for Song {
+    fn from_row(row: &rusqlite::Row<'_, '_>) -> Self {
+        let vid: String = row.get(1);
+        let start: i64 = row.get(5);
+        Self {
+            id: row.get(0),
+            url: watch_url(&vid, start),
+            vid,
+            timestamp: row.get(2),
+            duration: row.get(3),
+            title: row.get(4),
+            start,
+            // older rows may not have a channel
+            channel: row
+                .get_checked::<_, Option<String>>(6)
+                .ok()
+                .and_then(|s| s)
+                .unwrap_or_default(),
+            thumbnail: row
+                .get_checked::<_, Option<String>>(7)
+                .ok()
+                .and_then(|s| s)
+                .unwrap_or_default(),
+            requested_by: crate::requested_by(row),
+            position: row.get("position"),
+            played_at: row.get("played_at"),
+            requested: row.get_checked("requested").ok(),
+        }
+    }
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+
+    fn position(&self) -> i64 {
+        self.position
+    }
+
+    fn played_at(&self) -> Option<i64> {
+        self.played_at
+    }
+}
+
+fn watch_url(vid: &str, start: i64) -> String {
+    match start {
+        0 => format!("https://youtu.be/{}", vid),
+        start => format!("https://youtu.be/{}?t={}", vid, start),
+    }
+}
+
+#[derive(Default)]
+pub struct Youtube;
+
+impl Youtube {
+    // validates every item with as few api requests as possible, each item gets its own
+    // result. only a failed batch request fails the whole lot
+    pub fn validate_each(&self, items: &[server::Item]) -> Result<Vec<Result<Song>>> {
+        let prepared = items
+            .iter()
+            .map(|item| self.prepare(item))
+            .collect::<Vec<_>>();
+
+        let mut ids = prepared
+            .iter()
+            .filter_map(|res| res.as_ref().ok())
+            .map(|(id, _)| id.as_str())
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids.dedup();
+        let mut found = YoutubeItem::fetch_each(&ids)?
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+
+        Ok(items
+            .iter()
+            .zip(prepared)
+            .map(|(item, prepared)| {
+                let (id, start) = prepared?;
+                let info = match found.get(&id) {
+                    Some(Ok(info)) => info.clone(),
+                    // errors can't be copied, a repeat of a failed id just looks it up again
+                    Some(Err(..)) => found.remove(&id).expect("entry was found")?,
+                    None => YoutubeItem::fetch(&id)?,
+                };
+                self.finish(item, &id, start, info)
+            })
+            .collect())
+    }
+
+    // the checks that don't need the video, so known-bad requests don't cost an api call
+    fn prepare(&self, item: &server::Item) -> Result<(String, i64)> {
+        let url = match &item.kind {
+            server::ItemKind::Youtube(url) => url,
+            _ => return Err(Error::WrongItemKind("youtube")),
+        };
+
+        let id = match PATTERN.captures(url).and_then(|s| s.name("id")) {
+            Some(id) => id.as_str().to_string(),
+            // search costs far more quota than a lookup, so it's opt-in
+            None if config::get().allow_search => YoutubeItem::search(url)?,
+            None => return Err(Error::InvalidYoutubeUrl(url.to_string())),
+        };
+        let id = id.as_str();
+
+        let start = START
+            .captures(url)
+            .and_then(|s| s.name("start"))
+            .map(|s| parse_start(s.as_str()))
+            .unwrap_or(0);
+
+        // before anything else so known-bad ids don't cost an api call
+        blacklist::check(blacklist::EntryKind::Video, id)?;
+
+        let conn = database::get_connection();
+
+        // a window of 0 means a video can only ever be requested once
+        let since = match config::get().dedupe_window_secs {
+            0 => i64::MIN,
+            window => item.ts - window,
+        };
+        let exists: bool = conn.query_row_named(
+            include_str!("../sql/youtube/exists.sql"),
+            &[(":vid", &id), (":since", &since)],
+            |row| row.get(0),
+        )?;
+        if exists {
+            return Err(Error::DuplicateSong {
+                vid: id.to_string(),
+            });
+        }
+
+        Ok((id.to_string(), start))
+    }
+
+    // the checks on the looked up video
+    fn finish(
+        &self,
+        item: &server::Item,
+        id: &str,
+        start: i64,
+        mut info: YoutubeItem,
+    ) -> Result<Song> {
+        info.title = title::sanitize(&info.title, config::get().max_title_len);
+        title::check_words(&[&info.title, &info.channel])?;
+
+        let max = config::get().max_duration_secs;
+        if max > 0 && info.duration > max {
+            return Err(Error::DurationTooLong {
+                got: info.duration,
+                max,
+            });
+        }
+
+        if config::get().dedupe_titles {
+            let title = title::normalize(&info.title);
+            if self
+                .pending()?
+                .iter()
+                .any(|song| title::normalize(&song.title) == title)
+            {
+                return Err(Error::DuplicateTitle(info.title));
+            }
+        }
+
+        // not stored yet, so there's no row id
+        Ok(Song {
+            id: 0,
+            vid: id.to_string(),
+            timestamp: item.ts,
+            duration: info.duration,
+            title: info.title,
+            start,
+            channel: info.channel,
+            thumbnail: info.thumbnail,
+            requested_by: item.requested_by.clone(),
+            position: item.ts,
+            played_at: None,
+            url: watch_url(id, start),
+            requested: None,
+        })
+    }
+
+    // `all` with every song's request count, counted in the same query
+    pub fn all_with_counts(&self, limit: Option<u32>, offset: Option<u32>) -> Result<Vec<Song>> {
+        let limit = limit.map(i64::from).unwrap_or(-1);
+        let offset = offset.map(i64::from).unwrap_or(0);
+        Ok(database::get_connection()
+            .prepare(include_str!("../sql/youtube/get_all_counts.sql"))?
+            .query_map_named(&[(":limit", &limit), (":offset", &offset)], Song::from_row)
+            .map_err(Error::Sql)?
+            .filter_map(|s| s.ok())
+            .collect::<Vec<_>>())
+    }
+}
+
+impl crate::Storage<Song> for Youtube {
+    fn store(&self, tx: &rusqlite::Transaction<'_>, song: &Song) -> Result<Song> {
+        tx.execute_named(
+            include_str!("../sql/youtube/add_video.sql"),
+            &[
+                (":vid", &song.vid),
+                (":ts", &song.timestamp),
+                (":duration", &song.duration),
+                (":title", &song.title),
+                (":start", &song.start),
+                (":channel", &song.channel),
+                (":thumbnail", &song.thumbnail),
+                (":requested_by", &song.requested_by),
+            ],
+        )?;
+        tx.query_row_named(
+            include_str!("../sql/youtube/get.sql"),
+            &[(":id", &tx.last_insert_rowid())],
+            Song::from_row,
+        )
+        .map_err(Error::Sql)
+    }
+
+    fn validate(&self, item: &server::Item) -> Result<Song> {
+        let (id, start) = self.prepare(item)?;
+        let info = YoutubeItem::fetch(&id)?;
+        self.finish(item, &id, start, info)
+    }
+
+    fn get(&self, id: i64) -> Result<Option<Song>> {
+        database::get_connection()
+            .query_row_named(
+                include_str!("../sql/youtube/get.sql"),
+                &[(":id", &id)],
+                Song::from_row,
+            )
+            .optional()
+            .map_err(Error::Sql)
+    }
+
+    fn current(&self) -> Result<Option<Song>> {
+        database::get_connection()
+            .query_row(
+                include_str!("../sql/youtube/get_current.sql"),
+                rusqlite::NO_PARAMS,
+                Song::from_row,
+            )
+            .optional()
+            .map_err(Error::Sql)
+    }
+
+    fn previous(&self) -> Result<Option<Song>> {
+        database::get_connection()
+            .query_row(
+                include_str!("../sql/youtube/get_previous.sql"),
+                rusqlite::NO_PARAMS,
+                Song::from_row,
+            )
+            .optional()
+            .map_err(Error::Sql)
+    }
+
+    fn history(&self, n: u32) -> Result<Vec<Song>> {
+        Ok(database::get_connection()
+            .prepare(include_str!("../sql/youtube/get_history.sql"))?
+            .query_map_named(&[(":limit", &n)], Song::from_row)
+            .map_err(Error::Sql)?
+            .filter_map(|s| s.ok())
+            .collect::<Vec<_>>())
+    }
+
+    fn shuffle<R: rand::Rng + ?Sized>(&self, rng: &mut R, skip: u32) -> Result<()> {
+        let mut conn = database::get_connection();
+        let tx = conn.transaction()?;
+        let pending = tx
+            .prepare(include_str!("../sql/youtube/get_pending_positions.sql"))?
+            .query_map_named(&[(":skip", &skip)], |row| {
+                (row.get::<_, i64>(0), row.get::<_, i64>(1))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut positions = pending.iter().map(|(_, pos)| *pos).collect::<Vec<_>>();
+        positions.shuffle(rng);
+        for ((id, _), position) in pending.iter().zip(positions) {
+            tx.execute_named(
+                include_str!("../sql/youtube/set_position.sql"),
+                &[(":id", id), (":position", &position)],
+            )?;
+        }
+        tx.commit().map_err(Error::Sql)
+    }
+
+    fn move_to(&self, id: i64, pos: u32) -> Result<()> {
+        let mut conn = database::get_connection();
+        let tx = conn.transaction()?;
+        let pending = tx
+            .prepare(include_str!("../sql/youtube/get_pending_positions.sql"))?
+            .query_map_named(&[(":skip", &0)], |row| {
+                (row.get::<_, i64>(0), row.get::<_, i64>(1))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        for (id, position) in crate::reorder(pending, id, pos)? {
+            tx.execute_named(
+                include_str!("../sql/youtube/set_position.sql"),
+                &[(":id", &id), (":position", &position)],
+            )?;
+        }
+        tx.commit().map_err(Error::Sql)
+    }
+
+    fn requeue(&self, id: i64, position: i64) -> Result<bool> {
+        database::get_connection()
+            .execute_named(
+                include_str!("../sql/youtube/requeue.sql"),
+                &[(":id", &id), (":position", &position)],
+            )
+            .map(|n| n > 0)
+            .map_err(Error::Sql)
+    }
+
+    fn advance(&self) -> Result<Option<Song>> {
+        database::get_connection()
+            .execute_named(
+                include_str!("../sql/youtube/advance.sql"),
+                &[(":now", &crate::unix_time())],
+            )
+            .map_err(Error::Sql)?;
+        self.current()
+    }
+
+    fn all(&self, limit: Option<u32>, offset: Option<u32>) -> Result<Vec<Song>> {
+        // a negative limit is unbounded in sqlite
+        let limit = limit.map(i64::from).unwrap_or(-1);
+        let offset = offset.map(i64::from).unwrap_or(0);
+        Ok(database::get_connection()
+            .prepare(include_str!("../sql/youtube/get_all.sql"))?
+            .query_map_named(&[(":limit", &limit), (":offset", &offset)], Song::from_row)
+            .map_err(Error::Sql)?
+            .filter_map(|s| s.ok())
+            .collect::<Vec<_>>())
+    }
+
+    fn pending(&self) -> Result<Vec<Song>> {
+        Ok(database::get_connection()
+            .prepare(include_str!("../sql/youtube/get_pending.sql"))?
+            .query_map(rusqlite::NO_PARAMS, Song::from_row)
+            .map_err(Error::Sql)?
+            .filter_map(|s| s.ok())
+            .collect::<Vec<_>>())
+    }
+
+    fn between(&self, start: i64, end: i64) -> Result<Vec<Song>> {
+        Ok(database::get_connection()
+            .prepare(include_str!("../sql/youtube/get_between.sql"))?
+            .query_map_named(&[(":start", &start), (":end", &end)], Song::from_row)
+            .map_err(Error::Sql)?
+            .filter_map(|s| s.ok())
+            .collect::<Vec<_>>())
+    }
+
+    fn count(&self) -> Result<i64> {
+        database::get_connection()
+            .query_row(
+                include_str!("../sql/youtube/count.sql"),
+                rusqlite::NO_PARAMS,
+                |row| row.get(0),
+            )
+            .map_err(Error::Sql)
+    }
+
+    fn clear_pending(&self) -> Result<u64> {
+        database::get_connection()
+            .execute(
+                include_str!("../sql/youtube/clear_pending.sql"),
+                rusqlite::NO_PARAMS,
+            )
+            .map_err(Error::Sql)
+            .map(|n| n as u64)
+    }
+
+    fn clear_history(&self) -> Result<u64> {
+        database::get_connection()
+            .execute(
+                include_str!("../sql/youtube/clear_history.sql"),
+                rusqlite::NO_PARAMS,
+            )
+            .map_err(Error::Sql)
+            .map(|n| n as u64)
+    }
+
+    fn prune(&self, before: i64) -> Result<u64> {
+        database::get_connection()
+            .execute_named(
+                include_str!("../sql/youtube/prune.sql"),
+                &[(":before", &before)],
+            )
+            .map_err(Error::Sql)
+            .map(|n| n as u64)
+    }
+
+    fn delete(&self, id: i64) -> Result<bool> {
+        database::get_connection()
+            .execute_named(include_str!("../sql/youtube/delete.sql"), &[(":id", &id)])
+            .map_err(Error::Sql)
+            .map(|n| n > 0)
+    }
+}
+
+#[derive(Clone)]
+pub struct YoutubeItem {
+    pub title: String,
+    pub channel: String,
+    pub thumbnail: String,
+    pub duration: i64,
+    // country codes from `contentDetails.regionRestriction`
+    pub allowed: Option<Vec<String>>,
+    pub blocked: Vec<String>,
+}
+
+impl YoutubeItem {
+    pub fn fetch(id: &str) -> Result<Self> {
+        Self::fetch_async(id).wait()
+    }
+
+    // the lookup runs on its own thread, the caller is free until it needs the result.
+    // asking for an id that's already being looked up waits on that lookup instead
+    pub fn fetch_async(id: &str) -> Fetch {
+        let (tx, rx) = mpsc::channel();
+        let id = id.to_string();
+        {
+            let mut in_flight = IN_FLIGHT.lock().unwrap();
+            if let Some(waiters) = in_flight.get_mut(&id) {
+                waiters.push(tx);
+                return Fetch(rx);
+            }
+            in_flight.insert(id.clone(), vec![tx]);
+        }
+
+        thread::spawn(move || {
+            let item = Self::fetch_one(&id);
+            let waiters = IN_FLIGHT.lock().unwrap().remove(&id).unwrap_or_default();
+            let mut waiters = waiters.into_iter();
+            // callers may have stopped waiting
+            match item {
+                Ok(item) => {
+                    for waiter in waiters {
+                        let _ = waiter.send(Ok(item.clone()));
+                    }
+                }
+                // errors can't be shared, so anyone after the first looks it up again
+                Err(err) => {
+                    if let Some(first) = waiters.next() {
+                        let _ = first.send(Err(err));
+                    }
+                    for waiter in waiters {
+                        let _ = waiter.send(Self::fetch_one(&id));
+                    }
+                }
+            }
+        });
+        Fetch(rx)
+    }
+
+    fn fetch_one(id: &str) -> Result<Self> {
+        Self::fetch_each(&[id]).and_then(|mut items| {
+            items
+                .pop()
+                .map(|(_, item)| item)
+                .unwrap_or_else(|| Err(Error::InvalidYoutubeData))
+        })
+    }
+
+    // the id of the top video result for `query`
+    pub fn search(query: &str) -> Result<String> {
+        #[derive(Deserialize)]
+        struct Response {
+            items: Vec<Item>,
+        }
+        #[derive(Deserialize)]
+        struct Item {
+            id: Id,
+        }
+        #[derive(Deserialize)]
+        struct Id {
+            #[serde(rename = "videoId")]
+            video_id: String,
+        }
+
+        let data = Self::request(
+            "search",
+            &[
+                ("q", query),
+                ("part", "id"),
+                ("type", "video"),
+                ("maxResults", "1"),
+                ("fields", "items(id(videoId))"),
+            ],
+        )?;
+
+        let id = serde_json::from_slice::<Response>(&data)
+            .map_err(Error::Serialize)?
+            .items
+            .into_iter()
+            .next()
+            .map(|item| item.id.video_id)
+            .ok_or_else(|| Error::NoSearchResults(query.to_string()))?;
+        debug!("search for '{}' found {}", query, id);
+        Ok(id)
+    }
+
+    // the video ids in a playlist, in playlist order without repeats
+    pub fn playlist(url: &str) -> Result<Vec<String>> {
+        // 50 per page, anything past this is left out
+        const MAX_PAGES: usize = 20;
+
+        #[derive(Deserialize)]
+        struct Response {
+            #[serde(rename = "nextPageToken")]
+            next_page_token: Option<String>,
+            #[serde(default)]
+            items: Vec<Item>,
+        }
+        #[derive(Deserialize)]
+        struct Item {
+            #[serde(rename = "contentDetails")]
+            details: Details,
+        }
+        #[derive(Deserialize)]
+        struct Details {
+            #[serde(rename = "videoId")]
+            video_id: String,
+        }
+
+        let list = PLAYLIST
+            .captures(url)
+            .and_then(|c| c.name("list"))
+            .map(|s| s.as_str())
+            .ok_or_else(|| Error::InvalidYoutubeUrl(url.to_string()))?;
+
+        let mut ids: Vec<String> = vec![];
+        let mut token: Option<String> = None;
+        for _ in 0..MAX_PAGES {
+            let mut params = vec![
+                ("playlistId", list),
+                ("part", "contentDetails"),
+                ("maxResults", "50"),
+                ("fields", "nextPageToken, items(contentDetails(videoId))"),
+            ];
+            if let Some(token) = &token {
+                params.push(("pageToken", token.as_str()));
+            }
+            let data = Self::request("playlistItems", &params)?;
+            let page = serde_json::from_slice::<Response>(&data).map_err(Error::Serialize)?;
+            for item in page.items {
+                if !ids.contains(&item.details.video_id) {
+                    ids.push(item.details.video_id)
+                }
+            }
+            match page.next_page_token {
+                Some(next) => token = Some(next),
+                None => break,
+            }
+        }
+        debug!("playlist {} has {} videos", list, ids.len());
+        Ok(ids)
+    }
+
+    // results are in the same order as `ids`, missing videos are an error
+    fn fetch_each(ids: &[&str]) -> Result<Vec<(String, Result<Self>)>> {
+        // the api allows at most 50 ids per request
+        const MAX_IDS: usize = 50;
+
+        let mut found = HashMap::new();
+        {
+            let cache = CACHE.lock().unwrap();
+            for id in ids {
+                if let Some(item) = cache.get(id) {
+                    found.insert(id.to_string(), Ok(item));
+                }
+            }
+        }
+
+        let misses = ids
+            .iter()
+            .filter(|id| !found.contains_key(**id))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        // every chunk is requested at once rather than one after the other
+        let responses = thread::scope(|scope| {
+            misses
+                .chunks(MAX_IDS)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        Self::request(
+                            "videos",
+                            &[
+                                ("id", &chunk.join(",")),
+                                ("part", "snippet,contentDetails"),
+                                (
+                                    "fields",
+                                    "items(id, snippet(title, channelTitle, liveBroadcastContent, thumbnails), contentDetails(duration, contentRating(ytRating), regionRestriction))",
+                                ),
+                            ],
+                        )
+                    })
+                })
+                .collect::<Vec<_>>()
+                .into_iter()
+                .map(|handle| handle.join().expect("youtube request thread panicked"))
+                .collect::<Vec<_>>()
+        });
+
+        let max = config::get().cache_size;
+        for data in responses {
+            let data = data?;
+            let mut cache = CACHE.lock().unwrap();
+            for (id, item) in Self::serialize(&data)? {
+                if let Ok(item) = &item {
+                    cache.insert(id.clone(), item.clone(), max);
+                }
+                found.insert(id, item);
+            }
+        }
+
+        // checked after the cache, the region can change on a reload
+        let region = config::get().region.clone();
+        Ok(ids
+            .iter()
+            .map(|id| {
+                // the api omits videos that don't exist or are private
+                let item = found
+                    .remove(*id)
+                    .unwrap_or_else(|| Err(Error::VideoUnavailable("videoNotFound".into())))
+                    .and_then(|item| match &region {
+                        Some(region) if item.is_blocked_in(region) => {
+                            Err(Error::RegionBlocked(region.to_uppercase()))
+                        }
+                        _ => Ok(item),
+                    });
+                (id.to_string(), item)
+            })
+            .collect())
+    }
+
+    // videos are either blocked in a list of countries, or only allowed in one
+    fn is_blocked_in(&self, region: &str) -> bool {
+        let listed = |codes: &[String]| codes.iter().any(|c| c.eq_ignore_ascii_case(region));
+        match &self.allowed {
+            Some(allowed) if !listed(allowed) => true,
+            _ => listed(&self.blocked),
+        }
+    }
+
+    fn request(endpoint: &str, params: &[(&str, &str)]) -> Result<Vec<u8>> {
+        loop {
+            let (index, key) = API_KEYS.lock().unwrap().next()?;
+            match Self::request_with_key(endpoint, params, &key) {
+                Err(Error::QuotaExceeded) => API_KEYS.lock().unwrap().exhaust(index),
+                res => return res,
+            }
+        }
+    }
+
+    fn request_with_key(endpoint: &str, params: &[(&str, &str)], key: &str) -> Result<Vec<u8>> {
+        let config = config::get();
+        let query = Self::build_query(params, key);
+        let url = format!(
+            "{}/{}/?{}",
+            config.youtube_base_url.trim_end_matches('/'),
+            endpoint,
+            query
+        );
+
+        let mut delay = Duration::from_millis(config.retry_delay_ms);
+        let mut attempt = 0;
+        loop {
+            match Self::get(&url) {
+                Err(ref err) if attempt < config.retry_count && is_transient(err) => {
+                    attempt += 1;
+                    warn!(
+                        "youtube request failed ({}), retrying in {:?} ({}/{})",
+                        err, delay, attempt, config.retry_count
+                    );
+                    thread::sleep(delay);
+                    delay *= 2;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    fn get(url: &str) -> Result<Vec<u8>> {
+        let timeout = match config::get().youtube_timeout_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+
+        let uri = url.parse::<http_req::uri::Uri>()?;
+        let host = uri.host().unwrap_or_default();
+        let mut builder = http_req::request::RequestBuilder::new(&uri);
+        builder.header("Connection", "Close");
+
+        let mut data = vec![];
+        metrics::youtube_api_call();
+        let stream = connect(host, uri.corr_port(), timeout).map_err(timed_out)?;
+        let resp = if uri.scheme() == "https" {
+            let mut stream = http_req::tls::Config::default().connect(host, stream)?;
+            builder.send(&mut stream, &mut data)
+        } else {
+            builder.send(&mut &stream, &mut data)
+        };
+        let resp = resp.map_err(|err| match err {
+            http_req::error::Error::IO(err) => timed_out(err),
+            err => Error::HttpClient(err),
+        })?;
+
+        if !resp.status_code().is_success() {
+            return Err(Self::api_error(
+                resp.status_code().into(),
+                resp.reason(),
+                &data,
+            ));
+        }
+
+        Ok(data)
+    }
+
+    fn api_error(code: u16, reason: &str, data: &[u8]) -> Error {
+        #[derive(Deserialize)]
+        struct Response {
+            error: Inner,
+        }
+        #[derive(Deserialize)]
+        struct Inner {
+            #[serde(default)]
+            errors: Vec<Reason>,
+        }
+        #[derive(Deserialize)]
+        struct Reason {
+            reason: String,
+        }
+
+        let reasons = serde_json::from_slice::<Response>(data)
+            .map(|resp| resp.error.errors)
+            .unwrap_or_default();
+
+        for Reason { reason } in reasons {
+            match reason.as_str() {
+                "quotaExceeded" | "dailyLimitExceeded" | "rateLimitExceeded" => {
+                    return Error::QuotaExceeded
+                }
+                "videoNotFound" | "forbidden" | "videoPrivate" => {
+                    return Error::VideoUnavailable(reason)
+                }
+                _ => {}
+            }
+        }
+
+        Error::HttpResponse(code, reason.to_string())
+    }
+
+    fn serialize(data: &[u8]) -> Result<Vec<(String, Result<Self>)>> {
+        #[derive(Deserialize)]
+        struct Response<'a> {
+            #[serde(borrow)]
+            items: Vec<Item<'a>>,
+        }
+        #[derive(Deserialize)]
+        struct Item<'a> {
+            id: &'a str,
+            #[serde(borrow)]
+            snippet: Snippet<'a>,
+            #[serde(borrow, rename = "contentDetails")]
+            details: ContentDetails<'a>,
+        }
+        #[derive(Deserialize)]
+        struct Snippet<'a> {
+            // escaped text, newlines and quotes included, can't be borrowed
+            #[serde(borrow)]
+            title: Cow<'a, str>,
+            #[serde(borrow, rename = "channelTitle", default)]
+            channel: Option<Cow<'a, str>>,
+            #[serde(rename = "liveBroadcastContent", default)]
+            live: Option<&'a str>,
+            #[serde(borrow, default)]
+            thumbnails: HashMap<&'a str, Thumbnail<'a>>,
+        }
+        #[derive(Deserialize)]
+        struct Thumbnail<'a> {
+            url: &'a str,
+        }
+        #[derive(Deserialize)]
+        struct ContentDetails<'a> {
+            duration: &'a str,
+            #[serde(borrow, rename = "contentRating", default)]
+            rating: ContentRating<'a>,
+            #[serde(rename = "regionRestriction", default)]
+            region: RegionRestriction,
+        }
+        #[derive(Default, Deserialize)]
+        struct RegionRestriction {
+            #[serde(default)]
+            allowed: Option<Vec<String>>,
+            #[serde(default)]
+            blocked: Vec<String>,
+        }
+        #[derive(Default, Deserialize)]
+        struct ContentRating<'a> {
+            #[serde(rename = "ytRating", default)]
+            yt_rating: Option<&'a str>,
+        }
+
+        let data = serde_json::from_slice::<Response>(data).map_err(Error::Serialize)?;
+        Ok(data
+            .items
+            .iter()
+            .map(|item| {
+                // live broadcasts and premieres report a zero duration
+                if let Some("live") | Some("upcoming") = item.snippet.live {
+                    return (item.id.to_string(), Err(Error::LiveStreamNotAllowed));
+                }
+                if item.details.rating.yt_rating == Some("ytAgeRestricted") {
+                    return (item.id.to_string(), Err(Error::AgeRestricted));
+                }
+
+                let info = from_iso8601(item.details.duration).map(|duration| Self {
+                    title: item.snippet.title.to_string(),
+                    channel: item
+                        .snippet
+                        .channel
+                        .as_deref()
+                        .unwrap_or_default()
+                        .to_string(),
+                    thumbnail: ["medium", "default", "high"]
+                        .iter()
+                        .filter_map(|res| item.snippet.thumbnails.get(res))
+                        .chain(item.snippet.thumbnails.values())
+                        .map(|thumb| thumb.url.to_string())
+                        .next()
+                        .unwrap_or_default(),
+                    duration,
+                    allowed: item.details.region.allowed.clone(),
+                    blocked: item.details.region.blocked.clone(),
+                });
+                (item.id.to_string(), info)
+            })
+            .collect())
+    }
+
+    fn build_query(params: &[(&str, &str)], key: &str) -> String {
+        params
+            .iter()
+            .chain(std::iter::once(&("key", key)))
+            .map(|(k, v)| format!("{}={}&", encode(k), encode(v)))
+            .collect()
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct SearchResult {
+    pub vid: String,
+    pub title: String,
+    pub channel: String,
+    pub thumbnail: String,
+}
+
+// up to `limit` videos matching `query`, best match first
+pub fn search(query: &str, limit: u32) -> Result<Vec<SearchResult>> {
+    // results for the same query don't change much, but they do change
+    const MAX_AGE: Duration = Duration::from_secs(10 * 60);
+
+    #[derive(Deserialize)]
+    struct Response {
+        #[serde(default)]
+        items: Vec<Item>,
+    }
+    #[derive(Deserialize)]
+    struct Item {
+        id: Id,
+        snippet: Snippet,
+    }
+    #[derive(Deserialize)]
+    struct Id {
+        #[serde(rename = "videoId")]
+        video_id: String,
+    }
+    #[derive(Deserialize)]
+    struct Snippet {
+        #[serde(default)]
+        title: String,
+        #[serde(rename = "channelTitle", default)]
+        channel: String,
+        #[serde(default)]
+        thumbnails: HashMap<String, Thumbnail>,
+    }
+    #[derive(Deserialize)]
+    struct Thumbnail {
+        url: String,
+    }
+
+    let key = format!("{}:{}", limit, query.trim().to_lowercase());
+    if let Some((at, results)) = SEARCHES.lock().unwrap().get(&key) {
+        if at.elapsed() < MAX_AGE {
+            return Ok(results);
+        }
+    }
+
+    let limit = limit.to_string();
+    let data = YoutubeItem::request(
+        "search",
+        &[
+            ("q", query),
+            ("part", "snippet"),
+            ("type", "video"),
+            ("maxResults", &limit),
+            (
+                "fields",
+                "items(id(videoId), snippet(title, channelTitle, thumbnails))",
+            ),
+        ],
+    )?;
+    let results = serde_json::from_slice::<Response>(&data)
+        .map_err(Error::Serialize)?
+        .items
+        .into_iter()
+        .map(|Item { id, snippet }| SearchResult {
+            thumbnail: ["medium", "default", "high"]
+                .iter()
+                .filter_map(|res| snippet.thumbnails.get(*res))
+                .chain(snippet.thumbnails.values())
+                .map(|thumb| thumb.url.clone())
+                .next()
+                .unwrap_or_default(),
+            vid: id.video_id,
+            title: snippet.title,
+            channel: snippet.channel,
+        })
+        .collect::<Vec<_>>();
+
+    SEARCHES.lock().unwrap().insert(
+        key,
+        (Instant::now(), results.clone()),
+        config::get().cache_size,
+    );
+    Ok(results)
+}
+
+// a lookup started by `YoutubeItem::fetch_async`
+pub struct Fetch(mpsc::Receiver<Result<YoutubeItem>>);
+
+impl Fetch {
+    pub fn wait(self) -> Result<YoutubeItem> {
+        self.0.recv().unwrap_or(Err(Error::InvalidYoutubeData))
+    }
+}
+
+// keys are rotated when one runs out of quota, and are retried once it resets
+struct KeyPool {
+    keys: Vec<(String, Option<Instant>)>,
+    current: usize,
+}
+
+impl KeyPool {
+    const QUOTA_RESET: Duration = Duration::from_secs(60 * 60 * 24);
+
+    fn new(keys: Vec<String>) -> Self {
+        Self {
+            keys: keys.into_iter().map(|key| (key, None)).collect(),
+            current: 0,
+        }
+    }
+
+    fn next(&mut self) -> Result<(usize, String)> {
+        if self.keys.is_empty() {
+            return Err(Error::YoutubeNotConfigured);
+        }
+        for offset in 0..self.keys.len() {
+            let index = (self.current + offset) % self.keys.len();
+            let (key, exhausted) = &mut self.keys[index];
+            match exhausted {
+                Some(at) if at.elapsed() < Self::QUOTA_RESET => continue,
+                _ => *exhausted = None,
+            }
+            self.current = index;
+            return Ok((index, key.clone()));
+        }
+        Err(Error::AllKeysExhausted)
+    }
+
+    fn exhaust(&mut self, index: usize) {
+        warn!("youtube api key #{} has exceeded its quota", index);
+        self.keys[index].1 = Some(Instant::now());
+    }
+}
+
+// http_req has no timeouts of its own, so the stream is set up here
+pub(crate) fn connect(
+    host: &str,
+    port: u16,
+    timeout: Option<Duration>,
+) -> std::io::Result<TcpStream> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return TcpStream::connect((host, port)),
+    };
+
+    let mut last = None;
+    for addr in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                return Ok(stream);
+            }
+            Err(err) => last = Some(err),
+        }
+    }
+    Err(last.unwrap_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "host did not resolve")
+    }))
+}
+
+// blocking sockets report a read timeout as `WouldBlock` on unix
+pub(crate) fn timed_out(err: std::io::Error) -> Error {
+    match err.kind() {
+        std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => Error::Timeout,
+        _ => Error::HttpClient(err.into()),
+    }
+}
+
+// connection errors and server errors are worth retrying, client errors are not.
+// a timeout isn't retried, that would only stall the request for longer
+#[inline]
+fn is_transient(err: &Error) -> bool {
+    match err {
+        Error::HttpClient(..) => true,
+        Error::HttpResponse(code, _) => *code >= 500,
+        _ => false,
+    }
+}
+
+// entries aren't evicted by age, searches keep their own timestamp for that. once
+// there are `cache_size` of them, the one added first makes room for the next
+struct Cache<T> {
+    items: HashMap<String, T>,
+    order: VecDeque<String>,
+}
+
+impl<T> Default for Cache<T> {
+    fn default() -> Self {
+        Self {
+            items: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+}
+
+impl<T: Clone> Cache<T> {
+    fn get(&self, id: &str) -> Option<T> {
+        self.items.get(id).cloned()
+    }
+
+    fn insert(&mut self, id: String, item: T, max: usize) {
+        if max == 0 {
+            return;
+        }
+
+        if self.items.insert(id.clone(), item).is_none() {
+            self.order.push_back(id);
+        }
+
+        while self.items.len() > max {
+            match self.order.pop_front() {
+                Some(old) => self.items.remove(&old),
+                None => break,
+            };
+        }
+    }
+}
+
+// percent-encodes everything but the unreserved set, one utf-8 byte at a time
+#[inline]
+pub(crate) fn encode(data: &str) -> String {
+    data.bytes().fold(String::new(), |mut a, b| {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                a.push(b as char)
+            }
+            b => a.push_str(&format!("%{:02X}", b)),
+        }
+        a
+    })
+}
+
+// parses a `t=` offset, either plain seconds (`90`) or the `1h2m3s` shorthand
+#[inline]
+fn parse_start(start: &str) -> i64 {
+    let (total, rest) = start.chars().fold((0i64, 0i64), |(a, n), c| match c {
+        '0'..='9' => (
+            a,
+            n.saturating_mul(10).saturating_add(c as i64 - '0' as i64),
+        ),
+        'h' => (a.saturating_add(n.saturating_mul(60 * 60)), 0),
+        'm' => (a.saturating_add(n.saturating_mul(60)), 0),
+        _ => (a.saturating_add(n), 0),
+    });
+    total.saturating_add(rest)
+}
+
+#[inline]
+fn from_iso8601(period: &str) -> Result<i64> {
+    let invalid = || Error::InvalidDuration(period.to_string());
+    // `P` and `PT` have no components, and a `T` has to be followed by one
+    if !period.starts_with('P') || period.len() == 1 || period.ends_with('T') {
+        return Err(invalid());
+    }
+
+    // fractional components (e.g. `30.5S`) are truncated
+    let parse = |s: usize, e: usize| {
+        period[s + 1..e]
+            .split('.')
+            .next()
+            .and_then(|s| s.parse::<i64>().ok())
+            .ok_or_else(invalid)
+    };
+    // weeks and days come before the `T`, hours, minutes and seconds after it,
+    // each at most once and in that order. an `M` before the `T` is months,
+    // which don't have a fixed length
+    let unit = |c, time| match (c, time) {
+        ('W', false) => Some((0, 60 * 60 * 24 * 7)),
+        ('D', false) => Some((1, 60 * 60 * 24)),
+        ('H', true) => Some((2, 60 * 60)),
+        ('M', true) => Some((3, 60)),
+        ('S', true) => Some((4, 1)),
+        _ => None,
+    };
+
+    period
+        .char_indices()
+        .skip(1)
+        .try_fold((0i64, 0, 0, false), |(a, p, r, t), (i, c)| {
+            let (rank, unit) = match c {
+                c if c.is_ascii_digit() || c == '.' => return Ok((a, p, r, t)),
+                'T' if !t => return Ok((a, i, r, true)),
+                c => match unit(c, t) {
+                    Some((rank, unit)) if rank >= r => (rank, unit),
+                    _ => return Err(invalid()),
+                },
+            };
+            parse(p, i)?
+                .checked_mul(unit)
+                .and_then(|n| n.checked_add(a))
+                .map(|a| (a, i, rank + 1, t))
+                .ok_or_else(invalid)
+        })
+        .and_then(|(a, p, ..)| {
+            // trailing digits without a designator
+            if p + 1 == period.len() {
+                Ok(a)
+            } else {
+                Err(invalid())
+            }
+        })
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, MutexGuard};
+
+    use super::*;
+
+    type Handler = Box<dyn Fn(&str) -> (u16, String) + Send + Sync>;
+
+    // a stand-in for the data api, it keeps the path of every request it's sent
+    pub struct Mock(Arc<Mutex<Vec<String>>>);
+
+    impl Mock {
+        pub fn requests(&self) -> Vec<String> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    // an http server on an unused port, `handler` gets the request path and returns the
+    // status and body
+    pub fn mock(handler: impl Fn(&str) -> (u16, String) + Send + Sync + 'static) -> (String, Mock) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let handler: Arc<Handler> = Arc::new(Box::new(handler));
+        {
+            let requests = Arc::clone(&requests);
+            thread::spawn(move || {
+                for stream in listener.incoming().filter_map(|s| s.ok()) {
+                    let (handler, requests) = (Arc::clone(&handler), Arc::clone(&requests));
+                    thread::spawn(move || answer(stream, &handler, &requests));
+                }
+            });
+        }
+        (url, Mock(requests))
+    }
+
+    // `database::test::setup_with`, with the cache emptied and youtube requests sent to `handler`
+    pub fn setup_with(
+        f: impl FnOnce(&mut config::Config),
+        handler: impl Fn(&str) -> (u16, String) + Send + Sync + 'static,
+    ) -> (MutexGuard<'static, ()>, Mock) {
+        let (url, mock) = mock(handler);
+        let guard = database::test::setup_with(|config| {
+            config.youtube_base_url = url;
+            config.youtube_api_key = Some("key".into());
+            config.retry_delay_ms = 1;
+            f(config)
+        });
+        *CACHE.lock().unwrap() = Cache::default();
+        *API_KEYS.lock().unwrap() = KeyPool::new(api_keys().unwrap_or_default());
+        (guard, mock)
+    }
+
+    // every video asked for exists
+    pub fn setup() -> (MutexGuard<'static, ()>, Mock) {
+        setup_with(|_| {}, |path| (200, videos(path, |_| {})))
+    }
+
+    fn answer(stream: TcpStream, handler: &Handler, requests: &Mutex<Vec<String>>) {
+        let mut reader = BufReader::new(&stream);
+        let mut line = String::new();
+        if reader.read_line(&mut line).is_err() {
+            return;
+        }
+        let path = line.split(' ').nth(1).unwrap_or_default().to_string();
+        // the rest of the head isn't needed
+        while reader.read_line(&mut line).map(|n| n > 2).unwrap_or(false) {
+            line.clear();
+        }
+
+        requests.lock().unwrap().push(path.clone());
+        let (status, body) = handler(&path);
+        let _ = write!(
+            &stream,
+            "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+    }
+
+    // the decoded value of `key` in the request's query
+    pub fn param(path: &str, key: &str) -> Option<String> {
+        let (_, query) = path.split_once('?')?;
+        let (_, value) = query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(k, _)| *k == key)?;
+        // `encode` escapes everything outside the unreserved set as `%XX`
+        let (bytes, mut i) = (value.as_bytes(), 0);
+        let mut out = vec![];
+        while i < bytes.len() {
+            if bytes[i] == b'%' {
+                out.push(u8::from_str_radix(&value[i + 1..i + 3], 16).ok()?);
+                i += 3;
+            } else {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+        String::from_utf8(out).ok()
+    }
+
+    // a `videos` response with every id asked for, `edit` can change each one
+    pub fn videos(path: &str, edit: impl Fn(&mut serde_json::Value)) -> String {
+        let ids = param(path, "id").unwrap_or_default();
+        let items = ids
+            .split(',')
+            .filter(|id| !id.is_empty())
+            .map(|id| {
+                let mut item = serde_json::json!({
+                    "id": id,
+                    "snippet": {
+                        "title": format!("title {}", id),
+                        "channelTitle": "channel",
+                        "liveBroadcastContent": "none",
+                        "thumbnails": {"default": {"url": format!("http://i/{}/default.jpg", id)}},
+                    },
+                    "contentDetails": {"duration": "PT3M"},
+                });
+                edit(&mut item);
+                item
+            })
+            .collect::<Vec<_>>();
+        serde_json::json!({ "items": items }).to_string()
+    }
+
+    pub fn item(url: &str) -> server::Item {
+        server::Item {
+            kind: server::ItemKind::Youtube(url.to_string()),
+            ts: 1,
+            version: 1,
+            requested_by: String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test::*;
+    use super::*;
+    use crate::Storage;
+
+    #[test]
+    fn iso8601() {
+        for (period, secs) in &[
+            ("PT0S", 0),
+            ("PT15S", 15),
+            ("PT4M13S", 4 * 60 + 13),
+            ("PT1H", 60 * 60),
+            ("PT1H2M3S", 60 * 60 + 2 * 60 + 3),
+        ] {
+            assert_eq!(from_iso8601(period).unwrap(), *secs, "{}", period);
+        }
+    }
+
+    #[test]
+    fn iso8601_days_weeks_fractions() {
+        const DAY: i64 = 60 * 60 * 24;
+        for (period, secs) in &[
+            ("P1D", DAY),
+            ("P2W", 14 * DAY),
+            ("P1DT2H", DAY + 2 * 60 * 60),
+            ("P1W2DT3M", 9 * DAY + 3 * 60),
+            ("PT30.5S", 30),
+            ("PT1M0.999S", 60),
+        ] {
+            assert_eq!(from_iso8601(period).unwrap(), *secs, "{}", period);
+        }
+    }
+
+    #[test]
+    fn iso8601_malformed() {
+        for period in &[
+            "",
+            "P",
+            "PT",
+            "P1DT",
+            "1H",
+            "PT1X",
+            "PT1H2",
+            "PTS",
+            // overflows an i64
+            "P99999999999999999999W",
+            "P9999999999999999W",
+            // months, and designators out of order or repeated
+            "P1M",
+            "P1MT1S",
+            "PT1S2H",
+            "PT2M1H",
+            "PT1M1M",
+            "P1DT1H1D",
+            "P1D2W",
+            "PT1HT1S",
+            "P1H",
+        ] {
+            match from_iso8601(period) {
+                Err(Error::InvalidDuration(p)) => assert_eq!(p, *period),
+                other => panic!("{:?}: {:?}", period, other.map_err(|e| e.to_string())),
+            }
+        }
+    }
+
+    #[test]
+    fn url_forms() {
+        let id = |url: &str| {
+            PATTERN
+                .captures(url)
+                .and_then(|c| c.name("id"))
+                .map(|id| id.as_str().to_string())
+        };
+        for url in &[
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+            "http://youtube.com/watch?feature=share&v=dQw4w9WgXcQ",
+            "youtube.com/watch?v=dQw4w9WgXcQ&list=PL123",
+            "https://m.youtube.com/watch?v=dQw4w9WgXcQ",
+            "https://youtu.be/dQw4w9WgXcQ?t=10",
+            "https://www.youtube.com/shorts/dQw4w9WgXcQ",
+            "https://www.youtube.com/embed/dQw4w9WgXcQ",
+            "https://www.youtube.com/v/dQw4w9WgXcQ",
+            "https://www.youtube.com/live/dQw4w9WgXcQ",
+            "https://music.youtube.com/watch?v=dQw4w9WgXcQ&feature=share",
+        ] {
+            assert_eq!(id(url).as_deref(), Some("dQw4w9WgXcQ"), "{}", url);
+        }
+        for url in &[
+            "https://vimeo.com/watch?v=dQw4w9WgXcQ",
+            "https://www.youtube.com/channel/UCabcdefghij",
+            "https://youtu.be/short",
+            "dQw4w9WgXcQ",
+        ] {
+            assert_eq!(id(url), None, "{}", url);
+        }
+    }
+
+    #[test]
+    fn start_offsets() {
+        for (start, secs) in &[
+            ("90", 90),
+            ("1m30s", 90),
+            ("1h2m3s", 60 * 60 + 2 * 60 + 3),
+            ("2h", 2 * 60 * 60),
+            ("45s", 45),
+            ("", 0),
+        ] {
+            assert_eq!(parse_start(start), *secs, "{}", start);
+        }
+
+        let start = |url: &str| {
+            START
+                .captures(url)
+                .and_then(|s| s.name("start"))
+                .map(|s| parse_start(s.as_str()))
+        };
+        for (url, secs) in &[
+            ("https://youtu.be/aaaaaaaaaaa?t=1m30s", Some(90)),
+            ("https://www.youtube.com/watch?v=bbbbbbbbbbb&t=42", Some(42)),
+            ("https://www.youtube.com/embed/ccccccccccc?start=7", Some(7)),
+            (
+                "https://www.youtube.com/watch?v=ddddddddddd#t=1h",
+                Some(60 * 60),
+            ),
+            ("https://www.youtube.com/watch?v=eeeeeeeeeee", None),
+        ] {
+            assert_eq!(start(url), *secs, "{}", url);
+        }
+    }
+
+    #[test]
+    fn livestreams() {
+        let body = |live: &str| {
+            serde_json::json!({"items": [{
+                "id": "aaaaaaaaaaa",
+                "snippet": {"title": "title", "liveBroadcastContent": live},
+                "contentDetails": {"duration": "P0D"},
+            }]})
+            .to_string()
+        };
+        for live in &["live", "upcoming"] {
+            let (_, item) = YoutubeItem::serialize(body(live).as_bytes())
+                .unwrap()
+                .remove(0);
+            assert!(matches!(item, Err(Error::LiveStreamNotAllowed)), "{}", live);
+        }
+        let (_, item) = YoutubeItem::serialize(body("none").as_bytes())
+            .unwrap()
+            .remove(0);
+        assert!(item.is_ok());
+    }
+
+    #[test]
+    fn batched_response() {
+        // every video in the response keeps its own id and result
+        let body = serde_json::json!({"items": [
+            {
+                "id": "bbbbbbbbbbb",
+                "snippet": {"title": "b"},
+                "contentDetails": {"duration": "PT1M"},
+            },
+            {
+                "id": "aaaaaaaaaaa",
+                "snippet": {"title": "a"},
+                "contentDetails": {"duration": "PT1X"},
+            },
+        ]})
+        .to_string();
+        let found = YoutubeItem::serialize(body.as_bytes()).unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].0, "bbbbbbbbbbb");
+        assert_eq!(found[0].1.as_ref().unwrap().title, "b");
+        assert_eq!(found[0].1.as_ref().unwrap().duration, 60);
+        assert_eq!(found[1].0, "aaaaaaaaaaa");
+        assert!(matches!(found[1].1, Err(Error::InvalidDuration(..))));
+    }
+
+    #[test]
+    fn cache_evicts_oldest() {
+        let item = |title: &str| YoutubeItem {
+            title: title.into(),
+            channel: "".into(),
+            thumbnail: "".into(),
+            duration: 0,
+            allowed: None,
+            blocked: vec![],
+        };
+        let title = |cache: &Cache<YoutubeItem>, id| cache.get(id).map(|item| item.title);
+
+        let mut cache = Cache::default();
+        for id in &["a", "b", "c"] {
+            cache.insert(id.to_string(), item(id), 2);
+        }
+        assert_eq!(title(&cache, "a"), None);
+        assert_eq!(title(&cache, "b").as_deref(), Some("b"));
+        assert_eq!(title(&cache, "c").as_deref(), Some("c"));
+
+        // replacing an entry doesn't make room
+        cache.insert("b".into(), item("new"), 2);
+        assert_eq!(title(&cache, "b").as_deref(), Some("new"));
+        assert_eq!(title(&cache, "c").as_deref(), Some("c"));
+
+        // a size of 0 turns the cache off
+        let mut cache = Cache::default();
+        cache.insert("a".into(), item("a"), 0);
+        assert_eq!(title(&cache, "a"), None);
+    }
+
+    fn api_failure(reason: &str) -> String {
+        serde_json::json!({"error": {"errors": [{"reason": reason}]}}).to_string()
+    }
+
+    #[test]
+    fn api_errors() {
+        for reason in &["quotaExceeded", "dailyLimitExceeded", "rateLimitExceeded"] {
+            let err = YoutubeItem::api_error(403, "Forbidden", api_failure(reason).as_bytes());
+            assert!(matches!(err, Error::QuotaExceeded), "{}", reason);
+            assert_eq!(err.status(), 429);
+        }
+        let err = YoutubeItem::api_error(403, "Forbidden", api_failure("videoPrivate").as_bytes());
+        assert!(matches!(err, Error::VideoUnavailable(ref reason) if reason == "videoPrivate"));
+        assert_eq!(err.status(), 404);
+        // anything else keeps the status
+        let err = YoutubeItem::api_error(400, "Bad Request", api_failure("badRequest").as_bytes());
+        assert!(matches!(err, Error::HttpResponse(400, ref reason) if reason == "Bad Request"));
+        let err = YoutubeItem::api_error(500, "Internal Server Error", b"not json");
+        assert!(matches!(err, Error::HttpResponse(500, ..)));
+    }
+
+    #[test]
+    fn transient_errors() {
+        for code in &[500, 502, 503] {
+            assert!(
+                is_transient(&Error::HttpResponse(*code, "".into())),
+                "{}",
+                code
+            );
+        }
+        for code in &[400, 403, 404] {
+            assert!(
+                !is_transient(&Error::HttpResponse(*code, "".into())),
+                "{}",
+                code
+            );
+        }
+        assert!(!is_transient(&Error::QuotaExceeded));
+        assert!(!is_transient(&Error::VideoUnavailable(
+            "videoNotFound".into()
+        )));
+    }
+
+    // the item for a response with a single video
+    fn serialized(snippet: serde_json::Value) -> YoutubeItem {
+        let body = serde_json::json!({"items": [{
+            "id": "aaaaaaaaaaa",
+            "snippet": snippet,
+            "contentDetails": {"duration": "PT1M"},
+        }]});
+        let (_, item) = YoutubeItem::serialize(body.to_string().as_bytes())
+            .unwrap()
+            .remove(0);
+        item.unwrap()
+    }
+
+    #[test]
+    fn stores_channel() {
+        let channel = |snippet| serialized(snippet).channel;
+        let snippet = serde_json::json!({"title": "title", "channelTitle": "uploader"});
+        assert_eq!(channel(snippet), "uploader");
+        assert_eq!(channel(serde_json::json!({"title": "title"})), "");
+
+        // from before the column was added
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE youtube_videos (
+                id INTEGER PRIMARY KEY, vid TEXT, ts INTEGER, duration INTEGER, title TEXT,
+                start INTEGER, played INTEGER NOT NULL DEFAULT 0, position INTEGER NOT NULL DEFAULT 0,
+                played_at INTEGER
+            );
+            INSERT INTO youtube_videos VALUES (1, 'aaaaaaaaaaa', 0, 60, 'title', 0, 0, 0, NULL);",
+        )
+        .unwrap();
+        let song = conn
+            .query_row(
+                include_str!("../sql/youtube/get_current.sql"),
+                rusqlite::NO_PARAMS,
+                Song::from_row,
+            )
+            .unwrap();
+        assert_eq!(song.channel, "");
+    }
+
+    #[test]
+    fn thumbnail_resolution() {
+        let thumbnail = |thumbnails| {
+            serialized(serde_json::json!({"title": "title", "thumbnails": thumbnails})).thumbnail
+        };
+        let url = |url: &str| serde_json::json!({ "url": url });
+
+        let all = serde_json::json!({"high": url("h"), "default": url("d"), "medium": url("m")});
+        assert_eq!(thumbnail(all), "m");
+        let no_medium = serde_json::json!({"high": url("h"), "default": url("d")});
+        assert_eq!(thumbnail(no_medium), "d");
+        assert_eq!(thumbnail(serde_json::json!({"maxres": url("x")})), "x");
+        assert_eq!(thumbnail(serde_json::json!({})), "");
+    }
+
+    #[test]
+    fn duplicate_videos_ever() {
+        let _guard = database::test::setup();
+        database::get_connection()
+            .execute_named(
+                include_str!("../sql/youtube/add_video.sql"),
+                &[
+                    (":vid", &"aaaaaaaaaaa"),
+                    (":ts", &0),
+                    (":duration", &60),
+                    (":title", &"title"),
+                    (":start", &0),
+                    (":channel", &""),
+                    (":thumbnail", &""),
+                    (":requested_by", &""),
+                ],
+            )
+            .unwrap();
+
+        // turned away before the lookup, so nothing is asked of the api
+        let item = server::Item {
+            kind: server::ItemKind::Youtube("https://youtu.be/aaaaaaaaaaa".into()),
+            ts: 60 * 60 * 24 * 365,
+            version: 1,
+            requested_by: String::new(),
+        };
+        let res = Youtube.insert(&item, |_, _| Ok(()));
+        assert!(matches!(res.err(), Some(Error::DuplicateSong { vid }) if vid == "aaaaaaaaaaa"));
+    }
+
+    #[test]
+    fn base_url() {
+        let (_guard, mock) = setup_with(
+            |config| config.youtube_base_url = format!("{}/proxy/v3/", config.youtube_base_url),
+            |path| (200, videos(path, |_| {})),
+        );
+        assert!(YoutubeItem::fetch("aaaaaaaaaaa").is_ok());
+        let path = &mock.requests()[0];
+        assert!(path.starts_with("/proxy/v3/videos/?"), "{}", path);
+        assert_eq!(param(path, "key").unwrap(), "key");
+    }
+
+    #[test]
+    fn stored_songs() {
+        let (_guard, _mock) = setup_with(
+            |_| {},
+            |path| {
+                (
+                    200,
+                    videos(path, |item| {
+                        item["snippet"]["channelTitle"] = "uploader".into()
+                    }),
+                )
+            },
+        );
+        for (url, start) in &[
+            ("https://youtu.be/aaaaaaaaaaa?t=1m30s", 90),
+            ("https://www.youtube.com/watch?v=bbbbbbbbbbb&t=42", 42),
+            ("https://www.youtube.com/watch?v=eeeeeeeeeee", 0),
+        ] {
+            Youtube.insert(&item(url), |_, _| Ok(())).unwrap();
+            let song = Youtube.current().unwrap().unwrap();
+            assert_eq!(song.start, *start, "{}", url);
+            assert_eq!(song.channel, "uploader");
+            assert_eq!(song.duration, 3 * 60);
+            Youtube.advance().unwrap();
+        }
+    }
+
+    #[test]
+    fn max_duration() {
+        let (_guard, _mock) = setup_with(
+            |config| config.max_duration_secs = 180,
+            |path| {
+                let long = path.contains("longlonglon");
+                (
+                    200,
+                    videos(path, |item| {
+                        if long {
+                            item["contentDetails"]["duration"] = "PT3M1S".into();
+                        }
+                    }),
+                )
+            },
+        );
+        let err = Youtube
+            .insert(&item("https://youtu.be/longlonglon"), |_, _| Ok(()))
+            .err();
+        assert!(matches!(
+            err,
+            Some(Error::DurationTooLong { got: 181, max: 180 })
+        ));
+        // exactly the limit is fine
+        assert!(Youtube
+            .insert(&item("https://youtu.be/aaaaaaaaaaa"), |_, _| Ok(()))
+            .is_ok());
+
+        let mut config = (*config::get()).clone();
+        config.max_duration_secs = 0;
+        config::set(config);
+        assert!(Youtube
+            .insert(&item("https://youtu.be/longlonglon"), |_, _| Ok(()))
+            .is_ok());
+    }
+
+    #[test]
+    fn livestreams_not_stored() {
+        let (_guard, mock) = setup_with(
+            |_| {},
+            |path| {
+                (
+                    200,
+                    videos(path, |item| {
+                        item["snippet"]["liveBroadcastContent"] = "live".into();
+                        item["contentDetails"]["duration"] = "P0D".into();
+                    }),
+                )
+            },
+        );
+        let err = Youtube
+            .insert(&item("https://youtu.be/liveliveliv"), |_, _| Ok(()))
+            .err();
+        assert!(matches!(err, Some(Error::LiveStreamNotAllowed)));
+        assert!(Youtube.all(None, None).unwrap().is_empty());
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[test]
+    fn batched_lookup() {
+        // answered out of order, without the video that doesn't exist
+        let (_guard, mock) = setup_with(
+            |_| {},
+            |path| {
+                let mut body =
+                    serde_json::from_str::<serde_json::Value>(&videos(path, |_| {})).unwrap();
+                let items = body["items"].as_array_mut().unwrap();
+                items.retain(|item| item["id"] != "missingvid1");
+                items.reverse();
+                (200, body.to_string())
+            },
+        );
+
+        let ids = ["aaaaaaaaaaa", "missingvid1", "bbbbbbbbbbb"];
+        let found = YoutubeItem::fetch_each(&ids).unwrap();
+        assert_eq!(mock.requests().len(), 1);
+        assert_eq!(
+            param(&mock.requests()[0], "id").unwrap(),
+            "aaaaaaaaaaa,missingvid1,bbbbbbbbbbb"
+        );
+
+        let found = found
+            .into_iter()
+            .map(|(id, item)| (id, item.map(|item| item.title)))
+            .collect::<Vec<_>>();
+        assert_eq!(found[0].0, "aaaaaaaaaaa");
+        assert_eq!(found[0].1.as_ref().unwrap(), "title aaaaaaaaaaa");
+        assert_eq!(found[1].0, "missingvid1");
+        match &found[1].1 {
+            Err(Error::VideoUnavailable(reason)) => assert_eq!(reason, "videoNotFound"),
+            other => panic!("{:?}", other.as_ref().map_err(|e| e.to_string())),
+        }
+        assert_eq!(found[2].0, "bbbbbbbbbbb");
+        assert_eq!(found[2].1.as_ref().unwrap(), "title bbbbbbbbbbb");
+    }
+
+    #[test]
+    fn batches_of_fifty() {
+        let (_guard, mock) = setup();
+        let ids = (0..120).map(|i| format!("{:011}", i)).collect::<Vec<_>>();
+        let ids = ids.iter().map(String::as_str).collect::<Vec<_>>();
+        let found = YoutubeItem::fetch_each(&ids).unwrap();
+        assert_eq!(found.len(), 120);
+        assert!(found
+            .iter()
+            .zip(&ids)
+            .all(|((id, item), want)| id == want && item.is_ok()));
+
+        let mut sizes = mock
+            .requests()
+            .iter()
+            .map(|path| param(path, "id").unwrap().split(',').count())
+            .collect::<Vec<_>>();
+        sizes.sort_unstable();
+        assert_eq!(sizes, [20, 50, 50]);
+    }
+
+    #[test]
+    fn cached_lookups() {
+        let (_guard, mock) = setup();
+        YoutubeItem::fetch("aaaaaaaaaaa").unwrap();
+        assert_eq!(
+            YoutubeItem::fetch("aaaaaaaaaaa").unwrap().title,
+            "title aaaaaaaaaaa"
+        );
+        assert_eq!(mock.requests().len(), 1);
+
+        // only the ones that weren't cached are asked for
+        YoutubeItem::fetch_each(&["aaaaaaaaaaa", "bbbbbbbbbbb"]).unwrap();
+        assert_eq!(mock.requests().len(), 2);
+        assert_eq!(param(&mock.requests()[1], "id").unwrap(), "bbbbbbbbbbb");
+    }
+
+    #[test]
+    fn cache_disabled() {
+        let (_guard, mock) = setup_with(
+            |config| config.cache_size = 0,
+            |path| (200, videos(path, |_| {})),
+        );
+        YoutubeItem::fetch("aaaaaaaaaaa").unwrap();
+        YoutubeItem::fetch("aaaaaaaaaaa").unwrap();
+        assert_eq!(mock.requests().len(), 2);
+    }
+
+    #[test]
+    fn quota_from_the_api() {
+        let (_guard, mock) = setup_with(|_| {}, |_| (403, api_failure("dailyLimitExceeded")));
+        let err = Youtube
+            .insert(&item("https://youtu.be/aaaaaaaaaaa"), |_, _| Ok(()))
+            .err();
+        // the only key is spent, and it isn't worth retrying
+        assert!(matches!(err, Some(Error::AllKeysExhausted)));
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    // fails with `status` the first `failures` times
+    fn flaky(failures: usize, status: u16) -> impl Fn(&str) -> (u16, String) + Send + Sync {
+        let seen = std::sync::atomic::AtomicUsize::new(0);
+        move |path| match seen.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+            n if n < failures => (status, "{}".into()),
+            _ => (200, videos(path, |_| {})),
+        }
+    }
+
+    #[test]
+    fn retries_server_errors() {
+        let (_guard, mock) = setup_with(|config| config.retry_count = 2, flaky(2, 503));
+        assert!(YoutubeItem::fetch("aaaaaaaaaaa").is_ok());
+        assert_eq!(mock.requests().len(), 3);
+    }
+
+    #[test]
+    fn gives_up_retrying() {
+        let (_guard, mock) = setup_with(|config| config.retry_count = 2, flaky(3, 500));
+        let err = YoutubeItem::fetch("aaaaaaaaaaa").err();
+        assert!(matches!(err, Some(Error::HttpResponse(500, ..))));
+        assert_eq!(mock.requests().len(), 3);
+    }
+
+    #[test]
+    fn client_errors_not_retried() {
+        let (_guard, mock) = setup_with(|config| config.retry_count = 2, flaky(1, 400));
+        assert!(YoutubeItem::fetch("aaaaaaaaaaa").is_err());
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[test]
+    fn duplicate_videos() {
+        let (_guard, mock) = setup_with(
+            |config| config.dedupe_window_secs = 60,
+            |path| (200, videos(path, |_| {})),
+        );
+        let mut request = item("https://youtu.be/aaaaaaaaaaa");
+        Youtube.insert(&request, |_, _| Ok(())).unwrap();
+
+        let res = Youtube.insert(&request, |_, _| Ok(()));
+        assert!(matches!(res.err(), Some(Error::DuplicateSong { vid }) if vid == "aaaaaaaaaaa"));
+        // turned away before the lookup
+        assert_eq!(mock.requests().len(), 1);
+
+        // outside of the window
+        request.ts += 61;
+        assert!(Youtube.insert(&request, |_, _| Ok(())).is_ok());
+    }
+
+    #[test]
+    fn key_rotation() {
+        let (_guard, mock) = setup_with(
+            |config| config.youtube_api_keys = vec!["second".into(), "third".into()],
+            |path| match param(path, "key").as_deref() {
+                Some("third") => (200, videos(path, |_| {})),
+                _ => (403, api_failure("quotaExceeded")),
+            },
+        );
+        assert!(YoutubeItem::fetch("aaaaaaaaaaa").is_ok());
+        let keys = |mock: &Mock| {
+            mock.requests()
+                .iter()
+                .map(|path| param(path, "key").unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys(&mock), ["key", "second", "third"]);
+
+        // spent keys stay skipped
+        assert!(YoutubeItem::fetch("bbbbbbbbbbb").is_ok());
+        assert_eq!(keys(&mock), ["key", "second", "third", "third"]);
+    }
+
+    #[test]
+    fn key_pool() {
+        let mut pool = KeyPool::new(vec!["a".into(), "b".into()]);
+        assert_eq!(pool.next().unwrap(), (0, "a".to_string()));
+        pool.exhaust(0);
+        assert_eq!(pool.next().unwrap(), (1, "b".to_string()));
+        pool.exhaust(1);
+        assert!(matches!(pool.next(), Err(Error::AllKeysExhausted)));
+
+        // until the quota resets
+        pool.keys[0].1 = Instant::now().checked_sub(KeyPool::QUOTA_RESET);
+        assert_eq!(pool.next().unwrap(), (0, "a".to_string()));
+    }
+
+    #[test]
+    fn percent_encoding() {
+        for (input, expected) in &[
+            ("dQw4w9WgXcQ", "dQw4w9WgXcQ"),
+            ("A-z_0.9~", "A-z_0.9~"),
+            ("a b", "a%20b"),
+            ("a+b", "a%2Bb"),
+            ("a&b=c?d#e/f", "a%26b%3Dc%3Fd%23e%2Ff"),
+            ("100%", "100%25"),
+            ("é", "%C3%A9"),
+            ("日本", "%E6%97%A5%E6%9C%AC"),
+            ("", ""),
+        ] {
+            assert_eq!(encode(input), *expected, "{}", input);
+        }
+    }
+
+    // `n` different videos, requested a second apart from `ts` 100
+    fn queue(n: usize) -> Vec<Song> {
+        for i in 0..n {
+            let mut request = item(&format!("https://youtu.be/video{:06}", i));
+            request.ts = 100 + i as i64;
+            Youtube.insert(&request, |_, _| Ok(())).unwrap();
+        }
+        Youtube.all(None, None).unwrap()
+    }
+
+    #[test]
+    fn delete() {
+        let (_guard, _mock) = setup();
+        let songs = queue(2);
+        assert!(Youtube.delete(songs[0].id).unwrap());
+        assert!(!Youtube.delete(songs[0].id).unwrap());
+        // nothing else went with it
+        let left = Youtube.all(None, None).unwrap();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].id, songs[1].id);
+    }
+
+    #[test]
+    fn pages() {
+        let (_guard, _mock) = setup();
+        queue(5);
+        let vids = |limit, offset| {
+            Youtube
+                .all(limit, offset)
+                .unwrap()
+                .into_iter()
+                .map(|song| song.vid)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(vids(None, None).len(), 5);
+        assert_eq!(vids(Some(2), None), ["video000000", "video000001"]);
+        assert_eq!(vids(Some(2), Some(2)), ["video000002", "video000003"]);
+        assert_eq!(vids(None, Some(4)), ["video000004"]);
+        assert!(vids(Some(2), Some(10)).is_empty());
+    }
+
+    // `search` answers with `found`, or nothing at all
+    fn searching(found: Option<&'static str>) -> impl Fn(&str) -> (u16, String) + Send + Sync {
+        move |path| {
+            if !path.starts_with("/search/") {
+                return (200, videos(path, |_| {}));
+            }
+            let items = found
+                .map(|id| vec![serde_json::json!({ "id": { "videoId": id } })])
+                .unwrap_or_default();
+            (200, serde_json::json!({ "items": items }).to_string())
+        }
+    }
+
+    #[test]
+    fn search_fallback() {
+        let (_guard, mock) = setup_with(
+            |config| config.allow_search = true,
+            searching(Some("bbbbbbbbbbb")),
+        );
+        Youtube
+            .insert(&item("never gonna give you up"), |_, _| Ok(()))
+            .unwrap();
+        assert_eq!(Youtube.current().unwrap().unwrap().vid, "bbbbbbbbbbb");
+        let requests = mock.requests();
+        assert_eq!(
+            param(&requests[0], "q").as_deref(),
+            Some("never gonna give you up")
+        );
+        assert_eq!(param(&requests[0], "maxResults").as_deref(), Some("1"));
+        assert!(requests[1].starts_with("/videos/"));
+    }
+
+    #[test]
+    fn search_without_results() {
+        let (_guard, _mock) = setup_with(|config| config.allow_search = true, searching(None));
+        let res = Youtube.insert(&item("nothing like this"), |_, _| Ok(()));
+        assert!(
+            matches!(res.err(), Some(Error::NoSearchResults(ref q)) if q == "nothing like this")
+        );
+    }
+
+    #[test]
+    fn search_disabled() {
+        let (_guard, mock) = setup_with(|_| {}, searching(Some("bbbbbbbbbbb")));
+        let res = Youtube.insert(&item("never gonna give you up"), |_, _| Ok(()));
+        assert!(matches!(res.err(), Some(Error::InvalidYoutubeUrl(..))));
+        assert!(mock.requests().is_empty());
+    }
+
+    #[test]
+    fn blacklisted_before_lookup() {
+        let (_guard, mock) = setup();
+        let entry = blacklist::Entry {
+            kind: blacklist::EntryKind::Video,
+            value: "dQw4w9WgXcQ".into(),
+            ts: 0,
+        };
+        blacklist::add(&entry).unwrap();
+
+        let item = item("https://www.youtube.com/watch?v=dQw4w9WgXcQ");
+        match Youtube.insert(&item, |_, _| Ok(())).map(|_| ()) {
+            Err(Error::Blacklisted(vid)) => assert_eq!(vid, "dQw4w9WgXcQ"),
+            other => panic!("{:?}", other.map_err(|e| e.to_string())),
+        }
+        assert!(mock.requests().is_empty());
+
+        // anything else is still looked up
+        Youtube
+            .insert(&self::item("https://youtu.be/aaaaaaaaaaa"), |_, _| Ok(()))
+            .unwrap();
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[test]
+    fn watch_urls() {
+        assert_eq!(watch_url("aaaaaaaaaaa", 0), "https://youtu.be/aaaaaaaaaaa");
+        assert_eq!(
+            watch_url("aaaaaaaaaaa", 90),
+            "https://youtu.be/aaaaaaaaaaa?t=90"
+        );
+
+        let (_guard, _mock) = setup();
+        Youtube
+            .insert(
+                &item("https://www.youtube.com/watch?v=aaaaaaaaaaa&t=1m30s"),
+                |_, _| Ok(()),
+            )
+            .unwrap();
+        let json = serde_json::to_value(Youtube.current().unwrap().unwrap()).unwrap();
+        assert_eq!(json["url"], "https://youtu.be/aaaaaaaaaaa?t=90");
+        assert_eq!(json["start"], 90);
+    }
+
+    #[test]
+    fn api_key_sources() {
+        let _guard = database::test::setup_with(|config| {
+            config.youtube_api_key = Some("config".into());
+            config.youtube_api_keys = vec!["more".into(), "".into()];
+        });
+        assert_eq!(api_keys().unwrap(), ["config", "more"]);
+
+        // the env var wins over the config
+        std::env::set_var(YOUTUBE_API_KEY, "env");
+        let keys = api_keys();
+        std::env::remove_var(YOUTUBE_API_KEY);
+        assert_eq!(keys.unwrap(), ["env", "more"]);
+
+        config::set(config::Config::default());
+        assert!(matches!(api_keys(), Err(Error::YoutubeNotConfigured)));
+        assert_eq!(Error::YoutubeNotConfigured.status(), 503);
+    }
+
+    #[test]
+    fn requests_without_a_key() {
+        let (_guard, mock) = setup();
+        *API_KEYS.lock().unwrap() = KeyPool::new(vec![]);
+        let res = Youtube.insert(&item("https://youtu.be/aaaaaaaaaaa"), |_, _| Ok(()));
+        assert!(matches!(res.err(), Some(Error::YoutubeNotConfigured)));
+        assert!(mock.requests().is_empty());
+    }
+
+    #[test]
+    fn age_restricted() {
+        let (_guard, _mock) = setup_with(
+            |_| {},
+            |path| {
+                let body = videos(path, |item| {
+                    if item["id"] == "aaaaaaaaaaa" {
+                        item["contentDetails"]["contentRating"] =
+                            serde_json::json!({"ytRating": "ytAgeRestricted"});
+                    }
+                });
+                (200, body)
+            },
+        );
+        assert!(matches!(
+            YoutubeItem::fetch("aaaaaaaaaaa").err(),
+            Some(Error::AgeRestricted)
+        ));
+        assert_eq!(
+            YoutubeItem::fetch("bbbbbbbbbbb").unwrap().title,
+            "title bbbbbbbbbbb"
+        );
+    }
+
+    #[test]
+    fn region_restrictions() {
+        let restricted = |path: &str| {
+            let body = videos(path, |item| {
+                let region = match item["id"].as_str().unwrap() {
+                    "onlyinusaa1" => serde_json::json!({"allowed": ["US"]}),
+                    "notinusaaa1" => serde_json::json!({"blocked": ["us", "CA"]}),
+                    _ => return,
+                };
+                item["contentDetails"]["regionRestriction"] = region;
+            });
+            (200, body)
+        };
+        let blocked = |id| match YoutubeItem::fetch(id).err() {
+            Some(Error::RegionBlocked(region)) => Some(region),
+            Some(err) => panic!("{}", err),
+            None => None,
+        };
+
+        let (guard, _mock) = setup_with(|config| config.region = Some("us".into()), restricted);
+        assert_eq!(blocked("onlyinusaa1"), None);
+        assert_eq!(blocked("notinusaaa1").as_deref(), Some("US"));
+        assert_eq!(blocked("anywhereaa1"), None);
+        drop(guard);
+
+        let (guard, _mock) = setup_with(|config| config.region = Some("GB".into()), restricted);
+        assert_eq!(blocked("onlyinusaa1").as_deref(), Some("GB"));
+        assert_eq!(blocked("notinusaaa1"), None);
+        drop(guard);
+
+        // without a region nothing is checked
+        let (_guard, _mock) = setup_with(|_| {}, restricted);
+        assert_eq!(blocked("onlyinusaa1"), None);
+        assert_eq!(blocked("notinusaaa1"), None);
+    }
+
+    #[test]
+    fn duplicate_titles() {
+        let titles = |path: &str| {
+            let body = videos(path, |item| {
+                let title = match item["id"].as_str().unwrap() {
+                    "aaaaaaaaaaa" => "Artist - Song (Official Video)",
+                    "bbbbbbbbbbb" => "artist song [HD]",
+                    _ => "artist other song",
+                };
+                item["snippet"]["title"] = title.into();
+            });
+            (200, body)
+        };
+        let (guard, _mock) = setup_with(|_| {}, titles);
+        Youtube
+            .insert(&item("https://youtu.be/aaaaaaaaaaa"), |_, _| Ok(()))
+            .unwrap();
+        // off unless asked for
+        assert!(Youtube
+            .validate(&item("https://youtu.be/bbbbbbbbbbb"))
+            .is_ok());
+        drop(guard);
+
+        let (_guard, _mock) = setup_with(|config| config.dedupe_titles = true, titles);
+        Youtube
+            .insert(&item("https://youtu.be/aaaaaaaaaaa"), |_, _| Ok(()))
+            .unwrap();
+        assert!(matches!(
+            Youtube
+                .validate(&item("https://youtu.be/bbbbbbbbbbb"))
+                .err(),
+            Some(Error::DuplicateTitle(..))
+        ));
+        assert!(Youtube
+            .validate(&item("https://youtu.be/ccccccccccc"))
+            .is_ok());
+
+        // played songs don't count
+        Youtube.advance().unwrap();
+        assert!(Youtube
+            .validate(&item("https://youtu.be/bbbbbbbbbbb"))
+            .is_ok());
+    }
+
+    #[test]
+    fn fetches_overlap() {
+        const DELAY: Duration = Duration::from_millis(500);
+        let (_guard, mock) = setup_with(
+            |_| {},
+            |path| {
+                thread::sleep(DELAY);
+                (200, videos(path, |_| {}))
+            },
+        );
+
+        let start = Instant::now();
+        let (a, b) = (
+            YoutubeItem::fetch_async("aaaaaaaaaaa"),
+            YoutubeItem::fetch_async("bbbbbbbbbbb"),
+        );
+        assert_eq!(a.wait().unwrap().title, "title aaaaaaaaaaa");
+        assert_eq!(b.wait().unwrap().title, "title bbbbbbbbbbb");
+        assert_eq!(mock.requests().len(), 2);
+        assert!(start.elapsed() < DELAY * 2, "{:?}", start.elapsed());
+    }
+
+    #[test]
+    fn fetch_async_errors() {
+        const DELAY: Duration = Duration::from_millis(300);
+        let (_guard, _mock) = setup_with(
+            |_| {},
+            |_| {
+                thread::sleep(DELAY);
+                (200, r#"{"items":[]}"#.to_string())
+            },
+        );
+
+        // handed back right away, the lookup hasn't finished
+        let start = Instant::now();
+        let fetch = YoutubeItem::fetch_async("aaaaaaaaaaa");
+        assert!(start.elapsed() < DELAY, "{:?}", start.elapsed());
+        assert!(matches!(
+            fetch.wait().err(),
+            Some(Error::VideoUnavailable(..))
+        ));
+        assert!(start.elapsed() >= DELAY);
+    }
+
+    #[test]
+    fn lookup_times_out() {
+        let (_guard, mock) = setup_with(
+            |config| {
+                config.youtube_timeout_secs = 1;
+                config.retry_count = 0;
+            },
+            |path| {
+                thread::sleep(Duration::from_secs(3));
+                (200, videos(path, |_| {}))
+            },
+        );
+        let start = Instant::now();
+        assert!(matches!(
+            YoutubeItem::fetch("aaaaaaaaaaa").err(),
+            Some(Error::Timeout)
+        ));
+        assert!(start.elapsed() < Duration::from_secs(3));
+        assert_eq!(mock.requests().len(), 1);
+        assert_eq!(Error::Timeout.status(), 504);
+    }
+
+    #[test]
+    fn sanitized_titles() {
+        let (_guard, _mock) = setup_with(
+            |config| config.max_title_len = 12,
+            |path| {
+                let body = videos(path, |item| {
+                    item["snippet"]["title"] = "Artist\n -\u{7}  Song (official video)".into()
+                });
+                (200, body)
+            },
+        );
+        let song = Youtube
+            .insert(&item("https://youtu.be/aaaaaaaaaaa"), |_, _| Ok(()))
+            .unwrap();
+        assert_eq!(song.title, "Artist - Son");
+        assert_eq!(Youtube.get(song.id).unwrap().unwrap().title, "Artist - Son");
+    }
+
+    #[test]
+    fn shared_lookups() {
+        let (_guard, mock) = setup_with(
+            |_| {},
+            |path| {
+                thread::sleep(Duration::from_millis(300));
+                match param(path, "id").as_deref() {
+                    Some("ccccccccccc") => (200, r#"{"items":[]}"#.into()),
+                    _ => (200, videos(path, |_| {})),
+                }
+            },
+        );
+
+        let (a, b) = (
+            YoutubeItem::fetch_async("aaaaaaaaaaa"),
+            YoutubeItem::fetch_async("aaaaaaaaaaa"),
+        );
+        assert_eq!(a.wait().unwrap().title, "title aaaaaaaaaaa");
+        assert_eq!(b.wait().unwrap().title, "title aaaaaaaaaaa");
+        assert_eq!(mock.requests().len(), 1);
+
+        // an error can't be handed to both, so the second one asks again
+        let (a, b) = (
+            YoutubeItem::fetch_async("ccccccccccc"),
+            YoutubeItem::fetch_async("ccccccccccc"),
+        );
+        assert!(matches!(a.wait().err(), Some(Error::VideoUnavailable(..))));
+        assert!(matches!(b.wait().err(), Some(Error::VideoUnavailable(..))));
+        assert_eq!(mock.requests().len(), 3);
+
+        // nothing is left waiting once they're done
+        assert!(IN_FLIGHT.lock().unwrap().is_empty());
+    }
+}